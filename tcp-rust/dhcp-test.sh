@@ -0,0 +1,21 @@
+#!/bin/bash
+# Run the stack in TAP mode and serve it an address with dnsmasq from a network namespace
+
+PKG_NAME=tcp-rust
+NETNS=tcp-rust-dhcp
+
+./target/release/$PKG_NAME --tap&
+pid=$!
+sleep 0.5
+
+sudo ip netns add $NETNS
+sudo ip link set tap0 netns $NETNS
+sudo ip netns exec $NETNS ip addr add 10.0.0.1/24 dev tap0
+sudo ip netns exec $NETNS ip link set up dev tap0
+# Leases last 2 minutes, the minimum dnsmasq allows, so that renewal can be observed
+sudo ip netns exec $NETNS dnsmasq --no-daemon --port=0 --interface=tap0 --bind-interfaces \
+    --dhcp-range=10.0.0.10,10.0.0.50,2m&
+dnsmasq_pid=$!
+
+trap "kill $pid; sudo kill $dnsmasq_pid; sudo ip netns del $NETNS" INT TERM
+wait $pid
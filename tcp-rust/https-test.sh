@@ -0,0 +1,16 @@
+#!/bin/bash
+# Serve HTTPS from the stack with a throwaway self-signed certificate, then fetch the page with curl
+
+PKG_NAME=tcp-rust
+
+dir=$(mktemp -d)
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -days 1 \
+    -subj /CN=localhost -keyout $dir/key.pem -out $dir/cert.pem 2>/dev/null
+
+./target/release/$PKG_NAME --tls $dir/cert.pem $dir/key.pem&
+pid=$!
+sleep 0.5
+trap "kill $pid; rm -r $dir" EXIT
+make ip
+
+curl -k https://192.168.0.2/
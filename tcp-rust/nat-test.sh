@@ -0,0 +1,25 @@
+#!/bin/bash
+# Translate the flows from one network namespace to another through the stack, with a port forward back
+
+PKG_NAME=tcp-rust
+
+./target/release/$PKG_NAME --address 192.168.1.2/24 --address 192.168.2.2/24 --nat 1 --forward tcp:8080=192.168.1.1:8000&
+pid=$!
+sleep 0.5
+trap "kill $pid; sudo ip netns pids tcp-rust-a | xargs kill; sudo ip netns pids tcp-rust-b | xargs kill; sudo ip netns del tcp-rust-a; sudo ip netns del tcp-rust-b" EXIT
+
+for i in 0 1; do
+    netns=tcp-rust-$([ $i = 0 ] && echo a || echo b)
+    sudo ip netns add $netns
+    sudo ip link set tun$i netns $netns
+    sudo ip netns exec $netns ip addr add 192.168.$((i + 1)).1/24 dev tun$i
+    sudo ip netns exec $netns ip link set up dev tun$i
+    sudo ip netns exec $netns ip route add default dev tun$i
+    sudo ip netns exec $netns python3 -m http.server 8000 --bind 192.168.$((i + 1)).1&
+done
+sleep 1
+
+# The server in b sees the request coming from 192.168.2.2
+sudo ip netns exec tcp-rust-a curl -s -o /dev/null http://192.168.2.1:8000/
+# And reaches the server in a through the forwarded port
+sudo ip netns exec tcp-rust-b curl -s -o /dev/null http://192.168.2.2:8080/
@@ -0,0 +1,20 @@
+#!/bin/bash
+# Route between two network namespaces through the stack, then traceroute across it
+
+PKG_NAME=tcp-rust
+
+./target/release/$PKG_NAME --address 192.168.1.2/24 --address 192.168.2.2/24&
+pid=$!
+sleep 0.5
+trap "kill $pid; sudo ip netns del tcp-rust-a; sudo ip netns del tcp-rust-b" EXIT
+
+for i in 0 1; do
+    netns=tcp-rust-$([ $i = 0 ] && echo a || echo b)
+    sudo ip netns add $netns
+    sudo ip link set tun$i netns $netns
+    sudo ip netns exec $netns ip addr add 192.168.$((i + 1)).1/24 dev tun$i
+    sudo ip netns exec $netns ip link set up dev tun$i
+    sudo ip netns exec $netns ip route add default dev tun$i
+done
+
+sudo ip netns exec tcp-rust-a traceroute -n 192.168.2.1
@@ -0,0 +1,310 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use crate::{
+    ethernet::{ETHERTYPE_ARP, ETHERTYPE_IPV4, MacAddr},
+    interface::Interface,
+    ip::IpV4Addr,
+    packet::ParseHeaderError,
+    traits::{AsArrayUnchecked, Data, Prepare, ToMutable, WriteTo},
+};
+
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+/// Time a link address is used without hearing from its owner again, after which it is asked
+/// anew in case the address moved to another host
+pub const ENTRY_LIFETIME: Duration = Duration::from_secs(60);
+
+/// ARP for IPv4 over Ethernet, the only combination the stack speaks
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ARPHeaderView<'a> {
+    content: &'a [u8],
+}
+
+impl ToMutable for ARPHeaderView<'_> {
+    type MutableType = ARPHeader;
+
+    fn to_mutable(&self) -> Self::MutableType {
+        ARPHeader {
+            operation: self.get_operation(),
+            sender_mac: self.get_sender_mac(),
+            sender_address: self.get_sender_address(),
+            target_mac: self.get_target_mac(),
+            target_address: self.get_target_address(),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for ARPHeaderView<'a> {
+    type Error = ParseHeaderError;
+
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        Self::parse(value)
+    }
+}
+
+impl<'a> AsRef<[u8]> for ARPHeaderView<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.content
+    }
+}
+
+impl Data for ARPHeaderView<'_> {
+    fn size(&self) -> usize {
+        28
+    }
+}
+
+impl<'a> ARPHeaderView<'a> {
+    pub const SIZE: usize = 28;
+
+    /// Parse a header, failing if the slice is too short to hold it
+    pub fn parse(value: &'a [u8]) -> Result<Self, ParseHeaderError> {
+        match value.get(..Self::SIZE) {
+            Some(content) => Ok(Self { content }),
+            None => Err(ParseHeaderError),
+        }
+    }
+    pub fn get_hardware_type(&self) -> u16 {
+        u16::from_be_bytes(*unsafe { self.content[0..2].as_array_unchecked() })
+    }
+    pub fn get_protocol_type(&self) -> u16 {
+        u16::from_be_bytes(*unsafe { self.content[2..4].as_array_unchecked() })
+    }
+    pub fn get_operation(&self) -> u16 {
+        u16::from_be_bytes(*unsafe { self.content[6..8].as_array_unchecked() })
+    }
+    pub fn get_sender_mac(&self) -> MacAddr {
+        MacAddr(*unsafe { self.content[8..14].as_array_unchecked() })
+    }
+    pub fn get_sender_address(&self) -> IpV4Addr {
+        u32::from_be_bytes(*unsafe { self.content[14..18].as_array_unchecked() }).into()
+    }
+    pub fn get_target_mac(&self) -> MacAddr {
+        MacAddr(*unsafe { self.content[18..24].as_array_unchecked() })
+    }
+    pub fn get_target_address(&self) -> IpV4Addr {
+        u32::from_be_bytes(*unsafe { self.content[24..28].as_array_unchecked() }).into()
+    }
+}
+
+impl Debug for ARPHeaderView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ARPHeaderView")
+            .field("operation", &self.get_operation())
+            .field("sender_mac", &self.get_sender_mac())
+            .field("sender_address", &self.get_sender_address())
+            .field("target_mac", &self.get_target_mac())
+            .field("target_address", &self.get_target_address())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct ARPHeader {
+    pub operation: u16,
+    pub sender_mac: MacAddr,
+    pub sender_address: IpV4Addr,
+    pub target_mac: MacAddr,
+    pub target_address: IpV4Addr,
+}
+
+impl Prepare for ARPHeader {}
+impl Data for ARPHeader {
+    fn size(&self) -> usize {
+        28
+    }
+}
+impl WriteTo for ARPHeader {
+    fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        // Ethernet hardware, IPv4 protocol, with their address lengths
+        writer.write_all(&[0, 1])?;
+        writer.write_all(&u16::to_be_bytes(ETHERTYPE_IPV4))?;
+        writer.write_all(&[6, 4])?;
+        writer.write_all(&u16::to_be_bytes(self.operation))?;
+        writer.write_all(&self.sender_mac.0)?;
+        writer.write_all(&u32::to_be_bytes(self.sender_address.0))?;
+        writer.write_all(&self.target_mac.0)?;
+        writer.write_all(&u32::to_be_bytes(self.target_address.0))?;
+        Ok(self.size())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ARPManager {
+    /// Link address of each neighbour, with the time it was learned
+    pub cache: HashMap<IpV4Addr, (MacAddr, Instant)>,
+}
+
+impl ARPManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn lookup(&self, address: IpV4Addr) -> Option<MacAddr> {
+        self.cache
+            .get(&address)
+            .filter(|(_, learned)| learned.elapsed() < ENTRY_LIFETIME)
+            .map(|(mac, _)| *mac)
+    }
+
+    /// Ask the link who owns `address`, the answer is learned by `handle_arp_packet`
+    pub fn request(&self, interface: &mut Interface<impl Read + Write>, address: IpV4Addr) {
+        let Some(sender_address) = interface.config.address else {
+            return;
+        };
+        let request = ARPHeader {
+            operation: ARP_REQUEST,
+            sender_mac: interface.config.mac,
+            sender_address,
+            target_mac: MacAddr::default(),
+            target_address: address,
+        };
+        interface.write_frame(MacAddr::BROADCAST, ETHERTYPE_ARP, request);
+        interface.send();
+    }
+
+    pub fn handle_arp_packet(&mut self, interface: &mut Interface<impl Read + Write>) {
+        let Ok(request) = interface.try_get_packet::<ARPHeaderView>() else {
+            debug!("truncated ARP packet dropped");
+            return;
+        };
+        if request.get_hardware_type() != 1 || request.get_protocol_type() != ETHERTYPE_IPV4 {
+            return;
+        }
+        self.cache
+            .retain(|_, (_, learned)| learned.elapsed() < ENTRY_LIFETIME);
+        self.cache.insert(
+            request.get_sender_address(),
+            (request.get_sender_mac(), Instant::now()),
+        );
+
+        if request.get_operation() != ARP_REQUEST
+            || interface.config.address != Some(request.get_target_address())
+        {
+            return;
+        }
+        let response = ARPHeader {
+            operation: ARP_REPLY,
+            sender_mac: interface.config.mac,
+            sender_address: request.get_target_address(),
+            target_mac: request.get_sender_mac(),
+            target_address: request.get_sender_address(),
+        };
+        interface.write(response);
+        interface.send();
+        debug!("answered an ARP request");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support::Wire, tun_tap::Mode};
+
+    const STACK: IpV4Addr = IpV4Addr(0xC0A8_0002);
+    const HOST: IpV4Addr = IpV4Addr(0xC0A8_0001);
+    const HOST_MAC: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 0x10]);
+
+    fn receive(manager: &mut ARPManager, interface: &mut Interface<Wire>, header: ARPHeader) {
+        let ethernet_header = crate::ethernet::EthernetHeader {
+            destination: MacAddr::BROADCAST,
+            source: header.sender_mac,
+            ethertype: ETHERTYPE_ARP,
+        };
+        let frame = [
+            &[0, 0, 8, 6][..],
+            &crate::packet::Packet::new(ethernet_header, header)
+                .to_bytes()
+                .unwrap(),
+        ]
+        .concat();
+        interface.receive_frame(&frame);
+        manager.handle_arp_packet(interface);
+    }
+
+    #[test]
+    fn request_answered_and_learned() {
+        let wire = Wire::default();
+        let mut interface = Interface::with_mode(wire.clone(), Mode::Tap);
+        interface.config.address = Some(STACK);
+        let mut manager = ARPManager::new();
+        let request = ARPHeader {
+            operation: ARP_REQUEST,
+            sender_mac: HOST_MAC,
+            sender_address: HOST,
+            target_mac: MacAddr::default(),
+            target_address: STACK,
+        };
+        receive(&mut manager, &mut interface, request);
+        assert_eq!(manager.lookup(HOST), Some(HOST_MAC));
+
+        let sent = wire.sent.borrow_mut().pop().unwrap();
+        let reply = ARPHeaderView::parse(&sent[18..]).unwrap();
+        assert_eq!(reply.get_operation(), ARP_REPLY);
+        assert_eq!(
+            (reply.get_sender_mac(), reply.get_sender_address()),
+            (interface.config.mac, STACK)
+        );
+        assert_eq!(
+            (reply.get_target_mac(), reply.get_target_address()),
+            (HOST_MAC, HOST)
+        );
+
+        // Requests for other hosts are not answered, but tell the sender's address
+        let other = IpV4Addr(0xC0A8_0003);
+        receive(
+            &mut manager,
+            &mut interface,
+            ARPHeader {
+                sender_address: other,
+                target_address: HOST,
+                ..request
+            },
+        );
+        assert!(wire.sent.borrow().is_empty());
+        assert_eq!(manager.lookup(other), Some(HOST_MAC));
+    }
+
+    #[test]
+    fn reply_learned_then_expired() {
+        let wire = Wire::default();
+        let mut interface = Interface::with_mode(wire.clone(), Mode::Tap);
+        interface.config.address = Some(STACK);
+        let mut manager = ARPManager::new();
+        manager.request(&mut interface, HOST);
+        let sent = wire.sent.borrow_mut().pop().unwrap();
+        assert_eq!(sent[4..10], MacAddr::BROADCAST.0);
+        assert_eq!(
+            ARPHeaderView::parse(&sent[18..])
+                .unwrap()
+                .get_target_address(),
+            HOST
+        );
+
+        let mac = interface.config.mac;
+        receive(
+            &mut manager,
+            &mut interface,
+            ARPHeader {
+                operation: ARP_REPLY,
+                sender_mac: HOST_MAC,
+                sender_address: HOST,
+                target_mac: mac,
+                target_address: STACK,
+            },
+        );
+        assert_eq!(manager.lookup(HOST), Some(HOST_MAC));
+        assert!(wire.sent.borrow().is_empty());
+
+        let learned = &mut manager.cache.get_mut(&HOST).unwrap().1;
+        *learned -= ENTRY_LIFETIME;
+        assert_eq!(manager.lookup(HOST), None);
+    }
+}
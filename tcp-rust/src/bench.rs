@@ -0,0 +1,237 @@
+//! Throughput of the stack on an in-memory device, run with `cargo bench`.
+//!
+//! Each iteration of the echo benchmarks receives and answers one packet, so the packets per second
+//! are 10^9 divided by the reported nanoseconds per iteration. The worker benchmarks serve the
+//! same HTTP requests with more and more threads, their time per iteration falling with the cores,
+//! and the cost of handing the segments over to another thread is that of the `handed_over` ones.
+
+extern crate test;
+
+use std::{
+    io::{self, Read, Write},
+    thread,
+};
+
+use test::Bencher;
+
+use crate::{
+    buffer::BufferPool,
+    icmp::{ECHO_REPLY, ECHO_REQUEST, ICMPHeader, ICMPPacket, ICMPPacketView},
+    interface::Interface,
+    ip::{IPV4Header, IPV4Packet, IPV4PacketView, IpProtocol, IpV4Addr},
+    tcp::{TCPHeader, TCPPacket, manager::ConnectionId},
+    traits::{ToMutable, WriteTo},
+    worker::Worker,
+};
+
+/// Requests served by each iteration of the worker benchmarks
+const FLOWS: u16 = 512;
+
+/// Device receiving the same packets over and over, and discarding what is sent
+struct Replay {
+    packets: Vec<Vec<u8>>,
+    next: usize,
+}
+
+impl Replay {
+    fn new(packets: Vec<Vec<u8>>) -> Self {
+        Self { packets, next: 0 }
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = &self.packets[self.next];
+        self.next = (self.next + 1) % self.packets.len();
+        buf[..packet.len()].copy_from_slice(packet);
+        Ok(packet.len())
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(test::black_box(buf).len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn echo_request_interface() -> Interface<Replay> {
+    let request = IPV4Packet::new(
+        IPV4Header::new(IpProtocol::Icmp, IpV4Addr(0xC0A80001), IpV4Addr(0xC0A80002)),
+        ICMPPacket::new(ICMPHeader::new(ECHO_REQUEST, 0), vec![0x42; 60]),
+    );
+    Interface::new(Replay::new(vec![with_packet_info(request)]))
+}
+
+/// An IPv4 packet behind the packet information header of the TUN device
+fn with_packet_info(mut packet: impl WriteTo) -> Vec<u8> {
+    let mut bytes = vec![0, 0, 8, 0];
+    bytes.extend(packet.to_bytes().unwrap());
+    bytes
+}
+
+/// The segments of a client fetching a page: its SYN, the end of the handshake, the request along
+/// with its FIN, then a RST so that the connection can be opened again by the next round
+fn http_flow(peer_port: u16) -> (ConnectionId, Vec<Vec<u8>>) {
+    let id = ConnectionId {
+        local_address: IpV4Addr(0xC0A80002),
+        local_port: 80,
+        peer_address: IpV4Addr(0xC0A80001),
+        peer_port,
+    };
+    let request = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+    // The stack always starts its sequence numbers there
+    let server_sequence_number = 3453253245u32.wrapping_add(1);
+    let segment = |header: TCPHeader, payload: &[u8]| {
+        with_packet_info(IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Tcp, id.peer_address, id.local_address),
+            TCPPacket::new(
+                TCPHeader {
+                    source_port: id.peer_port,
+                    destination_port: id.local_port,
+                    window: 65535,
+                    ..header
+                },
+                payload.to_vec(),
+            ),
+        ))
+    };
+    let established = TCPHeader {
+        sequence_number: 1001,
+        acknowledgement_number: server_sequence_number,
+        ack: true,
+        ..Default::default()
+    };
+    let segments = vec![
+        segment(
+            TCPHeader {
+                sequence_number: 1000,
+                syn: true,
+                ..Default::default()
+            },
+            &[],
+        ),
+        segment(established.clone(), &[]),
+        segment(
+            TCPHeader {
+                psh: true,
+                fin: true,
+                ..established.clone()
+            },
+            request,
+        ),
+        segment(
+            TCPHeader {
+                sequence_number: 1001 + request.len() as u32 + 1,
+                rst: true,
+                ..established
+            },
+            &[],
+        ),
+    ];
+    (id, segments)
+}
+
+/// Serve `FLOWS` requests with `workers` threads, built once for all the iterations. Each worker
+/// receives the flows of its shard, unless they are `handed_over`, when each queue receives the
+/// flows of the previous shard and hands all its segments over to their owner.
+fn http_requests(b: &mut Bencher, workers: usize, handed_over: bool) {
+    let mut queues = vec![vec![]; workers];
+    for peer_port in 0..FLOWS {
+        let (id, segments) = http_flow(10000 + peer_port);
+        let shard = id.shard(workers);
+        let queue = if handed_over {
+            (shard + 1) % workers
+        } else {
+            shard
+        };
+        queues[queue].extend(segments);
+    }
+    let lengths: Vec<_> = queues.iter().map(Vec::len).collect();
+    // The segments each worker is handed over are those read by the next one
+    let handovers: Vec<_> = (0..workers)
+        .map(|index| match handed_over {
+            true => lengths[(index + 1) % workers],
+            false => 0,
+        })
+        .collect();
+    let interfaces = queues
+        .into_iter()
+        .map(|packets| Interface::new(Replay::new(packets)))
+        .collect();
+    let mut workers = Worker::new_group(interfaces).unwrap();
+    b.iter(|| {
+        thread::scope(|scope| {
+            for ((worker, &length), &handovers) in workers.iter_mut().zip(&lengths).zip(&handovers)
+            {
+                scope.spawn(move || {
+                    for _ in 0..length {
+                        worker.interface.receive();
+                        worker.handle_packet();
+                    }
+                    let mut handled = 0;
+                    while handled < handovers {
+                        handled += worker.handle_handovers();
+                        thread::yield_now();
+                    }
+                });
+            }
+        })
+    });
+}
+
+#[bench]
+fn http_requests_1_worker(b: &mut Bencher) {
+    http_requests(b, 1, false);
+}
+
+#[bench]
+fn http_requests_2_workers(b: &mut Bencher) {
+    http_requests(b, 2, false);
+}
+
+#[bench]
+fn http_requests_4_workers(b: &mut Bencher) {
+    http_requests(b, 4, false);
+}
+
+#[bench]
+fn http_requests_2_workers_handed_over(b: &mut Bencher) {
+    http_requests(b, 2, true);
+}
+
+#[bench]
+fn http_requests_4_workers_handed_over(b: &mut Bencher) {
+    http_requests(b, 4, true);
+}
+
+/// Reply built by copying the request into owned packets, as every answer used to be
+#[bench]
+fn echo_reply_owned(b: &mut Bencher) {
+    let mut interface = echo_request_interface();
+    b.iter(|| {
+        interface.receive();
+        let mut ip_response = interface
+            .try_get_packet::<IPV4PacketView<ICMPPacketView>>()
+            .unwrap()
+            .to_mutable();
+        ip_response.header.answer();
+        ip_response.payload.header.message_type = ECHO_REPLY;
+        interface.write(ip_response);
+        interface.send();
+    });
+}
+
+#[bench]
+fn echo_reply_pooled(b: &mut Bencher) {
+    let mut interface = echo_request_interface();
+    let mut pool = BufferPool::new();
+    b.iter(|| {
+        interface.receive();
+        crate::icmp::reply_to_echo(&mut interface, &mut pool);
+    });
+    assert_eq!(pool.allocated, 1);
+}
@@ -0,0 +1,196 @@
+use std::io;
+
+use crate::{
+    checksum::Checksum,
+    ip::{IPV4Header, TransportChecksum},
+    traits::{Data, WriteTo},
+};
+
+/// Room kept in front of the payload for the headers: packet information, Ethernet, and IPv4
+/// and TCP headers with all their options
+pub const HEADROOM: usize = 4 + 14 + 60 + 60;
+/// Headroom and a 1500 bytes MTU
+pub const BUFFER_SIZE: usize = HEADROOM + 1500;
+
+/// A packet built from its payload outward, each header being written in place in front of the
+/// previous one, so that nothing is copied once the payload is in.
+#[derive(Debug, Clone)]
+pub struct PacketBuffer {
+    data: Box<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl Default for PacketBuffer {
+    fn default() -> Self {
+        Self::new(BUFFER_SIZE, HEADROOM)
+    }
+}
+
+impl PacketBuffer {
+    pub fn new(capacity: usize, headroom: usize) -> Self {
+        Self {
+            data: vec![0; capacity].into_boxed_slice(),
+            start: headroom,
+            end: headroom,
+        }
+    }
+
+    /// Empty the buffer, keeping `headroom` bytes in front of the payload
+    pub fn reset(&mut self, headroom: usize) {
+        self.start = headroom.min(self.data.len());
+        self.end = self.start;
+    }
+
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data[self.start..self.end]
+    }
+
+    /// Append to the payload, failing if it does not fit after the headroom
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let end = self.end + bytes.len();
+        if end > self.data.len() {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        self.data[self.end..end].copy_from_slice(bytes);
+        self.end = end;
+        Ok(())
+    }
+
+    /// Take `size` bytes from the headroom, returned to be filled
+    pub fn prepend(&mut self, size: usize) -> io::Result<&mut [u8]> {
+        if size > self.start {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        self.start -= size;
+        Ok(&mut self.data[self.start..self.start + size])
+    }
+
+    pub fn push_header(&mut self, mut header: impl WriteTo + Data) -> io::Result<()> {
+        header.prepare();
+        let size = header.size();
+        header.write_to_inner(&mut self.prepend(size)?)?;
+        Ok(())
+    }
+
+    /// Push the header of a `P` transport packet, whose checksum covers the header, the payload
+    /// and the pseudo header of `ip_header` when `P` has one
+    pub fn push_transport_header<P: TransportChecksum>(
+        &mut self,
+        header: impl WriteTo + Data,
+        ip_header: &IPV4Header,
+    ) -> io::Result<()> {
+        self.push_header(header)?;
+        let offset = P::CHECKSUM_OFFSET;
+        self.as_mut_slice()[offset..offset + 2].fill(0);
+        let mut checksum = Checksum::new().add_slice(self.as_slice());
+        if P::PSEUDO_HEADER {
+            checksum = checksum
+                .add_4bytes(ip_header.source_address.0.to_be_bytes())
+                .add_4bytes(ip_header.destination_address.0.to_be_bytes())
+                .add_byte(ip_header.protocol.into())
+                .add_2bytes((self.len() as u16).to_be_bytes());
+        }
+        self.as_mut_slice()[offset..offset + 2]
+            .copy_from_slice(&P::checksum_field(checksum).to_be_bytes());
+        Ok(())
+    }
+
+    /// Push an IPv4 header, setting its length and checksum for the packet in the buffer
+    pub fn push_ipv4_header(&mut self, mut header: IPV4Header) -> io::Result<()> {
+        header.prepare_ip_header(header.size() + self.len());
+        self.push_header(header)
+    }
+}
+
+/// Packet buffers kept around once sent, so that building a packet does not allocate
+#[derive(Debug, Clone, Default)]
+pub struct BufferPool {
+    buffers: Vec<PacketBuffer>,
+    /// Number of buffers allocated because the pool was empty
+    pub allocated: usize,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An empty buffer with the default headroom, reused when possible
+    pub fn take(&mut self) -> PacketBuffer {
+        match self.buffers.pop() {
+            Some(mut buffer) => {
+                buffer.reset(HEADROOM);
+                buffer
+            }
+            None => {
+                self.allocated += 1;
+                PacketBuffer::default()
+            }
+        }
+    }
+
+    pub fn give_back(&mut self, buffer: PacketBuffer) {
+        self.buffers.push(buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_reuses_buffers() {
+        let mut pool = BufferPool::new();
+        let mut buffer = pool.take();
+        buffer.extend_from_slice(b"payload").unwrap();
+        buffer.prepend(4).unwrap();
+        let data = buffer.data.as_ptr();
+        pool.give_back(buffer);
+
+        // The buffer comes back emptied, with its headroom restored
+        let buffer = pool.take();
+        assert_eq!(buffer.data.as_ptr(), data);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.headroom(), HEADROOM);
+        let other = pool.take();
+        assert_eq!(pool.allocated, 2);
+        pool.give_back(buffer);
+        pool.give_back(other);
+        for _ in 0..10 {
+            let buffer = pool.take();
+            pool.give_back(buffer);
+        }
+        assert_eq!(pool.allocated, 2);
+    }
+
+    #[test]
+    fn headers_pushed_in_front() {
+        let mut buffer = PacketBuffer::new(64, 8);
+        buffer.extend_from_slice(b"data").unwrap();
+        buffer.prepend(2).unwrap().copy_from_slice(b"h2");
+        buffer.prepend(6).unwrap().copy_from_slice(b"header");
+        assert_eq!(buffer.as_slice(), b"headerh2data");
+        // Neither the headroom nor the room after the payload are exceeded
+        assert!(buffer.prepend(1).is_err());
+        assert!(buffer.extend_from_slice(&[0; 53]).is_err());
+        buffer.extend_from_slice(&[0; 52]).unwrap();
+        assert_eq!(buffer.len(), 64);
+    }
+}
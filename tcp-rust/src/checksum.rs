@@ -1,3 +1,5 @@
+use std::io::{self, Write};
+
 use crate::traits::AsArrayUnchecked;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
@@ -43,8 +45,22 @@ impl Checksum {
         self.add_u64(value as u64)
     }
 
+    /// Sum a slice starting at an even offset of the checksummed data.
+    ///
+    /// 32 bits words are summed in independent 64 bits lanes, which cannot overflow before 2^32
+    /// words: the carries are folded once at the end and the loop can be vectorized.
     #[must_use]
     pub fn add_slice(mut self, mut bytes: &[u8]) -> Self {
+        let mut lanes = [0u64; 4];
+        let mut chunks = bytes.chunks_exact(16);
+        for chunk in &mut chunks {
+            for (lane, word) in lanes.iter_mut().zip(chunk.chunks_exact(4)) {
+                *lane += u64::from(u32::from_be_bytes(*unsafe { word.as_array_unchecked() }));
+            }
+        }
+        self = lanes.into_iter().fold(self, Self::add_u64);
+        bytes = chunks.remainder();
+
         macro_rules! add_slice_inner {
             ($n:literal, $fn:ident) => {
                 let steps = bytes.len() / $n;
@@ -69,6 +85,16 @@ impl Checksum {
         self.add_u64(rhs.0)
     }
 
+    /// Start from the value of a checksum field, to update it with `update`
+    pub fn from_field(checksum: u16) -> Self {
+        Self(u64::from(!checksum))
+    }
+    /// Account for a 16 bits word of the checksummed data changing from `old` to `new`, without
+    /// summing the data again (RFC 1624): HC' = ~(~HC + ~m + m')
+    #[must_use]
+    pub fn update(self, old: u16, new: u16) -> Self {
+        self + !old + new
+    }
     #[must_use]
     pub fn to_u16(self) -> u16 {
         let (res, carry) = (self.0 as u16).overflowing_add((self.0 >> 16) as u16);
@@ -103,3 +129,205 @@ macro_rules! add_impls {
 add_impls! {
     u8, u16, u32, u64
 }
+
+/// Writer summing the data going through it, so that a checksum is computed while writing
+#[derive(Debug)]
+pub struct ChecksumWriter<W: Write> {
+    writer: W,
+    checksum: Checksum,
+    // Last byte of an odd sized write, waiting for the one completing its 16 bits word
+    pending: Option<u8>,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            checksum: Checksum::new(),
+            pending: None,
+        }
+    }
+
+    pub fn checksum(&self) -> Checksum {
+        match self.pending {
+            Some(byte) => self.checksum.add_2bytes([byte, 0]),
+            None => self.checksum,
+        }
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        let mut bytes = &buf[..written];
+        if let (Some(byte), Some((&first, rest))) = (self.pending, bytes.split_first()) {
+            self.checksum = self.checksum.add_2bytes([byte, first]);
+            self.pending = None;
+            bytes = rest;
+        }
+        let even = bytes.len() & !1;
+        self.checksum = self.checksum.add_slice(&bytes[..even]);
+        if even < bytes.len() {
+            self.pending = Some(bytes[even]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reflected polynomial of the CRC32c (Castagnoli)
+const CRC32C_POLYNOMIAL: u32 = 0x82F6_3B78;
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32C_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32c of data fed in pieces, the checksum of SCTP (RFC 9260 Appendix A)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc32c(u32);
+
+impl Default for Crc32c {
+    fn default() -> Self {
+        Self(!0)
+    }
+}
+
+impl Crc32c {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    #[must_use]
+    pub fn add_slice(self, data: &[u8]) -> Self {
+        Self(data.iter().fold(self.0, |crc, byte| {
+            CRC32C_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+        }))
+    }
+    pub fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sum of the 16 bits words, the last byte padded with a zero, folded one word at a time
+    fn naive_sum(bytes: &[u8]) -> u16 {
+        let sum = bytes.chunks(2).fold(0u32, |sum, word| {
+            let sum = sum + u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]));
+            (sum & 0xFFFF) + (sum >> 16)
+        });
+        sum as u16
+    }
+
+    /// Bytes covering the whole range of values, with many carries
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 167 + 0xF0) as u8).collect()
+    }
+
+    #[test]
+    fn slices_summed_as_words() {
+        // All the remainders of the 16 bytes lanes and of the smaller steps
+        for len in 0..=70 {
+            let bytes = data(len);
+            assert_eq!(
+                Checksum::new().add_slice(&bytes).to_u16(),
+                naive_sum(&bytes)
+            );
+        }
+        let ones = vec![0xFF; 4096];
+        assert_eq!(Checksum::new().add_slice(&ones).to_u16(), naive_sum(&ones));
+        assert_eq!(Checksum::new().add_slice(&ones).to_u16(), 0xFFFF);
+        assert_eq!(Checksum::new().add_slice(&[]).ones_complement(), 0xFFFF);
+
+        // Summing in pieces at even offsets gives the same result
+        let bytes = data(1501);
+        for split in (0..=bytes.len()).step_by(2) {
+            let (first, second) = bytes.split_at(split);
+            let checksum = Checksum::new().add_slice(first).add_slice(second);
+            assert_eq!(checksum.to_u16(), naive_sum(&bytes));
+        }
+    }
+
+    #[test]
+    fn writer_sums_odd_sized_writes() {
+        let bytes = data(257);
+        for split in [1, 2, 3, 7, 16, 17, 33, 256, 257] {
+            let mut written = vec![];
+            let mut writer = ChecksumWriter::new(&mut written);
+            for chunk in bytes.chunks(split) {
+                writer.write_all(chunk).unwrap();
+            }
+            // Words cut by the writes are rebuilt, the odd last byte is padded
+            assert_eq!(writer.checksum().to_u16(), naive_sum(&bytes), "{split}");
+            assert_eq!(written, bytes);
+        }
+
+        // Writes stopping short of the buffer only sum what was written
+        let mut buffer = [0; 5];
+        let mut writer = ChecksumWriter::new(&mut buffer[..]);
+        assert_eq!(writer.write(&bytes[..8]).unwrap(), 5);
+        assert_eq!(writer.checksum().to_u16(), naive_sum(&bytes[..5]));
+    }
+
+    #[test]
+    fn incremental_update() {
+        let mut bytes = data(40);
+        let field = Checksum::new().add_slice(&bytes).ones_complement();
+        let mut checksum = Checksum::from_field(field);
+        for (offset, new) in [(0, 0x1234), (8, 0x0000), (8, 0xFFFF), (38, 0xABCD)] {
+            let old = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+            bytes[offset..offset + 2].copy_from_slice(&u16::to_be_bytes(new));
+            checksum = checksum.update(old, new);
+            assert_eq!(
+                checksum.ones_complement(),
+                Checksum::new().add_slice(&bytes).ones_complement()
+            );
+        }
+
+        // Decrementing the TTL of a header (RFC 1624 section 3)
+        let header = [
+            0x45, 0x00, 0x00, 0x54, 0x00, 0x00, 0x40, 0x00, 0x40, 0x01, 0x00, 0x00, 0xC0, 0xA8,
+            0x00, 0x01, 0xC0, 0xA8, 0x00, 0x02,
+        ];
+        let field = Checksum::new().add_slice(&header).ones_complement();
+        let updated = Checksum::from_field(field).update(0x4001, 0x3F01);
+        let mut decremented = header;
+        decremented[8] = 0x3F;
+        assert_eq!(
+            updated.ones_complement(),
+            Checksum::new().add_slice(&decremented).ones_complement()
+        );
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(Crc32c::new().add_slice(b"123456789").finish(), 0xE306_9283);
+        assert_eq!(
+            Crc32c::new()
+                .add_slice(b"1234")
+                .add_slice(b"56789")
+                .finish(),
+            0xE306_9283
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Options of the stack read from a file given with `--config`, one per line as they are written
+//! on the command line but without their dashes:
+//!
+//! ```text
+//! # The stack on tun0, serving HTTP on port 80 only
+//! interface tun0
+//! address 192.168.0.2/24
+//! port 80
+//! protocols icmp,tcp
+//! log-level debug
+//! ```
+
+use std::{fs, io, path::Path};
+
+/// Parse the options of a configuration file into command line arguments
+pub fn parse(s: &str) -> Vec<String> {
+    let mut args = vec![];
+    for line in s.lines() {
+        let line = line.split_once('#').map_or(line, |(line, _)| line);
+        let mut tokens = line.split_whitespace();
+        let Some(option) = tokens.next() else {
+            continue;
+        };
+        args.push(format!("--{option}"));
+        args.extend(tokens.map(str::to_string));
+    }
+    args
+}
+
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<String>> {
+    Ok(parse(&fs::read_to_string(path)?))
+}
+
+/// Replace each `--config <file>` of the arguments by the options of the file, in place
+pub fn expand(mut args: impl Iterator<Item = String>) -> io::Result<Vec<String>> {
+    let mut expanded = vec![];
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            let path = args.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "missing configuration file")
+            })?;
+            expanded.extend(load(path)?);
+        } else {
+            expanded.push(arg);
+        }
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_of_a_file() {
+        let config =
+            "# The stack\ninterface tun1\n\nup  # brought up\nport 80\nkeepalive 60,10,3\n";
+        assert_eq!(
+            parse(config),
+            [
+                "--interface",
+                "tun1",
+                "--up",
+                "--port",
+                "80",
+                "--keepalive",
+                "60,10,3"
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_and_blank_lines_skipped() {
+        assert!(parse("").is_empty());
+        assert!(parse("# only a comment\n\n   \n\t# indented\n").is_empty());
+        // Values are split on any whitespace, a comment ending the line
+        assert_eq!(
+            parse("  tunnel\tgre,10.0.0.1,10.0.0.2,172.16.0.1/30 # to the lab\nnodelay#\n"),
+            [
+                "--tunnel",
+                "gre,10.0.0.1,10.0.0.2,172.16.0.1/30",
+                "--nodelay"
+            ]
+        );
+        assert_eq!(
+            parse("tls cert.pem key.pem"),
+            ["--tls", "cert.pem", "key.pem"]
+        );
+    }
+
+    #[test]
+    fn files_expanded_in_place() {
+        let path = std::env::temp_dir().join(format!("tcp-rust-config-{}", std::process::id()));
+        fs::write(&path, "port 80\nmode tap\n").unwrap();
+        fn args(args: &[&str]) -> impl Iterator<Item = String> {
+            args.iter()
+                .map(|arg| arg.to_string())
+                .collect::<Vec<_>>()
+                .into_iter()
+        }
+        let path_arg = path.to_str().unwrap();
+        let expanded = expand(args(&["--up", "--config", path_arg, "--port", "443"]));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            expanded.unwrap(),
+            ["--up", "--port", "80", "--mode", "tap", "--port", "443"]
+        );
+
+        assert_eq!(
+            expand(args(&["--config"])).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            expand(args(&["--config", path_arg])).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(expand(args(&["sniff", "tun0"])).unwrap(), ["sniff", "tun0"]);
+    }
+}
@@ -0,0 +1,545 @@
+use std::{
+    io::{Read, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    dhcp::{
+        DHCP_CLIENT_PORT, DHCP_SERVER_PORT, DHCPMessage, DHCPMessageType, DHCPMessageView,
+        DHCPOption, OPTION_DNS_SERVER, OPTION_LEASE_TIME, OPTION_REBINDING_TIME,
+        OPTION_RENEWAL_TIME, OPTION_REQUESTED_ADDRESS, OPTION_ROUTER, OPTION_SERVER_ID,
+        OPTION_SUBNET_MASK,
+    },
+    ethernet::{ETHERTYPE_IPV4, MacAddr},
+    interface::Interface,
+    ip::{IPV4Header, IPV4Packet, IpProtocol, IpV4Addr},
+    udp::{UDPHeader, UDPPacket},
+};
+
+/// First retransmission delay, doubled after each attempt as advised by RFC 2131 §4.1
+const INITIAL_RETRANSMIT: Duration = Duration::from_secs(4);
+const MAX_RETRANSMIT: Duration = Duration::from_secs(64);
+/// Shortest wait between the requests renewing or rebinding a lease (RFC 2131 §4.4.5)
+const MIN_RENEWAL_RETRANSMIT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DHCPState {
+    #[default]
+    Init,
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub address: IpV4Addr,
+    pub server: IpV4Addr,
+    /// Link address the acknowledgement came from, that of the server or of the relay toward it
+    pub server_mac: MacAddr,
+    pub acquired: Instant,
+    pub duration: Duration,
+    pub renewal: Duration,
+    pub rebinding: Duration,
+}
+
+/// DHCPv4 client configuring the interface it runs on (RFC 2131).
+///
+/// It must be polled regularly so that it can retransmit and renew its lease in time.
+#[derive(Debug, Clone, Default)]
+pub struct DHCPClient {
+    pub state: DHCPState,
+    pub lease: Option<Lease>,
+    xid: u32,
+    offer: Option<(IpV4Addr, IpV4Addr)>,
+    last_sent: Option<Instant>,
+    retransmit: Duration,
+}
+
+impl DHCPClient {
+    pub fn new() -> Self {
+        Self {
+            retransmit: INITIAL_RETRANSMIT,
+            ..Default::default()
+        }
+    }
+
+    pub fn poll(&mut self, interface: &mut Interface<impl Read + Write>) {
+        let now = Instant::now();
+        let timed_out = self
+            .last_sent
+            .is_some_and(|last_sent| now - last_sent >= self.retransmit);
+
+        match self.state {
+            DHCPState::Init => self.discover(interface),
+            DHCPState::Selecting | DHCPState::Requesting if timed_out => {
+                self.retransmit = (self.retransmit * 2).min(MAX_RETRANSMIT);
+                self.discover(interface);
+            }
+            DHCPState::Bound | DHCPState::Renewing | DHCPState::Rebinding => {
+                let lease = self.lease.unwrap();
+                let elapsed = now - lease.acquired;
+                if elapsed >= lease.duration {
+                    info!("DHCP lease of {} expired", lease.address);
+                    interface.config.address = None;
+                    interface.config.gateway = None;
+                    self.lease = None;
+                    self.state = DHCPState::Init;
+                    self.retransmit = INITIAL_RETRANSMIT;
+                } else if elapsed >= lease.rebinding
+                    && (self.state != DHCPState::Rebinding || timed_out)
+                {
+                    self.state = DHCPState::Rebinding;
+                    // Retransmitted after half the time left until the lease expires
+                    self.retransmit = ((lease.duration - elapsed) / 2).max(MIN_RENEWAL_RETRANSMIT);
+                    self.send_request(interface, lease.address, None, true);
+                } else if elapsed >= lease.renewal && (self.state == DHCPState::Bound || timed_out)
+                {
+                    self.state = DHCPState::Renewing;
+                    // Retransmitted after half the time left until rebinding
+                    self.retransmit = ((lease.rebinding - elapsed) / 2).max(MIN_RENEWAL_RETRANSMIT);
+                    self.send_request(interface, lease.address, None, true);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn handle_packet(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        message: DHCPMessageView,
+    ) {
+        if message.get_xid() != self.xid || message.get_client_mac() != interface.config.mac {
+            return;
+        }
+        let Some(message_type) = message.get_message_type() else {
+            return;
+        };
+        let server = message
+            .get_option(OPTION_SERVER_ID)
+            .and_then(|option| option.as_address())
+            .unwrap_or(message.get_server_address());
+
+        match (self.state, message_type) {
+            (DHCPState::Selecting, DHCPMessageType::Offer) => {
+                let address = message.get_your_address();
+                info!("DHCP offer of {address} from {server}");
+                self.offer = Some((address, server));
+                self.state = DHCPState::Requesting;
+                self.send_request(interface, address, Some(server), false);
+            }
+            (
+                DHCPState::Requesting | DHCPState::Renewing | DHCPState::Rebinding,
+                DHCPMessageType::Ack,
+            ) => self.bind(interface, message, server),
+            (
+                DHCPState::Requesting | DHCPState::Renewing | DHCPState::Rebinding,
+                DHCPMessageType::Nak,
+            ) => {
+                warn!("DHCP request refused by {server}");
+                interface.config.address = None;
+                self.lease = None;
+                self.state = DHCPState::Init;
+                self.retransmit = INITIAL_RETRANSMIT;
+            }
+            _ => {}
+        }
+    }
+
+    fn bind(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        message: DHCPMessageView,
+        server: IpV4Addr,
+    ) {
+        let seconds = |code, default| {
+            Duration::from_secs(
+                message
+                    .get_option(code)
+                    .and_then(|option| option.as_u32())
+                    .unwrap_or(default) as u64,
+            )
+        };
+        let duration = seconds(OPTION_LEASE_TIME, 3600);
+        let address = message.get_your_address();
+        self.lease = Some(Lease {
+            address,
+            server,
+            server_mac: interface.get_peer_mac(),
+            acquired: Instant::now(),
+            duration,
+            // Default timers are at half and seven eighths of the lease
+            renewal: seconds(OPTION_RENEWAL_TIME, duration.as_secs() as u32 / 2),
+            rebinding: seconds(OPTION_REBINDING_TIME, duration.as_secs() as u32 / 8 * 7),
+        });
+
+        let option = |code| {
+            message
+                .get_option(code)
+                .and_then(|option| option.as_address())
+        };
+        let config = &mut interface.config;
+        config.address = Some(address);
+        config.netmask = option(OPTION_SUBNET_MASK).unwrap_or(IpV4Addr(0xFFFFFF00));
+        config.gateway = option(OPTION_ROUTER);
+        config.dns_server = option(OPTION_DNS_SERVER);
+
+        self.state = DHCPState::Bound;
+        self.retransmit = INITIAL_RETRANSMIT;
+        self.last_sent = None;
+        info!(
+            "DHCP bound to {address}/{} for {}s",
+            config.prefix_len(),
+            duration.as_secs()
+        );
+    }
+
+    fn discover(&mut self, interface: &mut Interface<impl Read + Write>) {
+        self.xid = Self::new_xid(interface.config.mac);
+        self.offer = None;
+        self.state = DHCPState::Selecting;
+        let message =
+            DHCPMessage::request(self.xid, interface.config.mac, DHCPMessageType::Discover);
+        self.send(
+            interface,
+            message,
+            IpV4Addr(0),
+            (IpV4Addr(u32::MAX), MacAddr::BROADCAST),
+        );
+    }
+
+    /// Request an address, either offered by `server` or already leased when `renew` is set
+    fn send_request(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        address: IpV4Addr,
+        server: Option<IpV4Addr>,
+        renew: bool,
+    ) {
+        let mut message =
+            DHCPMessage::request(self.xid, interface.config.mac, DHCPMessageType::Request);
+        if renew {
+            message.client_address = address;
+        } else {
+            message.options.push(DHCPOption::new(
+                OPTION_REQUESTED_ADDRESS,
+                address.0.to_be_bytes(),
+            ));
+        }
+        if let Some(server) = server {
+            message
+                .options
+                .push(DHCPOption::new(OPTION_SERVER_ID, server.0.to_be_bytes()));
+        }
+        // Renewal is unicast to the server, rebinding is broadcast to any server
+        let destination = match (self.state, self.lease) {
+            (DHCPState::Renewing, Some(lease)) => (lease.server, lease.server_mac),
+            _ => (IpV4Addr(u32::MAX), MacAddr::BROADCAST),
+        };
+        let source = if renew { address } else { IpV4Addr(0) };
+        self.send(interface, message, source, destination);
+    }
+
+    fn send(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        mut message: DHCPMessage,
+        source_address: IpV4Addr,
+        (destination_address, destination_mac): (IpV4Addr, MacAddr),
+    ) {
+        // Without an address, replies can only reach us if they are broadcast
+        message.broadcast = source_address == IpV4Addr(0);
+        let ip_header = IPV4Header::new(IpProtocol::Udp, source_address, destination_address);
+        let udp_header = UDPHeader {
+            source_port: DHCP_CLIENT_PORT,
+            destination_port: DHCP_SERVER_PORT,
+            ..Default::default()
+        };
+        interface.write_frame(
+            destination_mac,
+            ETHERTYPE_IPV4,
+            IPV4Packet::new(ip_header, UDPPacket::new(udp_header, message)),
+        );
+        interface.send();
+        self.last_sent = Some(Instant::now());
+    }
+
+    fn new_xid(mac: MacAddr) -> u32 {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        nanos ^ u32::from_be_bytes([mac.0[2], mac.0[3], mac.0[4], mac.0[5]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dhcp::{BOOT_REPLY, OPTION_MESSAGE_TYPE},
+        ethernet::EthernetHeader,
+        ip::IPV4PacketView,
+        test_support::Wire,
+        traits::WriteTo,
+        tun_tap::Mode,
+        udp::UDPPacketView,
+    };
+
+    const SERVER: IpV4Addr = IpV4Addr(0xC0A8_0001);
+    const OFFERED: IpV4Addr = IpV4Addr(0xC0A8_0064);
+    const SERVER_MAC: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 0x10]);
+
+    /// Destination and content of the DHCP message last sent
+    fn sent(wire: &Wire) -> (IpV4Addr, DHCPMessage) {
+        sent_frame(wire).1
+    }
+
+    /// Link destination of the DHCP message last sent, with its destination and content
+    fn sent_frame(wire: &Wire) -> (MacAddr, (IpV4Addr, DHCPMessage)) {
+        let frame = wire.sent.borrow_mut().pop().unwrap();
+        let destination_mac = MacAddr(frame[4..10].try_into().unwrap());
+        let packet = IPV4PacketView::<UDPPacketView>::try_from(&frame[18..]).unwrap();
+        assert_eq!(
+            packet.payload.header.get_destination_port(),
+            DHCP_SERVER_PORT
+        );
+        let message = DHCPMessageView::try_from(packet.payload.payload).unwrap();
+        (
+            destination_mac,
+            (
+                packet.header.get_destination_address(),
+                crate::traits::ToMutable::to_mutable(&message),
+            ),
+        )
+    }
+
+    /// Time passing for the client, as if its lease was acquired and its last message sent earlier
+    fn elapse(client: &mut DHCPClient, duration: Duration) {
+        if let Some(lease) = &mut client.lease {
+            lease.acquired -= duration;
+        }
+        client.last_sent = client.last_sent.map(|last_sent| last_sent - duration);
+    }
+
+    fn reply(
+        client: &mut DHCPClient,
+        interface: &mut Interface<Wire>,
+        xid: u32,
+        message_type: DHCPMessageType,
+        options: Vec<DHCPOption>,
+    ) {
+        let mut message = DHCPMessage {
+            op: BOOT_REPLY,
+            xid,
+            your_address: OFFERED,
+            client_mac: interface.config.mac,
+            options,
+            ..Default::default()
+        };
+        message.options.insert(
+            0,
+            DHCPOption::new(OPTION_MESSAGE_TYPE, [message_type as u8]),
+        );
+        let bytes = message.to_bytes().unwrap();
+        // The server answers from its own link address, the message itself being handed over
+        let mut ethernet_header = EthernetHeader {
+            destination: interface.config.mac,
+            source: SERVER_MAC,
+            ethertype: ETHERTYPE_IPV4,
+        };
+        let frame = [&[0, 0, 8, 0][..], &ethernet_header.to_bytes().unwrap()].concat();
+        interface.receive_frame(&frame);
+        client.handle_packet(interface, DHCPMessageView::try_from(&bytes[..]).unwrap());
+    }
+
+    fn message_type(message: &DHCPMessage) -> u8 {
+        message
+            .options
+            .iter()
+            .find(|option| option.code == OPTION_MESSAGE_TYPE)
+            .unwrap()
+            .data[0]
+    }
+
+    #[test]
+    fn offer_request_ack_and_renewal() {
+        let wire = Wire::default();
+        let mut interface = Interface::with_mode(wire.clone(), Mode::Tap);
+        let mut client = DHCPClient::new();
+        client.poll(&mut interface);
+        let (destination, discover) = sent(&wire);
+        assert_eq!(destination, IpV4Addr(u32::MAX));
+        assert_eq!(message_type(&discover), DHCPMessageType::Discover as u8);
+        assert!(discover.broadcast);
+        assert_eq!(client.state, DHCPState::Selecting);
+
+        // An offer for another transaction is ignored
+        reply(
+            &mut client,
+            &mut interface,
+            discover.xid.wrapping_add(1),
+            DHCPMessageType::Offer,
+            vec![],
+        );
+        assert!(wire.sent.borrow().is_empty());
+
+        let server_id = DHCPOption::new(OPTION_SERVER_ID, SERVER.0.to_be_bytes());
+        reply(
+            &mut client,
+            &mut interface,
+            discover.xid,
+            DHCPMessageType::Offer,
+            vec![server_id.clone()],
+        );
+        assert_eq!(client.state, DHCPState::Requesting);
+        let (_, request) = sent(&wire);
+        assert_eq!(message_type(&request), DHCPMessageType::Request as u8);
+        assert!(request.options.contains(&server_id));
+        assert!(request.options.contains(&DHCPOption::new(
+            OPTION_REQUESTED_ADDRESS,
+            OFFERED.0.to_be_bytes()
+        )));
+
+        reply(
+            &mut client,
+            &mut interface,
+            discover.xid,
+            DHCPMessageType::Ack,
+            vec![
+                server_id,
+                DHCPOption::new(OPTION_LEASE_TIME, 120u32.to_be_bytes()),
+                DHCPOption::new(OPTION_SUBNET_MASK, [255, 255, 0, 0]),
+                DHCPOption::new(OPTION_ROUTER, SERVER.0.to_be_bytes()),
+                DHCPOption::new(OPTION_DNS_SERVER, [8, 8, 8, 8]),
+            ],
+        );
+        assert_eq!(client.state, DHCPState::Bound);
+        let lease = client.lease.unwrap();
+        assert_eq!((lease.address, lease.server), (OFFERED, SERVER));
+        assert_eq!(
+            (lease.duration, lease.renewal, lease.rebinding),
+            (
+                Duration::from_secs(120),
+                Duration::from_secs(60),
+                Duration::from_secs(105)
+            )
+        );
+        let config = interface.config;
+        assert_eq!(config.address, Some(OFFERED));
+        assert_eq!(config.prefix_len(), 16);
+        assert_eq!(config.gateway, Some(SERVER));
+        assert_eq!(config.dns_server, Some(IpV4Addr(0x0808_0808)));
+
+        // Half way through the lease, it is renewed with the server
+        client.lease.as_mut().unwrap().acquired -= Duration::from_secs(61);
+        client.poll(&mut interface);
+        assert_eq!(client.state, DHCPState::Renewing);
+        let (destination_mac, (destination, renewal)) = sent_frame(&wire);
+        assert_eq!((destination_mac, destination), (SERVER_MAC, SERVER));
+        assert_eq!(renewal.client_address, OFFERED);
+        assert!(!renewal.broadcast);
+
+        reply(
+            &mut client,
+            &mut interface,
+            discover.xid,
+            DHCPMessageType::Nak,
+            vec![],
+        );
+        assert_eq!(client.state, DHCPState::Init);
+        assert_eq!(interface.config.address, None);
+    }
+
+    #[test]
+    fn renewal_retransmitted_at_half_the_time_left() {
+        let wire = Wire::default();
+        let mut interface = Interface::with_mode(wire.clone(), Mode::Tap);
+        let mut client = DHCPClient::new();
+        client.poll(&mut interface);
+        let (_, discover) = sent(&wire);
+        reply(
+            &mut client,
+            &mut interface,
+            discover.xid,
+            DHCPMessageType::Offer,
+            vec![],
+        );
+        sent(&wire);
+        reply(
+            &mut client,
+            &mut interface,
+            discover.xid,
+            DHCPMessageType::Ack,
+            vec![
+                DHCPOption::new(OPTION_SERVER_ID, SERVER.0.to_be_bytes()),
+                DHCPOption::new(OPTION_LEASE_TIME, 3600u32.to_be_bytes()),
+            ],
+        );
+        assert_eq!(client.lease.unwrap().server_mac, SERVER_MAC);
+
+        // Renewing at 1800s, 1350s before rebinding at 3150s
+        elapse(&mut client, Duration::from_secs(1800));
+        client.poll(&mut interface);
+        assert_eq!(client.state, DHCPState::Renewing);
+        assert_eq!(sent_frame(&wire).0, SERVER_MAC);
+        elapse(&mut client, Duration::from_secs(674));
+        client.poll(&mut interface);
+        assert!(wire.sent.borrow().is_empty());
+        elapse(&mut client, Duration::from_secs(1));
+        client.poll(&mut interface);
+        assert_eq!(sent(&wire).0, SERVER);
+
+        // Rebinding at 3150s, broadcast and retransmitted 225s later, half of what is left
+        elapse(&mut client, Duration::from_secs(675));
+        client.poll(&mut interface);
+        assert_eq!(client.state, DHCPState::Rebinding);
+        let (destination_mac, (destination, _)) = sent_frame(&wire);
+        assert_eq!(
+            (destination_mac, destination),
+            (MacAddr::BROADCAST, IpV4Addr(u32::MAX))
+        );
+        elapse(&mut client, Duration::from_secs(224));
+        client.poll(&mut interface);
+        assert!(wire.sent.borrow().is_empty());
+        elapse(&mut client, Duration::from_secs(1));
+        client.poll(&mut interface);
+        assert_eq!(sent(&wire).0, IpV4Addr(u32::MAX));
+    }
+
+    #[test]
+    fn expired_lease_forgotten() {
+        let wire = Wire::default();
+        let mut interface = Interface::with_mode(wire.clone(), Mode::Tap);
+        let mut client = DHCPClient::new();
+        client.poll(&mut interface);
+        let (_, discover) = sent(&wire);
+        reply(
+            &mut client,
+            &mut interface,
+            discover.xid,
+            DHCPMessageType::Offer,
+            vec![],
+        );
+        reply(
+            &mut client,
+            &mut interface,
+            discover.xid,
+            DHCPMessageType::Ack,
+            vec![DHCPOption::new(OPTION_LEASE_TIME, 60u32.to_be_bytes())],
+        );
+        assert_eq!(client.state, DHCPState::Bound);
+
+        // Without a server answering, the lease ends and the client starts over
+        client.lease.as_mut().unwrap().acquired -= Duration::from_secs(60);
+        client.poll(&mut interface);
+        assert_eq!(client.state, DHCPState::Init);
+        assert_eq!(interface.config.address, None);
+        assert!(client.lease.is_none());
+    }
+}
@@ -0,0 +1,321 @@
+pub mod client;
+
+use std::{
+    fmt::Debug,
+    io::{self, Write},
+};
+
+use crate::{
+    ethernet::MacAddr,
+    ip::IpV4Addr,
+    traits::{AsArrayUnchecked, Data, Prepare, ToMutable, WriteTo},
+};
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+pub const BOOT_REQUEST: u8 = 1;
+pub const BOOT_REPLY: u8 = 2;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Size of the fixed BOOTP part of the message, cookie included
+const FIXED_SIZE: usize = 240;
+
+pub const OPTION_PAD: u8 = 0;
+pub const OPTION_SUBNET_MASK: u8 = 1;
+pub const OPTION_ROUTER: u8 = 3;
+pub const OPTION_DNS_SERVER: u8 = 6;
+pub const OPTION_REQUESTED_ADDRESS: u8 = 50;
+pub const OPTION_LEASE_TIME: u8 = 51;
+pub const OPTION_MESSAGE_TYPE: u8 = 53;
+pub const OPTION_SERVER_ID: u8 = 54;
+pub const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+pub const OPTION_RENEWAL_TIME: u8 = 58;
+pub const OPTION_REBINDING_TIME: u8 = 59;
+pub const OPTION_CLIENT_ID: u8 = 61;
+pub const OPTION_END: u8 = 255;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum DHCPMessageType {
+    #[default]
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl TryFrom<u8> for DHCPMessageType {
+    type Error = ParseDHCPError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1..=8 => Ok(unsafe { core::mem::transmute::<u8, DHCPMessageType>(value) }),
+            _ => Err(ParseDHCPError),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseDHCPError;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct DHCPOption {
+    pub code: u8,
+    pub data: Vec<u8>,
+}
+
+impl DHCPOption {
+    pub fn new(code: u8, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            code,
+            data: data.into(),
+        }
+    }
+
+    pub fn as_address(&self) -> Option<IpV4Addr> {
+        Some(u32::from_be_bytes(self.data.get(..4)?.try_into().ok()?).into())
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.data.get(..4)?.try_into().ok()?))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DHCPMessageView<'a> {
+    content: &'a [u8],
+}
+
+impl<'a> TryFrom<&'a [u8]> for DHCPMessageView<'a> {
+    type Error = ParseDHCPError;
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        if value.len() < FIXED_SIZE || value[236..240] != MAGIC_COOKIE {
+            return Err(ParseDHCPError);
+        }
+        let view = Self { content: value };
+        // Check the options are well formed, so that getters can assume it
+        let mut i = FIXED_SIZE;
+        while i < value.len() {
+            match value[i] {
+                OPTION_END => break,
+                OPTION_PAD => i += 1,
+                _ => i += 2 + *value.get(i + 1).ok_or(ParseDHCPError)? as usize,
+            }
+        }
+        if i >= value.len() {
+            return Err(ParseDHCPError);
+        }
+        Ok(Self {
+            content: &view.content[..=i],
+        })
+    }
+}
+
+impl<'a> AsRef<[u8]> for DHCPMessageView<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.content
+    }
+}
+
+impl Data for DHCPMessageView<'_> {
+    fn size(&self) -> usize {
+        self.content.len()
+    }
+}
+
+impl ToMutable for DHCPMessageView<'_> {
+    type MutableType = DHCPMessage;
+
+    fn to_mutable(&self) -> Self::MutableType {
+        DHCPMessage {
+            op: self.get_op(),
+            hops: self.get_hops(),
+            xid: self.get_xid(),
+            secs: self.get_secs(),
+            broadcast: self.get_broadcast(),
+            client_address: self.get_client_address(),
+            your_address: self.get_your_address(),
+            server_address: self.get_server_address(),
+            relay_address: self.get_relay_address(),
+            client_mac: self.get_client_mac(),
+            options: self.get_options(),
+        }
+    }
+}
+
+impl<'a> DHCPMessageView<'a> {
+    pub fn get_op(&self) -> u8 {
+        self.content[0]
+    }
+    pub fn get_hops(&self) -> u8 {
+        self.content[3]
+    }
+    pub fn get_xid(&self) -> u32 {
+        u32::from_be_bytes(*unsafe { self.content[4..8].as_array_unchecked() })
+    }
+    pub fn get_secs(&self) -> u16 {
+        u16::from_be_bytes(*unsafe { self.content[8..10].as_array_unchecked() })
+    }
+    pub fn get_broadcast(&self) -> bool {
+        (self.content[10] & (1 << 7)) != 0
+    }
+    pub fn get_client_address(&self) -> IpV4Addr {
+        u32::from_be_bytes(*unsafe { self.content[12..16].as_array_unchecked() }).into()
+    }
+    pub fn get_your_address(&self) -> IpV4Addr {
+        u32::from_be_bytes(*unsafe { self.content[16..20].as_array_unchecked() }).into()
+    }
+    pub fn get_server_address(&self) -> IpV4Addr {
+        u32::from_be_bytes(*unsafe { self.content[20..24].as_array_unchecked() }).into()
+    }
+    pub fn get_relay_address(&self) -> IpV4Addr {
+        u32::from_be_bytes(*unsafe { self.content[24..28].as_array_unchecked() }).into()
+    }
+    pub fn get_client_mac(&self) -> MacAddr {
+        MacAddr(*unsafe { self.content[28..34].as_array_unchecked() })
+    }
+    pub fn get_options(&self) -> Vec<DHCPOption> {
+        let mut i = FIXED_SIZE;
+        let mut res = vec![];
+        while self.content[i] != OPTION_END {
+            if self.content[i] == OPTION_PAD {
+                i += 1;
+                continue;
+            }
+            let len = self.content[i + 1] as usize;
+            res.push(DHCPOption::new(
+                self.content[i],
+                &self.content[i + 2..i + 2 + len],
+            ));
+            i += 2 + len;
+        }
+        res
+    }
+    pub fn get_option(&self, code: u8) -> Option<DHCPOption> {
+        self.get_options()
+            .into_iter()
+            .find(|option| option.code == code)
+    }
+    pub fn get_message_type(&self) -> Option<DHCPMessageType> {
+        self.get_option(OPTION_MESSAGE_TYPE)
+            .and_then(|option| DHCPMessageType::try_from(*option.data.first()?).ok())
+    }
+}
+
+impl Debug for DHCPMessageView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DHCPMessageView")
+            .field("op", &self.get_op())
+            .field("hops", &self.get_hops())
+            .field("xid", &self.get_xid())
+            .field("secs", &self.get_secs())
+            .field("broadcast", &self.get_broadcast())
+            .field("client_address", &self.get_client_address())
+            .field("your_address", &self.get_your_address())
+            .field("server_address", &self.get_server_address())
+            .field("relay_address", &self.get_relay_address())
+            .field("client_mac", &self.get_client_mac())
+            .field("options", &self.get_options())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DHCPMessage {
+    pub op: u8,
+    pub hops: u8,
+    pub xid: u32,
+    pub secs: u16,
+    pub broadcast: bool,
+    pub client_address: IpV4Addr,
+    pub your_address: IpV4Addr,
+    pub server_address: IpV4Addr,
+    pub relay_address: IpV4Addr,
+    pub client_mac: MacAddr,
+    pub options: Vec<DHCPOption>,
+}
+
+impl DHCPMessage {
+    pub fn request(xid: u32, client_mac: MacAddr, message_type: DHCPMessageType) -> Self {
+        Self {
+            op: BOOT_REQUEST,
+            xid,
+            client_mac,
+            options: vec![
+                DHCPOption::new(OPTION_MESSAGE_TYPE, [message_type as u8]),
+                DHCPOption::new(
+                    OPTION_CLIENT_ID,
+                    [&[1][..], &client_mac.0].concat(), // Ethernet hardware type
+                ),
+                DHCPOption::new(
+                    OPTION_PARAMETER_REQUEST_LIST,
+                    [OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS_SERVER],
+                ),
+            ],
+            ..Default::default()
+        }
+    }
+}
+
+impl Prepare for DHCPMessage {}
+impl Data for DHCPMessage {
+    fn size(&self) -> usize {
+        FIXED_SIZE
+            + self
+                .options
+                .iter()
+                .map(|option| 2 + option.data.len())
+                .sum::<usize>()
+            + 1
+    }
+}
+
+impl WriteTo for DHCPMessage {
+    fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        // Hardware type and address length of Ethernet
+        writer.write_all(&[self.op, 1, 6, self.hops])?;
+        writer.write_all(&u32::to_be_bytes(self.xid))?;
+        writer.write_all(&u16::to_be_bytes(self.secs))?;
+        writer.write_all(&[(self.broadcast as u8) << 7, 0])?;
+        writer.write_all(&u32::to_be_bytes(self.client_address.0))?;
+        writer.write_all(&u32::to_be_bytes(self.your_address.0))?;
+        writer.write_all(&u32::to_be_bytes(self.server_address.0))?;
+        writer.write_all(&u32::to_be_bytes(self.relay_address.0))?;
+        writer.write_all(&self.client_mac.0)?;
+        // Padding of the hardware address, then the unused server name and boot file name
+        writer.write_all(&[0; 10 + 64 + 128])?;
+        writer.write_all(&MAGIC_COOKIE)?;
+        for option in &self.options {
+            writer.write_all(&[option.code, option.data.len() as u8])?;
+            writer.write_all(&option.data)?;
+        }
+        writer.write_all(&[OPTION_END])?;
+        Ok(self.size())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_must_end_within_the_message() {
+        let mut message =
+            DHCPMessage::request(7, MacAddr([2, 0, 0, 0, 0, 1]), DHCPMessageType::Discover);
+        let bytes = message.to_bytes().unwrap();
+        let view = DHCPMessageView::try_from(&bytes[..]).unwrap();
+        assert_eq!(view.to_mutable(), message);
+        assert_eq!(view.get_message_type(), Some(DHCPMessageType::Discover));
+
+        // Without its end, or with an option longer than what is left
+        assert!(DHCPMessageView::try_from(&bytes[..bytes.len() - 1]).is_err());
+        let mut truncated = bytes[..FIXED_SIZE].to_vec();
+        truncated.extend_from_slice(&[OPTION_MESSAGE_TYPE, 8, 1]);
+        assert!(DHCPMessageView::try_from(&truncated[..]).is_err());
+        assert!(DHCPMessageView::try_from(&bytes[..FIXED_SIZE - 1]).is_err());
+    }
+}
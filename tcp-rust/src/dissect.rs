@@ -0,0 +1,703 @@
+use std::{
+    fmt::{Display, Write},
+    time::Duration,
+};
+
+use crate::{
+    arp::{ARP_REPLY, ARP_REQUEST, ARPHeaderView},
+    checksum::Checksum,
+    ethernet::{ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6, EthernetHeaderView},
+    icmp::{
+        ADMIN_PROHIBITED, DESTINATION_UNREACHABLE, ECHO_REPLY, ECHO_REQUEST, HOST_UNREACHABLE,
+        ICMPHeaderView, NET_UNREACHABLE, PORT_UNREACHABLE, PROTOCOL_UNREACHABLE, TIME_EXCEEDED,
+        TTL_EXCEEDED,
+    },
+    ip::{IPV4HeaderView, IpV6Addr},
+    tcp::TCPHeaderView,
+    traits::{AsArrayUnchecked, Data},
+    tun_tap::Mode,
+    udp::UDPHeaderView,
+};
+
+const PROTOCOL_ICMP: u8 = 1;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+const PROTOCOL_ICMPV6: u8 = 58;
+
+/// Link layer of the captured frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkType {
+    Ethernet,
+    /// IP packets, whose version is read from their first nibble
+    Raw,
+    /// Frames read from a TUN/TAP device, behind their packet information header
+    Device(Mode),
+}
+
+impl LinkType {
+    /// Link type of a pcap file, from its link-layer header type
+    pub fn from_pcap(link_type: u32) -> Option<Self> {
+        match link_type {
+            1 => Some(Self::Ethernet),
+            12 | 14 | 101 => Some(Self::Raw),
+            _ => None,
+        }
+    }
+}
+
+/// One line summary of a frame and, for the verbose output, the fields of each layer
+#[derive(Debug, Default)]
+struct Dissection {
+    line: String,
+    tree: String,
+}
+
+impl Dissection {
+    fn summary(&mut self, text: impl Display) {
+        let _ = write!(self.line, "{text}");
+    }
+    fn layer(&mut self, title: impl Display) {
+        let _ = writeln!(self.tree, "{title}");
+    }
+    fn field(&mut self, text: impl Display) {
+        let _ = writeln!(self.tree, "    {text}");
+    }
+    /// Mark the frame as cut in the middle of `protocol`, like tcpdump
+    fn truncated(&mut self, protocol: &str) {
+        self.summary(format_args!(" [|{protocol}]"));
+        self.layer(format_args!("[truncated {protocol}]"));
+    }
+
+    fn device(&mut self, mode: Mode, frame: &[u8]) {
+        let Some((packet_info, frame)) = frame.split_at_checked(4) else {
+            return self.truncated("tun");
+        };
+        let flags = u16::from_be_bytes([packet_info[0], packet_info[1]]);
+        let ethertype = u16::from_be_bytes([packet_info[2], packet_info[3]]);
+        self.layer("Packet information");
+        self.field(format_args!(
+            "flags {flags:#06x}, protocol {ethertype:#06x}"
+        ));
+        match mode {
+            Mode::Tun => self.network(ethertype, frame),
+            Mode::Tap => self.ethernet(frame),
+        }
+    }
+
+    fn ethernet(&mut self, frame: &[u8]) {
+        let Ok(header) = EthernetHeaderView::parse(frame) else {
+            return self.truncated("ether");
+        };
+        self.layer("Ethernet");
+        self.field(format_args!(
+            "{} > {}, ethertype {:#06x}",
+            header.get_source(),
+            header.get_destination(),
+            header.get_ethertype()
+        ));
+        self.network(header.get_ethertype(), &frame[14..]);
+    }
+
+    fn raw(&mut self, packet: &[u8]) {
+        match packet.first().map(|byte| byte >> 4) {
+            Some(4) => self.ipv4(packet),
+            Some(6) => self.ipv6(packet),
+            Some(version) => self.summary(format_args!("IP version {version}")),
+            None => self.truncated("ip"),
+        }
+    }
+
+    fn network(&mut self, ethertype: u16, packet: &[u8]) {
+        match ethertype {
+            ETHERTYPE_IPV4 => self.ipv4(packet),
+            ETHERTYPE_IPV6 => self.ipv6(packet),
+            ETHERTYPE_ARP => self.arp(packet),
+            _ => self.summary(format_args!(
+                "ethertype {ethertype:#06x}, length {}",
+                packet.len()
+            )),
+        }
+    }
+
+    fn arp(&mut self, packet: &[u8]) {
+        self.summary("ARP, ");
+        let Ok(header) = ARPHeaderView::parse(packet) else {
+            return self.truncated("arp");
+        };
+        match header.get_operation() {
+            ARP_REQUEST => self.summary(format_args!(
+                "Request who-has {} tell {}",
+                header.get_target_address(),
+                header.get_sender_address()
+            )),
+            ARP_REPLY => self.summary(format_args!(
+                "Reply {} is-at {}",
+                header.get_sender_address(),
+                header.get_sender_mac()
+            )),
+            operation => self.summary(format_args!("operation {operation}")),
+        }
+        self.summary(format_args!(", length {}", packet.len()));
+        self.layer("Address Resolution Protocol");
+        self.field(format_args!(
+            "hardware type {}, protocol type {:#06x}, operation {}",
+            header.get_hardware_type(),
+            header.get_protocol_type(),
+            header.get_operation()
+        ));
+        self.field(format_args!(
+            "sender {} {}",
+            header.get_sender_mac(),
+            header.get_sender_address()
+        ));
+        self.field(format_args!(
+            "target {} {}",
+            header.get_target_mac(),
+            header.get_target_address()
+        ));
+    }
+
+    fn ipv4(&mut self, packet: &[u8]) {
+        self.summary("IP ");
+        let Ok(header) = IPV4HeaderView::parse(packet) else {
+            return self.truncated("ip");
+        };
+        let header_size = header.size();
+        // The protocol and fragment fields are read as they are, any value being valid
+        let bytes = header.as_bytes();
+        let protocol = bytes[9];
+        let flags = bytes[6] >> 5;
+        let fragment_offset = u16::from_be_bytes([bytes[6] & 0x1F, bytes[7]]) as usize * 8;
+        let (source, destination) = (
+            header.get_source_address(),
+            header.get_destination_address(),
+        );
+        let checksum_ok = Checksum::new().add_slice(bytes).ones_complement() == 0;
+
+        self.layer("Internet Protocol version 4");
+        self.field(format_args!(
+            "header length {header_size}, tos {:#x}, total length {}",
+            bytes[1],
+            header.get_total_length()
+        ));
+        self.field(format_args!(
+            "id {}, flags [{}], offset {fragment_offset}, ttl {}",
+            header.get_identification(),
+            ip_flags(flags),
+            header.get_ttl()
+        ));
+        self.field(format_args!(
+            "protocol {protocol}, checksum {:#06x} ({})",
+            header.get_header_checksum(),
+            if checksum_ok { "correct" } else { "incorrect" }
+        ));
+        self.field(format_args!("{source} > {destination}"));
+
+        // Ethernet pads short frames, the payload ends where the IP header says
+        let end = (header.get_total_length() as usize).clamp(header_size, packet.len());
+        let payload = &packet[header_size..end];
+        if fragment_offset != 0 {
+            return self.summary(format_args!(
+                "{source} > {destination}: ip-proto-{protocol} fragment offset {fragment_offset}, length {}",
+                payload.len()
+            ));
+        }
+        // The checksum of a payload cut by the capture cannot be checked
+        let pseudo_header = (end == header.get_total_length() as usize).then(|| {
+            Checksum::new()
+                .add_4bytes(source.0.to_be_bytes())
+                .add_4bytes(destination.0.to_be_bytes())
+                .add_byte(protocol)
+                .add_2bytes((payload.len() as u16).to_be_bytes())
+        });
+        self.transport(protocol, source, destination, payload, pseudo_header);
+    }
+
+    fn ipv6(&mut self, packet: &[u8]) {
+        self.summary("IP6 ");
+        if packet.len() < 40 {
+            return self.truncated("ip6");
+        }
+        let word = u32::from_be_bytes(*unsafe { packet[0..4].as_array_unchecked() });
+        let payload_length = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+        let next_header = packet[6];
+        let source = IpV6Addr(u128::from_be_bytes(*unsafe {
+            packet[8..24].as_array_unchecked()
+        }));
+        let destination = IpV6Addr(u128::from_be_bytes(*unsafe {
+            packet[24..40].as_array_unchecked()
+        }));
+
+        self.layer("Internet Protocol version 6");
+        self.field(format_args!(
+            "traffic class {:#x}, flow label {:#x}, payload length {payload_length}",
+            (word >> 20) & 0xFF,
+            word & 0xFFFFF
+        ));
+        self.field(format_args!(
+            "next header {next_header}, hop limit {}",
+            packet[7]
+        ));
+        self.field(format_args!("{source} > {destination}"));
+
+        let end = (40 + payload_length).min(packet.len());
+        // Extension headers are not followed, their payload is shown as an unknown protocol
+        self.transport(next_header, source, destination, &packet[40..end], None);
+    }
+
+    /// Dissect the payload of an IP packet, whose checksum is checked when the pseudo header is
+    /// given
+    fn transport(
+        &mut self,
+        protocol: u8,
+        source: impl Display,
+        destination: impl Display,
+        payload: &[u8],
+        pseudo_header: Option<Checksum>,
+    ) {
+        let checksum = |payload: &[u8]| {
+            pseudo_header.map(|pseudo_header| {
+                match pseudo_header.add_slice(payload).ones_complement() {
+                    0 => "correct",
+                    _ => "incorrect",
+                }
+            })
+        };
+        match protocol {
+            PROTOCOL_TCP => self.tcp(source, destination, payload, checksum(payload)),
+            PROTOCOL_UDP => self.udp(source, destination, payload, checksum(payload)),
+            PROTOCOL_ICMP => {
+                self.summary(format_args!("{source} > {destination}: "));
+                self.icmp(payload);
+            }
+            PROTOCOL_ICMPV6 => {
+                self.summary(format_args!("{source} > {destination}: ICMP6"));
+                match payload {
+                    [message_type, code, ..] => self.summary(format_args!(
+                        ", type {message_type}, code {code}, length {}",
+                        payload.len()
+                    )),
+                    _ => self.truncated("icmp6"),
+                }
+            }
+            _ => self.summary(format_args!(
+                "{source} > {destination}: ip-proto-{protocol} {}",
+                payload.len()
+            )),
+        }
+    }
+
+    fn tcp(
+        &mut self,
+        source: impl Display,
+        destination: impl Display,
+        segment: &[u8],
+        checksum: Option<&str>,
+    ) {
+        let Ok(header) = TCPHeaderView::parse(segment) else {
+            self.summary(format_args!("{source} > {destination}:"));
+            return self.truncated("tcp");
+        };
+        let header_size = header.size();
+        let length = segment.len() - header_size;
+        let sequence_number = header.get_sequence_number();
+        let flags = tcp_flags(segment[13]);
+        let options = tcp_options(&segment[20..header_size]);
+
+        self.summary(format_args!(
+            "{source}.{} > {destination}.{}: Flags [{flags}]",
+            header.get_source_port(),
+            header.get_destination_port()
+        ));
+        if length > 0 {
+            self.summary(format_args!(
+                ", seq {sequence_number}:{}",
+                sequence_number.wrapping_add(length as u32)
+            ));
+        } else if header.get_syn() || header.get_fin() || header.get_rst() {
+            self.summary(format_args!(", seq {sequence_number}"));
+        }
+        if header.get_ack() {
+            self.summary(format_args!(
+                ", ack {}",
+                header.get_acknowledgement_number()
+            ));
+        }
+        self.summary(format_args!(", win {}", header.get_window()));
+        if header.get_urg() {
+            self.summary(format_args!(", urg {}", header.get_urgent_pointer()));
+        }
+        if !options.is_empty() {
+            self.summary(format_args!(", options [{options}]"));
+        }
+        self.summary(format_args!(", length {length}"));
+
+        self.layer("Transmission Control Protocol");
+        self.field(format_args!(
+            "{} > {}, header length {header_size}, flags [{flags}]",
+            header.get_source_port(),
+            header.get_destination_port()
+        ));
+        self.field(format_args!(
+            "seq {sequence_number}, ack {}, window {}, urgent pointer {}",
+            header.get_acknowledgement_number(),
+            header.get_window(),
+            header.get_urgent_pointer()
+        ));
+        self.checksum_field(header.get_checksum(), checksum);
+        if !options.is_empty() {
+            self.field(format_args!("options [{options}]"));
+        }
+        self.payload(&segment[header_size..]);
+    }
+
+    fn udp(
+        &mut self,
+        source: impl Display,
+        destination: impl Display,
+        datagram: &[u8],
+        checksum: Option<&str>,
+    ) {
+        let Ok(header) = UDPHeaderView::parse(datagram) else {
+            self.summary(format_args!("{source} > {destination}:"));
+            return self.truncated("udp");
+        };
+        self.summary(format_args!(
+            "{source}.{} > {destination}.{}: UDP, length {}",
+            header.get_source_port(),
+            header.get_destination_port(),
+            datagram.len() - 8
+        ));
+
+        self.layer("User Datagram Protocol");
+        self.field(format_args!(
+            "{} > {}, length {}",
+            header.get_source_port(),
+            header.get_destination_port(),
+            header.get_length()
+        ));
+        // A zero checksum means the sender did not compute one
+        let checksum = checksum.filter(|_| header.get_checksum() != 0);
+        self.checksum_field(header.get_checksum(), checksum);
+        self.payload(&datagram[8..]);
+    }
+
+    fn icmp(&mut self, message: &[u8]) {
+        self.summary("ICMP ");
+        if message.len() < 8 {
+            return self.truncated("icmp");
+        }
+        let Ok(header) = ICMPHeaderView::parse(message) else {
+            return self.truncated("icmp");
+        };
+        let (message_type, code) = (header.get_message_type(), header.get_code());
+        let rest = &message[4..8];
+        match message_type {
+            ECHO_REQUEST | ECHO_REPLY => self.summary(format_args!(
+                "echo {}, id {}, seq {}",
+                if message_type == ECHO_REQUEST {
+                    "request"
+                } else {
+                    "reply"
+                },
+                u16::from_be_bytes([rest[0], rest[1]]),
+                u16::from_be_bytes([rest[2], rest[3]])
+            )),
+            DESTINATION_UNREACHABLE => self.unreachable(code, &message[8..]),
+            TIME_EXCEEDED if code == TTL_EXCEEDED => self.summary("time exceeded in-transit"),
+            TIME_EXCEEDED => self.summary("ip reassembly time exceeded"),
+            _ => self.summary(format_args!("type {message_type}, code {code}")),
+        }
+        self.summary(format_args!(", length {}", message.len()));
+
+        self.layer("Internet Control Message Protocol");
+        let checksum_ok = Checksum::new().add_slice(message).ones_complement() == 0;
+        self.field(format_args!(
+            "type {message_type}, code {code}, checksum {:#06x} ({})",
+            header.get_checksum(),
+            if checksum_ok { "correct" } else { "incorrect" }
+        ));
+        self.payload(&message[4..]);
+    }
+
+    /// Summary of a destination unreachable message, from the packet it quotes
+    fn unreachable(&mut self, code: u8, quoted: &[u8]) {
+        let Ok(header) = IPV4HeaderView::parse(quoted) else {
+            return self.summary(format_args!("unreachable, code {code}"));
+        };
+        let header_size = header.size();
+        let destination = header.get_destination_address();
+        let protocol = header.as_bytes()[9];
+        let transport = &quoted[header_size..];
+        match code {
+            NET_UNREACHABLE => self.summary(format_args!("net {destination} unreachable")),
+            HOST_UNREACHABLE => self.summary(format_args!("host {destination} unreachable")),
+            PROTOCOL_UNREACHABLE => self.summary(format_args!(
+                "{destination} protocol {protocol} unreachable"
+            )),
+            PORT_UNREACHABLE if transport.len() >= 4 => self.summary(format_args!(
+                "{destination} {} port {} unreachable",
+                match protocol {
+                    PROTOCOL_TCP => "tcp",
+                    PROTOCOL_UDP => "udp",
+                    _ => "ip",
+                },
+                u16::from_be_bytes([transport[2], transport[3]])
+            )),
+            ADMIN_PROHIBITED => self.summary(format_args!(
+                "host {destination} unreachable - admin prohibited filter"
+            )),
+            _ => self.summary(format_args!("{destination} unreachable, code {code}")),
+        }
+    }
+
+    fn checksum_field(&mut self, checksum: u16, status: Option<&str>) {
+        match status {
+            Some(status) => self.field(format_args!("checksum {checksum:#06x} ({status})")),
+            None => self.field(format_args!("checksum {checksum:#06x}")),
+        }
+    }
+
+    fn payload(&mut self, payload: &[u8]) {
+        self.layer(format_args!("Payload, {} bytes", payload.len()));
+    }
+}
+
+fn ip_flags(flags: u8) -> String {
+    let mut text = String::new();
+    if flags & 0b010 != 0 {
+        text.push_str("DF");
+    }
+    if flags & 0b001 != 0 {
+        text.push_str(if text.is_empty() { "+" } else { ",+" });
+    }
+    if text.is_empty() {
+        text.push_str("none");
+    }
+    text
+}
+
+/// Flags in tcpdump's notation, `.` standing for ACK
+fn tcp_flags(flags: u8) -> String {
+    const NAMES: [(u8, char); 8] = [
+        (1 << 0, 'F'),
+        (1 << 1, 'S'),
+        (1 << 2, 'R'),
+        (1 << 3, 'P'),
+        (1 << 4, '.'),
+        (1 << 5, 'U'),
+        (1 << 6, 'E'),
+        (1 << 7, 'W'),
+    ];
+    let text: String = NAMES
+        .iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| name)
+        .collect();
+    if text.is_empty() {
+        "none".to_string()
+    } else {
+        text
+    }
+}
+
+fn tcp_options(mut options: &[u8]) -> String {
+    let mut text = vec![];
+    while let Some(&kind) = options.first() {
+        if kind == 0 {
+            text.push("eol".to_string());
+            break;
+        }
+        if kind == 1 {
+            text.push("nop".to_string());
+            options = &options[1..];
+            continue;
+        }
+        let length = options.get(1).map_or(0, |length| *length as usize);
+        if length < 2 || length > options.len() {
+            text.push("bad opt".to_string());
+            break;
+        }
+        let data = &options[2..length];
+        text.push(match (kind, data) {
+            (2, &[a, b]) => format!("mss {}", u16::from_be_bytes([a, b])),
+            (3, &[shift]) => format!("wscale {shift}"),
+            (4, &[]) => "sackOK".to_string(),
+            (5, blocks) if blocks.len() % 8 == 0 => {
+                let count = blocks.len() / 8;
+                let blocks: String = blocks
+                    .chunks_exact(8)
+                    .map(|block| {
+                        format!(
+                            "{{{}:{}}}",
+                            u32::from_be_bytes([block[0], block[1], block[2], block[3]]),
+                            u32::from_be_bytes([block[4], block[5], block[6], block[7]])
+                        )
+                    })
+                    .collect();
+                format!("sack {count} {blocks}")
+            }
+            (8, timestamps) if timestamps.len() == 8 => format!(
+                "TS val {} ecr {}",
+                u32::from_be_bytes([timestamps[0], timestamps[1], timestamps[2], timestamps[3]]),
+                u32::from_be_bytes([timestamps[4], timestamps[5], timestamps[6], timestamps[7]])
+            ),
+            _ => format!("unknown-{kind}"),
+        });
+        options = &options[length..];
+    }
+    text.join(",")
+}
+
+/// Dump of the frame in tcpdump's `-X` format, 16 bytes per line in hex and ASCII
+fn hex_dump(frame: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in frame.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (j, byte) in line.iter().enumerate() {
+            if j % 2 == 0 {
+                hex.push(' ');
+            }
+            let _ = write!(hex, "{byte:02x}");
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(dump, "\t0x{:04x}: {hex:<40}  {ascii}", i * 16);
+    }
+    dump
+}
+
+/// Time of day of a capture timestamp, in UTC
+pub fn format_timestamp(timestamp: Duration) -> String {
+    let seconds = timestamp.as_secs() % 86400;
+    format!(
+        "{:02}:{:02}:{:02}.{:06}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        timestamp.subsec_micros()
+    )
+}
+
+/// Render a frame on one line like tcpdump does, followed in verbose mode by the fields of each
+/// layer and a hex dump of the frame
+pub fn dissect(link_type: LinkType, frame: &[u8], verbose: bool) -> String {
+    let mut dissection = Dissection::default();
+    match link_type {
+        LinkType::Ethernet => dissection.ethernet(frame),
+        LinkType::Raw => dissection.raw(frame),
+        LinkType::Device(mode) => dissection.device(mode, frame),
+    }
+    if !verbose {
+        return dissection.line;
+    }
+    format!(
+        "{}\n{}{}",
+        dissection.line,
+        dissection.tree,
+        hex_dump(frame)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes of a frame written in hex
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    const TCP_SYN: &str = "02000000000102000000000208004500002c12344000400614960a0000020a000001\
+                           9c400050000003e8000000006002faf0e8ba0000020405b4";
+    const UDP: &str = "4500002100010000401166c90a0000010a000002003514e9000d92e168656c6c6f";
+
+    #[test]
+    fn summaries() {
+        for (link_type, frame, summary) in [
+            (
+                LinkType::Ethernet,
+                TCP_SYN,
+                "IP 10.0.0.2.40000 > 10.0.0.1.80: Flags [S], seq 1000, win 64240, \
+                 options [mss 1460], length 0",
+            ),
+            (
+                LinkType::Raw,
+                UDP,
+                "IP 10.0.0.1.53 > 10.0.0.2.5353: UDP, length 5",
+            ),
+            (
+                LinkType::Raw,
+                "4500002000024000400126d90a0000020a000001080019270007000170696e67",
+                "IP 10.0.0.2 > 10.0.0.1: ICMP echo request, id 7, seq 1, length 12",
+            ),
+            (
+                LinkType::Ethernet,
+                "ffffffffffff020000000002080600010800060400010200000000020a000002\
+                 0000000000000a000001",
+                "ARP, Request who-has 10.0.0.1 tell 10.0.0.2, length 28",
+            ),
+            // The capture stops in the middle of the TCP header
+            (
+                LinkType::Raw,
+                "4500002c12344000400614960a0000020a0000019c400050000003e80000",
+                "IP 10.0.0.2 > 10.0.0.1: [|tcp]",
+            ),
+            (LinkType::Ethernet, "0200000000010200", " [|ether]"),
+            (LinkType::Raw, "450000", "IP  [|ip]"),
+        ] {
+            assert_eq!(dissect(link_type, &hex(frame), false), summary);
+        }
+    }
+
+    #[test]
+    fn verbose_tree_and_hex_dump() {
+        let frame = hex(&format!("00000800{UDP}"));
+        assert_eq!(
+            dissect(LinkType::Device(Mode::Tun), &frame, true),
+            "IP 10.0.0.1.53 > 10.0.0.2.5353: UDP, length 5
+Packet information
+    flags 0x0000, protocol 0x0800
+Internet Protocol version 4
+    header length 20, tos 0x0, total length 33
+    id 1, flags [none], offset 0, ttl 64
+    protocol 17, checksum 0x66c9 (correct)
+    10.0.0.1 > 10.0.0.2
+User Datagram Protocol
+    53 > 5353, length 13
+    checksum 0x92e1 (correct)
+Payload, 5 bytes
+\t0x0000:  0000 0800 4500 0021 0001 0000 4011 66c9  ....E..!....@.f.
+\t0x0010:  0a00 0001 0a00 0002 0035 14e9 000d 92e1  .........5......
+\t0x0020:  6865 6c6c 6f                             hello
+"
+        );
+    }
+
+    #[test]
+    fn checksums_checked() {
+        let mut frame = hex(TCP_SYN);
+        let tree = dissect(LinkType::Ethernet, &frame, true);
+        assert!(tree.contains("    checksum 0xe8ba (correct)\n"));
+        // The window is covered by the TCP checksum only
+        frame[48] ^= 1;
+        let tree = dissect(LinkType::Ethernet, &frame, true);
+        assert!(tree.contains("    protocol 6, checksum 0x1496 (correct)\n"));
+        assert!(tree.contains("    checksum 0xe8ba (incorrect)\n"));
+        // Without its end, the checksum of a datagram cannot be checked
+        let frame = hex(UDP);
+        let tree = dissect(LinkType::Raw, &frame[..frame.len() - 1], true);
+        assert!(tree.contains("    checksum 0x92e1\n"));
+    }
+}
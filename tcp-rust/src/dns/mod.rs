@@ -0,0 +1,708 @@
+pub mod resolver;
+pub mod server;
+pub mod zone;
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{self, Write},
+};
+
+use crate::{
+    ip::{IpV4Addr, IpV6Addr},
+    traits::{AsArrayUnchecked, Data, Prepare, ToMutable, WriteTo},
+};
+
+pub const DNS_PORT: u16 = 53;
+pub const CLASS_IN: u16 = 1;
+/// Largest message sent over UDP, longer answers being truncated (RFC 1035 §4.2.1)
+pub const MAX_UDP_SIZE: usize = 512;
+
+/// Labels are limited to 63 bytes, and a pointer is marked by the two upper bits of the length
+const POINTER_MASK: u8 = 0b1100_0000;
+
+#[derive(Debug)]
+pub struct ParseDNSError;
+
+/// A name which cannot be written in a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeDNSError {
+    /// Two dots in a row, or a leading one
+    EmptyLabel,
+    /// A label of more than 63 bytes
+    LabelTooLong,
+}
+
+impl From<EncodeDNSError> for io::Error {
+    fn from(value: EncodeDNSError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{value:?}"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub enum DNSType {
+    #[default]
+    A,
+    Ns,
+    CName,
+    Ptr,
+    Txt,
+    AAAA,
+    Any,
+    Other(u16),
+}
+
+impl From<u16> for DNSType {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Self::A,
+            2 => Self::Ns,
+            5 => Self::CName,
+            12 => Self::Ptr,
+            16 => Self::Txt,
+            28 => Self::AAAA,
+            255 => Self::Any,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<DNSType> for u16 {
+    fn from(value: DNSType) -> Self {
+        match value {
+            DNSType::A => 1,
+            DNSType::Ns => 2,
+            DNSType::CName => 5,
+            DNSType::Ptr => 12,
+            DNSType::Txt => 16,
+            DNSType::AAAA => 28,
+            DNSType::Any => 255,
+            DNSType::Other(other) => other,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum DNSResponseCode {
+    #[default]
+    NoError = 0,
+    FormatError = 1,
+    ServerFailure = 2,
+    NameError = 3,
+    NotImplemented = 4,
+    Refused = 5,
+}
+
+/// Compare two domain names, which are case insensitive and may or may not be fully qualified
+pub fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DNSMessageView<'a> {
+    content: &'a [u8],
+}
+
+impl<'a> TryFrom<&'a [u8]> for DNSMessageView<'a> {
+    type Error = ParseDNSError;
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        if value.len() < 12 {
+            return Err(ParseDNSError);
+        }
+        let view = Self { content: value };
+        // Walk the whole message once, so that getters can assume it is well formed
+        let mut offset = 12;
+        for _ in 0..view.get_qdcount() {
+            offset = view.read_question(offset)?.1;
+        }
+        for _ in 0..view.get_ancount() as usize
+            + view.get_nscount() as usize
+            + view.get_arcount() as usize
+        {
+            offset = view.read_record(offset)?.1;
+        }
+        Ok(Self {
+            content: &value[..offset],
+        })
+    }
+}
+
+impl<'a> AsRef<[u8]> for DNSMessageView<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.content
+    }
+}
+
+impl Data for DNSMessageView<'_> {
+    fn size(&self) -> usize {
+        self.content.len()
+    }
+}
+
+impl ToMutable for DNSMessageView<'_> {
+    type MutableType = DNSMessage;
+
+    fn to_mutable(&self) -> Self::MutableType {
+        let records = self.get_records();
+        let (answers, rest) = records.split_at(self.get_ancount() as usize);
+        let (authorities, additionals) = rest.split_at(self.get_nscount() as usize);
+        DNSMessage {
+            header: DNSHeader {
+                id: self.get_id(),
+                qr: self.get_qr(),
+                opcode: self.get_opcode(),
+                aa: self.get_aa(),
+                tc: self.get_tc(),
+                rd: self.get_rd(),
+                ra: self.get_ra(),
+                rcode: self.get_rcode(),
+            },
+            questions: self.get_questions(),
+            answers: answers.to_vec(),
+            authorities: authorities.to_vec(),
+            additionals: additionals.to_vec(),
+        }
+    }
+}
+
+impl<'a> DNSMessageView<'a> {
+    pub fn get_id(&self) -> u16 {
+        u16::from_be_bytes(*unsafe { self.content[0..2].as_array_unchecked() })
+    }
+    pub fn get_qr(&self) -> bool {
+        (self.content[2] & (1 << 7)) != 0
+    }
+    pub fn get_opcode(&self) -> u8 {
+        (self.content[2] >> 3) & 0xF
+    }
+    pub fn get_aa(&self) -> bool {
+        (self.content[2] & (1 << 2)) != 0
+    }
+    pub fn get_tc(&self) -> bool {
+        (self.content[2] & (1 << 1)) != 0
+    }
+    pub fn get_rd(&self) -> bool {
+        (self.content[2] & (1 << 0)) != 0
+    }
+    pub fn get_ra(&self) -> bool {
+        (self.content[3] & (1 << 7)) != 0
+    }
+    pub fn get_rcode(&self) -> u8 {
+        self.content[3] & 0xF
+    }
+    pub fn get_qdcount(&self) -> u16 {
+        u16::from_be_bytes(*unsafe { self.content[4..6].as_array_unchecked() })
+    }
+    pub fn get_ancount(&self) -> u16 {
+        u16::from_be_bytes(*unsafe { self.content[6..8].as_array_unchecked() })
+    }
+    pub fn get_nscount(&self) -> u16 {
+        u16::from_be_bytes(*unsafe { self.content[8..10].as_array_unchecked() })
+    }
+    pub fn get_arcount(&self) -> u16 {
+        u16::from_be_bytes(*unsafe { self.content[10..12].as_array_unchecked() })
+    }
+
+    pub fn get_questions(&self) -> Vec<DNSQuestion> {
+        let mut offset = 12;
+        (0..self.get_qdcount())
+            .map(|_| {
+                let (question, next) = self.read_question(offset).unwrap();
+                offset = next;
+                question
+            })
+            .collect()
+    }
+
+    /// Answers, authorities and additionals, in this order
+    pub fn get_records(&self) -> Vec<DNSRecord> {
+        let mut offset = 12;
+        for _ in 0..self.get_qdcount() {
+            offset = self.read_question(offset).unwrap().1;
+        }
+        let count =
+            self.get_ancount() as usize + self.get_nscount() as usize + self.get_arcount() as usize;
+        (0..count)
+            .map(|_| {
+                let (record, next) = self.read_record(offset).unwrap();
+                offset = next;
+                record
+            })
+            .collect()
+    }
+
+    pub fn get_answers(&self) -> Vec<DNSRecord> {
+        let mut records = self.get_records();
+        records.truncate(self.get_ancount() as usize);
+        records
+    }
+
+    fn get_bytes<const N: usize>(&self, offset: usize) -> Result<[u8; N], ParseDNSError> {
+        self.content
+            .get(offset..offset + N)
+            .map(|bytes| *unsafe { bytes.as_array_unchecked() })
+            .ok_or(ParseDNSError)
+    }
+
+    /// Read a possibly compressed name, returning it with the offset right after it
+    fn read_name(&self, mut offset: usize) -> Result<(String, usize), ParseDNSError> {
+        let mut name = String::new();
+        let mut end = None;
+        // Each pointer must go backward, which prevents loops
+        let mut limit = offset;
+        loop {
+            let len = *self.content.get(offset).ok_or(ParseDNSError)?;
+            if len & POINTER_MASK == POINTER_MASK {
+                let pointer = u16::from_be_bytes(self.get_bytes(offset)?) & 0x3FFF;
+                end.get_or_insert(offset + 2);
+                if pointer as usize >= limit {
+                    return Err(ParseDNSError);
+                }
+                offset = pointer as usize;
+                limit = offset;
+            } else if len == 0 {
+                return Ok((name, end.unwrap_or(offset + 1)));
+            } else {
+                let label = self
+                    .content
+                    .get(offset + 1..offset + 1 + len as usize)
+                    .ok_or(ParseDNSError)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(str::from_utf8(label).map_err(|_| ParseDNSError)?);
+                offset += 1 + len as usize;
+            }
+        }
+    }
+
+    fn read_question(&self, offset: usize) -> Result<(DNSQuestion, usize), ParseDNSError> {
+        let (name, offset) = self.read_name(offset)?;
+        let qtype = u16::from_be_bytes(self.get_bytes(offset)?).into();
+        let qclass = u16::from_be_bytes(self.get_bytes(offset + 2)?);
+        Ok((
+            DNSQuestion {
+                name,
+                qtype,
+                qclass,
+            },
+            offset + 4,
+        ))
+    }
+
+    fn read_record(&self, offset: usize) -> Result<(DNSRecord, usize), ParseDNSError> {
+        let (name, offset) = self.read_name(offset)?;
+        let rtype = DNSType::from(u16::from_be_bytes(self.get_bytes(offset)?));
+        let class = u16::from_be_bytes(self.get_bytes(offset + 2)?);
+        let ttl = u32::from_be_bytes(self.get_bytes(offset + 4)?);
+        let rdlength = u16::from_be_bytes(self.get_bytes(offset + 8)?) as usize;
+        let start = offset + 10;
+        let rdata = self
+            .content
+            .get(start..start + rdlength)
+            .ok_or(ParseDNSError)?;
+
+        let data = match rtype {
+            DNSType::A if rdlength == 4 => {
+                DNSRecordData::A(u32::from_be_bytes(self.get_bytes(start)?).into())
+            }
+            DNSType::AAAA if rdlength == 16 => {
+                DNSRecordData::AAAA(u128::from_be_bytes(self.get_bytes(start)?).into())
+            }
+            DNSType::CName => DNSRecordData::CName(self.read_name(start)?.0),
+            DNSType::Ptr => DNSRecordData::Ptr(self.read_name(start)?.0),
+            DNSType::Txt => {
+                let mut strings = vec![];
+                let mut rdata = rdata;
+                while let Some((&len, rest)) = rdata.split_first() {
+                    let (string, rest) =
+                        rest.split_at_checked(len as usize).ok_or(ParseDNSError)?;
+                    strings.push(String::from_utf8_lossy(string).into_owned());
+                    rdata = rest;
+                }
+                DNSRecordData::Txt(strings)
+            }
+            _ => DNSRecordData::Other(u16::from(rtype), rdata.to_vec()),
+        };
+
+        Ok((
+            DNSRecord {
+                name,
+                class,
+                ttl,
+                data,
+            },
+            start + rdlength,
+        ))
+    }
+}
+
+impl Debug for DNSMessageView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DNSMessageView")
+            .field("id", &self.get_id())
+            .field("qr", &self.get_qr())
+            .field("opcode", &self.get_opcode())
+            .field("aa", &self.get_aa())
+            .field("tc", &self.get_tc())
+            .field("rd", &self.get_rd())
+            .field("ra", &self.get_ra())
+            .field("rcode", &self.get_rcode())
+            .field("questions", &self.get_questions())
+            .field("records", &self.get_records())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DNSHeader {
+    pub id: u16,
+    pub qr: bool,
+    pub opcode: u8,
+    pub aa: bool,
+    pub tc: bool,
+    pub rd: bool,
+    pub ra: bool,
+    pub rcode: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DNSQuestion {
+    pub name: String,
+    pub qtype: DNSType,
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DNSRecordData {
+    A(IpV4Addr),
+    AAAA(IpV6Addr),
+    CName(String),
+    Ptr(String),
+    Txt(Vec<String>),
+    Other(u16, Vec<u8>),
+}
+
+impl DNSRecordData {
+    pub fn get_type(&self) -> DNSType {
+        match self {
+            DNSRecordData::A(_) => DNSType::A,
+            DNSRecordData::AAAA(_) => DNSType::AAAA,
+            DNSRecordData::CName(_) => DNSType::CName,
+            DNSRecordData::Ptr(_) => DNSType::Ptr,
+            DNSRecordData::Txt(_) => DNSType::Txt,
+            DNSRecordData::Other(rtype, _) => DNSType::from(*rtype),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DNSRecord {
+    pub name: String,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: DNSRecordData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DNSMessage {
+    pub header: DNSHeader,
+    pub questions: Vec<DNSQuestion>,
+    pub answers: Vec<DNSRecord>,
+    pub authorities: Vec<DNSRecord>,
+    pub additionals: Vec<DNSRecord>,
+}
+
+/// Serialize names using message compression, remembering where each suffix was written
+#[derive(Default)]
+struct DNSEncoder {
+    buffer: Vec<u8>,
+    names: HashMap<String, u16>,
+}
+
+impl DNSEncoder {
+    fn write_name(&mut self, name: &str) -> Result<(), EncodeDNSError> {
+        let mut name = name.trim_end_matches('.');
+        while !name.is_empty() {
+            let key = name.to_ascii_lowercase();
+            if let Some(&pointer) = self.names.get(&key) {
+                self.buffer
+                    .extend_from_slice(&(pointer | (POINTER_MASK as u16) << 8).to_be_bytes());
+                return Ok(());
+            }
+            let (label, rest) = name.split_once('.').unwrap_or((name, ""));
+            if label.is_empty() {
+                return Err(EncodeDNSError::EmptyLabel);
+            }
+            if label.len() > 63 {
+                return Err(EncodeDNSError::LabelTooLong);
+            }
+            // Pointers only have 14 bits to address the message
+            if self.buffer.len() < 0x3FFF {
+                self.names.insert(key, self.buffer.len() as u16);
+            }
+            self.buffer.push(label.len() as u8);
+            self.buffer.extend_from_slice(label.as_bytes());
+            name = rest;
+        }
+        self.buffer.push(0);
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &DNSRecord) -> Result<(), EncodeDNSError> {
+        self.write_name(&record.name)?;
+        self.buffer
+            .extend_from_slice(&u16::from(record.data.get_type()).to_be_bytes());
+        self.buffer.extend_from_slice(&record.class.to_be_bytes());
+        self.buffer.extend_from_slice(&record.ttl.to_be_bytes());
+
+        let length_offset = self.buffer.len();
+        self.buffer.extend_from_slice(&[0, 0]);
+        match &record.data {
+            DNSRecordData::A(address) => self.buffer.extend_from_slice(&address.0.to_be_bytes()),
+            DNSRecordData::AAAA(address) => self.buffer.extend_from_slice(&address.0.to_be_bytes()),
+            DNSRecordData::CName(name) | DNSRecordData::Ptr(name) => self.write_name(name)?,
+            DNSRecordData::Txt(strings) => {
+                for string in strings {
+                    // Character strings are limited to 255 bytes, split longer ones
+                    for chunk in string.as_bytes().chunks(255) {
+                        self.buffer.push(chunk.len() as u8);
+                        self.buffer.extend_from_slice(chunk);
+                    }
+                }
+            }
+            DNSRecordData::Other(_, data) => self.buffer.extend_from_slice(data),
+        }
+        let length = (self.buffer.len() - length_offset - 2) as u16;
+        self.buffer[length_offset..length_offset + 2].copy_from_slice(&length.to_be_bytes());
+        Ok(())
+    }
+}
+
+impl DNSMessage {
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeDNSError> {
+        let mut encoder = DNSEncoder::default();
+        let header = &self.header;
+        encoder.buffer.extend_from_slice(&header.id.to_be_bytes());
+        encoder.buffer.extend_from_slice(&[
+            (header.qr as u8) << 7
+                | (header.opcode & 0xF) << 3
+                | (header.aa as u8) << 2
+                | (header.tc as u8) << 1
+                | (header.rd as u8),
+            (header.ra as u8) << 7 | (header.rcode & 0xF),
+        ]);
+        for count in [
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additionals.len(),
+        ] {
+            encoder
+                .buffer
+                .extend_from_slice(&(count as u16).to_be_bytes());
+        }
+        for question in &self.questions {
+            encoder.write_name(&question.name)?;
+            encoder
+                .buffer
+                .extend_from_slice(&u16::from(question.qtype).to_be_bytes());
+            encoder
+                .buffer
+                .extend_from_slice(&question.qclass.to_be_bytes());
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            encoder.write_record(record)?;
+        }
+        Ok(encoder.buffer)
+    }
+
+    /// Drop records from the end until the message fits in `max_size` bytes, setting the TC bit
+    /// if any answer or authority had to go
+    pub fn truncate(&mut self, max_size: usize) -> Result<(), EncodeDNSError> {
+        while self.encode()?.len() > max_size {
+            if self.additionals.pop().is_some() {
+                continue;
+            }
+            if self.authorities.pop().is_none() && self.answers.pop().is_none() {
+                break;
+            }
+            self.header.tc = true;
+        }
+        Ok(())
+    }
+
+    pub fn query(id: u16, name: &str, qtype: DNSType) -> Self {
+        Self {
+            header: DNSHeader {
+                id,
+                rd: true,
+                ..Default::default()
+            },
+            questions: vec![DNSQuestion {
+                name: name.to_string(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Make an empty response to a query, keeping its id and questions
+    pub fn answer(query: DNSMessageView) -> Self {
+        Self {
+            header: DNSHeader {
+                id: query.get_id(),
+                qr: true,
+                opcode: query.get_opcode(),
+                rd: query.get_rd(),
+                ..Default::default()
+            },
+            questions: query.get_questions(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Prepare for DNSMessage {}
+impl Data for DNSMessage {
+    fn size(&self) -> usize {
+        self.encode().map_or(0, |buffer| buffer.len())
+    }
+}
+
+impl WriteTo for DNSMessage {
+    fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        let buffer = self.encode()?;
+        writer.write_all(&buffer)?;
+        Ok(buffer.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str, data: DNSRecordData) -> DNSRecord {
+        DNSRecord {
+            name: name.to_string(),
+            class: CLASS_IN,
+            ttl: 300,
+            data,
+        }
+    }
+
+    #[test]
+    fn encode_and_parse_back() {
+        let mut message = DNSMessage::query(0x1234, "www.lab.test", DNSType::A);
+        message.header.qr = true;
+        message.header.aa = true;
+        message.answers = vec![
+            record(
+                "www.lab.test",
+                DNSRecordData::CName("host.lab.test".to_string()),
+            ),
+            record("host.lab.test", DNSRecordData::A(IpV4Addr(0xC0A8_0002))),
+            record(
+                "host.lab.test",
+                DNSRecordData::AAAA(IpV6Addr(0xFD00 << 112 | 2)),
+            ),
+        ];
+        message.authorities = vec![record(
+            "lab.test",
+            DNSRecordData::Txt(vec!["hello".to_string(), "x".repeat(300)]),
+        )];
+        message.additionals = vec![record(
+            "2.0.168.192.in-addr.arpa",
+            DNSRecordData::Ptr("host.lab.test".to_string()),
+        )];
+        let bytes = message.encode().unwrap();
+        let parsed = DNSMessageView::try_from(&bytes[..]).unwrap().to_mutable();
+
+        // The long string comes back in two, as character strings are at most 255 bytes
+        let mut expected = message.clone();
+        expected.authorities[0].data =
+            DNSRecordData::Txt(vec!["hello".to_string(), "x".repeat(255), "x".repeat(45)]);
+        assert_eq!(parsed, expected);
+        // `lab.test` is written once, the other names pointing to it
+        assert_eq!(
+            bytes
+                .windows(5)
+                .filter(|window| *window == b"\x03lab\x04")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn unencodable_names() {
+        for (name, error) in [
+            ("www..lab.test", EncodeDNSError::EmptyLabel),
+            (".lab.test", EncodeDNSError::EmptyLabel),
+            (
+                &format!("{}.test", "a".repeat(64)),
+                EncodeDNSError::LabelTooLong,
+            ),
+        ] {
+            let message = DNSMessage::query(1, name, DNSType::A);
+            assert_eq!(message.encode(), Err(error), "{name}");
+        }
+        let longest = format!("{}.test.", "a".repeat(63));
+        assert!(DNSMessage::query(1, &longest, DNSType::A).encode().is_ok());
+        assert!(DNSMessage::query(1, ".", DNSType::Ns).encode().is_ok());
+    }
+
+    #[test]
+    fn compression_pointer_loops() {
+        let header = [0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        // A name pointing to itself, then to the name right after it
+        for name in [&[0xC0, 12][..], &[0xC0, 14, 0]] {
+            let message = [&header[..], name, &[0, 1, 0, 1]].concat();
+            assert!(DNSMessageView::try_from(&message[..]).is_err());
+        }
+        // Two names pointing to each other
+        let message = [
+            &header[..],
+            &[1, b'a', 0xC0, 19, 0, 1, 0, 1, 1, b'b', 0xC0, 12, 0, 1, 0, 1],
+        ]
+        .concat();
+        assert!(DNSMessageView::try_from(&message[..]).is_err());
+        // A pointer backward is fine
+        let mut message = [&header[..], &[1, b'a', 0, 0, 1, 0, 1, 0xC0, 12, 0, 1, 0, 1]].concat();
+        message[5] = 2;
+        let view = DNSMessageView::try_from(&message[..]).unwrap();
+        assert!(
+            view.get_questions()
+                .iter()
+                .all(|question| question.name == "a")
+        );
+    }
+
+    #[test]
+    fn truncated_to_fit() {
+        let mut message = DNSMessage::query(1, "lab.test", DNSType::Txt);
+        message.header.qr = true;
+        message.answers = (0..8)
+            .map(|_| record("lab.test", DNSRecordData::Txt(vec!["x".repeat(100)])))
+            .collect();
+        message.truncate(MAX_UDP_SIZE).unwrap();
+        assert!(message.header.tc);
+        assert_eq!(message.answers.len(), 4);
+        assert!(message.encode().unwrap().len() <= MAX_UDP_SIZE);
+
+        let mut message = DNSMessage::query(1, "lab.test", DNSType::Txt);
+        message.answers = vec![record(
+            "lab.test",
+            DNSRecordData::Txt(vec!["x".repeat(2000)]),
+        )];
+        message.truncate(MAX_UDP_SIZE).unwrap();
+        assert!(message.header.tc && message.answers.is_empty());
+    }
+}
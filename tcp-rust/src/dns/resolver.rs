@@ -0,0 +1,253 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    time::{Duration, Instant},
+};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::{
+    dns::{DNS_PORT, DNSMessage, DNSMessageView, DNSRecordData, DNSType, same_name},
+    ethernet::ETHERTYPE_IPV4,
+    interface::Interface,
+    ip::{IPV4Header, IPV4Packet, IpProtocol, IpV4Addr},
+    udp::{UDPHeader, UDPPacket, UDPPacketView},
+};
+
+/// Time after which a query without answer is sent again
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A query waiting for its answer, which must come from the server it was sent to
+#[derive(Debug, Clone)]
+struct PendingQuery {
+    name: String,
+    server: IpV4Addr,
+    sent: Instant,
+}
+
+/// Stub resolver used by the stack itself, e.g. to find the address of the host to ping.
+///
+/// Queries are sent without blocking: `resolve` returns `None` until the answer has been received
+/// through `handle_datagram`, after which the address is served from the cache until its TTL ends.
+/// Each query is sent from a random ephemeral port with a random id, which an off-path attacker
+/// has to guess to poison the cache (RFC 5452).
+#[derive(Debug, Clone, Default)]
+pub struct Resolver {
+    pub server: Option<IpV4Addr>,
+    cache: HashMap<String, (IpV4Addr, Instant)>,
+    /// Queries by local port and id
+    pending: HashMap<(u16, u16), PendingQuery>,
+}
+
+/// Random port in the dynamic range, 49152 to 65535, and random id of a query
+fn random_port_and_id() -> (u16, u16) {
+    let mut bytes = [0; 4];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("no random source for the DNS queries");
+    (
+        u16::from_ne_bytes([bytes[0], bytes[1]]) | 0xC000,
+        u16::from_ne_bytes([bytes[2], bytes[3]]),
+    )
+}
+
+impl Resolver {
+    pub fn new(server: Option<IpV4Addr>) -> Self {
+        Self {
+            server,
+            ..Default::default()
+        }
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<IpV4Addr> {
+        self.cache
+            .get(&name.trim_end_matches('.').to_ascii_lowercase())
+            .filter(|(_, expiry)| *expiry > Instant::now())
+            .map(|(address, _)| *address)
+    }
+
+    pub fn resolve(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        source_address: IpV4Addr,
+        name: &str,
+    ) -> Option<IpV4Addr> {
+        if let Ok(address) = name.parse() {
+            return Some(address);
+        }
+        let address = self.lookup(name);
+        // Queries lost on the way are given up, and sent again
+        self.pending
+            .retain(|_, pending| pending.sent.elapsed() < QUERY_TIMEOUT);
+        if address.is_none() && !self.is_resolving(name) {
+            self.query(interface, source_address, name);
+        }
+        address
+    }
+
+    pub fn query(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        source_address: IpV4Addr,
+        name: &str,
+    ) {
+        let Some(server) = self.server else {
+            return;
+        };
+        let (port, id) = random_port_and_id();
+        let ip_header = IPV4Header::new(IpProtocol::Udp, source_address, server);
+        let udp_header = UDPHeader {
+            source_port: port,
+            destination_port: DNS_PORT,
+            ..Default::default()
+        };
+        // Like an answer, the query goes to the link address of the last received frame
+        if interface
+            .try_write_frame(
+                interface.get_peer_mac(),
+                ETHERTYPE_IPV4,
+                IPV4Packet::new(
+                    ip_header,
+                    UDPPacket::new(udp_header, DNSMessage::query(id, name, DNSType::A)),
+                ),
+            )
+            .is_err()
+        {
+            return;
+        }
+        interface.send();
+        self.pending.insert(
+            (port, id),
+            PendingQuery {
+                name: name.to_string(),
+                server,
+                sent: Instant::now(),
+            },
+        );
+    }
+
+    /// Whether a query for the name is waiting for its answer
+    pub fn is_resolving(&self, name: &str) -> bool {
+        self.pending
+            .values()
+            .any(|pending| same_name(&pending.name, name))
+    }
+
+    /// Whether a query was sent from the port, whose datagrams are for the resolver
+    pub fn is_waiting_on(&self, port: u16) -> bool {
+        self.pending.keys().any(|(pending, _)| *pending == port)
+    }
+
+    /// Take a datagram sent to the port of a query, ignoring it unless it is the answer of the
+    /// server the query was sent to
+    pub fn handle_datagram(&mut self, source_address: IpV4Addr, datagram: UDPPacketView) {
+        if datagram.header.get_source_port() != DNS_PORT {
+            return;
+        }
+        let Ok(response) = DNSMessageView::try_from(datagram.payload) else {
+            return;
+        };
+        let key = (datagram.header.get_destination_port(), response.get_id());
+        let Some(pending) = self.pending.get(&key) else {
+            return;
+        };
+        let answers_query = response.get_questions().first().is_some_and(|question| {
+            question.qtype == DNSType::A && same_name(&question.name, &pending.name)
+        });
+        if pending.server != source_address || !response.get_qr() || !answers_query {
+            return;
+        }
+        let name = self.pending.remove(&key).unwrap().name;
+
+        // Follow the CNAME chain of the answer section, in order
+        let mut target = name.clone();
+        for record in response.get_answers() {
+            if !same_name(&record.name, &target) {
+                continue;
+            }
+            match record.data {
+                DNSRecordData::CName(alias) => target = alias,
+                DNSRecordData::A(address) => {
+                    let expiry = Instant::now() + Duration::from_secs(record.ttl as u64);
+                    self.cache.insert(
+                        name.trim_end_matches('.').to_ascii_lowercase(),
+                        (address, expiry),
+                    );
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dns::DNSRecord, ip::IPV4PacketView, test_support::Wire, traits::WriteTo};
+
+    const STACK: IpV4Addr = IpV4Addr(0xC0A8_0002);
+    const SERVER: IpV4Addr = IpV4Addr(0xC0A8_0001);
+
+    /// The answer of `server` to the query written on the wire, from `source_port`
+    fn answer(wire: &Wire, server: IpV4Addr, source_port: u16) -> Vec<u8> {
+        let sent = wire.sent.borrow();
+        let query = IPV4PacketView::<UDPPacketView>::try_from(&sent[0][4..]).unwrap();
+        let mut answer =
+            DNSMessage::answer(DNSMessageView::try_from(query.payload.payload).unwrap());
+        answer.answers.push(DNSRecord {
+            name: "www.lab.test".to_string(),
+            class: crate::dns::CLASS_IN,
+            ttl: 60,
+            data: DNSRecordData::A(IpV4Addr(0x0A00_0001)),
+        });
+        let udp_header = UDPHeader {
+            source_port,
+            destination_port: query.payload.header.get_source_port(),
+            ..Default::default()
+        };
+        IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Udp, server, STACK),
+            UDPPacket::new(udp_header, answer),
+        )
+        .to_bytes()
+        .unwrap()
+    }
+
+    #[test]
+    fn answer_only_taken_from_the_server() {
+        let wire = Wire::default();
+        let mut interface = Interface::new(wire.clone());
+        let mut resolver = Resolver::new(Some(SERVER));
+        assert_eq!(
+            resolver.resolve(&mut interface, STACK, "www.lab.test"),
+            None
+        );
+        assert_eq!(wire.sent.borrow().len(), 1);
+
+        let query = IPV4PacketView::<UDPPacketView>::try_from(&wire.sent.borrow()[0][4..])
+            .unwrap()
+            .payload
+            .header
+            .get_source_port();
+        assert!(query >= 0xC000 && resolver.is_waiting_on(query));
+        for forged in [
+            answer(&wire, IpV4Addr(0xC0A8_0003), DNS_PORT),
+            answer(&wire, SERVER, 5353),
+        ] {
+            let packet = IPV4PacketView::<UDPPacketView>::try_from(&forged[..]).unwrap();
+            resolver.handle_datagram(packet.header.get_source_address(), packet.payload);
+            assert_eq!(resolver.lookup("www.lab.test"), None);
+        }
+
+        let genuine = answer(&wire, SERVER, DNS_PORT);
+        let packet = IPV4PacketView::<UDPPacketView>::try_from(&genuine[..]).unwrap();
+        resolver.handle_datagram(SERVER, packet.payload);
+        assert_eq!(
+            resolver.resolve(&mut interface, STACK, "WWW.lab.test."),
+            Some(IpV4Addr(0x0A00_0001))
+        );
+        assert!(!resolver.is_waiting_on(query));
+        assert_eq!(wire.sent.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::{self, Read, Write};
+
+use tracing::debug;
+
+use crate::{
+    dns::{
+        DNSMessage, DNSMessageView, DNSRecordData, DNSResponseCode, DNSType, MAX_UDP_SIZE,
+        zone::Zone,
+    },
+    interface::Interface,
+    ip::{IPV4Header, IPV4Packet, IpProtocol},
+    udp::{UDPHeader, UDPPacket, UDPPacketView},
+};
+
+/// Bound on the number of CNAMEs followed when answering a query
+const MAX_CNAME_CHAIN: usize = 8;
+
+#[derive(Debug, Clone, Default)]
+pub struct DNSServer {
+    pub zone: Zone,
+}
+
+impl DNSServer {
+    pub fn new(zone: Zone) -> Self {
+        Self { zone }
+    }
+
+    pub fn handle_query(&self, query: DNSMessageView) -> DNSMessage {
+        let mut response = DNSMessage::answer(query);
+        // Only standard queries are supported
+        if query.get_qr() || query.get_opcode() != 0 {
+            response.header.rcode = DNSResponseCode::NotImplemented as u8;
+            return response;
+        }
+
+        for question in &response.questions {
+            if !self.zone.is_authoritative(&question.name) {
+                response.header.rcode = DNSResponseCode::Refused as u8;
+                continue;
+            }
+            response.header.aa = true;
+            if !self.zone.contains_name(&question.name) {
+                response.header.rcode = DNSResponseCode::NameError as u8;
+                continue;
+            }
+
+            let mut name = question.name.clone();
+            for _ in 0..MAX_CNAME_CHAIN {
+                let records: Vec<_> = self.zone.lookup(&name, question.qtype).cloned().collect();
+                if !records.is_empty() || question.qtype == DNSType::CName {
+                    response.answers.extend(records);
+                    break;
+                }
+                // No record of the requested type, but the name may be an alias
+                let Some(alias) = self.zone.lookup(&name, DNSType::CName).next() else {
+                    break;
+                };
+                response.answers.push(alias.clone());
+                let DNSRecordData::CName(target) = &alias.data else {
+                    unreachable!()
+                };
+                name = target.clone();
+            }
+        }
+        response
+    }
+
+    /// Answer a query, failing if the answer cannot be written
+    pub fn handle_packet(&self, interface: &mut Interface<impl Read + Write>) -> io::Result<()> {
+        let Ok(ip_packet) = interface.try_get_ip_packet::<UDPPacketView>() else {
+            debug!("truncated UDP datagram dropped");
+            return Ok(());
+        };
+        let udp_packet = ip_packet.payload;
+        let Ok(query) = DNSMessageView::try_from(udp_packet.payload) else {
+            // Malformed query, ignore it
+            return Ok(());
+        };
+
+        let mut answer = self.handle_query(query);
+        answer.truncate(MAX_UDP_SIZE)?;
+        let ip_header = IPV4Header::new(
+            IpProtocol::Udp,
+            ip_packet.header.get_destination_address(),
+            ip_packet.header.get_source_address(),
+        );
+        let mut response = IPV4Packet::new(ip_header, UDPPacket::new(UDPHeader::default(), answer));
+        response.payload.header.answer(udp_packet.header);
+
+        interface.try_write(response)?;
+        interface.send();
+        debug!("answered a DNS query");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checksum::Checksum,
+        ip::{DEFAULT_TTL, IPV4HeaderView, IPV4PacketView, IpV4Addr},
+        test_support::Wire,
+        traits::WriteTo,
+    };
+
+    /// A query from 192.168.0.1 to the stack at 192.168.0.2
+    fn query_packet(query: DNSMessage) -> Vec<u8> {
+        IPV4Packet::new(
+            IPV4Header::new(
+                IpProtocol::Udp,
+                IpV4Addr(0xC0A8_0001),
+                IpV4Addr(0xC0A8_0002),
+            ),
+            UDPPacket::new(
+                UDPHeader {
+                    source_port: 40000,
+                    destination_port: crate::dns::DNS_PORT,
+                    ..Default::default()
+                },
+                query,
+            ),
+        )
+        .to_bytes()
+        .unwrap()
+    }
+
+    /// The packet sent by the server in answer to `packet`
+    fn answer(server: &DNSServer, packet: &[u8]) -> Vec<u8> {
+        let wire = Wire::default();
+        let mut interface = Interface::new(wire.clone());
+        interface.receive_frame(&[&[0, 0, 8, 0][..], packet].concat());
+        server.handle_packet(&mut interface).unwrap();
+        wire.sent.borrow_mut().remove(0).split_off(4)
+    }
+
+    #[test]
+    fn long_answer_truncated() {
+        let zone = (0..20)
+            .map(|i| format!("@ TXT \"{}\"\n", i.to_string().repeat(100)))
+            .collect::<String>();
+        let server = DNSServer::new(format!("$ORIGIN lab.test.\n{zone}").parse().unwrap());
+        let packet = query_packet(DNSMessage::query(7, "lab.test", DNSType::Txt));
+        let sent = answer(&server, &packet);
+        let response = IPV4PacketView::<UDPPacketView>::try_from(&sent[..]).unwrap();
+        assert!(response.payload.payload.len() <= MAX_UDP_SIZE);
+        let answer = DNSMessageView::try_from(response.payload.payload).unwrap();
+        assert!(answer.get_tc());
+        assert_eq!((answer.get_id(), answer.get_ancount()), (7, 4));
+    }
+
+    #[test]
+    fn reply_header_not_copied_from_the_query() {
+        let server = DNSServer::new("$ORIGIN lab.test.\n@ A 192.168.0.2\n".parse().unwrap());
+        let mut packet = query_packet(DNSMessage::query(7, "lab.test", DNSType::A));
+        // A query with 4 bytes of options, an expiring TTL and ECN bits
+        packet.splice(20..20, [1, 1, 1, 0]);
+        packet[0] = 0x46;
+        packet[1] = 0x03;
+        let total_length = packet.len() as u16;
+        packet[2..4].copy_from_slice(&total_length.to_be_bytes());
+        packet[8] = 1;
+        packet[10..12].fill(0);
+        let checksum = Checksum::new().add_slice(&packet[..24]).ones_complement();
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        let sent = answer(&server, &packet);
+        let response = IPV4PacketView::<UDPPacketView>::try_from(&sent[..]).unwrap();
+        let header = IPV4HeaderView::parse(&sent).unwrap();
+        assert_eq!((header.get_ihl(), header.get_ecn()), (5, 0));
+        assert_eq!(header.get_ttl(), DEFAULT_TTL);
+        assert_eq!(
+            (
+                header.get_source_address(),
+                header.get_destination_address()
+            ),
+            (IpV4Addr(0xC0A8_0002), IpV4Addr(0xC0A8_0001))
+        );
+        assert_eq!(response.payload.header.get_destination_port(), 40000);
+        assert_eq!(
+            DNSMessageView::try_from(response.payload.payload)
+                .unwrap()
+                .get_ancount(),
+            1
+        );
+    }
+}
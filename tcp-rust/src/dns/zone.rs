@@ -0,0 +1,222 @@
+use std::{fmt::Display, fs, io, path::Path, str::FromStr};
+
+use crate::{
+    dns::{CLASS_IN, DNSRecord, DNSRecordData, DNSType, same_name},
+    ip::IpV4Addr,
+};
+
+const DEFAULT_TTL: u32 = 3600;
+
+#[derive(Debug)]
+pub struct ParseZoneError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for ParseZoneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "zone file line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseZoneError {}
+
+impl From<ParseZoneError> for io::Error {
+    fn from(value: ParseZoneError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+/// A set of records the server is authoritative for, loaded from a master file (RFC 1035 §5).
+///
+/// Only a subset of the format is supported: one record per line, `;` comments, `$ORIGIN` and
+/// `$TTL` directives, `@` for the origin, and A, AAAA, CNAME, PTR and TXT records.
+/// ```text
+/// $ORIGIN lab.test.
+/// $TTL 300
+/// @      IN A     192.168.0.2
+/// www       CNAME @
+/// host   60 AAAA  fd00::2
+/// @         TXT   "hello from" "tcp-rust"
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Zone {
+    pub origin: String,
+    pub records: Vec<DNSRecord>,
+}
+
+impl Zone {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(fs::read_to_string(path)?.parse()?)
+    }
+
+    pub fn lookup(&self, name: &str, rtype: DNSType) -> impl Iterator<Item = &DNSRecord> {
+        self.records.iter().filter(move |record| {
+            same_name(&record.name, name)
+                && (rtype == DNSType::Any || record.data.get_type() == rtype)
+        })
+    }
+
+    pub fn contains_name(&self, name: &str) -> bool {
+        self.records
+            .iter()
+            .any(|record| same_name(&record.name, name))
+    }
+
+    /// Whether the name is inside this zone, even if no record exists for it
+    pub fn is_authoritative(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let origin = self.origin.trim_end_matches('.').to_ascii_lowercase();
+        origin.is_empty() || name == origin || name.ends_with(&format!(".{origin}"))
+    }
+
+    /// Resolve a name to an IPv4 address, following CNAMEs inside the zone
+    pub fn resolve(&self, name: &str) -> Option<IpV4Addr> {
+        let mut name = name.to_string();
+        // Bound the number of CNAMEs followed to avoid looping forever
+        for _ in 0..8 {
+            if let Some(address) =
+                self.lookup(&name, DNSType::A)
+                    .find_map(|record| match record.data {
+                        DNSRecordData::A(address) => Some(address),
+                        _ => None,
+                    })
+            {
+                return Some(address);
+            }
+            let target = match &self.lookup(&name, DNSType::CName).next()?.data {
+                DNSRecordData::CName(target) => target.clone(),
+                _ => return None,
+            };
+            name = target;
+        }
+        None
+    }
+
+    fn absolute_name(&self, name: &str) -> String {
+        if name == "@" {
+            self.origin.clone()
+        } else if name.ends_with('.') || self.origin.is_empty() {
+            name.to_string()
+        } else {
+            format!("{name}.{}", self.origin)
+        }
+    }
+}
+
+/// Split a line into fields, keeping quoted strings together and dropping comments
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => token.extend(chars.next()),
+                    c => token.push(c),
+                }
+            }
+            tokens.push(format!("\"{token}"));
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ';' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+impl FromStr for Zone {
+    type Err = ParseZoneError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut zone = Zone::default();
+        let mut ttl = DEFAULT_TTL;
+        let mut last_name = None;
+
+        for (i, line) in s.lines().enumerate() {
+            let error = |message: &str| ParseZoneError {
+                line: i + 1,
+                message: message.to_string(),
+            };
+            let tokens = tokenize(line);
+            let Some(first) = tokens.first() else {
+                continue;
+            };
+
+            match first.as_str() {
+                "$ORIGIN" => {
+                    let origin = tokens.get(1).ok_or_else(|| error("missing origin"))?;
+                    zone.origin = zone.absolute_name(origin);
+                    continue;
+                }
+                "$TTL" => {
+                    ttl = tokens
+                        .get(1)
+                        .and_then(|ttl| ttl.parse().ok())
+                        .ok_or_else(|| error("invalid ttl"))?;
+                    continue;
+                }
+                _ => {}
+            }
+
+            // A line starting with a blank reuses the previous owner name
+            let mut fields = tokens.iter().map(String::as_str).peekable();
+            let name = if line.starts_with(char::is_whitespace) {
+                last_name
+                    .clone()
+                    .ok_or_else(|| error("missing owner name"))?
+            } else {
+                zone.absolute_name(fields.next().unwrap())
+            };
+            last_name = Some(name.clone());
+
+            let mut record_ttl = ttl;
+            if let Some(value) = fields.peek().and_then(|field| field.parse().ok()) {
+                record_ttl = value;
+                fields.next();
+            }
+            if fields.peek() == Some(&"IN") {
+                fields.next();
+            }
+
+            let rtype = fields.next().ok_or_else(|| error("missing record type"))?;
+            let rdata: Vec<_> = fields.collect();
+            let value = rdata.first().ok_or_else(|| error("missing record data"))?;
+            let data = match rtype.to_ascii_uppercase().as_str() {
+                "A" => DNSRecordData::A(value.parse().map_err(|_| error("invalid address"))?),
+                "AAAA" => DNSRecordData::AAAA(value.parse().map_err(|_| error("invalid address"))?),
+                "CNAME" => DNSRecordData::CName(zone.absolute_name(value)),
+                "PTR" => DNSRecordData::Ptr(zone.absolute_name(value)),
+                "TXT" => DNSRecordData::Txt(
+                    rdata
+                        .iter()
+                        .map(|string| string.strip_prefix('"').unwrap_or(string).to_string())
+                        .collect(),
+                ),
+                _ => return Err(error("unsupported record type")),
+            };
+
+            zone.records.push(DNSRecord {
+                name,
+                class: CLASS_IN,
+                ttl: record_ttl,
+                data,
+            });
+        }
+        Ok(zone)
+    }
+}
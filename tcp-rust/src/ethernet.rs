@@ -0,0 +1,146 @@
+use std::{
+    fmt::{Debug, Display},
+    io::{self, Write},
+    num::ParseIntError,
+    str::FromStr,
+};
+
+use crate::{
+    packet::{Packet, PacketView, ParseHeaderError},
+    traits::{AsArrayUnchecked, Data, Prepare, ToMutable, WriteTo},
+};
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: Self = Self([0xFF; 6]);
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mac = [0; 6];
+        for (byte, part) in mac.iter_mut().zip(s.split(':')) {
+            *byte = u8::from_str_radix(part, 16)?;
+        }
+        Ok(Self(mac))
+    }
+}
+
+impl Debug for MacAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl Display for MacAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EthernetHeaderView<'a> {
+    content: &'a [u8],
+}
+
+impl ToMutable for EthernetHeaderView<'_> {
+    type MutableType = EthernetHeader;
+
+    fn to_mutable(&self) -> Self::MutableType {
+        EthernetHeader {
+            destination: self.get_destination(),
+            source: self.get_source(),
+            ethertype: self.get_ethertype(),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for EthernetHeaderView<'a> {
+    type Error = ParseHeaderError;
+
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        Self::parse(value)
+    }
+}
+
+impl<'a> AsRef<[u8]> for EthernetHeaderView<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.content
+    }
+}
+
+impl Data for EthernetHeaderView<'_> {
+    fn size(&self) -> usize {
+        14
+    }
+}
+
+impl<'a> EthernetHeaderView<'a> {
+    pub const SIZE: usize = 14;
+
+    /// Parse a header, failing if the slice is too short to hold it
+    pub fn parse(value: &'a [u8]) -> Result<Self, ParseHeaderError> {
+        match value.get(..Self::SIZE) {
+            Some(content) => Ok(Self { content }),
+            None => Err(ParseHeaderError),
+        }
+    }
+    pub fn get_destination(&self) -> MacAddr {
+        MacAddr(*unsafe { self.content[0..6].as_array_unchecked() })
+    }
+    pub fn get_source(&self) -> MacAddr {
+        MacAddr(*unsafe { self.content[6..12].as_array_unchecked() })
+    }
+    pub fn get_ethertype(&self) -> u16 {
+        u16::from_be_bytes(*unsafe { self.content[12..14].as_array_unchecked() })
+    }
+}
+
+impl Debug for EthernetHeaderView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EthernetHeaderView")
+            .field("destination", &self.get_destination())
+            .field("source", &self.get_source())
+            .field("ethertype", &self.get_ethertype())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct EthernetHeader {
+    pub destination: MacAddr,
+    pub source: MacAddr,
+    pub ethertype: u16,
+}
+
+impl Prepare for EthernetHeader {}
+impl Data for EthernetHeader {
+    fn size(&self) -> usize {
+        14
+    }
+}
+impl WriteTo for EthernetHeader {
+    fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        writer.write_all(&self.destination.0)?;
+        writer.write_all(&self.source.0)?;
+        writer.write_all(&u16::to_be_bytes(self.ethertype))?;
+        Ok(self.size())
+    }
+}
+
+pub type EthernetPacket<C = Vec<u8>> = Packet<EthernetHeader, C>;
+pub type EthernetPacketView<'a, C = &'a [u8]> = PacketView<'a, EthernetHeaderView<'a>, C>;
@@ -0,0 +1,785 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use crate::{
+    icmp::{
+        ADMIN_PROHIBITED, DESTINATION_UNREACHABLE, ECHO_REPLY, ECHO_REQUEST, PORT_UNREACHABLE,
+        is_icmp_error,
+    },
+    interface::Interface,
+    ip::{IPV4HeaderView, IpProtocol, IpV4Addr},
+    route::send_error,
+    tcp::manager::send_reset,
+    traits::Data,
+    udp::UDPHeaderView,
+};
+
+const TCP_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+const UDP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const ICMP_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub const TCP_FIN: u8 = 1 << 0;
+pub const TCP_SYN: u8 = 1 << 1;
+pub const TCP_RST: u8 = 1 << 2;
+pub const TCP_PSH: u8 = 1 << 3;
+pub const TCP_ACK: u8 = 1 << 4;
+pub const TCP_URG: u8 = 1 << 5;
+
+const TCP_FLAG_NAMES: [(&str, u8); 6] = [
+    ("fin", TCP_FIN),
+    ("syn", TCP_SYN),
+    ("rst", TCP_RST),
+    ("psh", TCP_PSH),
+    ("ack", TCP_ACK),
+    ("urg", TCP_URG),
+];
+
+#[derive(Debug)]
+pub struct ParseFirewallError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for ParseFirewallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "firewall rules line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseFirewallError {}
+
+impl From<ParseFirewallError> for io::Error {
+    fn from(value: ParseFirewallError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Accept,
+    Drop,
+    /// Drop and tell the sender, with a TCP reset or an ICMP unreachable message
+    Reject,
+}
+
+impl FromStr for Action {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accept" => Ok(Self::Accept),
+            "drop" => Ok(Self::Drop),
+            "reject" => Ok(Self::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Accept => "accept",
+            Self::Drop => "drop",
+            Self::Reject => "reject",
+        })
+    }
+}
+
+/// State of the flow a packet belongs to, as seen by the connection tracking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    New,
+    Established,
+    /// An ICMP error about an established flow
+    Related,
+}
+
+impl FromStr for ConnectionState {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "new" => Ok(Self::New),
+            "established" => Ok(Self::Established),
+            "related" => Ok(Self::Related),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::New => "new",
+            Self::Established => "established",
+            Self::Related => "related",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix {
+    pub address: IpV4Addr,
+    pub prefix_len: u8,
+}
+
+impl Prefix {
+    pub fn contains(&self, address: IpV4Addr) -> bool {
+        let netmask = u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0);
+        (self.address.0 ^ address.0) & netmask == 0
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = s.split_once('/').unwrap_or((s, "32"));
+        let prefix_len = prefix_len.parse().map_err(|_| ())?;
+        if prefix_len > 32 {
+            return Err(());
+        }
+        Ok(Self {
+            address: address.parse().map_err(|_| ())?,
+            prefix_len,
+        })
+    }
+}
+
+impl Display for Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let range = Self {
+            first: first.parse().map_err(|_| ())?,
+            last: last.parse().map_err(|_| ())?,
+        };
+        if range.first > range.last {
+            return Err(());
+        }
+        Ok(range)
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+/// TCP flags which must be set or cleared, written as `syn,!ack`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TCPFlags {
+    pub mask: u8,
+    pub value: u8,
+}
+
+impl TCPFlags {
+    pub fn matches(&self, flags: u8) -> bool {
+        flags & self.mask == self.value
+    }
+}
+
+impl FromStr for TCPFlags {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut flags = Self { mask: 0, value: 0 };
+        for flag in s.split(',') {
+            let (cleared, name) = match flag.strip_prefix('!') {
+                Some(name) => (true, name),
+                None => (false, flag),
+            };
+            let (_, bit) = TCP_FLAG_NAMES
+                .iter()
+                .find(|(flag_name, _)| *flag_name == name)
+                .ok_or(())?;
+            flags.mask |= bit;
+            if !cleared {
+                flags.value |= bit;
+            }
+        }
+        Ok(flags)
+    }
+}
+
+impl Display for TCPFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flags: Vec<_> = TCP_FLAG_NAMES
+            .iter()
+            .filter(|(_, bit)| self.mask & bit != 0)
+            .map(|(name, bit)| {
+                if self.value & bit != 0 {
+                    name.to_string()
+                } else {
+                    format!("!{name}")
+                }
+            })
+            .collect();
+        f.write_str(&flags.join(","))
+    }
+}
+
+/// A rule matching packets on each of its set fields, with the counters of the matched packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub action: Action,
+    pub protocol: Option<IpProtocol>,
+    pub source: Option<Prefix>,
+    pub destination: Option<Prefix>,
+    pub source_ports: Option<PortRange>,
+    pub destination_ports: Option<PortRange>,
+    pub flags: Option<TCPFlags>,
+    /// Any state matches when empty
+    pub states: Vec<ConnectionState>,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl Rule {
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            protocol: None,
+            source: None,
+            destination: None,
+            source_ports: None,
+            destination_ports: None,
+            flags: None,
+            states: vec![],
+            packets: 0,
+            bytes: 0,
+        }
+    }
+
+    fn matches(&self, packet: &PacketInfo) -> bool {
+        // Ports and flags only exist for some protocols, a rule using them never matches the others
+        self.protocol
+            .is_none_or(|protocol| u8::from(protocol) == packet.protocol)
+            && self
+                .source
+                .is_none_or(|prefix| prefix.contains(packet.source))
+            && self
+                .destination
+                .is_none_or(|prefix| prefix.contains(packet.destination))
+            && self
+                .source_ports
+                .is_none_or(|range| packet.ports.is_some_and(|(port, _)| range.contains(port)))
+            && self
+                .destination_ports
+                .is_none_or(|range| packet.ports.is_some_and(|(_, port)| range.contains(port)))
+            && self
+                .flags
+                .is_none_or(|flags| packet.flags.is_some_and(|value| flags.matches(value)))
+            && (self.states.is_empty() || self.states.contains(&packet.state))
+    }
+}
+
+/// Written back in the configuration syntax
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.action)?;
+        if let Some(protocol) = self.protocol {
+            write!(f, " proto {}", format!("{protocol:?}").to_ascii_lowercase())?;
+        }
+        if let Some(source) = self.source {
+            write!(f, " from {source}")?;
+        }
+        if let Some(destination) = self.destination {
+            write!(f, " to {destination}")?;
+        }
+        if let Some(ports) = self.source_ports {
+            write!(f, " sport {ports}")?;
+        }
+        if let Some(ports) = self.destination_ports {
+            write!(f, " dport {ports}")?;
+        }
+        if let Some(flags) = self.flags {
+            write!(f, " flags {flags}")?;
+        }
+        if !self.states.is_empty() {
+            let states: Vec<_> = self.states.iter().map(|state| state.to_string()).collect();
+            write!(f, " state {}", states.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// The fields of a packet the rules can match on
+#[derive(Debug, Clone, Copy)]
+struct PacketInfo {
+    protocol: u8,
+    source: IpV4Addr,
+    destination: IpV4Addr,
+    ports: Option<(u16, u16)>,
+    flags: Option<u8>,
+    state: ConnectionState,
+}
+
+/// A flow, identical in both directions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Flow {
+    protocol: u8,
+    endpoints: [(IpV4Addr, u16); 2],
+}
+
+impl Flow {
+    fn new(protocol: u8, source: (IpV4Addr, u16), destination: (IpV4Addr, u16)) -> Self {
+        let mut endpoints = [source, destination];
+        endpoints.sort();
+        Self {
+            protocol,
+            endpoints,
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        match self.protocol {
+            protocol if protocol == u8::from(IpProtocol::Tcp) => TCP_TIMEOUT,
+            protocol if protocol == u8::from(IpProtocol::Udp) => UDP_TIMEOUT,
+            _ => ICMP_TIMEOUT,
+        }
+    }
+}
+
+/// Read the protocol, addresses and ports of an IPv4 packet, the ICMP echo identifier standing
+/// for both ports
+fn parse_flow(packet: &[u8]) -> Option<(Flow, PacketInfo)> {
+    let header = IPV4HeaderView::parse(packet).ok()?;
+    let header_size = header.size();
+    let payload = &packet[header_size..];
+    let protocol = packet[9];
+
+    let (ports, flags) = match protocol {
+        // ICMP errors only quote the first 8 bytes of TCP segments, enough for the ports
+        protocol if protocol == u8::from(IpProtocol::Tcp) && payload.len() >= 4 => {
+            let ports = (
+                u16::from_be_bytes([payload[0], payload[1]]),
+                u16::from_be_bytes([payload[2], payload[3]]),
+            );
+            (Some(ports), payload.get(13).copied())
+        }
+        protocol if protocol == u8::from(IpProtocol::Udp) && payload.len() >= 8 => {
+            let udp_header = UDPHeaderView::parse(payload).ok()?;
+            let ports = (
+                udp_header.get_source_port(),
+                udp_header.get_destination_port(),
+            );
+            (Some(ports), None)
+        }
+        protocol
+            if protocol == u8::from(IpProtocol::Icmp)
+                && payload.len() >= 8
+                && matches!(payload[0], ECHO_REQUEST | ECHO_REPLY) =>
+        {
+            let identifier = u16::from_be_bytes([payload[4], payload[5]]);
+            (Some((identifier, identifier)), None)
+        }
+        _ => (None, None),
+    };
+
+    let (source_port, destination_port) = ports.unwrap_or_default();
+    let flow = Flow::new(
+        protocol,
+        (header.get_source_address(), source_port),
+        (header.get_destination_address(), destination_port),
+    );
+    let info = PacketInfo {
+        protocol,
+        source: header.get_source_address(),
+        destination: header.get_destination_address(),
+        // ICMP identifiers are not ports, the rules only see those of TCP and UDP
+        ports: ports.filter(|_| protocol != u8::from(IpProtocol::Icmp)),
+        flags,
+        state: ConnectionState::New,
+    };
+    Some((flow, info))
+}
+
+/// Stateful packet filter, evaluating its rules in order and applying the first match.
+///
+/// The rules are loaded from a text file, one per line with `#` comments: an action followed by
+/// the fields to match, and a `policy` line for the packets no rule matched.
+/// ```text
+/// policy drop
+/// accept state established,related
+/// accept proto tcp to 192.168.0.2 dport 80 flags syn,!ack
+/// reject proto udp from 10.0.0.0/8 dport 1-1023
+/// ```
+#[derive(Debug, Clone)]
+pub struct Firewall {
+    pub rules: Vec<Rule>,
+    pub policy: Action,
+    pub policy_packets: u64,
+    pub policy_bytes: u64,
+    // Accepted flows, with the time they were last seen
+    connections: HashMap<Flow, Instant>,
+}
+
+impl Default for Firewall {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            policy: Action::Accept,
+            policy_packets: 0,
+            policy_bytes: 0,
+            connections: HashMap::new(),
+        }
+    }
+}
+
+impl Firewall {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(fs::read_to_string(path)?.parse()?)
+    }
+
+    /// Forget the flows which have been idle for too long
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.connections
+            .retain(|flow, last_seen| now - *last_seen <= flow.timeout());
+    }
+
+    fn state(&self, flow: &Flow, packet: &[u8]) -> ConnectionState {
+        if self.connections.contains_key(flow) {
+            return ConnectionState::Established;
+        }
+        let header_size = (packet[0] & 0xF) as usize * 4;
+        let is_error = packet[9] == u8::from(IpProtocol::Icmp)
+            && packet
+                .get(header_size)
+                .is_some_and(|&message_type| is_icmp_error(message_type));
+        // Errors quote the header and the first 8 bytes of the packet they are about
+        if is_error
+            && let Some((quoted_flow, _)) = packet.get(header_size + 8..).and_then(parse_flow)
+            && self.connections.contains_key(&quoted_flow)
+        {
+            return ConnectionState::Related;
+        }
+        ConnectionState::New
+    }
+
+    /// Decide the fate of the IPv4 packet received by `interface`, answering it when rejected
+    pub fn filter(&mut self, interface: &mut Interface<impl Read + Write>) -> Action {
+        let packet = interface.packet();
+        let Some((flow, mut info)) = parse_flow(packet) else {
+            return self.policy;
+        };
+        info.state = self.state(&flow, packet);
+        let size = packet.len() as u64;
+
+        let action = match self.rules.iter_mut().find(|rule| rule.matches(&info)) {
+            Some(rule) => {
+                rule.packets += 1;
+                rule.bytes += size;
+                rule.action
+            }
+            None => {
+                self.policy_packets += 1;
+                self.policy_bytes += size;
+                self.policy
+            }
+        };
+
+        match action {
+            Action::Accept if info.state != ConnectionState::Related => {
+                // A reset ends the flow, the other packets keep it alive
+                if info.flags.is_some_and(|flags| flags & TCP_RST != 0) {
+                    self.connections.remove(&flow);
+                } else {
+                    self.connections.insert(flow, Instant::now());
+                }
+            }
+            Action::Accept => {}
+            Action::Drop => {
+                debug!(
+                    "firewall dropped a packet from {} to {}",
+                    info.source, info.destination
+                );
+            }
+            Action::Reject => {
+                debug!(
+                    "firewall rejected a packet from {} to {}",
+                    info.source, info.destination
+                );
+                if info.protocol == u8::from(IpProtocol::Tcp) {
+                    send_reset(interface);
+                } else if info.protocol == u8::from(IpProtocol::Udp) {
+                    send_error(interface, DESTINATION_UNREACHABLE, PORT_UNREACHABLE);
+                } else {
+                    send_error(interface, DESTINATION_UNREACHABLE, ADMIN_PROHIBITED);
+                }
+            }
+        }
+        action
+    }
+}
+
+impl FromStr for Firewall {
+    type Err = ParseFirewallError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut firewall = Firewall::new();
+        for (i, line) in s.lines().enumerate() {
+            let error = |message: &str| ParseFirewallError {
+                line: i + 1,
+                message: message.to_string(),
+            };
+            let line = line.split_once('#').map_or(line, |(line, _)| line);
+            let mut tokens = line.split_whitespace();
+            let Some(first) = tokens.next() else {
+                continue;
+            };
+            if first == "policy" {
+                firewall.policy = tokens
+                    .next()
+                    .and_then(|action| action.parse().ok())
+                    .ok_or_else(|| error("invalid policy"))?;
+                continue;
+            }
+
+            let mut rule = Rule::new(first.parse().map_err(|_| error("unknown action"))?);
+            while let Some(key) = tokens.next() {
+                let value = tokens
+                    .next()
+                    .ok_or_else(|| error(&format!("missing value for {key}")))?;
+                let invalid = || error(&format!("invalid {key} {value}"));
+                match key {
+                    "proto" => rule.protocol = Some(value.parse().map_err(|_| invalid())?),
+                    "from" => rule.source = Some(value.parse().map_err(|_| invalid())?),
+                    "to" => rule.destination = Some(value.parse().map_err(|_| invalid())?),
+                    "sport" => rule.source_ports = Some(value.parse().map_err(|_| invalid())?),
+                    "dport" => rule.destination_ports = Some(value.parse().map_err(|_| invalid())?),
+                    "flags" => rule.flags = Some(value.parse().map_err(|_| invalid())?),
+                    "state" => {
+                        rule.states = value
+                            .split(',')
+                            .map(|state| state.parse())
+                            .collect::<Result<_, _>>()
+                            .map_err(|_| invalid())?
+                    }
+                    _ => return Err(error(&format!("unknown field {key}"))),
+                }
+            }
+            firewall.rules.push(rule);
+        }
+        Ok(firewall)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ip::{IPV4Header, IPV4Packet},
+        tcp::{TCPHeader, TCPPacket},
+        test_support::Wire,
+        traits::WriteTo,
+        udp::{UDPHeader, UDPPacket},
+    };
+
+    const CLIENT: IpV4Addr = IpV4Addr(0x0A00_0005);
+    const SERVER: IpV4Addr = IpV4Addr(0xC0A8_0002);
+
+    fn tcp(source: (IpV4Addr, u16), destination: (IpV4Addr, u16), syn: bool) -> Vec<u8> {
+        let header = TCPHeader {
+            source_port: source.1,
+            destination_port: destination.1,
+            syn,
+            ack: !syn,
+            ..Default::default()
+        };
+        IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Tcp, source.0, destination.0),
+            TCPPacket::new(header, vec![]),
+        )
+        .to_bytes()
+        .unwrap()
+    }
+
+    fn udp(source: (IpV4Addr, u16), destination: (IpV4Addr, u16)) -> Vec<u8> {
+        let header = UDPHeader {
+            source_port: source.1,
+            destination_port: destination.1,
+            ..Default::default()
+        };
+        IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Udp, source.0, destination.0),
+            UDPPacket::new(header, b"data".to_vec()),
+        )
+        .to_bytes()
+        .unwrap()
+    }
+
+    /// Filter the packet as received by an interface, returning the action and the frames written
+    fn filter(firewall: &mut Firewall, packet: &[u8]) -> (Action, usize) {
+        let wire = Wire::default();
+        let mut interface = Interface::new(wire.clone());
+        interface.receive_frame(&[&[0, 0, 8, 0][..], packet].concat());
+        let action = firewall.filter(&mut interface);
+        (action, wire.sent.borrow().len())
+    }
+
+    #[test]
+    fn rules_parsed_and_written_back() {
+        let firewall: Firewall = "
+            # Only the web server is reachable
+            policy drop
+            accept state established,related
+            accept proto tcp to 192.168.0.2 dport 80 flags syn,!ack  # new connections
+            reject proto udp from 10.0.0.0/8 dport 1-1023
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(firewall.policy, Action::Drop);
+        let rules: Vec<_> = firewall.rules.iter().map(Rule::to_string).collect();
+        assert_eq!(
+            rules,
+            [
+                "accept state established,related",
+                "accept proto tcp to 192.168.0.2/32 dport 80 flags syn,!ack",
+                "reject proto udp from 10.0.0.0/8 dport 1-1023",
+            ]
+        );
+        assert_eq!(
+            rules.join("\n").parse::<Firewall>().unwrap().rules,
+            firewall.rules
+        );
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        for (text, line, message) in [
+            ("policy maybe", 1, "invalid policy"),
+            ("policy", 1, "invalid policy"),
+            ("accept\nallow proto tcp", 2, "unknown action"),
+            ("accept proto", 1, "missing value for proto"),
+            ("accept proto gre2", 1, "invalid proto gre2"),
+            ("drop from 10.0.0.0/33", 1, "invalid from 10.0.0.0/33"),
+            ("drop dport 80-20", 1, "invalid dport 80-20"),
+            ("drop flags syn,fin,xmas", 1, "invalid flags syn,fin,xmas"),
+            ("drop state new,old", 1, "invalid state new,old"),
+            ("# comment\n\ndrop iface eth0", 3, "unknown field iface"),
+        ] {
+            let error = text.parse::<Firewall>().unwrap_err();
+            assert_eq!(
+                (error.line, error.message.as_str()),
+                (line, message),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let mut firewall: Firewall = "
+            policy drop
+            drop from 10.0.0.5
+            accept proto tcp dport 80
+            reject proto udp
+        "
+        .parse()
+        .unwrap();
+        let client = (CLIENT, 40000);
+        let other = (IpV4Addr(0x0A00_0006), 40000);
+
+        // The earlier rule wins over the later one also matching
+        assert_eq!(
+            filter(&mut firewall, &tcp(client, (SERVER, 80), true)),
+            (Action::Drop, 0)
+        );
+        assert_eq!(
+            filter(&mut firewall, &tcp(other, (SERVER, 80), true)),
+            (Action::Accept, 0)
+        );
+        // Rejected packets are answered, with a reset or an ICMP error
+        assert_eq!(
+            filter(&mut firewall, &udp(other, (SERVER, 53))),
+            (Action::Reject, 1)
+        );
+        assert_eq!(
+            filter(&mut firewall, &tcp(other, (SERVER, 22), true)),
+            (Action::Drop, 0)
+        );
+
+        let counters: Vec<_> = firewall.rules.iter().map(|rule| rule.packets).collect();
+        assert_eq!(counters, [1, 1, 1]);
+        assert_eq!(firewall.policy_packets, 1);
+        assert_eq!(firewall.rules[1].bytes, 40);
+    }
+
+    #[test]
+    fn answers_of_accepted_flows_established() {
+        let mut firewall: Firewall = "
+            policy drop
+            accept state established
+            accept proto tcp to 192.168.0.2 dport 80 flags syn,!ack
+        "
+        .parse()
+        .unwrap();
+        let client = (CLIENT, 40000);
+        let server = (SERVER, 80);
+
+        // Only connections opened by a SYN are let in, and their answers out
+        assert_eq!(
+            filter(&mut firewall, &tcp(client, server, false)).0,
+            Action::Drop
+        );
+        assert_eq!(
+            filter(&mut firewall, &tcp(server, client, true)).0,
+            Action::Drop
+        );
+        assert_eq!(
+            filter(&mut firewall, &tcp(client, server, true)).0,
+            Action::Accept
+        );
+        assert_eq!(
+            filter(&mut firewall, &tcp(server, client, false)).0,
+            Action::Accept
+        );
+        assert_eq!(firewall.rules[0].packets, 1);
+
+        // Until the flow has been idle for too long
+        for last_seen in firewall.connections.values_mut() {
+            *last_seen -= TCP_TIMEOUT + Duration::from_secs(1);
+        }
+        firewall.expire();
+        assert_eq!(
+            filter(&mut firewall, &tcp(server, client, false)).0,
+            Action::Drop
+        );
+    }
+}
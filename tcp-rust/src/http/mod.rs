@@ -1,12 +1,15 @@
+pub mod websocket;
+
 use std::{
     collections::HashMap,
     fmt::{Debug, Display},
     io::{self, Write},
-    iter::Map,
-    str::Split,
 };
 
+use tokio::io::{AsyncRead, AsyncReadExt};
+
 use crate::{
+    metrics::METRICS,
     packet::{Packet, PacketView},
     traits::{Data, Prepare, ToMutable, WriteTo},
 };
@@ -35,6 +38,11 @@ pub struct HTTPVersion {
     minor: u8,
 }
 
+impl HTTPVersion {
+    /// Version of the answers to requests whose own version could not be read
+    pub const HTTP_1_1: Self = Self { major: 1, minor: 1 };
+}
+
 impl Display for HTTPVersion {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "HTTP/{}.{}", self.major, self.minor)
@@ -45,7 +53,7 @@ impl HTTPMethod {
     pub fn as_str(&self) -> &str {
         match self {
             HTTPMethod::Get => "GET",
-            HTTPMethod::Post => todo!(),
+            HTTPMethod::Post => "POST",
         }
     }
 }
@@ -55,9 +63,9 @@ impl ToMutable for HTTPRequestHeaderView<'_> {
 
     fn to_mutable(&self) -> Self::MutableType {
         HTTPRequestHeader {
-            method: self.get_method(),
-            path: self.get_path().to_string(),
-            version: self.get_version(),
+            method: self.get_method().unwrap_or_default(),
+            path: self.get_path().unwrap_or_default().to_string(),
+            version: self.get_version().unwrap_or_default(),
             headers: self
                 .get_headers_parsed()
                 .map(|header| (header.key.to_string(), header.value.to_string()))
@@ -104,49 +112,64 @@ impl Data for HTTPRequestHeaderView<'_> {
 
 impl<'a> HTTPRequestHeaderView<'a> {
     fn get_first_line(&self) -> &str {
-        self.content.split_once("\r\n").unwrap().0
+        self.content
+            .split_once("\r\n")
+            .map_or(self.content, |(line, _)| line)
     }
+    /// Header lines following the request line, empty for a request without any
     pub fn get_headers_raw(&self) -> &str {
-        self.content.split_once("\r\n").unwrap().1
+        self.content
+            .split_once("\r\n")
+            .map_or("", |(_, headers)| headers)
     }
-    pub fn get_headers(&self) -> Split<'_, &str> {
-        self.get_headers_raw().split("\r\n")
+    pub fn get_headers(&self) -> impl Iterator<Item = &str> {
+        self.get_headers_raw()
+            .split("\r\n")
+            .filter(|header| !header.is_empty())
     }
-    pub fn get_headers_parsed(
-        &'a self,
-    ) -> Map<Split<'a, &'a str>, impl FnMut(&'a str) -> HTTPHeaderView<'a>> {
-        self.get_headers().map(|header| {
-            let (key, value) = header.split_once(':').unwrap();
-            HTTPHeaderView { key, value }
+    pub fn get_headers_parsed(&self) -> impl Iterator<Item = HTTPHeaderView<'_>> {
+        self.get_headers().filter_map(|header| {
+            let (key, value) = header.split_once(':')?;
+            Some(HTTPHeaderView { key, value })
         })
     }
-    pub fn get_method(&self) -> HTTPMethod {
-        let method_name = self.get_first_line().split_once(' ').unwrap().0;
+    /// Method of the request, none if it is not one the server knows
+    pub fn get_method(&self) -> Option<HTTPMethod> {
+        let method_name = self.get_first_line().split_once(' ')?.0;
         if method_name.eq_ignore_ascii_case("GET") {
-            HTTPMethod::Get
+            Some(HTTPMethod::Get)
+        } else if method_name.eq_ignore_ascii_case("POST") {
+            Some(HTTPMethod::Post)
         } else {
-            todo!("{}", self.content)
+            None
         }
     }
-    pub fn get_version(&self) -> HTTPVersion {
-        let version = self.get_first_line().rsplit_once(' ').unwrap().1;
-        assert_eq!(&version[0..5], "HTTP/");
-
-        let (major, minor) = version[5..]
-            .split_once('.')
-            .map_or((0, 0), |(major, minor)| {
-                (major.parse().unwrap(), minor.parse().unwrap())
-            });
-        HTTPVersion { major, minor }
+    /// Version ending the request line, none unless it is `HTTP/` and two single digits
+    pub fn get_version(&self) -> Option<HTTPVersion> {
+        let version = self.get_first_line().rsplit_once(' ')?.1;
+        match version.strip_prefix("HTTP/")?.as_bytes() {
+            &[major @ b'0'..=b'9', b'.', minor @ b'0'..=b'9'] => Some(HTTPVersion {
+                major: major - b'0',
+                minor: minor - b'0',
+            }),
+            _ => None,
+        }
+    }
+    /// Value of a header, whose name is case insensitive, without the surrounding spaces
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.get_headers().find_map(|header| {
+            let (key, value) = header.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+        })
     }
-    pub fn get_path(&self) -> &str {
-        self.get_first_line()
-            .split_once(' ')
-            .unwrap()
-            .1
-            .rsplit_once(' ')
-            .unwrap()
-            .0
+    /// Length of the body following the header, none without a valid `Content-Length`
+    pub fn get_content_length(&self) -> Option<usize> {
+        self.get_header("Content-Length")?.parse().ok()
+    }
+    /// Target of the request, none without a method before it and a version after it
+    pub fn get_path(&self) -> Option<&str> {
+        let path = self.get_first_line().split_once(' ')?.1.rsplit_once(' ')?.0;
+        (!path.is_empty()).then_some(path)
     }
 }
 
@@ -205,7 +228,9 @@ impl Prepare for HTTPResponseHeader {
     fn prepare(&mut self) {
         if self.reason.is_empty() {
             match self.code {
+                101 => self.reason.push_str("Switching Protocols"),
                 200 => self.reason.push_str("OK"),
+                400 => self.reason.push_str("Bad Request"),
                 500 => self.reason.push_str("SERVER ERROR"),
                 _ => {}
             }
@@ -248,3 +273,64 @@ impl WriteTo for HTTPResponseHeader {
 
 pub type HTTPResponsePacket<C = Vec<u8>> = Packet<HTTPResponseHeader, C>;
 // pub type HTTPResponsePacketView<'a, C = &'a [u8]> = PacketView<'a, HTTPResponseHeaderView<'a>, C>;
+
+/// Largest request header, a longer one being rejected
+const MAX_REQUEST: usize = 8192;
+
+/// Read from a stream until the end of a request header, returned with the bytes that follow it
+pub async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let mut request = vec![];
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let length = stream.read(&mut buffer).await?;
+        if length == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        request.extend(&buffer[..length]);
+    }
+    Ok(request)
+}
+
+/// Answer to a request whose request line could not be read, closing the connection
+pub fn bad_request() -> HTTPResponsePacket {
+    let mut response = HTTPResponsePacket::new(HTTPResponseHeader::default(), vec![]);
+    let header = &mut response.header;
+    header.version = HTTPVersion::HTTP_1_1;
+    header.code = 400;
+    header.headers.extend([
+        ("Content-Length".to_string(), "0".to_string()),
+        ("Connection".to_string(), "close".to_string()),
+    ]);
+    response
+}
+
+/// Answer of the server to a request, its page or the metrics of the stack
+pub fn respond(request: HTTPRequestHeaderView) -> HTTPResponsePacket {
+    let (Some(path), Some(version)) = (request.get_path(), request.get_version()) else {
+        return bad_request();
+    };
+    let (content_type, body) = match path {
+        "/metrics" => ("text/plain; version=0.0.4", METRICS.render()),
+        _ => (
+            "text/html; charset=UTF-8",
+            "<html><b>Hello</b> World !</html>".to_string(),
+        ),
+    };
+    let mut http_response =
+        HTTPResponsePacket::new(HTTPResponseHeader::default(), body.into_bytes());
+    let http_header = &mut http_response.header;
+    http_header.version = version;
+    http_header.code = 200;
+    http_header.headers.extend([
+        ("Content-Type".to_string(), content_type.to_string()),
+        (
+            "Content-Length".to_string(),
+            http_response.payload.len().to_string(),
+        ),
+        ("Connection".to_string(), "close".to_string()),
+    ]);
+    http_response
+}
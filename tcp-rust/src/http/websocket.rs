@@ -0,0 +1,798 @@
+//! WebSocket (RFC 6455) over the sockets of the stack: the upgrade of an HTTP request, the frames,
+//! and connections handing the messages they carry to a handler.
+//!
+//! Pings are answered and fragmented messages reassembled by [`WebSocket::receive`], so handlers
+//! only see whole text and binary messages.
+
+use std::io::{self, Write};
+
+use ring::digest::{SHA1_FOR_LEGACY_USE_ONLY, digest};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::{
+    http::{
+        self, HTTPMethod, HTTPRequestHeaderView, HTTPResponseHeader, HTTPResponsePacket,
+        HTTPVersion,
+    },
+    runtime::TCPListener,
+    traits::{Data, Prepare, ToMutable, WriteTo},
+};
+
+/// Port the WebSocket listener of `--async` accepts upgrades on
+pub const WEBSOCKET_PORT: u16 = 8080;
+/// Appended to the key of the client before hashing it into the accept header
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Largest message received, a longer one closing the connection
+const MAX_MESSAGE: usize = 1 << 20;
+/// Largest frame sent, longer messages being fragmented
+const MAX_FRAME: usize = 16384;
+/// Largest payload of a control frame, which can't be fragmented
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const UNSUPPORTED_DATA: u16 = 1003;
+/// Reported for a close frame without a code, never sent
+pub const NO_STATUS_RECEIVED: u16 = 1005;
+pub const INVALID_PAYLOAD: u16 = 1007;
+pub const POLICY_VIOLATION: u16 = 1008;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+pub const INTERNAL_ERROR: u16 = 1011;
+
+/// Codes a close frame may carry, the others being reserved or only reported locally
+pub fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+/// Value of the `Sec-WebSocket-Accept` header answering a `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    base64(digest(&SHA1_FOR_LEGACY_USE_ONLY, [key, GUID].concat().as_bytes()).as_ref())
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0, |group, (i, &byte)| {
+            group | ((byte as u32) << (16 - 8 * i))
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Answer to a request asking for an upgrade, switching protocols or rejecting a bad handshake
+pub fn handshake(request: HTTPRequestHeaderView) -> Result<HTTPResponsePacket, HTTPResponsePacket> {
+    let has_token = |name: &str, token: &str| {
+        request.get_header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    let mut response = HTTPResponsePacket::new(HTTPResponseHeader::default(), vec![]);
+    let header = &mut response.header;
+    let version = request.get_version();
+    header.version = version.unwrap_or(HTTPVersion::HTTP_1_1);
+    let key = request
+        .get_header("Sec-WebSocket-Key")
+        .filter(|_| request.get_method() == Some(HTTPMethod::Get))
+        .filter(|_| request.get_path().is_some() && version.is_some())
+        .filter(|_| has_token("Upgrade", "websocket") && has_token("Connection", "upgrade"))
+        .filter(|_| request.get_header("Sec-WebSocket-Version") == Some("13"));
+    let Some(key) = key else {
+        header.code = 400;
+        header.headers.extend([
+            ("Sec-WebSocket-Version".to_string(), "13".to_string()),
+            ("Content-Length".to_string(), "0".to_string()),
+            ("Connection".to_string(), "close".to_string()),
+        ]);
+        return Err(response);
+    };
+    header.code = 101;
+    header.headers.extend([
+        ("Upgrade".to_string(), "websocket".to_string()),
+        ("Connection".to_string(), "Upgrade".to_string()),
+        ("Sec-WebSocket-Accept".to_string(), accept_key(key)),
+    ]);
+    Ok(response)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum OpCode {
+    #[default]
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    pub fn is_control(self) -> bool {
+        matches!(self, Self::Close | Self::Ping | Self::Pong)
+    }
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = ParseWebSocketError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Continuation),
+            1 => Ok(Self::Text),
+            2 => Ok(Self::Binary),
+            8 => Ok(Self::Close),
+            9 => Ok(Self::Ping),
+            10 => Ok(Self::Pong),
+            _ => Err(ParseWebSocketError::Invalid),
+        }
+    }
+}
+
+impl From<OpCode> for u8 {
+    fn from(value: OpCode) -> Self {
+        match value {
+            OpCode::Continuation => 0,
+            OpCode::Text => 1,
+            OpCode::Binary => 2,
+            OpCode::Close => 8,
+            OpCode::Ping => 9,
+            OpCode::Pong => 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseWebSocketError {
+    /// The frame continues past the end of the slice
+    Incomplete,
+    /// The frame uses a reserved opcode or a length that can't be represented
+    Invalid,
+}
+
+/// XOR a payload with a masking key, which masks and unmasks it alike
+pub fn apply_mask(payload: &mut [u8], masking_key: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= masking_key[i % 4];
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WebSocketFrameView<'a> {
+    content: &'a [u8],
+}
+
+impl<'a> TryFrom<&'a [u8]> for WebSocketFrameView<'a> {
+    type Error = ParseWebSocketError;
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        if value.len() < 2 {
+            return Err(ParseWebSocketError::Incomplete);
+        }
+        let view = Self { content: value };
+        OpCode::try_from(value[0] & 0xF)?;
+        let header_size = view.get_header_size();
+        if value.len() < header_size {
+            return Err(ParseWebSocketError::Incomplete);
+        }
+        // The most significant bit of a 64 bits length must be 0
+        let length = usize::try_from(view.get_payload_length())
+            .ok()
+            .filter(|&length| length <= isize::MAX as usize - header_size)
+            .ok_or(ParseWebSocketError::Invalid)?;
+        if value.len() < header_size + length {
+            return Err(ParseWebSocketError::Incomplete);
+        }
+        Ok(Self {
+            content: &value[..header_size + length],
+        })
+    }
+}
+
+impl<'a> AsRef<[u8]> for WebSocketFrameView<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.content
+    }
+}
+
+impl Data for WebSocketFrameView<'_> {
+    fn size(&self) -> usize {
+        self.content.len()
+    }
+}
+
+impl<'a> WebSocketFrameView<'a> {
+    pub fn get_fin(&self) -> bool {
+        self.content[0] & 0x80 != 0
+    }
+    /// Bits reserved for extensions, none of which are negotiated
+    pub fn get_rsv(&self) -> u8 {
+        (self.content[0] >> 4) & 0x7
+    }
+    pub fn get_opcode(&self) -> OpCode {
+        OpCode::try_from(self.content[0] & 0xF).unwrap()
+    }
+    pub fn is_masked(&self) -> bool {
+        self.content[1] & 0x80 != 0
+    }
+    fn get_length_size(&self) -> usize {
+        match self.content[1] & 0x7F {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        }
+    }
+    pub fn get_header_size(&self) -> usize {
+        2 + self.get_length_size() + if self.is_masked() { 4 } else { 0 }
+    }
+    pub fn get_payload_length(&self) -> u64 {
+        match self.get_length_size() {
+            0 => (self.content[1] & 0x7F) as u64,
+            size => self.content[2..2 + size]
+                .iter()
+                .fold(0, |length, &byte| (length << 8) | byte as u64),
+        }
+    }
+    pub fn get_masking_key(&self) -> Option<[u8; 4]> {
+        let offset = 2 + self.get_length_size();
+        self.is_masked()
+            .then(|| self.content[offset..offset + 4].try_into().unwrap())
+    }
+    /// Payload as it is on the wire, masked if the frame comes from a client
+    pub fn get_payload(&self) -> &'a [u8] {
+        &self.content[self.get_header_size()..]
+    }
+    pub fn get_payload_unmasked(&self) -> Vec<u8> {
+        let mut payload = self.get_payload().to_vec();
+        if let Some(masking_key) = self.get_masking_key() {
+            apply_mask(&mut payload, masking_key);
+        }
+        payload
+    }
+}
+
+impl std::fmt::Debug for WebSocketFrameView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketFrameView")
+            .field("fin", &self.get_fin())
+            .field("opcode", &self.get_opcode())
+            .field("masking_key", &self.get_masking_key())
+            .field("payload_length", &self.get_payload_length())
+            .finish()
+    }
+}
+
+impl ToMutable for WebSocketFrameView<'_> {
+    type MutableType = WebSocketFrame;
+
+    fn to_mutable(&self) -> Self::MutableType {
+        WebSocketFrame {
+            fin: self.get_fin(),
+            opcode: self.get_opcode(),
+            masking_key: self.get_masking_key(),
+            payload: self.get_payload_unmasked(),
+        }
+    }
+}
+
+/// A frame whose payload is kept unmasked, the masking key being applied as it is written
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WebSocketFrame {
+    pub fin: bool,
+    pub opcode: OpCode,
+    pub masking_key: Option<[u8; 4]>,
+    pub payload: Vec<u8>,
+}
+
+impl WebSocketFrame {
+    pub fn new(opcode: OpCode, payload: Vec<u8>) -> Self {
+        Self {
+            fin: true,
+            opcode,
+            masking_key: None,
+            payload,
+        }
+    }
+
+    /// Split a message in frames of at most `size` bytes, continued by continuation frames
+    pub fn fragments(opcode: OpCode, payload: &[u8], size: usize) -> Vec<Self> {
+        let mut frames = payload
+            .chunks(size)
+            .enumerate()
+            .map(|(i, chunk)| Self {
+                fin: false,
+                opcode: if i == 0 { opcode } else { OpCode::Continuation },
+                masking_key: None,
+                payload: chunk.to_vec(),
+            })
+            .collect::<Vec<_>>();
+        match frames.last_mut() {
+            Some(last) => last.fin = true,
+            None => frames.push(Self::new(opcode, vec![])),
+        }
+        frames
+    }
+
+    fn get_length_size(&self) -> usize {
+        match self.payload.len() {
+            0..126 => 0,
+            126..=0xFFFF => 2,
+            _ => 8,
+        }
+    }
+}
+
+impl Prepare for WebSocketFrame {}
+impl Data for WebSocketFrame {
+    fn size(&self) -> usize {
+        2 + self.get_length_size()
+            + if self.masking_key.is_some() { 4 } else { 0 }
+            + self.payload.len()
+    }
+}
+
+impl WriteTo for WebSocketFrame {
+    fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        let mask_bit = if self.masking_key.is_some() { 0x80 } else { 0 };
+        let length = self.payload.len();
+        writer.write_all(&[((self.fin as u8) << 7) | u8::from(self.opcode)])?;
+        match self.get_length_size() {
+            0 => writer.write_all(&[mask_bit | length as u8])?,
+            2 => {
+                writer.write_all(&[mask_bit | 126])?;
+                writer.write_all(&(length as u16).to_be_bytes())?;
+            }
+            _ => {
+                writer.write_all(&[mask_bit | 127])?;
+                writer.write_all(&(length as u64).to_be_bytes())?;
+            }
+        }
+        match self.masking_key {
+            Some(masking_key) => {
+                writer.write_all(&masking_key)?;
+                let mut payload = self.payload.clone();
+                apply_mask(&mut payload, masking_key);
+                writer.write_all(&payload)?;
+            }
+            None => writer.write_all(&self.payload)?,
+        }
+        Ok(self.size())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// A close code, [`NO_STATUS_RECEIVED`] if the frame had none, and its reason
+    Close(u16, String),
+}
+
+impl Message {
+    fn into_frames(self) -> Vec<WebSocketFrame> {
+        match self {
+            Self::Text(text) => WebSocketFrame::fragments(OpCode::Text, text.as_bytes(), MAX_FRAME),
+            Self::Binary(data) => WebSocketFrame::fragments(OpCode::Binary, &data, MAX_FRAME),
+            Self::Ping(data) => vec![WebSocketFrame::new(OpCode::Ping, data)],
+            Self::Pong(data) => vec![WebSocketFrame::new(OpCode::Pong, data)],
+            Self::Close(NO_STATUS_RECEIVED, _) => vec![WebSocketFrame::new(OpCode::Close, vec![])],
+            Self::Close(code, reason) => {
+                let payload = [&code.to_be_bytes(), reason.as_bytes()].concat();
+                vec![WebSocketFrame::new(OpCode::Close, payload)]
+            }
+        }
+    }
+}
+
+/// Server side of a WebSocket connection, once the handshake is done
+pub struct WebSocket<S> {
+    stream: S,
+    /// Received bytes which don't make a whole frame yet
+    buffer: Vec<u8>,
+    /// Opcode and payload of the frames of a fragmented message received so far
+    fragments: Option<(OpCode, Vec<u8>)>,
+    /// A close frame was sent, nothing but the answer of the peer is expected
+    closing: bool,
+    /// A close frame was received
+    closed: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocket<S> {
+    /// Read the request of a client and upgrade the connection, or reject it with 400
+    pub async fn accept(mut stream: S) -> io::Result<Self> {
+        let mut buffer = http::read_request(&mut stream).await?;
+        let request = HTTPRequestHeaderView::try_from(&buffer[..])
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+        let header_size = request.size() + 4;
+        match handshake(request) {
+            Ok(mut response) => stream.write_all(&response.to_bytes()?).await?,
+            Err(mut response) => {
+                stream.write_all(&response.to_bytes()?).await?;
+                stream.shutdown().await?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a WebSocket handshake",
+                ));
+            }
+        }
+        buffer.drain(..header_size);
+        Ok(Self {
+            stream,
+            buffer,
+            fragments: None,
+            closing: false,
+            closed: false,
+        })
+    }
+
+    /// Next text, binary or close message, `None` once the closing handshake is over
+    pub async fn receive(&mut self) -> io::Result<Option<Message>> {
+        while !self.closed {
+            let (frame, rsv, size) = match WebSocketFrameView::try_from(&self.buffer[..]) {
+                Ok(view) => (view.to_mutable(), view.get_rsv(), view.size()),
+                Err(ParseWebSocketError::Incomplete) => {
+                    if self.buffer.len() > MAX_MESSAGE {
+                        return Err(self.fail(MESSAGE_TOO_BIG).await);
+                    }
+                    let mut buffer = [0; 4096];
+                    let length = self.stream.read(&mut buffer).await?;
+                    if length == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    self.buffer.extend(&buffer[..length]);
+                    continue;
+                }
+                Err(ParseWebSocketError::Invalid) => return Err(self.fail(PROTOCOL_ERROR).await),
+            };
+            self.buffer.drain(..size);
+            // Clients mask all their frames, and no extension gives a meaning to the reserved bits
+            if frame.masking_key.is_none() || rsv != 0 {
+                return Err(self.fail(PROTOCOL_ERROR).await);
+            }
+            if let Some(message) = self.handle_frame(frame).await? {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn handle_frame(&mut self, frame: WebSocketFrame) -> io::Result<Option<Message>> {
+        if frame.opcode.is_control() && (!frame.fin || frame.payload.len() > MAX_CONTROL_PAYLOAD) {
+            return Err(self.fail(PROTOCOL_ERROR).await);
+        }
+        let (opcode, payload) = match (frame.opcode, self.fragments.as_mut()) {
+            (OpCode::Ping, _) => {
+                if !self.closing {
+                    self.send(Message::Pong(frame.payload)).await?;
+                }
+                return Ok(None);
+            }
+            (OpCode::Pong, _) => return Ok(None),
+            (OpCode::Close, _) => return self.handle_close(frame.payload).await.map(Some),
+            (OpCode::Continuation, Some((_, fragments))) => {
+                fragments.extend(frame.payload);
+                if fragments.len() > MAX_MESSAGE {
+                    return Err(self.fail(MESSAGE_TOO_BIG).await);
+                }
+                if !frame.fin {
+                    return Ok(None);
+                }
+                self.fragments.take().unwrap()
+            }
+            (OpCode::Continuation, None) | (_, Some(_)) => {
+                return Err(self.fail(PROTOCOL_ERROR).await);
+            }
+            (opcode, None) if !frame.fin => {
+                self.fragments = Some((opcode, frame.payload));
+                return Ok(None);
+            }
+            (opcode, None) => (opcode, frame.payload),
+        };
+        if self.closing {
+            return Ok(None);
+        }
+        match opcode {
+            OpCode::Text => match String::from_utf8(payload) {
+                Ok(text) => Ok(Some(Message::Text(text))),
+                Err(_) => Err(self.fail(INVALID_PAYLOAD).await),
+            },
+            _ => Ok(Some(Message::Binary(payload))),
+        }
+    }
+
+    /// Answer a close frame if the peer started the closing handshake, then close the stream
+    async fn handle_close(&mut self, payload: Vec<u8>) -> io::Result<Message> {
+        let (code, reason) = match payload.len() {
+            0 => (NO_STATUS_RECEIVED, String::new()),
+            1 => return Err(self.fail(PROTOCOL_ERROR).await),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                match String::from_utf8(payload[2..].to_vec()) {
+                    Ok(reason) if is_valid_close_code(code) => (code, reason),
+                    Ok(_) => return Err(self.fail(PROTOCOL_ERROR).await),
+                    Err(_) => return Err(self.fail(INVALID_PAYLOAD).await),
+                }
+            }
+        };
+        self.closed = true;
+        if !self.closing {
+            let answer = match code {
+                NO_STATUS_RECEIVED => NORMAL_CLOSURE,
+                code => code,
+            };
+            self.send(Message::Close(answer, String::new())).await?;
+        }
+        self.stream.shutdown().await?;
+        Ok(Message::Close(code, reason))
+    }
+
+    /// Close the connection after a protocol error, returning the error to report
+    async fn fail(&mut self, code: u16) -> io::Error {
+        debug!(code, "failing WebSocket connection");
+        if !self.closing {
+            let _ = self.send(Message::Close(code, String::new())).await;
+        }
+        self.closed = true;
+        let _ = self.stream.shutdown().await;
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("WebSocket protocol error {code}"),
+        )
+    }
+
+    /// Send a message, starting the closing handshake if it is a close message
+    pub async fn send(&mut self, message: Message) -> io::Result<()> {
+        if self.closing {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.closing = matches!(message, Message::Close(..));
+        let mut bytes = vec![];
+        for mut frame in message.into_frames() {
+            frame.write_to(&mut bytes)?;
+        }
+        self.stream.write_all(&bytes).await
+    }
+}
+
+/// Receiver of the text and binary messages of a connection, which may answer each of them
+pub trait MessageHandler {
+    fn message(&mut self, message: Message) -> Option<Message>;
+}
+
+impl<F: FnMut(Message) -> Option<Message>> MessageHandler for F {
+    fn message(&mut self, message: Message) -> Option<Message> {
+        self(message)
+    }
+}
+
+/// Upgrade the connections to a listener, each of them getting its own copy of the handler
+pub async fn serve<H: MessageHandler + Clone + Send + 'static>(
+    listener: TCPListener,
+    handler: H,
+) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, handler).await {
+                debug!(%peer, "WebSocket connection failed: {err}");
+            }
+        });
+    }
+}
+
+/// Hand the messages of a connection to a handler until it is closed by either side
+async fn handle(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    mut handler: impl MessageHandler,
+) -> io::Result<()> {
+    let mut socket = WebSocket::accept(stream).await?;
+    while let Some(message) = socket.receive().await? {
+        if let Message::Close(..) = message {
+            continue;
+        }
+        if let Some(answer) = handler.message(message) {
+            socket.send(answer).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    const MASKING_KEY: [u8; 4] = [0x37, 0xFA, 0x21, 0x3D];
+    const HANDSHAKE: &[u8] = b"GET /chat HTTP/1.1\r\nHost: server.example.com\r\n\
+        Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+    fn client_frame(fin: bool, opcode: OpCode, payload: &[u8]) -> Vec<u8> {
+        let mut frame = WebSocketFrame {
+            fin,
+            opcode,
+            masking_key: Some(MASKING_KEY),
+            payload: payload.to_vec(),
+        };
+        frame.to_bytes().unwrap()
+    }
+
+    /// Connect to a server echoing the messages, returning the stream after the handshake
+    async fn connect() -> DuplexStream {
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        tokio::spawn(handle(server, Some));
+        client.write_all(HANDSHAKE).await.unwrap();
+        let response = http::read_request(&mut client).await.unwrap();
+        let response = str::from_utf8(&response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        client
+    }
+
+    async fn server_frame(client: &mut DuplexStream) -> WebSocketFrame {
+        let mut buffer = vec![];
+        loop {
+            match WebSocketFrameView::try_from(&buffer[..]) {
+                Ok(frame) => return frame.to_mutable(),
+                Err(ParseWebSocketError::Incomplete) => {
+                    let mut byte = [0];
+                    client.read_exact(&mut byte).await.unwrap();
+                    buffer.push(byte[0]);
+                }
+                Err(err) => panic!("{err:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn accept_key_of_the_rfc() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+    }
+
+    #[test]
+    fn frames_of_the_rfc() {
+        let masked = [
+            0x81, 0x85, 0x37, 0xFA, 0x21, 0x3D, 0x7F, 0x9F, 0x4D, 0x51, 0x58,
+        ];
+        let frame = WebSocketFrameView::try_from(&masked[..]).unwrap();
+        assert_eq!(frame.get_payload_unmasked(), b"Hello");
+        assert_eq!(frame.to_mutable().to_bytes().unwrap(), masked);
+        assert_eq!(
+            WebSocketFrame::new(OpCode::Text, b"Hello".to_vec())
+                .to_bytes()
+                .unwrap(),
+            [0x81, 0x05, 0x48, 0x65, 0x6C, 0x6C, 0x6F]
+        );
+
+        let long = WebSocketFrame::new(OpCode::Binary, vec![0; 65536])
+            .to_bytes()
+            .unwrap();
+        assert_eq!(long[..10], [0x82, 0x7F, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(
+            WebSocketFrameView::try_from(&long[..long.len() - 1]),
+            Err(ParseWebSocketError::Incomplete)
+        );
+        assert_eq!(
+            WebSocketFrameView::try_from(&[0x83, 0x00][..]),
+            Err(ParseWebSocketError::Invalid)
+        );
+    }
+
+    #[tokio::test]
+    async fn fragmented_message_with_ping() {
+        let mut client = connect().await;
+        client
+            .write_all(&client_frame(false, OpCode::Text, b"Hel"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OpCode::Ping, b"ping"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OpCode::Continuation, b"lo"))
+            .await
+            .unwrap();
+        assert_eq!(
+            server_frame(&mut client).await,
+            WebSocketFrame::new(OpCode::Pong, b"ping".to_vec())
+        );
+        assert_eq!(
+            server_frame(&mut client).await,
+            WebSocketFrame::new(OpCode::Text, b"Hello".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn long_message_is_fragmented() {
+        let mut client = connect().await;
+        let message = vec![7; MAX_FRAME + 1];
+        client
+            .write_all(&client_frame(true, OpCode::Binary, &message))
+            .await
+            .unwrap();
+        let first = server_frame(&mut client).await;
+        assert_eq!(
+            (first.fin, first.opcode, first.payload.len()),
+            (false, OpCode::Binary, MAX_FRAME)
+        );
+        let last = server_frame(&mut client).await;
+        assert_eq!(
+            (last.fin, last.opcode, last.payload),
+            (true, OpCode::Continuation, vec![7])
+        );
+    }
+
+    #[tokio::test]
+    async fn closing_handshake() {
+        let mut client = connect().await;
+        let payload = [&GOING_AWAY.to_be_bytes()[..], b"bye"].concat();
+        client
+            .write_all(&client_frame(true, OpCode::Close, &payload))
+            .await
+            .unwrap();
+        assert_eq!(
+            server_frame(&mut client).await,
+            WebSocketFrame::new(OpCode::Close, GOING_AWAY.to_be_bytes().to_vec())
+        );
+        assert_eq!(client.read(&mut [0]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn unmasked_frame_is_a_protocol_error() {
+        let mut client = connect().await;
+        let mut frame = WebSocketFrame::new(OpCode::Text, b"Hello".to_vec());
+        client.write_all(&frame.to_bytes().unwrap()).await.unwrap();
+        assert_eq!(
+            server_frame(&mut client).await,
+            WebSocketFrame::new(OpCode::Close, PROTOCOL_ERROR.to_be_bytes().to_vec())
+        );
+    }
+
+    /// Send a request which is not a valid handshake, returning the response rejecting it
+    async fn rejected(request: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(request).await.unwrap();
+        assert!(WebSocket::accept(server).await.is_err());
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn request_without_upgrade_is_rejected() {
+        let response = rejected(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[tokio::test]
+    async fn malformed_upgrade_is_rejected() {
+        let response = rejected(b"GET /chat HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let response = rejected(b"GET /chat\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let handshake = str::from_utf8(HANDSHAKE).unwrap();
+        let without_key = handshake.replace("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n", "");
+        let response = rejected(without_key.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+    }
+}
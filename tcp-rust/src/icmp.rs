@@ -1,108 +1,113 @@
-use std::{
-    fmt::Debug,
-    io::{self, Write},
-};
+use std::io::{Read, Write};
+
+use tcp_rust_macros::PacketHeader;
+
+use tracing::{debug, warn};
 
 use crate::{
+    buffer::BufferPool,
+    interface::Interface,
+    ip::{IPV4Header, IpProtocol, TransportChecksum},
     packet::{Packet, PacketView},
-    traits::{AsArrayUnchecked, Data, DataOwned, Prepare, ToMutable, WriteTo},
+    traits::DataOwned,
 };
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct ICMPHeaderView<'a> {
-    content: &'a [u8],
-}
+pub const ECHO_REPLY: u8 = 0;
+pub const DESTINATION_UNREACHABLE: u8 = 3;
+pub const ECHO_REQUEST: u8 = 8;
+pub const TIME_EXCEEDED: u8 = 11;
 
-impl<'a> ICMPHeaderView<'a> {
-    pub fn get_message_type(&self) -> u8 {
-        self.content[0]
-    }
-    pub fn get_code(&self) -> u8 {
-        self.content[1]
-    }
-    pub fn get_checksum(&self) -> u16 {
-        u16::from_be_bytes(*unsafe { self.content[2..=3].as_array_unchecked() })
-    }
+pub const NET_UNREACHABLE: u8 = 0;
+pub const HOST_UNREACHABLE: u8 = 1;
+pub const PROTOCOL_UNREACHABLE: u8 = 2;
+pub const PORT_UNREACHABLE: u8 = 3;
+pub const ADMIN_PROHIBITED: u8 = 13;
+pub const TTL_EXCEEDED: u8 = 0;
 
-    pub fn to_mutable(self) -> ICMPHeader {
-        ICMPHeader {
-            message_type: self.get_message_type(),
-            code: self.get_code(),
-            checksum: self.get_checksum(),
-        }
-    }
+pub fn is_icmp_error(message_type: u8) -> bool {
+    matches!(
+        message_type,
+        DESTINATION_UNREACHABLE | 4 | 5 | TIME_EXCEEDED | 12
+    )
 }
 
-impl Debug for ICMPHeaderView<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("ICMPHeaderView")
-            .field("message_type", &self.get_message_type())
-            .field("code", &self.get_code())
-            .field("checksum", &self.get_checksum())
-            .finish()
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, PacketHeader)]
+pub struct ICMPHeader {
+    pub message_type: u8,
+    pub code: u8,
+    pub checksum: u16,
 }
-impl<'a> ToMutable for ICMPHeaderView<'a> {
-    type MutableType = ICMPHeader;
 
-    fn to_mutable(&self) -> Self::MutableType {
-        Self::MutableType {
-            message_type: self.get_message_type(),
-            code: self.get_code(),
-            checksum: self.get_checksum(),
+impl ICMPHeader {
+    pub fn new(message_type: u8, code: u8) -> Self {
+        Self {
+            message_type,
+            code,
+            checksum: 0,
         }
     }
 }
 
-impl<'a> From<&'a [u8]> for ICMPHeaderView<'a> {
-    fn from(value: &'a [u8]) -> Self {
-        Self { content: value }
-    }
-}
+pub type ICMPPacket<C = Vec<u8>> = Packet<ICMPHeader, C>;
+pub type ICMPPacketView<'a, C = &'a [u8]> = PacketView<'a, ICMPHeaderView<'a>, C>;
 
-impl<'a> AsRef<[u8]> for ICMPHeaderView<'a> {
-    fn as_ref(&self) -> &[u8] {
-        self.content
+impl ICMPPacket {
+    /// Make an echo request, whose identifier, sequence number and data come back in the reply
+    pub fn echo_request(identifier: u16, sequence_number: u16, data: &[u8]) -> Self {
+        Self::new(
+            ICMPHeader::new(ECHO_REQUEST, 0),
+            [
+                &identifier.to_be_bytes()[..],
+                &sequence_number.to_be_bytes(),
+                data,
+            ]
+            .concat(),
+        )
     }
-}
 
-impl Data for ICMPHeaderView<'_> {
-    fn size(&self) -> usize {
-        4
+    /// Make an error message about `original`, which quotes its IP header and 8 bytes of its payload
+    pub fn error(message_type: u8, code: u8, original: &[u8]) -> Self {
+        let header_size = (original[0] & 0xF) as usize * 4;
+        let quoted = &original[..original.len().min(header_size + 8)];
+        // The 4 bytes after the header are unused by error messages
+        Self::new(
+            ICMPHeader::new(message_type, code),
+            [&[0; 4], quoted].concat(),
+        )
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct ICMPHeader {
-    pub message_type: u8,
-    pub code: u8,
-    pub checksum: u16,
-}
-
-impl Prepare for ICMPHeader {}
-impl WriteTo for ICMPHeader {
-    fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
-        writer.write_all(&[self.message_type])?;
-        writer.write_all(&[self.code])?;
-        writer.write_all(&self.checksum.to_be_bytes())?;
-        Ok(self.size())
-    }
-}
+impl<C: DataOwned> TransportChecksum for ICMPPacket<C> {
+    const CHECKSUM_OFFSET: usize = 2;
+    const PSEUDO_HEADER: bool = false;
 
-impl Data for ICMPHeader {
-    fn size(&self) -> usize {
-        4
+    fn set_checksum(&mut self, checksum: u16) {
+        self.header.checksum = checksum;
     }
 }
 
-pub type ICMPPacket<C = Vec<u8>> = Packet<ICMPHeader, C>;
-pub type ICMPPacketView<'a, C = &'a [u8]> = PacketView<'a, ICMPHeaderView<'a>, C>;
-
-impl<C: DataOwned> Prepare for ICMPPacket<C> {
-    fn prepare(&mut self) {
-        self.header.prepare();
-        self.payload.prepare();
-        self.header.checksum = 0;
-        self.header.checksum = self.compute_checksum().ones_complement();
+/// Answer the echo request received by `interface`, building the reply in a pooled buffer
+pub fn reply_to_echo(interface: &mut Interface<impl Read + Write>, pool: &mut BufferPool) {
+    let Ok(ip_packet) = interface.try_get_ip_packet::<ICMPPacketView>() else {
+        debug!("truncated ICMP message dropped");
+        return;
+    };
+    let ip_header = IPV4Header::new(
+        IpProtocol::Icmp,
+        ip_packet.header.get_destination_address(),
+        ip_packet.header.get_source_address(),
+    );
+    // The identifier, sequence number and data are echoed as they are
+    let mut buffer = pool.take();
+    let built = buffer
+        .extend_from_slice(ip_packet.payload.payload)
+        .and_then(|()| {
+            buffer.push_transport_header::<ICMPPacket>(ICMPHeader::new(ECHO_REPLY, 0), &ip_header)
+        })
+        .and_then(|()| buffer.push_ipv4_header(ip_header));
+    match built {
+        Ok(()) => interface.reply(&mut buffer),
+        Err(err) => warn!("could not answer an echo packet: {err}"),
     }
+    pool.give_back(buffer);
 }
@@ -0,0 +1,539 @@
+//! IGMP membership of the multicast groups joined by the UDP sockets (RFC 3376, and RFC 2236
+//! when an IGMPv2 querier is heard on the link).
+//!
+//! The reports are sent with a TTL of 1 but without the Router Alert option, which the IPv4
+//! header does not carry.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tcp_rust_macros::PacketHeader;
+use tracing::debug;
+
+use crate::{
+    checksum::Checksum,
+    ethernet::{ETHERTYPE_IPV4, MacAddr},
+    interface::Interface,
+    ip::{IPV4Header, IPV4Packet, IPV4PacketView, IpProtocol, IpV4Addr, TransportChecksum},
+    packet::{Packet, PacketView},
+    traits::{Data, DataOwned},
+};
+
+pub const MEMBERSHIP_QUERY: u8 = 0x11;
+pub const V1_MEMBERSHIP_REPORT: u8 = 0x12;
+pub const V2_MEMBERSHIP_REPORT: u8 = 0x16;
+pub const LEAVE_GROUP: u8 = 0x17;
+pub const V3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+/// Types of the group records of IGMPv3 reports, the stack only excluding no source
+pub const MODE_IS_EXCLUDE: u8 = 2;
+pub const CHANGE_TO_INCLUDE_MODE: u8 = 3;
+pub const CHANGE_TO_EXCLUDE_MODE: u8 = 4;
+
+/// Group of all the hosts of the link, joined without being reported
+pub const ALL_HOSTS: IpV4Addr = IpV4Addr(0xE000_0001);
+/// Destination of the IGMPv2 leaves
+pub const ALL_ROUTERS: IpV4Addr = IpV4Addr(0xE000_0002);
+/// Destination of the IGMPv3 reports
+pub const ALL_V3_ROUTERS: IpV4Addr = IpV4Addr(0xE000_0016);
+
+/// Time between the unsolicited reports of a join, sent twice in case one is lost
+const UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Time an IGMPv2 querier keeps the stack speaking IGMPv2: the robustness variable times the
+/// query interval, plus the query response interval (RFC 3376 §8.12)
+const OLDER_QUERIER_TIMEOUT: Duration = Duration::from_secs(2 * 125 + 10);
+/// Delay allowed by an IGMPv1 query, which does not give one
+const V1_MAX_RESPONSE_TIME: Duration = Duration::from_secs(10);
+/// Length of the IGMPv1 and v2 messages, and the shortest IGMPv3 query
+const V2_LENGTH: usize = 8;
+const V3_QUERY_MIN_LENGTH: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, PacketHeader)]
+pub struct IGMPHeader {
+    pub message_type: u8,
+    /// Longest delay before answering a query, reserved in the other messages
+    pub max_response_code: u8,
+    pub checksum: u16,
+}
+
+impl IGMPHeader {
+    pub fn new(message_type: u8, max_response_code: u8) -> Self {
+        Self {
+            message_type,
+            max_response_code,
+            checksum: 0,
+        }
+    }
+}
+
+pub type IGMPPacket<C = Vec<u8>> = Packet<IGMPHeader, C>;
+pub type IGMPPacketView<'a, C = &'a [u8]> = PacketView<'a, IGMPHeaderView<'a>, C>;
+
+impl IGMPPacket {
+    /// Make an IGMPv2 report or leave of a group
+    pub fn v2(message_type: u8, group: IpV4Addr) -> Self {
+        Self::new(
+            IGMPHeader::new(message_type, 0),
+            group.0.to_be_bytes().to_vec(),
+        )
+    }
+
+    /// Make an IGMPv3 report with a record of each group, none of them with sources
+    pub fn v3_report(records: &[(u8, IpV4Addr)]) -> Self {
+        let mut payload = [[0; 2], (records.len() as u16).to_be_bytes()].concat();
+        for (record_type, group) in records {
+            payload.extend([*record_type, 0, 0, 0]);
+            payload.extend(group.0.to_be_bytes());
+        }
+        Self::new(IGMPHeader::new(V3_MEMBERSHIP_REPORT, 0), payload)
+    }
+
+    /// Make a query of `group`, or of all of them when unspecified, in IGMPv3 unless `v2` is set
+    pub fn query(group: IpV4Addr, max_response_code: u8, v2: bool) -> Self {
+        let mut payload = group.0.to_be_bytes().to_vec();
+        if !v2 {
+            // No sources, with the default robustness and query interval
+            payload.extend([2, 125, 0, 0]);
+        }
+        Self::new(
+            IGMPHeader::new(MEMBERSHIP_QUERY, max_response_code),
+            payload,
+        )
+    }
+}
+
+impl<C: DataOwned> TransportChecksum for IGMPPacket<C> {
+    const CHECKSUM_OFFSET: usize = 2;
+    const PSEUDO_HEADER: bool = false;
+
+    fn set_checksum(&mut self, checksum: u16) {
+        self.header.checksum = checksum;
+    }
+}
+
+/// Link address of a multicast group, its low 23 bits under 01:00:5e (RFC 1112 §6.4)
+pub fn multicast_mac(group: IpV4Addr) -> MacAddr {
+    let [_, b, c, d] = group.0.to_be_bytes();
+    MacAddr([0x01, 0x00, 0x5E, b & 0x7F, c, d])
+}
+
+/// Delay given by the max response code of a query, in tenths of seconds, with a floating point
+/// encoding above 127 in IGMPv3 (RFC 3376 §4.1.1)
+fn max_response_time(code: u8) -> Duration {
+    let tenths = match code {
+        0..128 => code as u64,
+        _ => ((code as u64 & 0xF) | 0x10) << ((code as u64 >> 4 & 0x7) + 3),
+    };
+    Duration::from_millis(tenths * 100)
+}
+
+/// Random delay up to `max`, as reports must not be sent by all the members at once
+fn random_delay(max: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    max.mul_f64(nanos as f64 / 1e9)
+}
+
+/// Groups joined by the stack, reported when joined and when queried
+#[derive(Debug, Clone, Default)]
+pub struct IGMPManager {
+    /// Groups joined, with the number of sockets in each
+    groups: HashMap<IpV4Addr, usize>,
+    /// Reports waiting for their time, of a group, or of all of them answering a general IGMPv3
+    /// query, with the type of their records
+    pending: Vec<(Option<IpV4Addr>, u8, Instant)>,
+    /// End of the IGMPv2 compatibility mode, entered when an IGMPv2 querier is heard
+    v2_querier_until: Option<Instant>,
+}
+
+impl IGMPManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the multicast datagrams sent to `group` are for the stack
+    pub fn is_member(&self, group: IpV4Addr) -> bool {
+        group == ALL_HOSTS || self.groups.contains_key(&group)
+    }
+
+    fn v2(&self) -> bool {
+        self.v2_querier_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Join a group for one more socket, reporting it when it is new
+    pub fn join(&mut self, interface: &mut Interface<impl Read + Write>, group: IpV4Addr) {
+        let sockets = self.groups.entry(group).or_default();
+        *sockets += 1;
+        if *sockets > 1 || group == ALL_HOSTS {
+            return;
+        }
+        debug!(%group, "joined a multicast group");
+        self.send_report(interface, Some(group), CHANGE_TO_EXCLUDE_MODE);
+        // The state change is what is repeated, not the current state
+        self.pending.push((
+            Some(group),
+            CHANGE_TO_EXCLUDE_MODE,
+            Instant::now() + UNSOLICITED_REPORT_INTERVAL,
+        ));
+    }
+
+    /// Leave a group for one socket, telling the routers when no socket is left in it
+    pub fn leave(&mut self, interface: &mut Interface<impl Read + Write>, group: IpV4Addr) {
+        let Some(sockets) = self.groups.get_mut(&group) else {
+            return;
+        };
+        *sockets -= 1;
+        if *sockets > 0 {
+            return;
+        }
+        self.groups.remove(&group);
+        self.pending
+            .retain(|(pending, _, _)| *pending != Some(group));
+        if group == ALL_HOSTS {
+            return;
+        }
+        debug!(%group, "left a multicast group");
+        if self.v2() {
+            self.send(interface, ALL_ROUTERS, IGMPPacket::v2(LEAVE_GROUP, group));
+        } else {
+            self.send_report(interface, Some(group), CHANGE_TO_INCLUDE_MODE);
+        }
+    }
+
+    /// Schedule the reports answering a query, or cancel ours when another member of the group
+    /// reported it in IGMPv2
+    pub fn handle_packet(&mut self, interface: &mut Interface<impl Read + Write>) {
+        let Ok(ip_packet) = interface.try_get_packet::<IPV4PacketView>() else {
+            return;
+        };
+        // The message ends with the IP packet, before the padding of a short Ethernet frame
+        let header_size = ip_packet.header.size();
+        let end = (ip_packet.header.get_total_length() as usize)
+            .saturating_sub(header_size)
+            .min(ip_packet.payload.len());
+        let message = &ip_packet.payload[..end];
+        if message.len() < V2_LENGTH {
+            debug!(length = message.len(), "truncated IGMP message dropped");
+            return;
+        }
+        if Checksum::new().add_slice(message).ones_complement() != 0 {
+            debug!("IGMP message with an invalid checksum dropped");
+            return;
+        }
+        let Ok(igmp_packet) = IGMPPacketView::<&[u8]>::try_from(message) else {
+            return;
+        };
+        let Some(group) = igmp_packet
+            .payload
+            .first_chunk()
+            .map(|group| IpV4Addr(u32::from_be_bytes(*group)))
+        else {
+            return;
+        };
+        let code = igmp_packet.header.get_max_response_code();
+        match igmp_packet.header.get_message_type() {
+            // Neither an IGMPv1 or v2 query nor a complete IGMPv3 one (RFC 3376 §7.1)
+            MEMBERSHIP_QUERY
+                if message.len() != V2_LENGTH && message.len() < V3_QUERY_MIN_LENGTH => {}
+            MEMBERSHIP_QUERY => {
+                // IGMPv1 and v2 queries are 8 bytes long, the first with no max response time
+                let v2 = message.len() == V2_LENGTH;
+                let max = match (v2, code) {
+                    (true, 0) => V1_MAX_RESPONSE_TIME,
+                    _ => max_response_time(code),
+                };
+                let now = Instant::now();
+                if v2 {
+                    self.v2_querier_until = Some(now + OLDER_QUERIER_TIMEOUT);
+                }
+                debug!(%group, v2, "multicast query");
+                let groups: Vec<_> = match group {
+                    IpV4Addr(0) if !v2 => vec![None],
+                    IpV4Addr(0) => self.groups.keys().copied().map(Some).collect(),
+                    group if self.groups.contains_key(&group) => vec![Some(group)],
+                    _ => vec![],
+                };
+                for group in groups {
+                    let time = now + random_delay(max);
+                    // A report already due sooner answers the query as well
+                    match self.pending.iter_mut().find(|(pending, record_type, _)| {
+                        *pending == group && *record_type == MODE_IS_EXCLUDE
+                    }) {
+                        Some((_, _, pending_time)) => *pending_time = time.min(*pending_time),
+                        None => self.pending.push((group, MODE_IS_EXCLUDE, time)),
+                    }
+                }
+            }
+            // Only the answers to the queries are suppressed, not the reports of our joins
+            V1_MEMBERSHIP_REPORT | V2_MEMBERSHIP_REPORT => {
+                self.pending.retain(|(pending, record_type, _)| {
+                    *pending != Some(group) || *record_type != MODE_IS_EXCLUDE
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Send the reports whose time came
+    pub fn poll(&mut self, interface: &mut Interface<impl Read + Write>) {
+        let now = Instant::now();
+        let (due, pending) = self
+            .pending
+            .drain(..)
+            .partition(|(_, _, time)| *time <= now);
+        self.pending = pending;
+        for (group, record_type, _) in due {
+            self.send_report(interface, group, record_type);
+        }
+    }
+
+    /// Report a group, or all of them when unspecified, in the version spoken on the link
+    fn send_report(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        group: Option<IpV4Addr>,
+        record_type: u8,
+    ) {
+        let groups: Vec<_> = match group {
+            Some(group) => vec![group],
+            None => self.groups.keys().copied().collect(),
+        };
+        let groups = groups.into_iter().filter(|group| *group != ALL_HOSTS);
+        if self.v2() {
+            for group in groups {
+                self.send(
+                    interface,
+                    group,
+                    IGMPPacket::v2(V2_MEMBERSHIP_REPORT, group),
+                );
+            }
+        } else {
+            let records: Vec<_> = groups.map(|group| (record_type, group)).collect();
+            if !records.is_empty() {
+                self.send(interface, ALL_V3_ROUTERS, IGMPPacket::v3_report(&records));
+            }
+        }
+    }
+
+    fn send(
+        &self,
+        interface: &mut Interface<impl Read + Write>,
+        destination: IpV4Addr,
+        packet: IGMPPacket,
+    ) {
+        let source = interface.config.address.unwrap_or_default();
+        let mut ip_header = IPV4Header::new(IpProtocol::Igmp, source, destination);
+        ip_header.ttl = 1;
+        interface.write_frame(
+            multicast_mac(destination),
+            ETHERTYPE_IPV4,
+            IPV4Packet::new(ip_header, packet),
+        );
+        interface.send();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support::Wire, traits::WriteTo};
+
+    const GROUP: IpV4Addr = IpV4Addr(0xE000_00FB);
+    const ROUTER: IpV4Addr = IpV4Addr(0xC0A8_0001);
+
+    fn receive_query(
+        interface: &mut Interface<Wire>,
+        query: IGMPPacket,
+        manager: &mut IGMPManager,
+    ) {
+        let packet = IPV4Packet::new(IPV4Header::new(IpProtocol::Igmp, ROUTER, ALL_HOSTS), query)
+            .to_bytes()
+            .unwrap();
+        interface.receive_frame(&[&[0, 0, 8, 0][..], &packet].concat());
+        manager.handle_packet(interface);
+    }
+
+    /// Destination, type and payload of the IGMP messages sent
+    fn sent(wire: &Wire) -> Vec<(IpV4Addr, u8, Vec<u8>)> {
+        wire.sent
+            .borrow_mut()
+            .drain(..)
+            .map(|frame| {
+                let ip_packet = IPV4PacketView::<IGMPPacketView>::try_from(&frame[4..]).unwrap();
+                assert_eq!(ip_packet.header.get_ttl(), 1);
+                (
+                    ip_packet.header.get_destination_address(),
+                    ip_packet.payload.header.get_message_type(),
+                    ip_packet.payload.payload.to_vec(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn v3_join_query_and_leave() {
+        let wire = Wire::default();
+        let mut interface = Interface::new(wire.clone());
+        let mut manager = IGMPManager::new();
+        manager.join(&mut interface, GROUP);
+        manager.join(&mut interface, GROUP);
+        let record = |record_type| {
+            [
+                &[0, 0, 0, 1, record_type, 0, 0, 0][..],
+                &GROUP.0.to_be_bytes(),
+            ]
+            .concat()
+        };
+        assert_eq!(
+            sent(&wire),
+            [(
+                ALL_V3_ROUTERS,
+                V3_MEMBERSHIP_REPORT,
+                record(CHANGE_TO_EXCLUDE_MODE)
+            )]
+        );
+        assert!(manager.is_member(GROUP));
+
+        // A general query with no delay is answered by the next poll
+        receive_query(
+            &mut interface,
+            IGMPPacket::query(IpV4Addr(0), 0, false),
+            &mut manager,
+        );
+        manager.poll(&mut interface);
+        assert_eq!(
+            sent(&wire),
+            [(
+                ALL_V3_ROUTERS,
+                V3_MEMBERSHIP_REPORT,
+                record(MODE_IS_EXCLUDE)
+            )]
+        );
+
+        manager.leave(&mut interface, GROUP);
+        assert!(sent(&wire).is_empty());
+        manager.leave(&mut interface, GROUP);
+        assert_eq!(
+            sent(&wire),
+            [(
+                ALL_V3_ROUTERS,
+                V3_MEMBERSHIP_REPORT,
+                record(CHANGE_TO_INCLUDE_MODE)
+            )]
+        );
+        assert!(!manager.is_member(GROUP));
+    }
+
+    #[test]
+    fn v2_querier() {
+        let wire = Wire::default();
+        let mut interface = Interface::new(wire.clone());
+        let mut manager = IGMPManager::new();
+        manager.join(&mut interface, GROUP);
+        sent(&wire);
+        receive_query(
+            &mut interface,
+            IGMPPacket::query(GROUP, 0, true),
+            &mut manager,
+        );
+        manager.poll(&mut interface);
+        // The query of IGMPv1 allows 10 seconds
+        assert!(sent(&wire).is_empty());
+
+        receive_query(
+            &mut interface,
+            IGMPPacket::query(IpV4Addr(0), 1, true),
+            &mut manager,
+        );
+        std::thread::sleep(Duration::from_millis(100));
+        manager.poll(&mut interface);
+        let group = GROUP.0.to_be_bytes().to_vec();
+        assert_eq!(sent(&wire), [(GROUP, V2_MEMBERSHIP_REPORT, group.clone())]);
+        manager.leave(&mut interface, GROUP);
+        assert_eq!(sent(&wire), [(ALL_ROUTERS, LEAVE_GROUP, group)]);
+    }
+
+    #[test]
+    fn join_repeated_as_a_state_change() {
+        let wire = Wire::default();
+        let mut interface = Interface::new(wire.clone());
+        let mut manager = IGMPManager::new();
+        manager.join(&mut interface, GROUP);
+        let report = sent(&wire);
+        // The report of another member in IGMPv2 does not cancel the repetition of our join
+        let other = IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Igmp, ROUTER, GROUP),
+            IGMPPacket::v2(V2_MEMBERSHIP_REPORT, GROUP),
+        )
+        .to_bytes()
+        .unwrap();
+        interface.receive_frame(&[&[0, 0, 8, 0][..], &other].concat());
+        manager.handle_packet(&mut interface);
+        manager.pending[0].2 = Instant::now();
+        manager.poll(&mut interface);
+        assert_eq!(sent(&wire), report);
+        assert_eq!(report[0].2[4], CHANGE_TO_EXCLUDE_MODE);
+        assert!(manager.pending.is_empty());
+    }
+
+    #[test]
+    fn invalid_messages_dropped() {
+        let wire = Wire::default();
+        let mut interface = Interface::new(wire.clone());
+        let mut manager = IGMPManager::new();
+        manager.join(&mut interface, GROUP);
+        manager.pending.clear();
+        let query = |query: IGMPPacket| {
+            IPV4Packet::new(IPV4Header::new(IpProtocol::Igmp, ROUTER, ALL_HOSTS), query)
+                .to_bytes()
+                .unwrap()
+        };
+        let mut receive = |packet: &[u8]| {
+            interface.receive_frame(&[&[0, 0, 8, 0][..], packet].concat());
+            manager.handle_packet(&mut interface);
+            !std::mem::take(&mut manager.pending).is_empty()
+        };
+        let general = query(IGMPPacket::query(IpV4Addr(0), 100, false));
+        assert!(receive(&general));
+
+        let mut corrupted = general.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(!receive(&corrupted));
+
+        // Cut in the middle of the group, or of the fields of an IGMPv3 query
+        for length in [20, 20 + 6, 20 + 10] {
+            let mut truncated = general[..length].to_vec();
+            truncated[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+            assert!(!receive(&truncated));
+            // Or with an IP header claiming more than was received
+            assert!(!receive(&general[..length]));
+        }
+        let mut short = query(IGMPPacket::new(
+            IGMPHeader::new(MEMBERSHIP_QUERY, 0),
+            vec![0; 6],
+        ));
+        assert!(!receive(&short));
+        short.truncate(20);
+        assert!(!receive(&short));
+
+        // The padding of a short Ethernet frame does not make an IGMPv2 query an IGMPv3 one
+        let mut padded = query(IGMPPacket::query(GROUP, 100, true));
+        padded.extend([0; 18]);
+        assert!(receive(&padded));
+        assert!(manager.v2());
+    }
+
+    #[test]
+    fn max_response_codes() {
+        assert_eq!(max_response_time(100), Duration::from_secs(10));
+        // Mantissa 0 and exponent 0 stand for 16 << 3 tenths
+        assert_eq!(max_response_time(0x80), Duration::from_millis(12800));
+        assert_eq!(multicast_mac(GROUP), MacAddr([1, 0, 0x5E, 0, 0, 0xFB]));
+    }
+}
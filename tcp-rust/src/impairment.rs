@@ -0,0 +1,633 @@
+//! Network impairments of a device, to see how applications cope with a bad link: rate limiting
+//! through a token bucket, fixed or jittered latency, random and burst losses, reordering and
+//! corruption, each set for either direction.
+//!
+//! The frames taken in or let out are held until their time comes, so that the reads of the device
+//! fail with `WouldBlock` while none is due. A device with a file descriptor is wrapped with
+//! [`Impaired::pollable`], which makes the descriptor polled by the stack wake up when a held frame
+//! is due as well. The other devices, like an in-memory link, must fail their reads with
+//! `WouldBlock` when empty, the frames being let through by the next read or write.
+//!
+//! The packet information header in front of each frame is not on the wire: it is neither counted
+//! by the rate nor corrupted.
+
+use std::{
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{trace, warn};
+
+use crate::{interface::PACKET_INFO_SIZE, tcp::manager::Clock};
+
+/// Longest a frame waits for the token bucket, the later ones being dropped as by a full queue
+const MAX_SHAPING_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Frames read from the device, on their way to the stack
+    Incoming,
+    /// Frames written by the stack, on their way to the device
+    Outgoing,
+}
+
+#[derive(Debug)]
+pub struct ParseImpairmentsError;
+
+/// Impairments of one direction, none by default
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Impairments {
+    /// Bytes per second let through, unlimited when 0
+    pub rate: u64,
+    /// Size of the token bucket, the bytes let through at once after an idle time
+    pub burst: u64,
+    pub latency: Duration,
+    /// Largest difference with the latency either way, which reorders the frames close together
+    pub jitter: Duration,
+    /// Probability for a frame to be lost
+    pub loss: f64,
+    /// Probability for the frame following a lost one to be lost as well, 0 making the losses
+    /// independent
+    pub burst_loss: f64,
+    /// Probability for a frame to skip the latency, overtaking the frames before it
+    pub reorder: f64,
+    /// Probability for a frame to get one of its bits flipped
+    pub corruption: f64,
+}
+
+impl FromStr for Impairments {
+    type Err = ParseImpairmentsError;
+
+    /// Parse `key=value` pairs separated by commas: `rate` in bytes per second, `burst` in bytes,
+    /// `latency` and `jitter` in milliseconds, and the probabilities `loss`, `burst-loss`,
+    /// `reorder` and `corruption` between 0 and 1
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut impairments = Self::default();
+        for setting in s.split(',').filter(|setting| !setting.is_empty()) {
+            let (key, value) = setting.split_once('=').ok_or(ParseImpairmentsError)?;
+            let integer = || value.parse::<u64>().map_err(|_| ParseImpairmentsError);
+            let probability = || {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|probability| (0.0..=1.0).contains(probability))
+                    .ok_or(ParseImpairmentsError)
+            };
+            match key {
+                "rate" => impairments.rate = integer()?,
+                "burst" => impairments.burst = integer()?,
+                "latency" => impairments.latency = Duration::from_millis(integer()?),
+                "jitter" => impairments.jitter = Duration::from_millis(integer()?),
+                "loss" => impairments.loss = probability()?,
+                "burst-loss" => impairments.burst_loss = probability()?,
+                "reorder" => impairments.reorder = probability()?,
+                "corruption" => impairments.corruption = probability()?,
+                _ => return Err(ParseImpairmentsError),
+            }
+        }
+        Ok(impairments)
+    }
+}
+
+/// Impairments of both directions, shared by the devices they apply to and changed from anywhere
+/// while the devices are in use
+#[derive(Debug, Clone, Default)]
+pub struct ImpairmentHandle(Arc<Mutex<[Impairments; 2]>>);
+
+impl ImpairmentHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, direction: Direction) -> Impairments {
+        self.0.lock().unwrap()[direction as usize]
+    }
+
+    /// Change the impairments of a direction, the frames already held keeping their time
+    pub fn set(&self, direction: Direction, impairments: Impairments) {
+        self.0.lock().unwrap()[direction as usize] = impairments;
+    }
+}
+
+/// xorshift64*, reproducible from its seed, as impairments need no unpredictable randomness
+#[derive(Debug, Clone)]
+struct Random(u64);
+
+impl Random {
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.unit() < probability
+    }
+}
+
+/// Frames of one direction held until their time, and the state of its impairments
+#[derive(Debug, Clone)]
+struct Lane {
+    /// Frames in the order of their time
+    held: Vec<(Instant, Vec<u8>)>,
+    /// Bytes the token bucket holds at `refilled`, below 0 while a frame waits for it
+    tokens: f64,
+    refilled: Instant,
+    /// The previous frame was lost
+    losing: bool,
+}
+
+impl Lane {
+    fn new(now: Instant) -> Self {
+        Self {
+            held: vec![],
+            tokens: 0.0,
+            refilled: now,
+            losing: false,
+        }
+    }
+
+    /// Time the token bucket lets a frame of `size` bytes through, None if it would wait too long
+    fn shape(&mut self, now: Instant, size: usize, rate: u64, burst: u64) -> Option<Instant> {
+        if rate == 0 {
+            return Some(now);
+        }
+        // The frames go through in order, after those still waiting for tokens
+        if now > self.refilled {
+            let refill = (now - self.refilled).as_secs_f64() * rate as f64;
+            self.tokens = (self.tokens + refill).min(burst as f64);
+            self.refilled = now;
+        }
+        let tokens = self.tokens - size as f64;
+        let wait = Duration::from_secs_f64((-tokens).max(0.0) / rate as f64);
+        let time = self.refilled + wait;
+        if time > now + MAX_SHAPING_DELAY {
+            return None;
+        }
+        self.tokens = tokens;
+        Some(time)
+    }
+
+    fn hold(&mut self, time: Instant, frame: Vec<u8>) {
+        let position = self.held.partition_point(|(held, _)| *held <= time);
+        self.held.insert(position, (time, frame));
+    }
+
+    /// The oldest frame whose time came
+    fn next_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        match self.held.first() {
+            Some((time, _)) if *time <= now => Some(self.held.remove(0).1),
+            _ => None,
+        }
+    }
+}
+
+/// Timer waking the pollers of a device up when a held frame is due, registered with the device
+/// in an epoll instance which stands for the device
+#[derive(Debug)]
+struct Timer {
+    epoll: OwnedFd,
+    timer: OwnedFd,
+}
+
+impl Timer {
+    fn new(device: RawFd) -> io::Result<Self> {
+        let timer = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if timer < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let timer = unsafe { OwnedFd::from_raw_fd(timer) };
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let epoll = unsafe { OwnedFd::from_raw_fd(epoll) };
+        for fd in [device, timer.as_raw_fd()] {
+            let mut event = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: fd as u64,
+            };
+            if unsafe {
+                libc::epoll_ctl(epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &raw mut event)
+            } < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(Self { epoll, timer })
+    }
+
+    /// Expire at `deadline`, or never
+    fn arm(&self, deadline: Option<Instant>, now: Instant) {
+        let delay = deadline.map(|deadline| {
+            // A zero time disarms the timer
+            deadline
+                .saturating_duration_since(now)
+                .max(Duration::from_nanos(1))
+        });
+        let value = delay.map_or(
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            |delay| libc::timespec {
+                tv_sec: delay.as_secs() as libc::time_t,
+                tv_nsec: delay.subsec_nanos() as libc::c_long,
+            },
+        );
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: value,
+        };
+        if unsafe {
+            libc::timerfd_settime(
+                self.timer.as_raw_fd(),
+                0,
+                &raw const spec,
+                std::ptr::null_mut(),
+            )
+        } < 0
+        {
+            warn!(
+                "could not arm the impairment timer: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    /// Acknowledge an expiry, which keeps the timer readable until then
+    fn clear(&self) {
+        let mut expirations = 0u64;
+        if unsafe {
+            libc::read(
+                self.timer.as_raw_fd(),
+                &raw mut expirations as *mut libc::c_void,
+                size_of::<u64>(),
+            )
+        } < 0
+        {
+            // The timer not having expired yet is the usual case
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                warn!("could not clear the impairment timer: {err}");
+            }
+        }
+    }
+}
+
+/// A device whose frames go through impairments
+#[derive(Debug)]
+pub struct Impaired<T: Read + Write> {
+    device: T,
+    handle: ImpairmentHandle,
+    incoming: Lane,
+    outgoing: Lane,
+    random: Random,
+    timer: Option<Timer>,
+    clock: Clock,
+}
+
+impl<T: Read + Write> Impaired<T> {
+    /// Impair a device whose reads fail with `WouldBlock` when it has no frame
+    pub fn new(device: T, handle: ImpairmentHandle) -> Self {
+        Self::with_clock(device, handle, Clock::default())
+    }
+
+    /// Like `new`, the frames being held on `clock`
+    pub fn with_clock(device: T, handle: ImpairmentHandle, clock: Clock) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self {
+            device,
+            handle,
+            incoming: Lane::new(clock.now()),
+            outgoing: Lane::new(clock.now()),
+            random: Random(nanos | 1),
+            timer: None,
+            clock,
+        }
+    }
+
+    /// Draw the same impairments on each run with the same frames
+    pub fn set_seed(&mut self, seed: u64) {
+        self.random = Random(seed | 1);
+    }
+
+    pub fn handle(&self) -> &ImpairmentHandle {
+        &self.handle
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.device
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.device
+    }
+
+    /// Time of the next held frame, when the device should be read or written again at the
+    /// latest to let it through
+    pub fn next_timer(&self) -> Option<Instant> {
+        [self.incoming.held.first(), self.outgoing.held.first()]
+            .into_iter()
+            .flatten()
+            .map(|(time, _)| *time)
+            .min()
+    }
+
+    /// Hold a frame until the time its impairments give it, unless it is lost
+    fn impair(&mut self, direction: Direction, mut frame: Vec<u8>) {
+        let impairments = self.handle.get(direction);
+        let lane = match direction {
+            Direction::Incoming => &mut self.incoming,
+            Direction::Outgoing => &mut self.outgoing,
+        };
+        let random = &mut self.random;
+        let loss = match lane.losing {
+            true => impairments.burst_loss,
+            false => impairments.loss,
+        };
+        lane.losing = random.chance(loss);
+        if lane.losing {
+            trace!(?direction, "frame lost");
+            return;
+        }
+        let size = frame.len().saturating_sub(PACKET_INFO_SIZE);
+        if size > 0 && random.chance(impairments.corruption) {
+            let bit = (random.next_u64() % (size as u64 * 8)) as usize;
+            frame[PACKET_INFO_SIZE + bit / 8] ^= 1 << (bit % 8);
+            trace!(?direction, bit, "frame corrupted");
+        }
+        let now = self.clock.now();
+        let Some(mut time) = lane.shape(now, size, impairments.rate, impairments.burst) else {
+            trace!(?direction, "frame dropped by the rate limit");
+            return;
+        };
+        if random.chance(impairments.reorder) {
+            trace!(?direction, "frame reordered");
+        } else {
+            let jitter = impairments.jitter.mul_f64(2.0 * random.unit());
+            time += (impairments.latency + jitter).saturating_sub(impairments.jitter);
+        }
+        lane.hold(time, frame);
+    }
+
+    /// Write the outgoing frames whose time came, the device being the only one to know of a
+    /// failure
+    fn let_out(&mut self) {
+        let now = self.clock.now();
+        while let Some(frame) = self.outgoing.next_due(now) {
+            if let Err(err) = self.device.write_all(&frame) {
+                warn!("could not send impaired frame: {err}");
+            }
+        }
+    }
+
+    fn arm_timer(&self) {
+        if let Some(timer) = &self.timer {
+            timer.arm(self.next_timer(), self.clock.now());
+        }
+    }
+}
+
+impl<T: Read + Write + AsRawFd> Impaired<T> {
+    /// Impair a device with a file descriptor, which is made non-blocking. The descriptor of the
+    /// impaired device is then ready when the device is or when a held frame is due.
+    pub fn pollable(device: T, handle: ImpairmentHandle) -> io::Result<Self> {
+        let fd = device.as_raw_fd();
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let mut impaired = Self::new(device, handle);
+        impaired.timer = Some(Timer::new(fd)?);
+        Ok(impaired)
+    }
+}
+
+impl<T: Read + Write> Read for Impaired<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(timer) = &self.timer {
+            timer.clear();
+        }
+        self.let_out();
+        let mut frame = vec![0; buf.len()];
+        match self.device.read(&mut frame) {
+            Ok(nbytes) => {
+                frame.truncate(nbytes);
+                self.impair(Direction::Incoming, frame);
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+        let result = match self.incoming.next_due(self.clock.now()) {
+            // A frame held while reading into a larger buffer is truncated, as a datagram is
+            Some(frame) => {
+                let len = frame.len().min(buf.len());
+                buf[..len].copy_from_slice(&frame[..len]);
+                Ok(len)
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        };
+        self.arm_timer();
+        result
+    }
+}
+
+impl<T: Read + Write> Write for Impaired<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.impair(Direction::Outgoing, buf.to_vec());
+        self.let_out();
+        self.arm_timer();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.device.flush()
+    }
+}
+
+impl<T: Read + Write + AsRawFd> AsRawFd for Impaired<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.timer {
+            Some(timer) => timer.epoll.as_raw_fd(),
+            None => self.device.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use super::*;
+    use crate::test_support::{Datagram, Wire};
+
+    fn frame(byte: u8, size: usize) -> Vec<u8> {
+        [&[0, 0, 8, 0][..], &vec![byte; size]].concat()
+    }
+
+    /// An in-memory link impaired on a manual clock
+    fn impaired_wire(handle: ImpairmentHandle) -> (Impaired<Wire>, Wire, Clock) {
+        let wire = Wire::default();
+        let clock = Clock::manual();
+        let impaired = Impaired::with_clock(wire.clone(), handle, clock.clone());
+        (impaired, wire, clock)
+    }
+
+    #[test]
+    fn latency_and_reordering_changed_at_runtime() {
+        let handle = ImpairmentHandle::new();
+        handle.set(Direction::Outgoing, "latency=30".parse().unwrap());
+        let (mut impaired, wire, clock) = impaired_wire(handle.clone());
+        impaired.write_all(&frame(1, 10)).unwrap();
+        handle.set(Direction::Outgoing, "latency=30,reorder=1".parse().unwrap());
+        impaired.write_all(&frame(2, 10)).unwrap();
+        assert_eq!(*wire.sent.borrow(), [frame(2, 10)]);
+        assert_eq!(
+            impaired.next_timer(),
+            Some(clock.now() + Duration::from_millis(30))
+        );
+
+        // Reading lets the outgoing frames due through, none before their time
+        clock.advance(Duration::from_millis(29));
+        let mut buf = [0; 64];
+        let error = impaired.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(wire.sent.borrow().len(), 1);
+        clock.advance(Duration::from_millis(1));
+        // The incoming direction is unimpaired
+        wire.incoming.borrow_mut().push_back(frame(3, 10));
+        assert_eq!(impaired.read(&mut buf).unwrap(), 14);
+        assert_eq!(*wire.sent.borrow(), [frame(2, 10), frame(1, 10)]);
+        assert!(impaired.next_timer().is_none());
+        assert!("loss=2".parse::<Impairments>().is_err());
+        assert!("delay=10".parse::<Impairments>().is_err());
+    }
+    #[test]
+    fn losses_and_corruption() {
+        let handle = ImpairmentHandle::new();
+        handle.set(Direction::Incoming, "loss=1".parse().unwrap());
+        let (mut impaired, wire, _) = impaired_wire(handle.clone());
+        impaired.set_seed(42);
+        wire.incoming.borrow_mut().push_back(frame(0, 100));
+        let mut buf = [0; 128];
+        let error = impaired.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+
+        handle.set(Direction::Incoming, "corruption=1".parse().unwrap());
+        wire.incoming.borrow_mut().push_back(frame(0, 100));
+        assert_eq!(impaired.read(&mut buf).unwrap(), 104);
+        assert_eq!(buf[..4], [0, 0, 8, 0]);
+        let flipped: u32 = buf[4..104].iter().map(|byte| byte.count_ones()).sum();
+        assert_eq!(flipped, 1);
+
+        // Losses in bursts of 10 frames on average
+        handle.set(
+            Direction::Outgoing,
+            "loss=0.01,burst-loss=0.9".parse().unwrap(),
+        );
+        for _ in 0..10000 {
+            impaired.write_all(&frame(0, 1)).unwrap();
+        }
+        let lost = 10000 - wire.sent.borrow().len();
+        assert!((500..=1500).contains(&lost), "{lost} frames lost");
+    }
+
+    #[test]
+    fn rate_limited_by_a_token_bucket() {
+        let handle = ImpairmentHandle::new();
+        handle.set(Direction::Outgoing, "rate=1000,burst=200".parse().unwrap());
+        let (mut impaired, wire, clock) = impaired_wire(handle);
+        // The bucket starts empty and takes 200 ms to fill
+        clock.advance(Duration::from_millis(200));
+        for byte in 0..3 {
+            impaired.write_all(&frame(byte, 100)).unwrap();
+        }
+        assert_eq!(*wire.sent.borrow(), [frame(0, 100), frame(1, 100)]);
+        assert_eq!(
+            impaired.next_timer(),
+            Some(clock.now() + Duration::from_millis(100))
+        );
+        // A second of frames waiting for the bucket fills the queue
+        for _ in 0..9 {
+            impaired.write_all(&frame(3, 100)).unwrap();
+        }
+        impaired.write_all(&frame(4, 100)).unwrap();
+        assert_eq!(impaired.outgoing.held.len(), 10);
+
+        clock.advance(Duration::from_millis(100));
+        let error = impaired.read(&mut [0; 128]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(wire.sent.borrow()[2], frame(2, 100));
+        assert_eq!(impaired.outgoing.held.len(), 9);
+    }
+
+    #[test]
+    fn held_frames_truncated_to_the_buffer() {
+        let handle = ImpairmentHandle::new();
+        handle.set(Direction::Incoming, "latency=10".parse().unwrap());
+        let (mut impaired, wire, clock) = impaired_wire(handle);
+        wire.incoming.borrow_mut().push_back(frame(1, 100));
+        let mut buf = [0; 128];
+        let error = impaired.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+
+        clock.advance(Duration::from_millis(10));
+        let mut buf = [0; 16];
+        assert_eq!(impaired.read(&mut buf).unwrap(), 16);
+        assert_eq!(buf[..], frame(1, 100)[..16]);
+    }
+
+    fn readable(fd: RawFd, timeout: Duration) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&raw mut pollfd, 1, timeout.as_millis() as i32) > 0 }
+    }
+
+    #[test]
+    fn held_frames_wake_the_poller() {
+        let (device, peer) = UnixDatagram::pair().unwrap();
+        let handle = ImpairmentHandle::new();
+        handle.set(Direction::Incoming, "latency=50".parse().unwrap());
+        let mut impaired = Impaired::pollable(Datagram(device), handle).unwrap();
+        let fd = impaired.as_raw_fd();
+        assert!(!readable(fd, Duration::ZERO));
+
+        peer.send(&frame(1, 20)).unwrap();
+        assert!(readable(fd, Duration::ZERO));
+        let mut buf = [0; 64];
+        let error = impaired.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert!(!readable(fd, Duration::ZERO));
+        let start = Instant::now();
+        assert!(readable(fd, Duration::from_millis(500)));
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(impaired.read(&mut buf).unwrap(), 24);
+        assert!(!readable(fd, Duration::ZERO));
+    }
+}
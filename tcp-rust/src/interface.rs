@@ -1,41 +1,256 @@
 use std::{
-    fmt::Debug,
-    io::{Read, Write},
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, RawFd},
+    time::Duration,
 };
 
+use tracing::warn;
+
 use crate::{
-    ip::{IPV4PacketView, IpProtocol},
-    traits::WriteTo,
+    buffer::PacketBuffer,
+    ethernet::{ETHERTYPE_IPV6, EthernetHeader, EthernetHeaderView, MacAddr},
+    impairment::Impaired,
+    ip::{IPV4HeaderView, IPV4PacketView, IpProtocol, IpV4Addr, IpV6Addr},
+    metrics::METRICS,
+    packet::ParseHeaderError,
+    packet_socket::PacketSocket,
+    traits::{Data, DataView, WriteTo},
+    tun_tap::{self, Mode},
+    tunnel::TunnelDevice,
 };
 
+/// Size of the packet information header the TUN/TAP driver puts in front of each packet
+pub const PACKET_INFO_SIZE: usize = 4;
+
+/// Packet information header of a frame carrying `ethertype`, as the TUN/TAP driver writes it
+pub fn packet_info(ethertype: u16) -> [u8; PACKET_INFO_SIZE] {
+    let [high, low] = ethertype.to_be_bytes();
+    [0, 0, high, low]
+}
+const ETHERNET_HEADER_SIZE: usize = 14;
+/// Transport protocols the stack answers unless told otherwise
+pub const DEFAULT_PROTOCOLS: [IpProtocol; 5] = [
+    IpProtocol::Icmp,
+    IpProtocol::Igmp,
+    IpProtocol::Sctp,
+    IpProtocol::Tcp,
+    IpProtocol::Udp,
+];
+
+/// Addressing of the stack on its link, either static or learned through DHCP
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InterfaceConfig {
+    pub mac: MacAddr,
+    pub address: Option<IpV4Addr>,
+    pub netmask: IpV4Addr,
+    pub gateway: Option<IpV4Addr>,
+    pub dns_server: Option<IpV4Addr>,
+    /// IPv6 address, which the stack does not answer yet but tells apart from other hosts'
+    pub address6: Option<IpV6Addr>,
+}
+
+impl InterfaceConfig {
+    /// Locally administered address used by the stack in TAP mode
+    pub const DEFAULT_MAC: MacAddr = MacAddr([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+    /// Address of the stack in TUN mode, unless given, the host taking 192.168.0.1
+    pub const DEFAULT_ADDRESS: IpV4Addr = IpV4Addr(0xC0A8_0002);
+
+    /// Parse an address written as `<address>[/<prefix length>]`, the prefix being 24 by default
+    pub fn parse(value: &str) -> Option<Self> {
+        let (address, prefix_len) = value.split_once('/').unwrap_or((value, "24"));
+        let prefix_len: u32 = prefix_len.parse().ok().filter(|len| *len <= 32)?;
+        Some(Self {
+            mac: Self::DEFAULT_MAC,
+            address: Some(address.parse().ok()?),
+            netmask: IpV4Addr(u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0)),
+            ..Default::default()
+        })
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.netmask.0.leading_ones() as u8
+    }
+
+    /// Whether the address is on the local network and can be reached without the gateway
+    pub fn is_local(&self, address: IpV4Addr) -> bool {
+        self.address
+            .is_some_and(|own| (own.0 ^ address.0) & self.netmask.0 == 0)
+    }
+
+    pub fn broadcast(&self) -> Option<IpV4Addr> {
+        self.address.map(|own| IpV4Addr(own.0 | !self.netmask.0))
+    }
+
+    /// Whether a packet to `destination` is for the stack: sent to its address, a broadcast or a
+    /// multicast group, or to any address while it has none, waiting for a DHCP lease
+    pub fn accepts(&self, destination: IpV4Addr) -> bool {
+        self.address.is_none_or(|own| {
+            destination == own
+                || Some(destination) == self.broadcast()
+                || destination == IpV4Addr(u32::MAX)
+                || destination.is_multicast()
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct Interface<T: Read + Write> {
     interface: T,
-    // Define a buffer of size 1504 bytes (maximum Ethernet frame size without CRC) to store received data.
-    buffer: [u8; 1504],
+    // Define a buffer of size 1518 bytes (packet information, Ethernet header and a 1500 bytes MTU) to store received data.
+    buffer: [u8; 1518],
     pub nbytes: usize,
+    pub mode: Mode,
+    /// Largest IP packet the device takes
+    pub mtu: usize,
+    pub config: InterfaceConfig,
+    /// Transport protocols the stack answers on the interface, the others being dropped
+    pub protocols: Vec<IpProtocol>,
+    // Link address and ethertype of the last received frame, kept as the buffer is reused to answer
+    peer: (MacAddr, u16),
 }
 
 impl<T: Read + Write> Interface<T> {
     pub fn new(interface: T) -> Self {
+        Self::with_mode(interface, Mode::Tun)
+    }
+
+    pub fn with_mode(interface: T, mode: Mode) -> Self {
         Self {
             interface,
             buffer: [0; _],
             nbytes: 0,
+            mode,
+            mtu: 1500,
+            config: InterfaceConfig {
+                mac: InterfaceConfig::DEFAULT_MAC,
+                ..Default::default()
+            },
+            protocols: DEFAULT_PROTOCOLS.to_vec(),
+            peer: Default::default(),
+        }
+    }
+
+    fn link_header_size(&self) -> usize {
+        match self.mode {
+            Mode::Tun => PACKET_INFO_SIZE,
+            Mode::Tap => PACKET_INFO_SIZE + ETHERNET_HEADER_SIZE,
         }
     }
 
     pub fn receive(&mut self) {
+        self.try_receive().unwrap();
+    }
+    /// Receive a frame, failing with `WouldBlock` when a non-blocking device has none
+    pub fn try_receive(&mut self) -> io::Result<()> {
         // Receive data from the TUN interface and store the number of bytes received in `nbytes`.
-        self.nbytes = self.interface.read(&mut self.buffer[..]).unwrap();
+        self.nbytes = self.interface.read(&mut self.buffer[..])?;
+        METRICS.received(self.nbytes);
+        self.remember_peer();
+        Ok(())
+    }
+    /// Take a frame received by another interface on the same device, as if read from it
+    pub fn receive_frame(&mut self, frame: &[u8]) {
+        self.buffer[..frame.len()].copy_from_slice(frame);
+        self.nbytes = frame.len();
+        self.remember_peer();
+    }
+    /// The last received frame, with its packet information header
+    pub fn frame(&self) -> &[u8] {
+        &self.buffer[..self.nbytes]
+    }
+    fn remember_peer(&mut self) {
+        if self.mode == Mode::Tap
+            && let Ok(ethernet_header) = self.get_ethernet_header()
+        {
+            self.peer = (
+                ethernet_header.get_source(),
+                ethernet_header.get_ethertype(),
+            );
+        }
     }
     pub fn send(&mut self) {
-        self.interface
-            .write_all(&self.buffer[..self.nbytes])
+        // Writing fails while the link is down, which is not fatal
+        match self.interface.write_all(&self.buffer[..self.nbytes]) {
+            Ok(()) => METRICS.sent(self.nbytes),
+            Err(err) => warn!("could not send packet: {err}"),
+        }
+    }
+    /// Link address the last packet came from, meaningless in TUN mode
+    pub fn get_peer_mac(&self) -> MacAddr {
+        self.peer.0
+    }
+    /// Write an answer to the last received packet, sent back to the same link address
+    pub fn write(&mut self, writter: impl WriteTo) {
+        self.try_write(writter).unwrap();
+    }
+    /// Like `write`, failing when the packet does not fit in the buffer
+    pub fn try_write(&mut self, writter: impl WriteTo) -> io::Result<()> {
+        match self.mode {
+            Mode::Tun => self.try_write_frame(MacAddr::BROADCAST, self.get_proto(), writter),
+            Mode::Tap => self.try_write_frame(self.peer.0, self.peer.1, writter),
+        }
+    }
+    /// Write a packet to the given link address, which is ignored in TUN mode
+    pub fn write_frame(&mut self, destination: MacAddr, ethertype: u16, writter: impl WriteTo) {
+        self.try_write_frame(destination, ethertype, writter)
             .unwrap();
     }
-    pub fn write(&mut self, mut writter: impl WriteTo) {
-        self.nbytes = writter.write_to(&mut &mut self.buffer[4..]).unwrap() + 4;
+    /// Like `write_frame`, failing when the packet does not fit in the buffer
+    pub fn try_write_frame(
+        &mut self,
+        destination: MacAddr,
+        ethertype: u16,
+        mut writter: impl WriteTo,
+    ) -> io::Result<()> {
+        self.buffer[2..4].copy_from_slice(&ethertype.to_be_bytes());
+        if self.mode == Mode::Tap {
+            EthernetHeader {
+                destination,
+                source: self.config.mac,
+                ethertype,
+            }
+            .write_to(&mut &mut self.buffer[PACKET_INFO_SIZE..])?;
+        }
+        let offset = self.link_header_size();
+        self.nbytes = writter.write_to_buffer(&mut self.buffer[offset..])? + offset;
+        Ok(())
+    }
+
+    /// Send a packet built in a buffer, its link headers being written in its headroom
+    pub fn transmit(&mut self, destination: MacAddr, ethertype: u16, buffer: &mut PacketBuffer) {
+        if let Err(err) = self.push_link_headers(destination, ethertype, buffer) {
+            warn!("could not send packet: {err}");
+            return;
+        }
+        match self.interface.write_all(buffer.as_slice()) {
+            Ok(()) => METRICS.sent(buffer.len()),
+            Err(err) => warn!("could not send packet: {err}"),
+        }
+    }
+    fn push_link_headers(
+        &self,
+        destination: MacAddr,
+        ethertype: u16,
+        buffer: &mut PacketBuffer,
+    ) -> io::Result<()> {
+        if self.mode == Mode::Tap {
+            buffer.push_header(EthernetHeader {
+                destination,
+                source: self.config.mac,
+                ethertype,
+            })?;
+        }
+        buffer
+            .prepend(PACKET_INFO_SIZE)?
+            .copy_from_slice(&packet_info(ethertype));
+        Ok(())
+    }
+    /// Send a packet built in a buffer as an answer to the last received packet
+    pub fn reply(&mut self, buffer: &mut PacketBuffer) {
+        match self.mode {
+            Mode::Tun => self.transmit(MacAddr::BROADCAST, self.get_proto(), buffer),
+            Mode::Tap => self.transmit(self.peer.0, self.peer.1, buffer),
+        }
     }
 
     pub fn get_proto(&self) -> u16 {
@@ -45,26 +260,232 @@ impl<T: Read + Write> Interface<T> {
         u16::from_be_bytes([self.buffer[0], self.buffer[1]])
     }
 
-    pub fn get_packet<'a, V: TryFrom<&'a [u8]>>(&'a self) -> V
-    where
-        <V as TryFrom<&'a [u8]>>::Error: Debug,
-    {
-        V::try_from(&self.buffer[4..self.nbytes]).unwrap()
+    /// # Panics
+    /// The interface must be in TAP mode
+    pub fn get_ethernet_header(&self) -> Result<EthernetHeaderView<'_>, ParseHeaderError> {
+        assert_eq!(self.mode, Mode::Tap);
+        EthernetHeaderView::parse(self.frame().get(PACKET_INFO_SIZE..).unwrap_or_default())
     }
 
+    /// Whether the last received frame is addressed to the stack at the link layer
+    pub fn is_for_us(&self) -> bool {
+        match self.mode {
+            Mode::Tun => true,
+            Mode::Tap => self.get_ethernet_header().is_ok_and(|header| {
+                let destination = header.get_destination();
+                destination == self.config.mac || destination.is_multicast()
+            }),
+        }
+    }
+
+    /// The packet in the buffer, after its link headers
+    pub fn packet(&self) -> &[u8] {
+        // A frame shorter than its link headers holds an empty packet
+        self.frame()
+            .get(self.link_header_size()..)
+            .unwrap_or_default()
+    }
+
+    /// The packet in the buffer parsed as `V`, failing when it is too short or malformed
     pub fn try_get_packet<'a, V: TryFrom<&'a [u8]>>(
         &'a self,
     ) -> Result<V, <V as TryFrom<&'a [u8]>>::Error> {
-        V::try_from(&self.buffer[4..self.nbytes])
+        V::try_from(self.packet())
     }
 
+    /// The IPv4 packet in the buffer, its payload parsed as `C`, without the padding of a short
+    /// Ethernet frame
+    pub fn try_get_ip_packet<'a, C: DataView<'a, Error: Into<ParseHeaderError>>>(
+        &'a self,
+    ) -> Result<IPV4PacketView<'a, C>, ParseHeaderError> {
+        let packet = self.packet();
+        let header = IPV4HeaderView::parse(packet)?;
+        let end = (header.get_total_length() as usize).clamp(header.size(), packet.len());
+        IPV4PacketView::try_from(&packet[..end])
+    }
+
+    /// The packet in the buffer, which can be patched after a `write` and before a `send`
+    pub fn get_packet_mut(&mut self) -> &mut [u8] {
+        let offset = self.link_header_size();
+        &mut self.buffer[offset..self.nbytes]
+    }
+
+    /// Whether the buffer holds an IPv4 packet, at least as long as its header
     pub fn is_ip(&self) -> bool {
-        self.get_proto() == 0x0800
+        self.get_proto() == 0x0800 && self.try_get_packet::<IPV4HeaderView>().is_ok()
+    }
+
+    /// Whether the stack answers the IP packet in the buffer, sent to its address with an
+    /// enabled protocol
+    pub fn is_addressed_to_us(&self) -> bool {
+        self.try_get_packet::<IPV4HeaderView>().is_ok_and(|header| {
+            self.config.accepts(header.get_destination_address())
+                && self.protocols.contains(&header.get_protocol())
+        })
+    }
+
+    /// Destination of the IPv6 packet in the buffer, `None` when it holds none
+    pub fn get_ipv6_destination(&self) -> Option<IpV6Addr> {
+        if self.get_proto() != ETHERTYPE_IPV6 {
+            return None;
+        }
+        // The destination follows the fixed fields and the source of the header
+        let destination = self.packet().get(24..40)?.try_into().ok()?;
+        Some(IpV6Addr(u128::from_be_bytes(destination)))
+    }
+
+    /// Transport protocol of the IP packet in the buffer, `None` when it holds none
+    pub fn get_ip_protocol(&self) -> Option<IpProtocol> {
+        self.try_get_packet::<IPV4HeaderView>()
+            .ok()
+            .map(|header| header.get_protocol())
+    }
+}
+
+/// Wait at most `timeout` for any of the interfaces to have a packet, returning the ready ones
+pub fn poll_interfaces<T: Read + Write + AsRawFd>(
+    interfaces: &[Interface<T>],
+    timeout: Duration,
+) -> io::Result<Vec<usize>> {
+    let mut fds: Vec<_> = interfaces
+        .iter()
+        .map(|interface| libc::pollfd {
+            fd: interface.interface.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let result = unsafe {
+        libc::poll(
+            fds.as_mut_ptr(),
+            fds.len() as libc::nfds_t,
+            timeout.as_millis() as i32,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fds
+        .iter()
+        .enumerate()
+        .filter(|(_, fd)| fd.revents & libc::POLLIN != 0)
+        .map(|(i, _)| i)
+        .collect())
+}
+
+impl<T: Read + Write + AsRawFd> Interface<T> {
+    /// Wait at most `timeout` for a packet, returning whether one was received
+    pub fn receive_timeout(&mut self, timeout: Duration) -> io::Result<bool> {
+        let mut fd = libc::pollfd {
+            fd: self.interface.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let result = unsafe { libc::poll(&raw mut fd, 1, timeout.as_millis() as i32) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if result == 0 {
+            return Ok(false);
+        }
+        self.receive();
+        Ok(true)
+    }
+}
+
+impl<T: Read + Write + AsRawFd> AsRawFd for Interface<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.interface.as_raw_fd()
+    }
+}
+
+/// Device under an interface, either created by the stack, an existing link or a tunnel, or one
+/// of them through network impairments
+#[derive(Debug)]
+pub enum Device {
+    TunTap(tun_tap::Interface),
+    Packet(PacketSocket),
+    Tunnel(TunnelDevice),
+    Impaired(Box<Impaired<Device>>),
+}
+
+impl Device {
+    pub fn name(&self) -> &str {
+        match self {
+            Device::TunTap(interface) => &interface.name,
+            Device::Packet(socket) => &socket.name,
+            Device::Tunnel(device) => &device.name,
+            Device::Impaired(device) => device.get_ref().name(),
+        }
+    }
+}
+
+impl Read for Device {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Device::TunTap(interface) => interface.read(buf),
+            Device::Packet(socket) => socket.read(buf),
+            Device::Tunnel(device) => device.read(buf),
+            Device::Impaired(device) => device.read(buf),
+        }
+    }
+}
+
+impl Write for Device {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Device::TunTap(interface) => interface.write(buf),
+            Device::Packet(socket) => socket.write(buf),
+            Device::Tunnel(device) => device.write(buf),
+            Device::Impaired(device) => device.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Device::TunTap(interface) => interface.flush(),
+            Device::Packet(socket) => socket.flush(),
+            Device::Tunnel(device) => device.flush(),
+            Device::Impaired(device) => device.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Device {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Device::TunTap(interface) => interface.as_raw_fd(),
+            Device::Packet(socket) => socket.as_raw_fd(),
+            Device::Tunnel(device) => device.as_raw_fd(),
+            Device::Impaired(device) => device.as_raw_fd(),
+        }
     }
+}
 
-    pub fn get_ip_protocol(&self) -> IpProtocol {
-        self.get_packet::<IPV4PacketView>()
-            .get_header()
-            .get_protocol()
+#[cfg(test)]
+mod tests {
+    use crate::{ethernet::ETHERTYPE_IPV4, test_support::Wire};
+
+    use super::*;
+
+    #[test]
+    fn ipv6_destination_read_from_the_header() {
+        let wire = Wire::default();
+        let mut interface = Interface::new(wire.clone());
+        let destination = IpV6Addr(0xFD00 << 112 | 2);
+        let mut header = [0; 40];
+        header[0] = 0x60;
+        header[24..].copy_from_slice(&u128::from(destination).to_be_bytes());
+        for (ethertype, packet, expected) in [
+            (ETHERTYPE_IPV6, &header[..], Some(destination)),
+            // Truncated before the end of the destination
+            (ETHERTYPE_IPV6, &header[..39], None),
+            (ETHERTYPE_IPV4, &header[..], None),
+        ] {
+            let frame = [&packet_info(ethertype)[..], packet].concat();
+            wire.incoming.borrow_mut().push_back(frame);
+            interface.receive();
+            assert_eq!(interface.get_ipv6_destination(), expected);
+        }
     }
 }
@@ -1,28 +1,99 @@
 use std::{
+    cell::RefCell,
     fmt::{Debug, Display},
     io::{self, Write},
+    net::{AddrParseError, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
 };
 
+use tcp_rust_macros::PacketHeader;
+
 use crate::{
-    checksum::Checksum,
+    buffer::{BufferPool, PacketBuffer},
+    checksum::{Checksum, ChecksumWriter},
     packet::{Packet, PacketView},
-    traits::{AsArrayUnchecked, Data, DataOwned, Prepare, ToMutable, WriteTo},
+    traits::{Data, DataOwned, Prepare, WriteTo},
 };
 
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 pub enum IpProtocol {
     #[default]
-    Icmp = 1,
-    Igmp = 2,
-    Tcp = 6,
-    Udp = 17,
-    IpV6Encap = 41,
-    Ospf = 89,
-    Sctp = 132,
+    Icmp,
+    Igmp,
+    IpIp,
+    Tcp,
+    Udp,
+    IpV6Encap,
+    Gre,
+    Ospf,
+    Sctp,
+    /// A protocol the stack does not know, which it may still forward. Known protocols are never
+    /// made `Other`, see `From<u8>`
+    Other(u8),
+}
+
+impl From<u8> for IpProtocol {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Icmp,
+            2 => Self::Igmp,
+            4 => Self::IpIp,
+            6 => Self::Tcp,
+            17 => Self::Udp,
+            41 => Self::IpV6Encap,
+            47 => Self::Gre,
+            89 => Self::Ospf,
+            132 => Self::Sctp,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<IpProtocol> for u8 {
+    fn from(value: IpProtocol) -> Self {
+        match value {
+            IpProtocol::Icmp => 1,
+            IpProtocol::Igmp => 2,
+            IpProtocol::IpIp => 4,
+            IpProtocol::Tcp => 6,
+            IpProtocol::Udp => 17,
+            IpProtocol::IpV6Encap => 41,
+            IpProtocol::Gre => 47,
+            IpProtocol::Ospf => 89,
+            IpProtocol::Sctp => 132,
+            IpProtocol::Other(other) => other,
+        }
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[derive(Debug)]
+pub struct ParseIpProtocolError;
+
+impl FromStr for IpProtocol {
+    type Err = ParseIpProtocolError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "icmp" => Self::Icmp,
+            "igmp" => Self::Igmp,
+            "ipip" => Self::IpIp,
+            "tcp" => Self::Tcp,
+            "udp" => Self::Udp,
+            "ipv6" => Self::IpV6Encap,
+            "gre" => Self::Gre,
+            "ospf" => Self::Ospf,
+            "sctp" => Self::Sctp,
+            _ => return Err(ParseIpProtocolError),
+        })
+    }
+}
+
+pub const DEFAULT_TTL: u8 = 64;
+/// Codepoints of the `ecn` field: an ECN capable transport, and a congestion experienced on the
+/// way
+pub const ECN_ECT0: u8 = 0b10;
+pub const ECN_CE: u8 = 0b11;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
 pub struct IpV4Addr(pub u32);
 
 impl From<u32> for IpV4Addr {
@@ -31,137 +102,96 @@ impl From<u32> for IpV4Addr {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct IPV4HeaderView<'a> {
-    content: &'a [u8],
+impl From<IpV4Addr> for u32 {
+    fn from(value: IpV4Addr) -> Self {
+        value.0
+    }
 }
 
-impl ToMutable for IPV4HeaderView<'_> {
-    type MutableType = IPV4Header;
+impl FromStr for IpV4Addr {
+    type Err = AddrParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(Ipv4Addr::from_str(s)?.to_bits()))
+    }
+}
 
-    fn to_mutable(&self) -> Self::MutableType {
-        IPV4Header {
-            version: self.get_version(),
-            ihl: self.get_ihl(),
-            dscp: self.get_dscp(),
-            ecn: self.get_ecn(),
-            total_length: self.get_total_length(),
-            identification: self.get_identification(),
-            flags: self.get_flags(),
-            fragment_offset: self.get_fragment_offset(),
-            ttl: self.get_ttl(),
-            protocol: self.get_protocol(),
-            header_checksum: self.get_header_checksum(),
-            source_address: self.get_source_address(),
-            destination_address: self.get_destination_address(),
-        }
+impl IpV4Addr {
+    /// Whether the address is a multicast group, in 224.0.0.0/4
+    pub fn is_multicast(&self) -> bool {
+        self.0 >> 28 == 0xE
     }
 }
 
-impl<'a> From<&'a [u8]> for IPV4HeaderView<'a> {
-    fn from(value: &'a [u8]) -> Self {
-        let ihl = value[0] & 0xF;
-        Self {
-            content: &value[..ihl as usize * 4],
-        }
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
+pub struct IpV6Addr(pub u128);
+
+impl From<u128> for IpV6Addr {
+    fn from(value: u128) -> Self {
+        Self(value)
     }
 }
-impl<'a> AsRef<[u8]> for IPV4HeaderView<'a> {
-    fn as_ref(&self) -> &[u8] {
-        self.content
+
+impl From<IpV6Addr> for u128 {
+    fn from(value: IpV6Addr) -> Self {
+        value.0
+    }
+}
+
+impl FromStr for IpV6Addr {
+    type Err = AddrParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(Ipv6Addr::from_str(s)?.to_bits()))
     }
 }
 
-impl Data for IPV4HeaderView<'_> {
-    fn size(&self) -> usize {
-        self.content.len()
+impl Debug for IpV6Addr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", Ipv6Addr::from_bits(self.0))
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+impl Display for IpV6Addr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// An IPv4 header, whose options are skipped: the view covers them, as given by `ihl`, but
+/// they are neither parsed nor written back, the owned header being prepared without them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, PacketHeader)]
+#[packet_header(length = header_length, prepare = drop_options)]
 pub struct IPV4Header {
+    #[bits(4)]
     pub version: u8,
+    #[bits(4)]
     pub ihl: u8,
+    #[bits(6)]
     pub dscp: u8,
+    #[bits(2)]
     pub ecn: u8,
     pub total_length: u16,
     pub identification: u16,
+    #[bits(3)]
     pub flags: u8,
+    #[bits(13)]
     pub fragment_offset: u16,
     pub ttl: u8,
+    #[bits(8)]
     pub protocol: IpProtocol,
     pub header_checksum: u16,
+    #[bits(32)]
     pub source_address: IpV4Addr,
+    #[bits(32)]
     pub destination_address: IpV4Addr,
 }
 
-impl Prepare for IPV4Header {}
-impl WriteTo for IPV4Header {
-    fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
-        writer.write_all(&[self.version << 4 | self.ihl, self.dscp << 2 | self.ecn])?;
-        writer.write_all(&u16::to_be_bytes(self.total_length))?;
-        writer.write_all(&u16::to_be_bytes(self.identification))?;
-        writer.write_all(&[
-            self.flags << 5 | (self.fragment_offset >> 8) as u8,
-            (self.fragment_offset & 0xFF) as u8,
-            self.ttl,
-            self.protocol as u8,
-        ])?;
-        writer.write_all(&u16::to_be_bytes(self.header_checksum))?;
-        writer.write_all(&u32::to_be_bytes(self.source_address.0))?;
-        writer.write_all(&u32::to_be_bytes(self.destination_address.0))?;
-        Ok(self.size())
-    }
-}
-
-impl Data for IPV4Header {
-    fn size(&self) -> usize {
-        self.ihl as usize * 4
-    }
-}
-
 impl<'a> IPV4HeaderView<'a> {
     pub fn as_bytes(&self) -> &[u8] {
         self.content
     }
-    pub fn get_version(&self) -> u8 {
-        self.content[0] >> 4
-    }
-    pub fn get_ihl(&self) -> u8 {
-        self.content[0] & 0xF
-    }
-    pub fn get_dscp(&self) -> u8 {
-        self.content[1] >> 2
-    }
-    pub fn get_ecn(&self) -> u8 {
-        self.content[1] & 0b111
-    }
-    pub fn get_total_length(&self) -> u16 {
-        u16::from_be_bytes(*unsafe { self.content[2..=3].as_array_unchecked() })
-    }
-    pub fn get_identification(&self) -> u16 {
-        u16::from_be_bytes(*unsafe { self.content[4..=5].as_array_unchecked() })
-    }
-    pub fn get_flags(&self) -> u8 {
-        self.content[6] >> 5
-    }
-    pub fn get_fragment_offset(&self) -> u16 {
-        u16::from_be((((self.content[6] & 0x1F) as u16) << 8) + self.content[7] as u16)
-    }
-    pub fn get_ttl(&self) -> u8 {
-        self.content[8]
-    }
-    pub fn get_protocol(&self) -> IpProtocol {
-        unsafe { core::mem::transmute::<u8, IpProtocol>(self.content[9]) }
-    }
-    pub fn get_header_checksum(&self) -> u16 {
-        u16::from_be_bytes(*unsafe { self.content[10..=11].as_array_unchecked() })
-    }
-    pub fn get_source_address(&self) -> IpV4Addr {
-        u32::from_be_bytes(*unsafe { self.content[12..=15].as_array_unchecked() }).into()
-    }
-    pub fn get_destination_address(&self) -> IpV4Addr {
-        u32::from_be_bytes(*unsafe { self.content[16..=19].as_array_unchecked() }).into()
+    /// Length of the header with its options
+    fn header_length(&self) -> usize {
+        self.get_ihl() as usize * 4
     }
 }
 
@@ -184,27 +214,28 @@ impl Display for IpV4Addr {
     }
 }
 
-impl Debug for IPV4HeaderView<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("IPV4HeaderView")
-            .field("version", &self.get_version())
-            .field("ihl", &self.get_ihl())
-            .field("dscp", &self.get_dscp())
-            .field("ecn", &self.get_ecn())
-            .field("total_length", &self.get_total_length())
-            .field("identification", &self.get_identification())
-            .field("flags", &self.get_flags())
-            .field("fragment_offset", &self.get_fragment_offset())
-            .field("ttl", &self.get_ttl())
-            .field("protocol", &self.get_protocol())
-            .field("header_checksum", &self.get_header_checksum())
-            .field("source_address", &self.get_source_address())
-            .field("destination_address", &self.get_destination_address())
-            .finish()
+impl IPV4Header {
+    pub fn new(
+        protocol: IpProtocol,
+        source_address: IpV4Addr,
+        destination_address: IpV4Addr,
+    ) -> Self {
+        Self {
+            version: 4,
+            ihl: 5,
+            ttl: DEFAULT_TTL,
+            protocol,
+            source_address,
+            destination_address,
+            ..Default::default()
+        }
+    }
+
+    /// Announce the fixed fields only, the options of a parsed header not being written back
+    fn drop_options(&mut self) {
+        self.ihl = 5;
     }
-}
 
-impl IPV4Header {
     pub fn compute_checksum(&self) -> Checksum {
         Checksum::new().add_2bytes(
             (((self.version as u16) << 12)
@@ -217,7 +248,7 @@ impl IPV4Header {
             + ((self.flags as u16) << 13)
             + self.fragment_offset
             + ((self.ttl as u16) << 8)
-            + self.protocol as u16
+            + u8::from(self.protocol) as u16
             + self.header_checksum
             + (self.source_address.0 >> 16) as u16
             + (self.source_address.0 & 0xFFFF) as u16
@@ -235,11 +266,26 @@ impl IPV4Header {
     }
 
     pub fn prepare_ip_header(&mut self, total_length: usize) {
+        self.drop_options();
         self.total_length = total_length as u16;
         self.set_checksum();
     }
 }
 
+/// Decrement the TTL of a serialized IPv4 packet, updating its checksum incrementally (RFC 1624).
+///
+/// Returns the new TTL, the packet should be dropped if it is 0.
+pub fn decrement_ttl(packet: &mut [u8]) -> u8 {
+    let old_word = u16::from_be_bytes([packet[8], packet[9]]);
+    packet[8] = packet[8].saturating_sub(1);
+    let new_word = u16::from_be_bytes([packet[8], packet[9]]);
+
+    let checksum = Checksum::from_field(u16::from_be_bytes([packet[10], packet[11]]))
+        .update(old_word, new_word);
+    packet[10..12].copy_from_slice(&checksum.ones_complement().to_be_bytes());
+    packet[8]
+}
+
 pub type IPV4Packet<C = Vec<u8>> = Packet<IPV4Header, C>;
 pub type IPV4PacketView<'a, C = &'a [u8]> = PacketView<'a, IPV4HeaderView<'a>, C>;
 
@@ -250,3 +296,161 @@ impl<C: DataOwned> Prepare for IPV4Packet<C> {
         self.header.prepare_ip_header(self.size());
     }
 }
+
+/// Transport packets whose checksum covers all their content, filled in when written over IPv4
+pub trait TransportChecksum: DataOwned {
+    /// Offset of the checksum field in the transport header
+    const CHECKSUM_OFFSET: usize;
+    /// Whether the IPv4 pseudo header is part of the sum, as for TCP and UDP but not ICMP
+    const PSEUDO_HEADER: bool = true;
+
+    fn set_checksum(&mut self, checksum: u16);
+
+    /// Value of the checksum field for a computed checksum
+    fn checksum_field(checksum: Checksum) -> u16 {
+        checksum.ones_complement()
+    }
+}
+
+impl<P: TransportChecksum> IPV4Packet<P> {
+    fn pseudo_header_checksum(&self) -> Checksum {
+        if !P::PSEUDO_HEADER {
+            return Checksum::new();
+        }
+        Checksum::new()
+            .add_4bytes(self.header.source_address.0.to_be_bytes())
+            .add_4bytes(self.header.destination_address.0.to_be_bytes())
+            .add_byte(self.header.protocol.into())
+            .add_2bytes((self.payload.size() as u16).to_be_bytes())
+    }
+}
+
+thread_local! {
+    /// Buffers the transport packets written to a writer are built in, see `write_to`
+    static BUFFERS: RefCell<BufferPool> = RefCell::default();
+}
+
+impl<P: TransportChecksum> WriteTo for IPV4Packet<P> {
+    /// The checksum is written before the data it covers: the packet is summed while written to a
+    /// pooled buffer, copied to the writer afterwards
+    fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        // The sizes of the headers are only known once prepared
+        self.prepare();
+        let size = self.size();
+        let mut buffer = BUFFERS.with_borrow_mut(BufferPool::take);
+        // The packet fills the headroom, a buffer of its own being allocated if it is too large
+        buffer.reset(size);
+        if buffer.headroom() < size {
+            buffer = PacketBuffer::new(size, size);
+        }
+        let written = self
+            .write_to_buffer(buffer.prepend(size)?)
+            .and_then(|written| {
+                writer.write_all(&buffer.as_slice()[..written])?;
+                Ok(written)
+            });
+        BUFFERS.with_borrow_mut(|pool| pool.give_back(buffer));
+        written
+    }
+
+    /// Sum the transport packet while writing it, then fill its checksum in
+    fn write_to_buffer(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.prepare();
+        self.payload.set_checksum(0);
+        let header_size = self.header.write_to_inner(&mut &mut buffer[..])?;
+        let mut writer = ChecksumWriter::new(&mut buffer[header_size..]);
+        let payload_size = self.payload.write_to_inner(&mut writer)?;
+        let checksum = P::checksum_field(
+            writer
+                .checksum()
+                .add_checksum(self.pseudo_header_checksum()),
+        );
+        self.payload.set_checksum(checksum);
+        let offset = header_size + P::CHECKSUM_OFFSET;
+        buffer[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+        Ok(header_size + payload_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        traits::ToMutable,
+        udp::{UDPHeader, UDPPacket},
+    };
+
+    #[test]
+    fn header_view_parsed_with_its_options() {
+        let mut header = IPV4Header::new(
+            IpProtocol::Tcp,
+            IpV4Addr(0x0A00_0001),
+            IpV4Addr(0x0A00_0002),
+        );
+        header.prepare();
+        let mut bytes = header.to_bytes().unwrap();
+        assert_eq!(bytes.len(), IPV4HeaderView::SIZE);
+        for len in 0..bytes.len() {
+            assert!(IPV4HeaderView::try_from(&bytes[..len]).is_err());
+        }
+        let view = IPV4HeaderView::try_from(&bytes[..]).unwrap();
+        assert_eq!((view.get_version(), view.get_ihl()), (4, 5));
+        assert_eq!(view.get_protocol(), IpProtocol::Tcp);
+        assert_eq!(view.get_destination_address(), IpV4Addr(0x0A00_0002));
+        assert_eq!(view.as_bytes().len(), IPV4HeaderView::SIZE);
+
+        // Options announced but missing, then present
+        bytes[0] = 0x46;
+        assert!(IPV4HeaderView::parse(&bytes).is_err());
+        bytes.extend([1, 1, 1, 0, 0xFF]);
+        let view = IPV4HeaderView::parse(&bytes).unwrap();
+        assert_eq!(view.size(), 24);
+        assert_eq!(&view.as_bytes()[20..], [1, 1, 1, 0]);
+        // The options are left out of the owned header, which announces none
+        let mut header = view.to_mutable();
+        header.prepare_ip_header(IPV4HeaderView::SIZE);
+        let written = header.to_bytes().unwrap();
+        let view = IPV4HeaderView::parse(&written).unwrap();
+        assert_eq!((view.get_ihl(), view.size()), (5, IPV4HeaderView::SIZE));
+        assert_eq!(Checksum::new().add_slice(&written).ones_complement(), 0);
+        assert_eq!(written[12..], bytes[12..20]);
+        // A header shorter than its fixed fields
+        bytes[0] = 0x44;
+        assert!(IPV4HeaderView::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn transport_checksum_written_in_one_pass() {
+        // The last one does not fit in a pooled buffer
+        for len in [0, 1, 2, 3, 1471, 4000] {
+            let header = UDPHeader {
+                source_port: 1234,
+                destination_port: 53,
+                ..Default::default()
+            };
+            let mut packet = IPV4Packet::new(
+                IPV4Header::new(
+                    IpProtocol::Udp,
+                    IpV4Addr(0x0A00_0001),
+                    IpV4Addr(0x0A00_0002),
+                ),
+                UDPPacket::new(header, (0..len).map(|i| i as u8).collect::<Vec<_>>()),
+            );
+            let bytes = packet.to_bytes().unwrap();
+            let mut buffer = vec![0; bytes.len()];
+            let size = packet.write_to_buffer(&mut buffer).unwrap();
+            assert_eq!(&buffer[..size], bytes);
+
+            let pseudo_header = Checksum::new()
+                .add_slice(&bytes[12..20])
+                .add_byte(bytes[9])
+                .add_2bytes((bytes.len() as u16 - 20).to_be_bytes());
+            assert_eq!(Checksum::new().add_slice(&bytes[..20]).ones_complement(), 0);
+            assert_eq!(
+                pseudo_header.add_slice(&bytes[20..]).ones_complement(),
+                0,
+                "{len}"
+            );
+        }
+    }
+}
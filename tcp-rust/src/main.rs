@@ -1,60 +1,724 @@
 #![allow(incomplete_features)]
-#![feature(specialization, slice_split_once)]
+#![feature(specialization)]
+#![cfg_attr(test, feature(test))]
 
+pub mod arp;
+#[cfg(test)]
+mod bench;
+pub mod buffer;
 pub mod checksum;
+pub mod config;
+pub mod dhcp;
+pub mod dissect;
+pub mod dns;
+pub mod ethernet;
+pub mod firewall;
 pub mod http;
 pub mod icmp;
+pub mod igmp;
+pub mod impairment;
 pub mod interface;
 pub mod ip;
+pub mod metrics;
+pub mod nat;
 pub mod packet;
+pub mod packet_socket;
+pub mod pcap;
+pub mod probe;
+pub mod route;
+pub mod runtime;
+pub mod sctp;
 pub mod tcp;
+#[cfg(test)]
+mod test_support;
+pub mod tls;
 pub mod traits;
 pub mod tun_tap;
+pub mod tunnel;
+pub mod udp;
+pub mod worker;
 
-use std::io;
+use std::{
+    io::{self, Read},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rustls::ServerConfig;
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+use tracing_subscriber::EnvFilter;
 
 use crate::{
-    icmp::ICMPPacketView,
-    interface::Interface,
-    ip::{IPV4HeaderView, IPV4PacketView, IpProtocol},
-    tcp::manager::TCPManager,
-    traits::ToMutable,
+    arp::ARPManager,
+    buffer::BufferPool,
+    dhcp::client::DHCPClient,
+    dissect::{LinkType, dissect, format_timestamp},
+    dns::{server::DNSServer, zone::Zone},
+    ethernet::{ETHERTYPE_ARP, ETHERTYPE_IPV6},
+    firewall::{Action, Firewall},
+    http::websocket,
+    icmp::{ECHO_REQUEST, ICMPPacketView},
+    impairment::{Direction, Impaired, ImpairmentHandle},
+    interface::{DEFAULT_PROTOCOLS, Device, Interface, InterfaceConfig, poll_interfaces},
+    ip::{IPV4HeaderView, IPV4PacketView, IpProtocol, IpV6Addr},
+    nat::Nat,
+    packet_socket::PacketSocket,
+    pcap::PcapReader,
+    route::{Route, RoutingTable},
+    runtime::{AsyncInterface, Stack, TCPListener},
+    sctp::manager::SCTPManager,
+    tcp::manager::{TCPConfig, TCPManager},
+    tunnel::Tunnel,
+    udp::manager::UDPManager,
+    worker::Worker,
 };
 
-fn main() -> io::Result<()> {
-    let mut interface =
-        Interface::new(tun_tap::Interface::new("tun%d", tun_tap::Mode::Tun).unwrap());
-    let mut tcp_manager = TCPManager::new();
+/// Maximum time spent waiting for a packet before running the timers
+const TICK: Duration = Duration::from_millis(100);
+/// Port of the echo service of `--async`
+const ECHO_PORT: u16 = 7;
+
+/// Print the packets of a pcap file, or those sent to a TUN/TAP device, one line each like
+/// tcpdump. `-v` adds the fields of each layer and a hex dump.
+fn sniff(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    let mut verbose = false;
+    let mut mode = tun_tap::Mode::Tun;
+    let mut name = None;
+    let mut file = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-v" | "--verbose" => verbose = true,
+            "--tap" => mode = tun_tap::Mode::Tap,
+            "--interface" => {
+                name = Some(
+                    args.next()
+                        .ok_or_else(|| invalid("missing interface name"))?,
+                )
+            }
+            _ => file = Some(arg),
+        }
+    }
 
+    if let Some(path) = file {
+        let pcap = PcapReader::open(path)?;
+        let link_type = LinkType::from_pcap(pcap.link_type)
+            .ok_or_else(|| invalid("unsupported pcap link type"))?;
+        for record in pcap {
+            let record = record?;
+            println!(
+                "{} {}",
+                format_timestamp(record.timestamp),
+                dissect(link_type, &record.data, verbose)
+            );
+        }
+        return Ok(());
+    }
+
+    let default_name = match mode {
+        tun_tap::Mode::Tun => "tun%d",
+        tun_tap::Mode::Tap => "tap%d",
+    };
+    let mut interface = tun_tap::Interface::new(name.as_deref().unwrap_or(default_name), mode)?;
+    println!("listening on {}", interface.name);
+    let mut buffer = [0; 65536];
     loop {
-        interface.receive();
+        let nbytes = interface.read(&mut buffer)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        println!(
+            "{} {}",
+            format_timestamp(timestamp),
+            dissect(LinkType::Device(mode), &buffer[..nbytes], verbose)
+        );
+    }
+}
 
-        if !interface.is_ip() {
-            // Not an IP packet
-            println!("Not an IP Packet: {}", interface.get_proto());
-            continue;
+/// Run the stack on tokio, its echo services being tasks reading and writing sockets, and HTTPS
+/// being served on port 443 with a TLS configuration
+fn run_async(
+    interface: Interface<tun_tap::Interface>,
+    config: TCPConfig,
+    ports: Vec<u16>,
+    tls_config: Option<Arc<ServerConfig>>,
+) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let stack = Stack::new(config);
+        stack.set_ports(ports);
+        let listener = TCPListener::bind(&stack, ECHO_PORT)?;
+        if let Some(tls_config) = tls_config {
+            let https_listener = TCPListener::bind(&stack, tls::HTTPS_PORT)?;
+            tokio::spawn(tls::serve(https_listener, tls_config));
+        }
+        let websocket_listener = TCPListener::bind(&stack, websocket::WEBSOCKET_PORT)?;
+        tokio::spawn(websocket::serve(websocket_listener, Some));
+        let mut running = tokio::spawn(stack.run(AsyncInterface::new(interface)?));
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = accepted?;
+                    debug!(%peer, "echo connection");
+                    tokio::spawn(async move {
+                        let (mut reader, mut writer) = tokio::io::split(stream);
+                        if let Err(err) = tokio::io::copy(&mut reader, &mut writer).await {
+                            debug!("echo connection failed: {err}");
+                        }
+                        let _ = writer.shutdown().await;
+                    });
+                }
+                result = &mut running => return result.map_err(io::Error::other)?,
+            }
         }
+    })
+}
 
-        if interface.get_ip_protocol() == IpProtocol::Icmp {
-            let ip_packet = interface.get_packet::<IPV4PacketView<ICMPPacketView>>();
-            let mut ip_response = ip_packet.to_mutable();
+fn main() -> io::Result<()> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
 
-            ip_response.header.answer();
+    // In TUN mode, the stack answers at 192.168.0.2/24 unless given an `--address`, and drops the
+    // packets to other hosts. In TAP mode, the stack gets its address from a DHCP server on the
+    // link.
+    // Each `--address` opens one more interface, and `--route` enables forwarding between them.
+    // `--nat` translates the flows leaving by the given interface, `--forward` adds port forwards.
+    // `--firewall` filters the received packets with the rules of the given file.
+    // `--keepalive idle,interval,probes` (in seconds, or `off`) and `--syn-backlog` tune the TCP
+    // timers and the most half-open connections, `--nodelay` disables Nagle's algorithm.
+    // Each `--link` runs one interface on an existing link such as a veth, instead of a TUN
+    // device. `--mtu` and `--up` configure the devices, without having to use `ip link`.
+    // `--queues N` opens the device with N queues, each served by its own thread, see `worker`.
+    // `--async` runs the stack on tokio with an echo service on port 7, see `runtime`, and a
+    // WebSocket one on port 8080, see `http::websocket`, and `--tls cert.pem key.pem` serves
+    // HTTPS there on port 443, see `tls`.
+    // Each `--tunnel kind,local,remote,address[,id]` opens one more interface, an IP-in-IP, GRE
+    // or VXLAN tunnel whose outer packets are sent on the first one unless routed, see `tunnel`.
+    // `--interface` names the device of one more interface, `--mode tun` or `tap` selects the
+    // kind of the devices and `--no-packet-info` opens them without the packet information header.
+    // Each `--address6` gives the IPv6 address of one more interface, whose packets are dropped
+    // as the stack does not speak IPv6 yet, like those to other hosts. `--port` restricts the
+    // HTTP server to the given ports, `--protocols icmp,igmp,sctp,tcp,udp` selects the answered
+    // protocols. `--log-level` selects the logged events like `RUST_LOG`, `info` by default. `--config` reads options from a file, see `config`, and `--zone` gives
+    // the zone file served over DNS. `--dns` gives the server the stack resolves names with,
+    // learned through DHCP in TAP mode.
+    // `--impair in|out|both,loss=0.01,latency=50,...` impairs the traffic of the devices in one
+    // or both directions, see `impairment`.
+    // `sniff` prints packets instead of answering them, see `sniff`, and `ping` and `traceroute`
+    // send probes from the stack, see `probe`.
+    let mut mode = tun_tap::Mode::Tun;
+    let mut names = vec![];
+    let mut packet_info = true;
+    let mut configs = vec![];
+    let mut addresses6 = vec![];
+    let mut ports = vec![];
+    let mut protocols = None;
+    let mut log_level = None;
+    let mut links = vec![];
+    let mut tunnels = vec![];
+    let mut mtu = None;
+    let mut up = false;
+    let mut queues = 1;
+    let mut asynchronous = false;
+    let mut tls_config = None;
+    let mut routing_table = RoutingTable::new();
+    let mut nat: Option<Nat> = None;
+    let mut forwards = vec![];
+    let mut firewall = None;
+    let mut tcp_config = TCPConfig::default();
+    let mut zone_file = None;
+    let mut dns = None;
+    let mut impairments = None;
+    let mut args = config::expand(std::env::args().skip(1))?
+        .into_iter()
+        .peekable();
+    if args.next_if_eq("sniff").is_some() {
+        return sniff(args);
+    }
+    if args.next_if_eq("ping").is_some() {
+        return probe::ping(args);
+    }
+    if args.next_if_eq("traceroute").is_some() {
+        return probe::traceroute(args);
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tap" => mode = tun_tap::Mode::Tap,
+            "--mode" => {
+                mode = match args.next().as_deref() {
+                    Some("tun") => tun_tap::Mode::Tun,
+                    Some("tap") => tun_tap::Mode::Tap,
+                    _ => return Err(invalid("invalid mode")),
+                }
+            }
+            "--interface" => names.push(
+                args.next()
+                    .ok_or_else(|| invalid("missing interface name"))?,
+            ),
+            "--no-packet-info" => packet_info = false,
+            "--address6" => addresses6.push(
+                args.next()
+                    .and_then(|address| address.parse::<IpV6Addr>().ok())
+                    .ok_or_else(|| invalid("invalid IPv6 address"))?,
+            ),
+            "--port" => ports.push(
+                args.next()
+                    .and_then(|port| port.parse().ok())
+                    .ok_or_else(|| invalid("invalid port"))?,
+            ),
+            "--protocols" => {
+                protocols = Some(
+                    args.next()
+                        .and_then(|protocols| {
+                            protocols
+                                .split(',')
+                                .map(|protocol| protocol.parse().ok())
+                                .collect::<Option<Vec<IpProtocol>>>()
+                        })
+                        .ok_or_else(|| invalid("invalid protocols"))?,
+                )
+            }
+            "--log-level" => {
+                log_level = Some(args.next().ok_or_else(|| invalid("missing log level"))?)
+            }
+            "--impair" => {
+                let spec = args.next().unwrap_or_default();
+                let (directions, spec) = spec.split_once(',').unwrap_or((&spec, ""));
+                let directions: &[Direction] = match directions {
+                    "in" => &[Direction::Incoming],
+                    "out" => &[Direction::Outgoing],
+                    "both" => &[Direction::Incoming, Direction::Outgoing],
+                    _ => return Err(invalid("invalid impairment direction")),
+                };
+                let spec = spec.parse().map_err(|_| invalid("invalid impairments"))?;
+                let handle = impairments.get_or_insert_with(ImpairmentHandle::new);
+                for direction in directions {
+                    handle.set(*direction, spec);
+                }
+            }
+            "--dns" => {
+                dns = Some(
+                    args.next()
+                        .and_then(|address| address.parse().ok())
+                        .ok_or_else(|| invalid("invalid DNS server"))?,
+                )
+            }
+            "--zone" => zone_file = Some(args.next().ok_or_else(|| invalid("missing zone file"))?),
+            "--address" => configs.push(
+                args.next()
+                    .as_deref()
+                    .and_then(InterfaceConfig::parse)
+                    .ok_or_else(|| invalid("invalid address"))?,
+            ),
+            "--route" => routing_table.add(
+                args.next()
+                    .and_then(|route| route.parse().ok())
+                    .ok_or_else(|| invalid("invalid route"))?,
+            ),
+            "--nat" => {
+                nat = Some(Nat::new(
+                    args.next()
+                        .and_then(|interface| interface.parse().ok())
+                        .ok_or_else(|| invalid("invalid NAT interface"))?,
+                ))
+            }
+            "--firewall" => {
+                firewall = Some(Firewall::load(
+                    args.next()
+                        .ok_or_else(|| invalid("missing firewall rules"))?,
+                )?)
+            }
+            "--forward" => forwards.push(
+                args.next()
+                    .and_then(|forward| forward.parse().ok())
+                    .ok_or_else(|| invalid("invalid port forward"))?,
+            ),
+            "--keepalive" => {
+                tcp_config.keepalive = match args.next().as_deref() {
+                    Some("off") => None,
+                    keepalive => Some(
+                        keepalive
+                            .and_then(|keepalive| keepalive.parse().ok())
+                            .ok_or_else(|| invalid("invalid keepalive"))?,
+                    ),
+                }
+            }
+            "--nodelay" => tcp_config.no_delay = true,
+            "--link" => {
+                links.push(args.next().ok_or_else(|| invalid("missing link name"))?);
+                mode = tun_tap::Mode::Tap;
+            }
+            // The interface buffers hold frames of at most 1500 bytes of payload
+            "--mtu" => {
+                mtu = Some(
+                    args.next()
+                        .and_then(|mtu| mtu.parse().ok())
+                        .filter(|mtu| (68..=1500).contains(mtu))
+                        .ok_or_else(|| invalid("invalid MTU"))?,
+                )
+            }
+            "--tunnel" => tunnels.push(
+                args.next()
+                    .and_then(|tunnel| tunnel.parse().ok())
+                    .ok_or_else(|| invalid("invalid tunnel"))?,
+            ),
+            "--up" => up = true,
+            "--queues" => {
+                queues = args
+                    .next()
+                    .and_then(|queues| queues.parse().ok())
+                    .filter(|queues| *queues >= 1)
+                    .ok_or_else(|| invalid("invalid number of queues"))?
+            }
+            "--async" => asynchronous = true,
+            "--tls" => {
+                let certificates = args.next().ok_or_else(|| invalid("missing certificate"))?;
+                let key = args.next().ok_or_else(|| invalid("missing private key"))?;
+                tls_config = Some(tls::load_config(certificates, key)?);
+                asynchronous = true;
+            }
+            "--syn-backlog" => {
+                tcp_config.max_half_open = args
+                    .next()
+                    .and_then(|backlog| backlog.parse().ok())
+                    .ok_or_else(|| invalid("invalid SYN backlog"))?
+            }
+            option if option.starts_with("--") => {
+                return Err(invalid(&format!("unknown option {option}")));
+            }
+            // A positional argument is the zone file, like `--zone` gives it
+            _ => zone_file = Some(arg),
+        }
+    }
+    // `RUST_LOG` selects the logged events unless `--log-level` does, `info` by default
+    let filter = match log_level {
+        Some(level) => EnvFilter::try_new(level).map_err(|_| invalid("invalid log level"))?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    tracing_subscriber::fmt().with_env_filter(filter).init();
 
-            ip_response.payload.header.message_type = 0;
-            ip_response.payload.header.code = 0;
+    let forwarding = configs.len() > 1 || !routing_table.routes().is_empty();
+    if let Some(nat) = &mut nat {
+        if nat.external_interface >= configs.len().max(1) {
+            return Err(invalid("invalid NAT interface"));
+        }
+        nat.forwards = forwards;
+    } else if !forwards.is_empty() {
+        return Err(invalid("port forwards require --nat"));
+    }
+    // The interfaces are those of the addresses, or the default one, then those of the tunnels
+    let interface_count = configs.len().max(1) + tunnels.len();
+    if let Some(route) = routing_table
+        .routes()
+        .iter()
+        .find(|route| route.interface >= interface_count)
+    {
+        return Err(invalid(&format!(
+            "no interface {} for route {route}",
+            route.interface
+        )));
+    }
+    if configs.is_empty() {
+        configs.push(match mode {
+            tun_tap::Mode::Tun => {
+                InterfaceConfig::parse(&format!("{}/24", InterfaceConfig::DEFAULT_ADDRESS)).unwrap()
+            }
+            // The address comes from DHCP
+            tun_tap::Mode::Tap => InterfaceConfig {
+                mac: InterfaceConfig::DEFAULT_MAC,
+                ..Default::default()
+            },
+        });
+    }
+    if addresses6.len() > configs.len() {
+        return Err(invalid("each --address6 needs its interface"));
+    }
+    for (config, address6) in configs.iter_mut().zip(addresses6) {
+        config.address6 = Some(address6);
+    }
+    let protocols = protocols.unwrap_or_else(|| DEFAULT_PROTOCOLS.to_vec());
+    if impairments.is_some() && (asynchronous || queues > 1) {
+        return Err(invalid("--impair runs the devices of a single thread"));
+    }
 
-            interface.write(ip_response);
+    let default_name = match mode {
+        tun_tap::Mode::Tun => "tun%d",
+        tun_tap::Mode::Tap => "tap%d",
+    };
+    let name = |i: usize| names.get(i).map_or(default_name, String::as_str);
+    let open = |name: &str| match packet_info {
+        true => tun_tap::Interface::new(name, mode),
+        false => tun_tap::Interface::without_packet_info(name, mode),
+    };
+    if !links.is_empty() && links.len() != configs.len() {
+        return Err(invalid("each --link needs its --address"));
+    }
+    if !links.is_empty() && !names.is_empty() {
+        return Err(invalid("--link runs on an existing link, named by itself"));
+    }
+    let configure = |name: &str| -> io::Result<()> {
+        if let Some(mtu) = mtu {
+            tun_tap::set_mtu(name, mtu)?;
+        }
+        if up {
+            tun_tap::set_up(name, true)?;
+        }
+        Ok(())
+    };
 
-            interface.send();
-            println!("answered an echo packet");
-        } else if interface.get_ip_protocol() == IpProtocol::Tcp {
-            tcp_manager.handle_tcp_packet(&mut interface);
-        } else {
-            println!(
-                "received a non ICMP packet, protocol {:?}",
-                interface.get_packet::<IPV4HeaderView>().get_protocol()
-            );
+    if asynchronous {
+        if forwarding
+            || nat.is_some()
+            || firewall.is_some()
+            || !links.is_empty()
+            || !tunnels.is_empty()
+            || queues > 1
+        {
+            return Err(invalid(
+                "--async runs a single TUN/TAP device without forwarding",
+            ));
+        }
+        let device = open(name(0))?;
+        configure(&device.name)?;
+        let mut interface = Interface::with_mode(device, mode);
+        interface.config = configs[0];
+        interface.protocols = protocols;
+        if let Some(mtu) = mtu {
+            interface.mtu = mtu as usize;
+        }
+        return run_async(interface, tcp_config, ports, tls_config);
+    }
+
+    // The workers only serve the stack's own address, there is nothing to share between them
+    if queues > 1 {
+        if forwarding
+            || nat.is_some()
+            || firewall.is_some()
+            || !links.is_empty()
+            || !tunnels.is_empty()
+        {
+            return Err(invalid(
+                "--queues runs a single TUN/TAP device without forwarding",
+            ));
+        }
+        if mode == tun_tap::Mode::Tap && configs[0].address.is_none() {
+            return Err(invalid("--queues needs an --address in TAP mode"));
+        }
+        if !packet_info {
+            return Err(invalid("--queues opens the device with packet information"));
+        }
+        let devices = tun_tap::Interface::multi_queue(name(0), mode, queues)?;
+        configure(&devices[0].name)?;
+        let interfaces = devices
+            .into_iter()
+            .map(|device| {
+                let mut interface = Interface::with_mode(device, mode);
+                interface.config = configs[0];
+                interface.protocols = protocols.clone();
+                if let Some(mtu) = mtu {
+                    interface.mtu = mtu as usize;
+                }
+                interface
+            })
+            .collect();
+        let dns_server = zone_file.map(Zone::load).transpose()?.map(DNSServer::new);
+        let mut workers = Worker::new_group(interfaces)?;
+        for worker in &mut workers {
+            worker.tcp_manager.config = tcp_config;
+            worker.tcp_manager.ports = ports.clone();
+            worker.udp_manager.dns_server = dns_server.clone();
+            worker.udp_manager.resolver.server = dns;
+        }
+        return worker::run(workers);
+    }
+
+    let mut interfaces = vec![];
+    for (i, config) in configs.into_iter().enumerate() {
+        let device = match links.get(i) {
+            // A packet socket bound to a link which is down fails its first read
+            Some(link) => {
+                configure(link)?;
+                Device::Packet(PacketSocket::new(link)?)
+            }
+            None => {
+                let device = open(name(i))?;
+                configure(&device.name)?;
+                Device::TunTap(device)
+            }
+        };
+        let device = match &impairments {
+            Some(handle) => Device::Impaired(Box::new(Impaired::pollable(device, handle.clone())?)),
+            None => device,
+        };
+        let mut interface = Interface::with_mode(device, mode);
+        interface.config = config;
+        interface.protocols = protocols.clone();
+        if let Some(mtu) = mtu {
+            interface.mtu = mtu as usize;
+        }
+        if let Some(route) = Route::connected(&config, i) {
+            routing_table.add(route);
+        }
+        interfaces.push(interface);
+    }
+    let tunnels = tunnels
+        .into_iter()
+        .map(|config: tunnel::TunnelConfig| {
+            let index = interfaces.len();
+            let (tunnel, device) = Tunnel::open(config, index)?;
+            let mut interface = Interface::with_mode(Device::Tunnel(device), config.kind.mode());
+            interface.config = config.interface;
+            interface.protocols = protocols.clone();
+            interface.mtu = interfaces[0].mtu - config.kind.overhead();
+            if let Some(route) = Route::connected(&config.interface, index) {
+                routing_table.add(route);
+            }
+            interfaces.push(interface);
+            Ok(tunnel)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut arp_manager = ARPManager::new();
+    let mut buffer_pool = BufferPool::new();
+    let mut tcp_manager = TCPManager::new();
+    tcp_manager.config = tcp_config;
+    tcp_manager.ports = ports;
+    let mut udp_manager = UDPManager::new();
+    udp_manager.resolver.server = dns;
+    // Without a listener, the SCTP associations echo the messages they receive, see `sctp`
+    let mut sctp_manager = SCTPManager::new();
+
+    // The remaining argument is an optional zone file to serve over DNS
+    if let Some(path) = zone_file {
+        udp_manager.dns_server = Some(DNSServer::new(Zone::load(path)?));
+    }
+    if mode == tun_tap::Mode::Tap && interfaces[0].config.address.is_none() {
+        udp_manager.dhcp_client = Some(DHCPClient::new());
+    }
+
+    loop {
+        udp_manager.poll(&mut interfaces[0]);
+        tcp_manager.poll(&mut interfaces);
+        sctp_manager.poll(&mut interfaces);
+        if let Some(nat) = &mut nat {
+            nat.expire();
+        }
+        if let Some(firewall) = &mut firewall {
+            firewall.expire();
+        }
+        for tunnel in &tunnels {
+            tunnel.transmit(&mut interfaces, &routing_table, &arp_manager);
+        }
+        let timer = [tcp_manager.next_timer(), sctp_manager.next_timer()]
+            .into_iter()
+            .flatten()
+            .min();
+        let timeout = timer.map_or(TICK, |timer| {
+            timer.saturating_duration_since(Instant::now()).min(TICK)
+        });
+        for i in poll_interfaces(&interfaces, timeout)? {
+            // An impaired device may have been woken up by a frame it is holding back
+            match interfaces[i].try_receive() {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+            if !interfaces[i].is_for_us() {
+                continue;
+            }
+
+            if interfaces[i].get_proto() == ETHERTYPE_ARP {
+                arp_manager.handle_arp_packet(&mut interfaces[i]);
+                continue;
+            }
+
+            if interfaces[i].get_proto() == ETHERTYPE_IPV6 {
+                // The stack does not speak IPv6 yet, its own packets are told apart but dropped
+                let destination = interfaces[i].get_ipv6_destination();
+                if destination.is_some()
+                    && interfaces
+                        .iter()
+                        .any(|interface| interface.config.address6 == destination)
+                {
+                    debug!(
+                        ?destination,
+                        "IPv6 packet for the stack dropped, IPv6 is not supported"
+                    );
+                } else {
+                    debug!(?destination, "IPv6 packet for another host dropped");
+                }
+                continue;
+            }
+            if !interfaces[i].is_ip() {
+                // Not an IP packet
+                debug!("Not an IP Packet: {}", interfaces[i].get_proto());
+                continue;
+            }
+
+            // Like netfilter, the rules see the internal addresses of translated flows
+            let external = nat.as_ref().map(|nat| nat.external_interface);
+            if let Some(nat) = &mut nat
+                && external == Some(i)
+            {
+                nat.translate(&mut interfaces, i, &routing_table);
+            }
+            if let Some(firewall) = &mut firewall
+                && firewall.filter(&mut interfaces[i]) != Action::Accept
+            {
+                continue;
+            }
+            if let Some(nat) = &mut nat
+                && external != Some(i)
+            {
+                nat.translate(&mut interfaces, i, &routing_table);
+            }
+            let Ok(header) = interfaces[i].try_get_packet::<IPV4HeaderView>() else {
+                continue;
+            };
+            let destination = header.get_destination_address();
+            if !interfaces
+                .iter()
+                .any(|interface| interface.config.accepts(destination))
+            {
+                if forwarding {
+                    routing_table.forward(&mut interfaces, i, &arp_manager);
+                } else {
+                    debug!(%destination, "packet for another host dropped");
+                }
+                continue;
+            }
+
+            let interface = &mut interfaces[i];
+            let protocol = interface.get_ip_protocol();
+            if tunnels.iter().any(|tunnel| tunnel.decapsulate(interface)) {
+                continue;
+            } else if !protocol.is_some_and(|protocol| interface.protocols.contains(&protocol)) {
+                debug!(?protocol, "packet of a disabled protocol dropped");
+            } else if protocol == Some(IpProtocol::Icmp) {
+                let Ok(ip_packet) = interface.try_get_packet::<IPV4PacketView<ICMPPacketView>>()
+                else {
+                    debug!("truncated ICMP message dropped");
+                    continue;
+                };
+                if ip_packet.payload.header.get_message_type() != ECHO_REQUEST {
+                    continue;
+                }
+                icmp::reply_to_echo(interface, &mut buffer_pool);
+                debug!("answered an echo packet");
+            } else if protocol == Some(IpProtocol::Tcp) {
+                tcp_manager.handle_tcp_packet(interface, i);
+            } else if protocol == Some(IpProtocol::Udp) {
+                udp_manager.handle_udp_packet(interface);
+            } else if protocol == Some(IpProtocol::Igmp) {
+                udp_manager.igmp.handle_packet(interface);
+            } else if protocol == Some(IpProtocol::Sctp) {
+                sctp_manager.handle_sctp_packet(interface, i);
+            } else {
+                debug!("received a non ICMP packet, protocol {protocol:?}");
+            }
         }
     }
 }
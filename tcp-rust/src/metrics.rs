@@ -0,0 +1,290 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::tcp::manager::TCPConnectionState;
+
+/// Metrics of the whole stack, updated where the events happen and served as `/metrics`
+pub static METRICS: Metrics = Metrics::new();
+
+/// Distribution of observed values, each bucket counting the values up to its bound
+#[derive(Debug)]
+pub struct Histogram<const N: usize> {
+    bounds: [u64; N],
+    buckets: [AtomicU64; N],
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    pub const fn new(bounds: [u64; N]) -> Self {
+        Self {
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; N],
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Write the histogram in the Prometheus text format, whose buckets are cumulative
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {}", self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub packets_received: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub packets_sent: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub tcp_segments_received: AtomicU64,
+    /// Segments received again, the peer retransmitting data we already acknowledged
+    pub tcp_duplicate_segments_received: AtomicU64,
+    /// Segments sent again, their acknowledgement having timed out
+    pub tcp_retransmits: AtomicU64,
+    pub tcp_connections_opened: AtomicU64,
+    /// Connections dropped by a timer, their peer having gone silent
+    pub tcp_timeouts: AtomicU64,
+    /// Connections in SynReceived dropped to make room for newer ones
+    pub tcp_half_open_dropped: AtomicU64,
+    /// Segments whose IP header carries the congestion experienced mark
+    pub tcp_ecn_ce_received: AtomicU64,
+    /// Open connections, indexed by `TCPConnectionState`
+    pub tcp_connections: [AtomicI64; TCPConnectionState::ALL.len()],
+    /// Round trip time between a segment and its acknowledgement, in microseconds
+    pub tcp_rtt: Histogram<10>,
+    /// Windows advertised by the peers, in bytes
+    pub tcp_window: Histogram<8>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            packets_received: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            packets_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            tcp_segments_received: AtomicU64::new(0),
+            tcp_duplicate_segments_received: AtomicU64::new(0),
+            tcp_retransmits: AtomicU64::new(0),
+            tcp_connections_opened: AtomicU64::new(0),
+            tcp_timeouts: AtomicU64::new(0),
+            tcp_half_open_dropped: AtomicU64::new(0),
+            tcp_ecn_ce_received: AtomicU64::new(0),
+            tcp_connections: [const { AtomicI64::new(0) }; TCPConnectionState::ALL.len()],
+            tcp_rtt: Histogram::new([
+                100, 250, 500, 1000, 2500, 5000, 10000, 50000, 100000, 1000000,
+            ]),
+            tcp_window: Histogram::new([0, 1024, 2048, 4096, 8192, 16384, 32768, 65535]),
+        }
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn observe_rtt(&self, rtt: Duration) {
+        self.tcp_rtt.observe(rtt.as_micros() as u64);
+    }
+
+    /// Move a connection from one state to another, `None` standing for no connection
+    pub fn connection_state(
+        &self,
+        from: Option<TCPConnectionState>,
+        to: Option<TCPConnectionState>,
+    ) {
+        if let Some(from) = from {
+            self.tcp_connections[from as usize].fetch_sub(1, Ordering::Relaxed);
+        }
+        if let Some(to) = to {
+            self.tcp_connections[to as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// All the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "tcp_rust_packets_received_total",
+                "Packets received on all interfaces",
+                &self.packets_received,
+            ),
+            (
+                "tcp_rust_bytes_received_total",
+                "Bytes received on all interfaces",
+                &self.bytes_received,
+            ),
+            (
+                "tcp_rust_packets_sent_total",
+                "Packets sent on all interfaces",
+                &self.packets_sent,
+            ),
+            (
+                "tcp_rust_bytes_sent_total",
+                "Bytes sent on all interfaces",
+                &self.bytes_sent,
+            ),
+            (
+                "tcp_rust_tcp_segments_received_total",
+                "TCP segments received",
+                &self.tcp_segments_received,
+            ),
+            (
+                "tcp_rust_tcp_duplicate_segments_received_total",
+                "TCP segments received again after being acknowledged",
+                &self.tcp_duplicate_segments_received,
+            ),
+            (
+                "tcp_rust_tcp_retransmits_total",
+                "TCP segments sent again after the retransmission timeout",
+                &self.tcp_retransmits,
+            ),
+            (
+                "tcp_rust_tcp_connections_opened_total",
+                "TCP connections opened",
+                &self.tcp_connections_opened,
+            ),
+            (
+                "tcp_rust_tcp_timeouts_total",
+                "TCP connections dropped after their peer went silent",
+                &self.tcp_timeouts,
+            ),
+            (
+                "tcp_rust_tcp_half_open_dropped_total",
+                "Half-open TCP connections dropped because the queue was full",
+                &self.tcp_half_open_dropped,
+            ),
+            (
+                "tcp_rust_tcp_ecn_ce_received_total",
+                "TCP segments received with the ECN congestion experienced mark",
+                &self.tcp_ecn_ce_received,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+
+        let name = "tcp_rust_tcp_connections";
+        let _ = writeln!(out, "# HELP {name} Open TCP connections by state");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        for state in TCPConnectionState::ALL {
+            let _ = writeln!(
+                out,
+                "{name}{{state=\"{}\"}} {}",
+                state.name(),
+                self.tcp_connections[state as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        self.tcp_rtt.render(
+            &mut out,
+            "tcp_rust_tcp_rtt_microseconds",
+            "Round trip time of TCP segments",
+        );
+        self.tcp_window.render(
+            &mut out,
+            "tcp_rust_tcp_window_bytes",
+            "Windows advertised by the TCP peers",
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_rendered_cumulative() {
+        let histogram = Histogram::new([10, 100]);
+        for value in [5, 10, 50, 1000] {
+            histogram.observe(value);
+        }
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "Latency");
+        assert_eq!(
+            out,
+            "# HELP latency Latency
+# TYPE latency histogram
+latency_bucket{le=\"10\"} 2
+latency_bucket{le=\"100\"} 3
+latency_bucket{le=\"+Inf\"} 4
+latency_sum 1065
+latency_count 4
+"
+        );
+    }
+
+    #[test]
+    fn counters_and_gauges_rendered() {
+        let metrics = Metrics::new();
+        metrics.received(100);
+        metrics.received(60);
+        metrics.sent(40);
+        metrics
+            .tcp_duplicate_segments_received
+            .fetch_add(3, Ordering::Relaxed);
+        metrics.connection_state(None, Some(TCPConnectionState::Listen));
+        metrics.connection_state(
+            Some(TCPConnectionState::Listen),
+            Some(TCPConnectionState::SynReceived),
+        );
+        metrics.connection_state(None, Some(TCPConnectionState::Established));
+        metrics.observe_rtt(Duration::from_millis(2));
+
+        let out = metrics.render();
+        for line in [
+            "tcp_rust_packets_received_total 2",
+            "tcp_rust_bytes_received_total 160",
+            "tcp_rust_packets_sent_total 1",
+            "tcp_rust_bytes_sent_total 40",
+            "# TYPE tcp_rust_tcp_duplicate_segments_received_total counter",
+            "tcp_rust_tcp_duplicate_segments_received_total 3",
+            "tcp_rust_tcp_connections{state=\"listen\"} 0",
+            "tcp_rust_tcp_connections{state=\"syn_received\"} 1",
+            "tcp_rust_tcp_connections{state=\"established\"} 1",
+            "tcp_rust_tcp_rtt_microseconds_bucket{le=\"1000\"} 0",
+            "tcp_rust_tcp_rtt_microseconds_bucket{le=\"2500\"} 1",
+            "tcp_rust_tcp_rtt_microseconds_sum 2000",
+        ] {
+            assert!(out.lines().any(|rendered| rendered == line), "{line}");
+        }
+    }
+}
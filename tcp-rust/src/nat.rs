@@ -0,0 +1,698 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{Read, Write},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use crate::{
+    checksum::Checksum,
+    icmp::{ECHO_REPLY, ECHO_REQUEST, is_icmp_error},
+    interface::Interface,
+    ip::{IPV4HeaderView, IpProtocol, IpV4Addr, TransportChecksum},
+    route::RoutingTable,
+    tcp::manager::Clock,
+    traits::Data,
+    udp::UDPPacket,
+};
+
+/// Ports handed out to translated flows
+const FIRST_PORT: u16 = 49152;
+
+const TCP_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+const UDP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const ICMP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A transport endpoint, where the port of ICMP echo messages is their identifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    pub protocol: IpProtocol,
+    pub address: IpV4Addr,
+    pub port: u16,
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} {}:{}", self.protocol, self.address, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub internal: Endpoint,
+    pub external_port: u16,
+    pub last_seen: Instant,
+    pub packets: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortForward {
+    pub protocol: IpProtocol,
+    pub external_port: u16,
+    pub address: IpV4Addr,
+    pub port: u16,
+}
+
+#[derive(Debug)]
+pub struct ParsePortForwardError;
+
+/// Parse a port forward written as `<tcp|udp>:<external port>=<address>:<port>`
+impl FromStr for PortForward {
+    type Err = ParsePortForwardError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, rest) = s.split_once(':').ok_or(ParsePortForwardError)?;
+        let (external_port, internal) = rest.split_once('=').ok_or(ParsePortForwardError)?;
+        let (address, port) = internal.split_once(':').ok_or(ParsePortForwardError)?;
+        Ok(Self {
+            protocol: match protocol {
+                "tcp" => IpProtocol::Tcp,
+                "udp" => IpProtocol::Udp,
+                _ => return Err(ParsePortForwardError),
+            },
+            external_port: external_port.parse().map_err(|_| ParsePortForwardError)?,
+            address: address.parse().map_err(|_| ParsePortForwardError)?,
+            port: port.parse().map_err(|_| ParsePortForwardError)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NatStats {
+    pub outbound: u64,
+    pub inbound: u64,
+    pub forwarded: u64,
+    pub mappings_created: u64,
+    pub mappings_expired: u64,
+    /// Outbound packets dropped because no external port was free
+    pub exhausted: u64,
+}
+
+/// Network address and port translation (RFC 3022) of the flows leaving by one interface.
+///
+/// Packets are rewritten in the receive buffer, before they are routed: outbound packets get the
+/// address of the external interface and a port of their own, and the answers are translated back.
+#[derive(Debug, Clone)]
+pub struct Nat {
+    pub external_interface: usize,
+    pub forwards: Vec<PortForward>,
+    pub stats: NatStats,
+    pub clock: Clock,
+    mappings: HashMap<Endpoint, Mapping>,
+    // External port of each protocol to the internal endpoint using it
+    ports: HashMap<(IpProtocol, u16), Endpoint>,
+    next_port: u16,
+}
+
+/// Overwrite the 16 bits word at `offset`, updating the checksums at `checksums` (RFC 1624)
+fn rewrite(packet: &mut [u8], offset: usize, value: u16, checksums: &[usize]) {
+    let old = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+    packet[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    for &checksum_offset in checksums {
+        let checksum = u16::from_be_bytes([packet[checksum_offset], packet[checksum_offset + 1]]);
+        let checksum = Checksum::from_field(checksum)
+            .update(old, value)
+            .ones_complement();
+        packet[checksum_offset..checksum_offset + 2].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+fn rewrite_address(packet: &mut [u8], offset: usize, address: IpV4Addr, checksums: &[usize]) {
+    rewrite(packet, offset, (address.0 >> 16) as u16, checksums);
+    rewrite(packet, offset + 2, address.0 as u16, checksums);
+}
+
+/// Rewrite the address at `address_offset` and the port or identifier at `port_offset`, updating
+/// the IP checksum and the transport one at `checksum_offset`
+fn rewrite_endpoint(
+    packet: &mut [u8],
+    protocol: IpProtocol,
+    (address_offset, address): (usize, IpV4Addr),
+    (port_offset, port): (usize, u16),
+    checksum_offset: usize,
+) {
+    let checksum =
+        |packet: &[u8]| u16::from_be_bytes([packet[checksum_offset], packet[checksum_offset + 1]]);
+    // A UDP datagram without a checksum keeps none
+    if protocol == IpProtocol::Udp && checksum(packet) == 0 {
+        rewrite_address(packet, address_offset, address, &[10]);
+        rewrite(packet, port_offset, port, &[]);
+        return;
+    }
+    // The transport checksum covers the addresses through the pseudo header, except for ICMP
+    let checksums: &[usize] = if protocol == IpProtocol::Icmp {
+        &[10]
+    } else {
+        &[10, checksum_offset]
+    };
+    rewrite_address(packet, address_offset, address, checksums);
+    rewrite(packet, port_offset, port, &[checksum_offset]);
+    if protocol == IpProtocol::Udp {
+        // Written again as UDP writes it, a zero standing for no checksum
+        let field = UDPPacket::<Vec<u8>>::checksum_field(Checksum::from_field(checksum(packet)));
+        packet[checksum_offset..checksum_offset + 2].copy_from_slice(&field.to_be_bytes());
+    }
+}
+
+/// Offsets, relative to the transport header, of the port or identifier and of the checksum
+fn transport_offsets(
+    protocol: IpProtocol,
+    source: bool,
+    message_type: u8,
+) -> Option<(usize, usize)> {
+    match protocol {
+        IpProtocol::Tcp => Some((if source { 0 } else { 2 }, 16)),
+        IpProtocol::Udp => Some((if source { 0 } else { 2 }, 6)),
+        // Echo messages are matched by identifier, whatever their direction
+        IpProtocol::Icmp if matches!(message_type, ECHO_REQUEST | ECHO_REPLY) => Some((4, 2)),
+        _ => None,
+    }
+}
+
+impl Nat {
+    pub fn new(external_interface: usize) -> Self {
+        Self {
+            external_interface,
+            forwards: vec![],
+            stats: NatStats::default(),
+            clock: Clock::default(),
+            mappings: HashMap::new(),
+            ports: HashMap::new(),
+            next_port: FIRST_PORT,
+        }
+    }
+
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.values()
+    }
+
+    /// Forget the flows which have been idle for too long
+    pub fn expire(&mut self) {
+        let now = self.clock.now();
+        let expired: Vec<_> = self
+            .mappings
+            .values()
+            .filter(|mapping| {
+                let timeout = match mapping.internal.protocol {
+                    IpProtocol::Tcp => TCP_TIMEOUT,
+                    IpProtocol::Udp => UDP_TIMEOUT,
+                    _ => ICMP_TIMEOUT,
+                };
+                now - mapping.last_seen > timeout
+            })
+            .copied()
+            .collect();
+        for mapping in expired {
+            debug!("NAT mapping of {} expired", mapping.internal);
+            self.mappings.remove(&mapping.internal);
+            self.ports
+                .remove(&(mapping.internal.protocol, mapping.external_port));
+            self.stats.mappings_expired += 1;
+        }
+    }
+
+    fn allocate_port(&mut self, protocol: IpProtocol) -> Option<u16> {
+        for _ in FIRST_PORT..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = self.next_port.checked_add(1).unwrap_or(FIRST_PORT);
+            let forwarded = self
+                .forwards
+                .iter()
+                .any(|forward| forward.protocol == protocol && forward.external_port == port);
+            if !forwarded && !self.ports.contains_key(&(protocol, port)) {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    /// Translate the packet received by `interfaces[input]` if it belongs to a translated flow
+    pub fn translate<T: Read + Write>(
+        &mut self,
+        interfaces: &mut [Interface<T>],
+        input: usize,
+        routing_table: &RoutingTable,
+    ) {
+        let Some(external_address) = interfaces[self.external_interface].config.address else {
+            return;
+        };
+        let packet = interfaces[input].get_packet_mut();
+        let Ok(header) = IPV4HeaderView::parse(packet) else {
+            return;
+        };
+        let destination = header.get_destination_address();
+        // Packets expiring here are answered by the router, with their original source
+        let expiring = header.get_ttl() <= 1;
+
+        if input == self.external_interface {
+            if destination == external_address {
+                self.translate_inbound(packet);
+            }
+        } else if !expiring
+            && routing_table
+                .lookup(destination)
+                .is_some_and(|route| route.interface == self.external_interface)
+        {
+            self.translate_outbound(packet, external_address);
+        }
+    }
+
+    fn translate_outbound(&mut self, packet: &mut [u8], external_address: IpV4Addr) {
+        let Ok(header) = IPV4HeaderView::parse(packet) else {
+            return;
+        };
+        let protocol = header.get_protocol();
+        let header_size = header.size();
+        let message_type = packet.get(header_size).copied().unwrap_or_default();
+        let Some((port_offset, checksum_offset)) = transport_offsets(protocol, true, message_type)
+        else {
+            return;
+        };
+        let (port_offset, checksum_offset) =
+            (header_size + port_offset, header_size + checksum_offset);
+        if packet.len() < checksum_offset + 2 {
+            return;
+        }
+        let internal = Endpoint {
+            protocol,
+            address: header.get_source_address(),
+            port: u16::from_be_bytes([packet[port_offset], packet[port_offset + 1]]),
+        };
+
+        // Statically forwarded endpoints keep their external port
+        let forward = self.forwards.iter().find(|forward| {
+            forward.protocol == protocol
+                && forward.address == internal.address
+                && forward.port == internal.port
+        });
+        let external_port = match (forward, self.mappings.get_mut(&internal)) {
+            (Some(forward), _) => forward.external_port,
+            (None, Some(mapping)) => {
+                mapping.last_seen = self.clock.now();
+                mapping.packets += 1;
+                mapping.external_port
+            }
+            (None, None) => {
+                let Some(external_port) = self.allocate_port(protocol) else {
+                    self.stats.exhausted += 1;
+                    return;
+                };
+                debug!("NAT mapping {internal} to port {external_port}");
+                self.mappings.insert(
+                    internal,
+                    Mapping {
+                        internal,
+                        external_port,
+                        last_seen: self.clock.now(),
+                        packets: 1,
+                    },
+                );
+                self.ports.insert((protocol, external_port), internal);
+                self.stats.mappings_created += 1;
+                external_port
+            }
+        };
+
+        rewrite_endpoint(
+            packet,
+            protocol,
+            (12, external_address),
+            (port_offset, external_port),
+            checksum_offset,
+        );
+        self.stats.outbound += 1;
+    }
+
+    fn translate_inbound(&mut self, packet: &mut [u8]) {
+        let Ok(header) = IPV4HeaderView::parse(packet) else {
+            return;
+        };
+        let protocol = header.get_protocol();
+        let header_size = header.size();
+        let message_type = packet.get(header_size).copied().unwrap_or_default();
+
+        if protocol == IpProtocol::Icmp && is_icmp_error(message_type) {
+            self.translate_inbound_error(packet, header_size);
+            return;
+        }
+        let Some((port_offset, checksum_offset)) = transport_offsets(protocol, false, message_type)
+        else {
+            return;
+        };
+        let (port_offset, checksum_offset) =
+            (header_size + port_offset, header_size + checksum_offset);
+        if packet.len() < checksum_offset + 2 {
+            return;
+        }
+        let external_port = u16::from_be_bytes([packet[port_offset], packet[port_offset + 1]]);
+
+        let internal =
+            if let Some(forward) = self.forwards.iter().find(|forward| {
+                forward.protocol == protocol && forward.external_port == external_port
+            }) {
+                self.stats.forwarded += 1;
+                Endpoint {
+                    protocol,
+                    address: forward.address,
+                    port: forward.port,
+                }
+            } else if let Some(internal) = self.ports.get(&(protocol, external_port)) {
+                let mapping = self.mappings.get_mut(internal).unwrap();
+                mapping.last_seen = self.clock.now();
+                mapping.packets += 1;
+                *internal
+            } else {
+                // Not a translated flow, the packet is for the stack itself
+                return;
+            };
+
+        rewrite_endpoint(
+            packet,
+            protocol,
+            (16, internal.address),
+            (port_offset, internal.port),
+            checksum_offset,
+        );
+        self.stats.inbound += 1;
+    }
+
+    /// Translate an ICMP error about a translated packet, which quotes it after the ICMP header
+    fn translate_inbound_error(&mut self, packet: &mut [u8], header_size: usize) {
+        let icmp_checksum = header_size + 2;
+        let quoted = header_size + 8;
+        let Ok(quoted_header) = IPV4HeaderView::parse(packet.get(quoted..).unwrap_or_default())
+        else {
+            return;
+        };
+        let protocol = quoted_header.get_protocol();
+        let quoted_size = quoted_header.size();
+        let message_type = packet
+            .get(quoted + quoted_size)
+            .copied()
+            .unwrap_or_default();
+        let Some((port_offset, _)) = transport_offsets(protocol, true, message_type) else {
+            return;
+        };
+        let port_offset = quoted + quoted_size + port_offset;
+        if packet.len() < port_offset + 2 {
+            return;
+        }
+        let external_port = u16::from_be_bytes([packet[port_offset], packet[port_offset + 1]]);
+        let Some(&internal) = self.ports.get(&(protocol, external_port)) else {
+            return;
+        };
+
+        // The quoted packet is the one we sent, its source is translated back
+        rewrite_address(packet, 16, internal.address, &[10]);
+        let quoted_checksum = quoted + 10;
+        let old_quoted_checksum =
+            u16::from_be_bytes([packet[quoted_checksum], packet[quoted_checksum + 1]]);
+        rewrite_address(
+            packet,
+            quoted + 12,
+            internal.address,
+            &[quoted_checksum, icmp_checksum],
+        );
+        rewrite(packet, port_offset, internal.port, &[icmp_checksum]);
+        // The quoted header checksum changed too, and is covered by the ICMP checksum
+        let new_quoted_checksum =
+            u16::from_be_bytes([packet[quoted_checksum], packet[quoted_checksum + 1]]);
+        packet[quoted_checksum..quoted_checksum + 2]
+            .copy_from_slice(&old_quoted_checksum.to_be_bytes());
+        rewrite(
+            packet,
+            quoted_checksum,
+            new_quoted_checksum,
+            &[icmp_checksum],
+        );
+        self.stats.inbound += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        interface::InterfaceConfig,
+        ip::{IPV4Header, IPV4Packet},
+        route::Route,
+        tcp::{TCPHeader, TCPPacket},
+        test_support::Wire,
+        traits::WriteTo,
+        udp::{UDPHeader, UDPPacket},
+    };
+
+    const INTERNAL: IpV4Addr = IpV4Addr(0x0A00_0005);
+    const EXTERNAL: IpV4Addr = IpV4Addr(0xCB00_7102);
+    const SERVER: IpV4Addr = IpV4Addr(0x0808_0808);
+
+    /// A gateway between 10.0.0.0/8 on interface 0 and the rest of the world on interface 1
+    fn gateway() -> (Nat, RoutingTable, Vec<Interface<Wire>>) {
+        let mut routing_table = RoutingTable::new();
+        routing_table.add("10.0.0.0/8,0".parse::<Route>().unwrap());
+        routing_table.add("0.0.0.0/0,1,203.0.113.1".parse::<Route>().unwrap());
+        let interfaces = ["10.0.0.1/8", "203.0.113.2/24"]
+            .into_iter()
+            .map(|address| {
+                let mut interface = Interface::new(Wire::default());
+                interface.config = InterfaceConfig::parse(address).unwrap();
+                interface
+            })
+            .collect();
+        (Nat::new(1), routing_table, interfaces)
+    }
+
+    fn tcp(source: (IpV4Addr, u16), destination: (IpV4Addr, u16)) -> Vec<u8> {
+        let header = TCPHeader {
+            source_port: source.1,
+            destination_port: destination.1,
+            ack: true,
+            ..Default::default()
+        };
+        IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Tcp, source.0, destination.0),
+            TCPPacket::new(header, b"data".to_vec()),
+        )
+        .to_bytes()
+        .unwrap()
+    }
+
+    /// Translate a packet received by `interfaces[input]`, returning it with its endpoints
+    fn translate(
+        nat: &mut Nat,
+        routing_table: &RoutingTable,
+        interfaces: &mut [Interface<Wire>],
+        input: usize,
+        packet: &[u8],
+    ) -> (Vec<u8>, (IpV4Addr, u16), (IpV4Addr, u16)) {
+        interfaces[input].receive_frame(&[&[0, 0, 8, 0][..], packet].concat());
+        nat.translate(interfaces, input, routing_table);
+        let packet = interfaces[input].packet().to_vec();
+        let header = IPV4HeaderView::parse(&packet).unwrap();
+        let port = |offset: usize| u16::from_be_bytes([packet[20 + offset], packet[21 + offset]]);
+        let endpoints = (
+            (header.get_source_address(), port(0)),
+            (header.get_destination_address(), port(2)),
+        );
+        assert!(checksums_valid(&packet));
+        (packet, endpoints.0, endpoints.1)
+    }
+
+    /// Whether the IP checksum and the transport one, with its pseudo header, are right
+    fn checksums_valid(packet: &[u8]) -> bool {
+        let header = IPV4HeaderView::parse(packet).unwrap();
+        let segment = &packet[header.size()..];
+        let transport = Checksum::new()
+            .add_4bytes(header.get_source_address().0.to_be_bytes())
+            .add_4bytes(header.get_destination_address().0.to_be_bytes())
+            .add_byte(header.get_protocol().into())
+            .add_2bytes((segment.len() as u16).to_be_bytes())
+            .add_slice(segment);
+        Checksum::new().add_slice(header.as_ref()).ones_complement() == 0
+            && transport.ones_complement() == 0
+    }
+
+    #[test]
+    fn outbound_mapping_and_answers() {
+        let (mut nat, routing_table, mut interfaces) = gateway();
+        let (_, source, destination) = translate(
+            &mut nat,
+            &routing_table,
+            &mut interfaces,
+            0,
+            &tcp((INTERNAL, 1234), (SERVER, 80)),
+        );
+        assert_eq!(source, (EXTERNAL, FIRST_PORT));
+        assert_eq!(destination, (SERVER, 80));
+
+        // The flow keeps its port, another one gets the next
+        let (_, source, _) = translate(
+            &mut nat,
+            &routing_table,
+            &mut interfaces,
+            0,
+            &tcp((INTERNAL, 1234), (SERVER, 80)),
+        );
+        assert_eq!(source, (EXTERNAL, FIRST_PORT));
+        let (_, source, _) = translate(
+            &mut nat,
+            &routing_table,
+            &mut interfaces,
+            0,
+            &tcp((IpV4Addr(0x0A00_0006), 1234), (SERVER, 80)),
+        );
+        assert_eq!(source, (EXTERNAL, FIRST_PORT + 1));
+
+        let (_, source, destination) = translate(
+            &mut nat,
+            &routing_table,
+            &mut interfaces,
+            1,
+            &tcp((SERVER, 80), (EXTERNAL, FIRST_PORT)),
+        );
+        assert_eq!(source, (SERVER, 80));
+        assert_eq!(destination, (INTERNAL, 1234));
+
+        // Packets inside the internal network, or to unmapped ports, are left alone
+        let local = tcp((INTERNAL, 1234), (IpV4Addr(0x0A00_0007), 22));
+        let (packet, _, _) = translate(&mut nat, &routing_table, &mut interfaces, 0, &local);
+        assert_eq!(packet, local);
+        let unmapped = tcp((SERVER, 80), (EXTERNAL, 22));
+        let (packet, _, _) = translate(&mut nat, &routing_table, &mut interfaces, 1, &unmapped);
+        assert_eq!(packet, unmapped);
+
+        assert_eq!(
+            nat.stats,
+            NatStats {
+                outbound: 3,
+                inbound: 1,
+                mappings_created: 2,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn port_forwards_and_udp() {
+        let (mut nat, routing_table, mut interfaces) = gateway();
+        nat.forwards = vec!["udp:49152=10.0.0.9:53".parse().unwrap()];
+
+        let (_, _, destination) = translate(
+            &mut nat,
+            &routing_table,
+            &mut interfaces,
+            1,
+            &IPV4Packet::new(
+                IPV4Header::new(IpProtocol::Udp, SERVER, EXTERNAL),
+                UDPPacket::new(
+                    UDPHeader {
+                        source_port: 5353,
+                        destination_port: FIRST_PORT,
+                        ..Default::default()
+                    },
+                    b"query".to_vec(),
+                ),
+            )
+            .to_bytes()
+            .unwrap(),
+        );
+        assert_eq!(destination, (IpV4Addr(0x0A00_0009), 53));
+
+        // The forwarded port is not handed out to other flows
+        let (_, source, _) = translate(
+            &mut nat,
+            &routing_table,
+            &mut interfaces,
+            0,
+            &IPV4Packet::new(
+                IPV4Header::new(IpProtocol::Udp, INTERNAL, SERVER),
+                UDPPacket::new(
+                    UDPHeader {
+                        source_port: 5000,
+                        destination_port: 53,
+                        ..Default::default()
+                    },
+                    b"query".to_vec(),
+                ),
+            )
+            .to_bytes()
+            .unwrap(),
+        );
+        assert_eq!(source, (EXTERNAL, FIRST_PORT + 1));
+        assert_eq!(nat.stats.forwarded, 1);
+        assert!("sctp:1=10.0.0.1:1".parse::<PortForward>().is_err());
+    }
+
+    #[test]
+    fn udp_checksum_of_zero_sent_as_all_ones() {
+        let (mut nat, routing_table, mut interfaces) = gateway();
+        let datagram = |payload: [u8; 2]| {
+            IPV4Packet::new(
+                IPV4Header::new(IpProtocol::Udp, INTERNAL, SERVER),
+                UDPPacket::new(
+                    UDPHeader {
+                        source_port: 5000,
+                        destination_port: 53,
+                        ..Default::default()
+                    },
+                    payload.to_vec(),
+                ),
+            )
+            .to_bytes()
+            .unwrap()
+        };
+        let (packet, _, _) = translate(
+            &mut nat,
+            &routing_table,
+            &mut interfaces,
+            0,
+            &datagram([0, 0]),
+        );
+        // Carrying the checksum it got, the datagram sums to all ones once translated
+        let payload = [packet[26], packet[27]];
+        let (packet, _, _) = translate(
+            &mut nat,
+            &routing_table,
+            &mut interfaces,
+            0,
+            &datagram(payload),
+        );
+        assert_eq!(packet[26..28], [0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn idle_mappings_expire() {
+        let (mut nat, routing_table, mut interfaces) = gateway();
+        nat.clock = Clock::manual();
+        translate(
+            &mut nat,
+            &routing_table,
+            &mut interfaces,
+            0,
+            &tcp((INTERNAL, 1234), (SERVER, 80)),
+        );
+        nat.expire();
+        assert_eq!(nat.mappings().count(), 1);
+
+        nat.clock.advance(TCP_TIMEOUT + Duration::from_secs(1));
+        nat.expire();
+        assert_eq!(nat.mappings().count(), 0);
+        assert_eq!(nat.stats.mappings_expired, 1);
+
+        // The answer is no longer translated, and the port can be handed out again
+        let answer = tcp((SERVER, 80), (EXTERNAL, FIRST_PORT));
+        let (packet, _, _) = translate(&mut nat, &routing_table, &mut interfaces, 1, &answer);
+        assert_eq!(packet, answer);
+        nat.next_port = FIRST_PORT;
+        let (_, source, _) = translate(
+            &mut nat,
+            &routing_table,
+            &mut interfaces,
+            0,
+            &tcp((INTERNAL, 4321), (SERVER, 80)),
+        );
+        assert_eq!(source, (EXTERNAL, FIRST_PORT));
+    }
+}
@@ -1,14 +1,23 @@
-use crate::{
-    checksum::Checksum,
-    traits::{Data, DataOwned, DataView, Prepare, ToMutable, WriteTo},
-};
+use crate::traits::{Data, DataOwned, DataView, Prepare, ToMutable, WriteTo};
 use std::{
+    convert::Infallible,
     fmt::Debug,
     io::{self, Write},
     marker::PhantomData,
     ptr::slice_from_raw_parts,
 };
 
+/// The slice is too short to hold the header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseHeaderError;
+
+/// Raw payloads always parse, whatever header is in front of them
+impl From<Infallible> for ParseHeaderError {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PacketView<'a, H: DataView<'a>, C: DataView<'a> = &'a [u8]> {
     pub header: H,
@@ -31,7 +40,16 @@ impl<H: DataOwned, C: DataOwned> Prepare for Packet<H, C> {
 }
 
 impl<H: DataOwned, C: DataOwned> WriteTo for Packet<H, C> {
-    fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+    default fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        self.prepare();
+        self.write_to_inner(writer)
+    }
+
+    default fn write_to_buffer(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.write_to(&mut &mut buffer[..])
+    }
+
+    default fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
         let mut nbytes = self.header.write_to_inner(writer)?;
         nbytes += self.payload.write_to_inner(writer)?;
         Ok(nbytes)
@@ -78,14 +96,15 @@ impl<'a, H: DataView<'a>, C: DataView<'a>> Debug for PacketView<'a, H, C> {
     }
 }
 
-impl<'a, H: DataView<'a>, C: DataView<'a, Error = H::Error>> TryFrom<&'a [u8]>
+impl<'a, H: DataView<'a>, C: DataView<'a, Error: Into<H::Error>>> TryFrom<&'a [u8]>
     for PacketView<'a, H, C>
 {
     type Error = H::Error;
     fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
         let header = H::try_from(value)?;
         let header_size = header.size();
-        Ok(Self::new(header, C::try_from(&value[header_size..])?))
+        let payload = C::try_from(&value[header_size..]).map_err(Into::into)?;
+        Ok(Self::new(header, payload))
     }
 }
 
@@ -108,10 +127,56 @@ impl<H: DataOwned, C: DataOwned> Data for Packet<H, C> {
     }
 }
 
-impl<H: DataOwned, C: DataOwned> Packet<H, C> {
-    pub fn compute_checksum(&mut self) -> Checksum {
-        self.header
-            .compute_checksum()
-            .add_checksum(self.payload.compute_checksum())
+#[cfg(test)]
+mod tests {
+    use tcp_rust_macros::PacketHeader;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, PacketHeader)]
+    struct ExampleHeader {
+        #[bits(4)]
+        version: u8,
+        #[bits(12)]
+        length: u16,
+        flag: bool,
+        #[bits(7)]
+        kind: u8,
+        address: [u8; 2],
+    }
+
+    const HEADER: [u8; 5] = [0x4A, 0xBC, 0x85, 0x01, 0x02];
+
+    #[test]
+    fn derived_view_parses_valid_and_truncated_headers() {
+        let header = ExampleHeaderView::try_from(&HEADER[..]).unwrap();
+        let owned = ExampleHeader {
+            version: 4,
+            length: 0xABC,
+            flag: true,
+            kind: 5,
+            address: [1, 2],
+        };
+        assert_eq!(header.to_mutable(), owned);
+        assert_eq!(header.size(), ExampleHeaderView::SIZE);
+        assert_eq!(owned.clone().to_bytes().unwrap(), HEADER);
+        for length in 0..HEADER.len() {
+            assert_eq!(
+                ExampleHeaderView::try_from(&HEADER[..length]),
+                Err(ParseHeaderError)
+            );
+        }
+
+        // The payload follows the header, and a truncated header fails the whole packet
+        let packet = [&HEADER[..], b"data"].concat();
+        let view = PacketView::<ExampleHeaderView>::try_from(&packet[..]).unwrap();
+        assert_eq!(view.payload, b"data");
+        assert!(PacketView::<ExampleHeaderView>::try_from(&packet[..4]).is_err());
+
+        let mut patched = HEADER;
+        let mut header = ExampleHeaderViewMut::try_from(&mut patched[..]).unwrap();
+        header.set_flag(false);
+        header.set_length(0x123);
+        assert_eq!(patched, [0x41, 0x23, 0x05, 0x01, 0x02]);
     }
 }
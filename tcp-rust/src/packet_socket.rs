@@ -0,0 +1,204 @@
+use std::{
+    ffi::CString,
+    io::{self, Read, Write},
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use crate::interface::{PACKET_INFO_SIZE, packet_info};
+
+use libc::{
+    AF_PACKET, ETH_P_ALL, PACKET_ADD_MEMBERSHIP, PACKET_MR_PROMISC, PACKET_OUTGOING, SOCK_RAW,
+    SOL_PACKET, packet_mreq, sockaddr_ll, socklen_t,
+};
+
+/// Raw `AF_PACKET` socket on an existing link, such as one end of a veth pair.
+///
+/// Frames are read and written behind the packet information header of TAP devices, so that an
+/// `Interface` in TAP mode runs on either.
+#[derive(Debug)]
+pub struct PacketSocket {
+    fd: OwnedFd,
+    pub name: String,
+}
+
+impl PacketSocket {
+    /// Open a socket receiving every frame of the link, whatever its destination address
+    pub fn new(name: &str) -> io::Result<Self> {
+        let c_name = CString::new(name).map_err(|_| io::ErrorKind::InvalidInput)?;
+        let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let protocol = (ETH_P_ALL as u16).to_be();
+        let fd = unsafe { libc::socket(AF_PACKET, SOCK_RAW, protocol as i32) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut address: sockaddr_ll = unsafe { mem::zeroed() };
+        address.sll_family = AF_PACKET as u16;
+        address.sll_protocol = protocol;
+        address.sll_ifindex = index as i32;
+        let result = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &raw const address as *const libc::sockaddr,
+                mem::size_of::<sockaddr_ll>() as socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The stack has its own link address, which the link must not filter out
+        let mut membership: packet_mreq = unsafe { mem::zeroed() };
+        membership.mr_ifindex = index as i32;
+        membership.mr_type = PACKET_MR_PROMISC as u16;
+        let result = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                SOL_PACKET,
+                PACKET_ADD_MEMBERSHIP,
+                &raw const membership as *const libc::c_void,
+                mem::size_of::<packet_mreq>() as socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd,
+            name: name.to_string(),
+        })
+    }
+}
+
+impl Read for PacketSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(frame) = buf.get_mut(PACKET_INFO_SIZE..) else {
+            return Err(io::ErrorKind::InvalidInput.into());
+        };
+        loop {
+            let mut address: sockaddr_ll = unsafe { mem::zeroed() };
+            let mut address_length = mem::size_of::<sockaddr_ll>() as socklen_t;
+            let nbytes = unsafe {
+                libc::recvfrom(
+                    self.fd.as_raw_fd(),
+                    frame.as_mut_ptr() as *mut libc::c_void,
+                    frame.len(),
+                    0,
+                    &raw mut address as *mut libc::sockaddr,
+                    &raw mut address_length,
+                )
+            };
+            if nbytes < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // The socket also sees the frames sent on the link, ours included
+            if address.sll_pkttype == PACKET_OUTGOING {
+                continue;
+            }
+            let nbytes = nbytes as usize;
+            let ethertype = if nbytes >= 14 {
+                u16::from_be_bytes([frame[12], frame[13]])
+            } else {
+                0
+            };
+            buf[..PACKET_INFO_SIZE].copy_from_slice(&packet_info(ethertype));
+            return Ok(PACKET_INFO_SIZE + nbytes);
+        }
+    }
+}
+
+impl Write for PacketSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(frame) = buf.get(PACKET_INFO_SIZE..) else {
+            return Err(io::ErrorKind::InvalidInput.into());
+        };
+        let nbytes = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                frame.as_ptr() as *const libc::c_void,
+                frame.len(),
+                0,
+            )
+        };
+        if nbytes < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PACKET_INFO_SIZE + nbytes as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for PacketSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use super::*;
+
+    /// Socket over one end of a datagram pair, which `recvfrom` and `send` treat as a link
+    fn pair() -> (PacketSocket, UnixDatagram) {
+        let (ours, theirs) = UnixDatagram::pair().unwrap();
+        let socket = PacketSocket {
+            fd: OwnedFd::from(ours),
+            name: "test".to_string(),
+        };
+        (socket, theirs)
+    }
+
+    #[test]
+    fn frames_framed_with_the_packet_information() {
+        let (mut socket, link) = pair();
+        let mut frame = [0; 14 + 4];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        link.send(&frame).unwrap();
+        let mut buf = [0xFF; 64];
+        assert_eq!(
+            socket.read(&mut buf).unwrap(),
+            PACKET_INFO_SIZE + frame.len()
+        );
+        assert_eq!(buf[..PACKET_INFO_SIZE], [0, 0, 0x08, 0x00]);
+        assert_eq!(buf[PACKET_INFO_SIZE..PACKET_INFO_SIZE + frame.len()], frame);
+
+        let mut written = vec![0, 0, 0x08, 0x00];
+        written.extend(frame);
+        assert_eq!(socket.write(&written).unwrap(), written.len());
+        let mut received = [0; 64];
+        assert_eq!(link.recv(&mut received).unwrap(), frame.len());
+        assert_eq!(received[..frame.len()], frame);
+    }
+
+    #[test]
+    fn buffers_shorter_than_the_packet_information_rejected() {
+        let (mut socket, link) = pair();
+        link.send(&[0; 14]).unwrap();
+        for length in 0..PACKET_INFO_SIZE {
+            let mut buf = vec![0; length];
+            assert_eq!(
+                socket.read(&mut buf).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+            assert_eq!(
+                socket.write(&buf).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+        // Nothing was sent, and the frame waiting is still read whole
+        let mut buf = [0; 64];
+        assert_eq!(socket.read(&mut buf).unwrap(), PACKET_INFO_SIZE + 14);
+        link.set_nonblocking(true).unwrap();
+        assert!(link.recv(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+    time::Duration,
+};
+
+const MAGIC_MICROSECONDS: u32 = 0xA1B2C3D4;
+const MAGIC_NANOSECONDS: u32 = 0xA1B23C4D;
+/// Largest record accepted whatever the snap length of the file, that of tcpdump
+const MAX_SNAP_LENGTH: u32 = 262144;
+
+/// A packet of a capture file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcapRecord {
+    /// Time since the Unix epoch
+    pub timestamp: Duration,
+    /// Length of the packet on the wire, of which `data` may only be the start
+    pub original_length: u32,
+    pub data: Vec<u8>,
+}
+
+/// Reader of the classic pcap capture format, in either byte order and timestamp precision
+#[derive(Debug)]
+pub struct PcapReader<R: Read> {
+    reader: R,
+    swapped: bool,
+    nanoseconds: bool,
+    pub link_type: u32,
+    pub snap_length: u32,
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 24];
+        reader.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let (swapped, nanoseconds) = match magic {
+            MAGIC_MICROSECONDS => (false, false),
+            MAGIC_NANOSECONDS => (false, true),
+            _ if magic.swap_bytes() == MAGIC_MICROSECONDS => (true, false),
+            _ if magic.swap_bytes() == MAGIC_NANOSECONDS => (true, true),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a pcap file",
+                ));
+            }
+        };
+        let mut pcap = Self {
+            reader,
+            swapped,
+            nanoseconds,
+            link_type: 0,
+            snap_length: 0,
+        };
+        pcap.snap_length = pcap.u32_at(&header, 16);
+        pcap.link_type = pcap.u32_at(&header, 20) & 0xFFFF;
+        Ok(pcap)
+    }
+
+    fn u32_at(&self, bytes: &[u8], offset: usize) -> u32 {
+        let value = u32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ]);
+        if self.swapped {
+            value.swap_bytes()
+        } else {
+            value
+        }
+    }
+
+    /// Read the next packet, returning `None` at the end of the file
+    pub fn next_record(&mut self) -> io::Result<Option<PcapRecord>> {
+        let mut header = [0; 16];
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.reader.read(&mut header[filled..])? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => filled += n,
+            }
+        }
+        let seconds = self.u32_at(&header, 0) as u64;
+        let fraction = self.u32_at(&header, 4);
+        let captured_length = self.u32_at(&header, 8);
+        let original_length = self.u32_at(&header, 12);
+        // Guard against a corrupted length making us allocate gigabytes
+        if captured_length > self.snap_length.min(MAX_SNAP_LENGTH) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "pcap record longer than the snap length",
+            ));
+        }
+        let mut data = vec![0; captured_length as usize];
+        self.reader.read_exact(&mut data)?;
+        let timestamp = if self.nanoseconds {
+            Duration::new(seconds, fraction)
+        } else {
+            Duration::new(seconds, fraction.saturating_mul(1000))
+        };
+        Ok(Some(PcapRecord {
+            timestamp,
+            original_length,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = io::Result<PcapRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seconds: u32, fraction: u32, data: &[u8]) -> Vec<u8> {
+        [seconds, fraction, data.len() as u32, 1500]
+            .iter()
+            .flat_map(|field| field.to_be_bytes())
+            .chain(data.iter().copied())
+            .collect()
+    }
+
+    fn file(magic: u32, snap_length: u32, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = [magic, 0x0002_0004, 0, 0, snap_length, 101]
+            .iter()
+            .flat_map(|field| field.to_be_bytes())
+            .collect::<Vec<_>>();
+        // The version is two 16 bits fields
+        file[4..8].copy_from_slice(&[0, 2, 0, 4]);
+        records.iter().for_each(|record| file.extend(record));
+        file
+    }
+
+    #[test]
+    fn records_read_in_the_byte_order_of_the_file() {
+        let records = [record(3600, 250, b"first"), record(3601, 0, b"second")];
+        // Written big endian, which a little endian host reads swapped
+        let bytes = file(MAGIC_NANOSECONDS, 65535, &records);
+        let mut reader = PcapReader::new(&bytes[..]).unwrap();
+        assert_eq!((reader.link_type, reader.snap_length), (101, 65535));
+        assert_eq!(
+            reader.next_record().unwrap(),
+            Some(PcapRecord {
+                timestamp: Duration::new(3600, 250),
+                original_length: 1500,
+                data: b"first".to_vec(),
+            })
+        );
+        assert_eq!(reader.next().unwrap().unwrap().data, b"second");
+        assert!(reader.next().is_none());
+
+        // Microseconds, and a file cut in the middle of a record header
+        let mut bytes = file(MAGIC_MICROSECONDS, 65535, &records);
+        bytes.truncate(bytes.len() - 10);
+        let mut reader = PcapReader::new(&bytes[..]).unwrap();
+        let record = reader.next_record().unwrap().unwrap();
+        assert_eq!(record.timestamp, Duration::new(3600, 250_000));
+        assert_eq!(
+            reader.next_record().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert!(PcapReader::new(&[0; 24][..]).is_err());
+    }
+
+    #[test]
+    fn records_longer_than_the_snap_length_rejected() {
+        let records = [record(0, 0, &[0; 64]), record(0, 0, &[0; 65])];
+        let bytes = file(MAGIC_MICROSECONDS, 64, &records);
+        let mut reader = PcapReader::new(&bytes[..]).unwrap();
+        assert!(reader.next_record().unwrap().is_some());
+        assert_eq!(
+            reader.next_record().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // A corrupted snap length does not raise the cap
+        let mut bytes = file(MAGIC_MICROSECONDS, u32::MAX, &[]);
+        bytes.extend(record(0, 0, &[]));
+        bytes[24 + 8..24 + 12].copy_from_slice(&(MAX_SNAP_LENGTH + 1).to_be_bytes());
+        let mut reader = PcapReader::new(&bytes[..]).unwrap();
+        assert_eq!(
+            reader.next_record().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
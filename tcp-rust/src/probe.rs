@@ -0,0 +1,654 @@
+//! `ping` and `traceroute`, whose probes are built with the packet types of the stack and sent
+//! on a TUN device, the host routing them like any other packet.
+//!
+//! The tools wait for the device to be brought up, once its address is set:
+//!
+//! ```text
+//! tcp-rust ping 192.168.0.1 -c 3 & make ip
+//! ```
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    dns::resolver::{QUERY_TIMEOUT, Resolver},
+    ethernet::{ETHERTYPE_IPV4, MacAddr},
+    icmp::{
+        DESTINATION_UNREACHABLE, ECHO_REPLY, ECHO_REQUEST, HOST_UNREACHABLE, ICMPHeaderView,
+        ICMPPacket, NET_UNREACHABLE, PORT_UNREACHABLE, PROTOCOL_UNREACHABLE, TIME_EXCEEDED,
+    },
+    interface::{Interface, InterfaceConfig},
+    ip::{IPV4Header, IPV4HeaderView, IPV4Packet, IPV4PacketView, IpProtocol, IpV4Addr},
+    traits::Data,
+    tun_tap,
+    udp::{UDPHeader, UDPHeaderView, UDPPacket, UDPPacketView},
+};
+
+/// First destination port of the UDP probes of traceroute, unlikely to be listened on
+const TRACEROUTE_PORT: u16 = 33434;
+/// Data of the traceroute probes, which makes 60 bytes packets
+const TRACEROUTE_DATA: usize = 32;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/// A probe, told apart from those of other processes by its identifier or source port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Probe {
+    Echo {
+        identifier: u16,
+        sequence_number: u16,
+    },
+    Udp {
+        source_port: u16,
+        destination_port: u16,
+    },
+}
+
+/// The answer to a probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Reply {
+    source: IpV4Addr,
+    ttl: u8,
+    /// Size of the ICMP message
+    length: usize,
+    probe: Probe,
+    /// Type and code of an ICMP error, none for an echo reply
+    error: Option<(u8, u8)>,
+}
+
+/// Parse an IP packet answering a probe: an echo reply, or an ICMP error quoting the probe
+fn parse_reply(packet: &[u8]) -> Option<Reply> {
+    let header = IPV4HeaderView::parse(packet).ok()?;
+    let header_size = header.size();
+    if header.get_version() != 4 || header.get_protocol() != IpProtocol::Icmp {
+        return None;
+    }
+    // The header of the message, and the identifier and sequence number or unused word after it
+    let message = &packet[header_size..];
+    if message.len() < 8 {
+        return None;
+    }
+    let icmp_header = ICMPHeaderView::parse(message).ok()?;
+    let word = |bytes: &[u8], offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+    let (probe, error) = match icmp_header.get_message_type() {
+        ECHO_REPLY => (
+            Probe::Echo {
+                identifier: word(message, 4),
+                sequence_number: word(message, 6),
+            },
+            None,
+        ),
+        message_type @ (DESTINATION_UNREACHABLE | TIME_EXCEEDED) => {
+            // The error quotes the IP header of the probe and the first 8 bytes of its payload
+            let quoted = &message[8..];
+            let quoted_size = (*quoted.first()? & 0xF) as usize * 4;
+            let transport = quoted.get(quoted_size.max(20)..quoted_size.max(20) + 8)?;
+            let probe = match quoted[9] {
+                protocol
+                    if protocol == u8::from(IpProtocol::Icmp) && transport[0] == ECHO_REQUEST =>
+                {
+                    Probe::Echo {
+                        identifier: word(transport, 4),
+                        sequence_number: word(transport, 6),
+                    }
+                }
+                protocol if protocol == u8::from(IpProtocol::Udp) => {
+                    let udp_header = UDPHeaderView::parse(transport).ok()?;
+                    Probe::Udp {
+                        source_port: udp_header.get_source_port(),
+                        destination_port: udp_header.get_destination_port(),
+                    }
+                }
+                _ => return None,
+            };
+            (probe, Some((message_type, icmp_header.get_code())))
+        }
+        _ => return None,
+    };
+    Some(Reply {
+        source: header.get_source_address(),
+        ttl: header.get_ttl(),
+        length: (header.get_total_length() as usize).clamp(header_size, packet.len()) - header_size,
+        probe,
+        error,
+    })
+}
+
+/// What an ICMP error says about the probe, in the words of ping
+fn describe_error((message_type, code): (u8, u8)) -> String {
+    match (message_type, code) {
+        (TIME_EXCEEDED, _) => "Time to live exceeded".to_string(),
+        (DESTINATION_UNREACHABLE, NET_UNREACHABLE) => "Destination Net Unreachable".to_string(),
+        (DESTINATION_UNREACHABLE, HOST_UNREACHABLE) => "Destination Host Unreachable".to_string(),
+        (DESTINATION_UNREACHABLE, PROTOCOL_UNREACHABLE) => {
+            "Destination Protocol Unreachable".to_string()
+        }
+        (DESTINATION_UNREACHABLE, PORT_UNREACHABLE) => "Destination Port Unreachable".to_string(),
+        (message_type, code) => format!("ICMP type {message_type}, code {code}"),
+    }
+}
+
+/// Sends probes on a TUN device and receives what answers them
+struct Prober {
+    interface: Interface<tun_tap::Interface>,
+    source: IpV4Addr,
+    destination: IpV4Addr,
+    /// Identifier of the echo requests and source port of the UDP probes of the process
+    identifier: u16,
+}
+
+impl Prober {
+    /// Open the device and wait for it to be up, then find the address of the destination
+    fn open(
+        name: Option<String>,
+        source: IpV4Addr,
+        destination: &str,
+        dns: Option<IpV4Addr>,
+    ) -> io::Result<Self> {
+        let device =
+            tun_tap::Interface::new(name.as_deref().unwrap_or("tun%d"), tun_tap::Mode::Tun)?;
+        while !tun_tap::is_up(&device.name)? {
+            thread::sleep(Duration::from_millis(100));
+        }
+        // Ctrl-C stops the probes, the statistics being printed as the tool ends
+        unsafe { libc::signal(libc::SIGINT, interrupt as *const () as libc::sighandler_t) };
+        let mut prober = Self {
+            interface: Interface::new(device),
+            source,
+            destination: IpV4Addr::default(),
+            identifier: std::process::id() as u16 | 0x8000,
+        };
+        prober.destination = prober.resolve(destination, dns)?;
+        Ok(prober)
+    }
+
+    /// Find the address of a host, asking the DNS server unless it is an address already
+    fn resolve(&mut self, host: &str, dns: Option<IpV4Addr>) -> io::Result<IpV4Addr> {
+        if let Ok(address) = host.parse() {
+            return Ok(address);
+        }
+        let Some(dns) = dns else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "resolving a host name needs --dns",
+            ));
+        };
+        let mut resolver = Resolver::new(Some(dns));
+        resolver.query(&mut self.interface, self.source, host);
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        loop {
+            if let Some(address) = resolver.lookup(host) {
+                return Ok(address);
+            }
+            let now = Instant::now();
+            if !resolver.is_resolving(host)
+                || now >= deadline
+                || INTERRUPTED.load(Ordering::Relaxed)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{host}: could not resolve"),
+                ));
+            }
+            match self.interface.receive_timeout(deadline - now) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+            if let Ok(packet) = self
+                .interface
+                .try_get_packet::<IPV4PacketView<UDPPacketView>>()
+                && self.interface.is_ip()
+                && packet.header.get_protocol() == IpProtocol::Udp
+            {
+                resolver.handle_datagram(packet.header.get_source_address(), packet.payload);
+            }
+        }
+    }
+
+    fn send(&mut self, probe: Probe, ttl: u8, data: &[u8]) {
+        let protocol = match probe {
+            Probe::Echo { .. } => IpProtocol::Icmp,
+            Probe::Udp { .. } => IpProtocol::Udp,
+        };
+        let mut ip_header = IPV4Header::new(protocol, self.source, self.destination);
+        ip_header.ttl = ttl;
+        match probe {
+            Probe::Echo {
+                identifier,
+                sequence_number,
+            } => self.interface.write_frame(
+                MacAddr::BROADCAST,
+                ETHERTYPE_IPV4,
+                IPV4Packet::new(
+                    ip_header,
+                    ICMPPacket::echo_request(identifier, sequence_number, data),
+                ),
+            ),
+            Probe::Udp {
+                source_port,
+                destination_port,
+            } => self.interface.write_frame(
+                MacAddr::BROADCAST,
+                ETHERTYPE_IPV4,
+                IPV4Packet::new(
+                    ip_header,
+                    UDPPacket::new(
+                        UDPHeader {
+                            source_port,
+                            destination_port,
+                            ..Default::default()
+                        },
+                        data,
+                    ),
+                ),
+            ),
+        }
+        self.interface.send();
+    }
+
+    /// Wait until `deadline` for a reply to one of the probes of the process
+    fn receive(&mut self, deadline: Instant) -> io::Result<Option<Reply>> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline || INTERRUPTED.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            match self.interface.receive_timeout(deadline - now) {
+                Ok(true) => {}
+                Ok(false) => return Ok(None),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => return Ok(None),
+                Err(err) => return Err(err),
+            }
+            if !self.interface.is_ip() {
+                continue;
+            }
+            let Some(reply) = parse_reply(self.interface.packet()) else {
+                continue;
+            };
+            let ours = match reply.probe {
+                Probe::Echo { identifier, .. } => identifier == self.identifier,
+                Probe::Udp { source_port, .. } => source_port == self.identifier,
+            };
+            if ours {
+                return Ok(Some(reply));
+            }
+        }
+    }
+}
+
+fn parse_seconds(value: Option<String>) -> Option<Duration> {
+    value?
+        .parse()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+}
+
+/// Send echo requests to a host and print the round trip times, like ping. `-c` stops after a
+/// number of requests, `-i` and `-W` give the interval and the time waited for the last replies in
+/// seconds, `-s` the size of the data and `-t` the TTL. A host name is resolved with the server
+/// given by `--dns`.
+pub fn ping(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    let mut name = None;
+    let mut source = InterfaceConfig::DEFAULT_ADDRESS;
+    let mut dns = None;
+    let mut destination = None;
+    let mut count = None;
+    let mut interval = Duration::from_secs(1);
+    let mut linger = Duration::from_secs(2);
+    let mut size = 56;
+    let mut ttl = 64;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interface" => {
+                name = Some(
+                    args.next()
+                        .ok_or_else(|| invalid("missing interface name"))?,
+                )
+            }
+            "--address" => {
+                source = args
+                    .next()
+                    .and_then(|address| address.parse().ok())
+                    .ok_or_else(|| invalid("invalid address"))?
+            }
+            "--dns" => {
+                dns = Some(
+                    args.next()
+                        .and_then(|address| address.parse().ok())
+                        .ok_or_else(|| invalid("invalid DNS server"))?,
+                )
+            }
+            "-c" => {
+                count = Some(
+                    args.next()
+                        .and_then(|count| count.parse().ok())
+                        .filter(|count| *count > 0)
+                        .ok_or_else(|| invalid("invalid count"))?,
+                )
+            }
+            "-i" => {
+                interval = parse_seconds(args.next()).ok_or_else(|| invalid("invalid interval"))?
+            }
+            "-W" => {
+                linger = parse_seconds(args.next()).ok_or_else(|| invalid("invalid timeout"))?
+            }
+            // The reply must fit the 1500 bytes of the device
+            "-s" => {
+                size = args
+                    .next()
+                    .and_then(|size| size.parse().ok())
+                    .filter(|size| *size <= 1472)
+                    .ok_or_else(|| invalid("invalid size"))?
+            }
+            "-t" => {
+                ttl = args
+                    .next()
+                    .and_then(|ttl| ttl.parse().ok())
+                    .filter(|ttl| *ttl > 0)
+                    .ok_or_else(|| invalid("invalid TTL"))?
+            }
+            _ => destination = Some(arg),
+        }
+    }
+    let destination = destination.ok_or_else(|| invalid("missing destination"))?;
+
+    let mut prober = Prober::open(name, source, &destination, dns)?;
+    let destination = prober.destination;
+    println!("PING {destination} {size}({}) bytes of data.", size + 28);
+    let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+    let start = Instant::now();
+    let mut pending = HashMap::new();
+    let (mut transmitted, mut errors) = (0u32, 0u32);
+    let mut times = vec![];
+    while !INTERRUPTED.load(Ordering::Relaxed) && count.is_none_or(|count| transmitted < count) {
+        transmitted += 1;
+        let sequence_number = transmitted as u16;
+        let probe = Probe::Echo {
+            identifier: prober.identifier,
+            sequence_number,
+        };
+        pending.insert(sequence_number, Instant::now());
+        prober.send(probe, ttl, &data);
+        let last = count == Some(transmitted);
+        let deadline = Instant::now() + if last { linger } else { interval };
+        while let Some(reply) = prober.receive(deadline)? {
+            let Probe::Echo {
+                sequence_number, ..
+            } = reply.probe
+            else {
+                continue;
+            };
+            // Duplicates and replies to requests answered by an error are not counted
+            let Some(sent) = pending.remove(&sequence_number) else {
+                continue;
+            };
+            match reply.error {
+                Some(error) => {
+                    errors += 1;
+                    println!(
+                        "From {} icmp_seq={sequence_number} {}",
+                        reply.source,
+                        describe_error(error)
+                    );
+                }
+                None => {
+                    let time = sent.elapsed();
+                    times.push(time.as_secs_f64() * 1000.);
+                    println!(
+                        "{} bytes from {}: icmp_seq={sequence_number} ttl={} time={:.3} ms",
+                        reply.length,
+                        reply.source,
+                        reply.ttl,
+                        time.as_secs_f64() * 1000.
+                    );
+                }
+            }
+            if last && pending.is_empty() {
+                break;
+            }
+        }
+    }
+
+    let received = times.len() as u32;
+    println!();
+    println!("--- {destination} ping statistics ---");
+    print!("{transmitted} packets transmitted, {received} received, ");
+    if errors > 0 {
+        print!("+{errors} errors, ");
+    }
+    println!(
+        "{}% packet loss, time {}ms",
+        (transmitted - received) * 100 / transmitted.max(1),
+        start.elapsed().as_millis()
+    );
+    if !times.is_empty() {
+        let mean = times.iter().sum::<f64>() / times.len() as f64;
+        let deviation = (times.iter().map(|time| time * time).sum::<f64>() / times.len() as f64
+            - mean * mean)
+            .max(0.)
+            .sqrt();
+        println!(
+            "rtt min/avg/max/mdev = {:.3}/{mean:.3}/{:.3}/{deviation:.3} ms",
+            times.iter().copied().fold(f64::INFINITY, f64::min),
+            times.iter().copied().fold(0., f64::max),
+        );
+    }
+    Ok(())
+}
+
+/// Print the routers on the way to a host, like traceroute: the TTL of the probes grows until
+/// the destination answers. The probes are UDP datagrams to unused ports, or echo requests with
+/// `-I`. `-m` gives the largest TTL, `-q` the number of probes per hop and `-w` the time waited
+/// for each in seconds. A host name is resolved with the server given by `--dns`.
+pub fn traceroute(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    let mut name = None;
+    let mut source = InterfaceConfig::DEFAULT_ADDRESS;
+    let mut dns = None;
+    let mut destination = None;
+    let mut icmp = false;
+    let mut max_ttl = 30;
+    let mut queries = 3;
+    let mut wait = Duration::from_secs(2);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interface" => {
+                name = Some(
+                    args.next()
+                        .ok_or_else(|| invalid("missing interface name"))?,
+                )
+            }
+            "--address" => {
+                source = args
+                    .next()
+                    .and_then(|address| address.parse().ok())
+                    .ok_or_else(|| invalid("invalid address"))?
+            }
+            "--dns" => {
+                dns = Some(
+                    args.next()
+                        .and_then(|address| address.parse().ok())
+                        .ok_or_else(|| invalid("invalid DNS server"))?,
+                )
+            }
+            "-I" => icmp = true,
+            "-m" => {
+                max_ttl = args
+                    .next()
+                    .and_then(|ttl| ttl.parse().ok())
+                    .filter(|ttl| *ttl > 0)
+                    .ok_or_else(|| invalid("invalid max TTL"))?
+            }
+            "-q" => {
+                queries = args
+                    .next()
+                    .and_then(|queries| queries.parse().ok())
+                    .filter(|queries| (1..=10).contains(queries))
+                    .ok_or_else(|| invalid("invalid number of probes"))?
+            }
+            "-w" => wait = parse_seconds(args.next()).ok_or_else(|| invalid("invalid wait"))?,
+            _ => destination = Some(arg),
+        }
+    }
+    let destination = destination.ok_or_else(|| invalid("missing destination"))?;
+
+    let mut prober = Prober::open(name, source, &destination, dns)?;
+    let destination = prober.destination;
+    println!(
+        "traceroute to {destination}, {max_ttl} hops max, {} byte packets",
+        28 + TRACEROUTE_DATA
+    );
+    let data = [0; TRACEROUTE_DATA];
+    let mut probes = 0u16;
+    for ttl in 1..=max_ttl {
+        print!("{ttl:2} ");
+        let mut last_source = None;
+        let mut done = false;
+        for _ in 0..queries {
+            probes = probes.wrapping_add(1);
+            let probe = if icmp {
+                Probe::Echo {
+                    identifier: prober.identifier,
+                    sequence_number: probes,
+                }
+            } else {
+                Probe::Udp {
+                    source_port: prober.identifier,
+                    destination_port: TRACEROUTE_PORT.wrapping_add(probes),
+                }
+            };
+            let sent = Instant::now();
+            prober.send(probe, ttl, &data);
+            let deadline = sent + wait;
+            // Late replies to earlier probes are skipped
+            let reply = loop {
+                match prober.receive(deadline)? {
+                    Some(reply) if reply.probe == probe => break Some(reply),
+                    Some(_) => continue,
+                    None => break None,
+                }
+            };
+            let Some(reply) = reply else {
+                print!(" *");
+                continue;
+            };
+            if last_source != Some(reply.source) {
+                print!(" {}", reply.source);
+                last_source = Some(reply.source);
+            }
+            print!("  {:.3} ms", sent.elapsed().as_secs_f64() * 1000.);
+            match reply.error {
+                Some((TIME_EXCEEDED, _)) => {}
+                // The destination answers the UDP probes with a port unreachable
+                Some((DESTINATION_UNREACHABLE, PORT_UNREACHABLE)) | None => done = true,
+                Some((DESTINATION_UNREACHABLE, code)) => {
+                    print!(
+                        " !{}",
+                        match code {
+                            NET_UNREACHABLE => "N".to_string(),
+                            HOST_UNREACHABLE => "H".to_string(),
+                            PROTOCOL_UNREACHABLE => "P".to_string(),
+                            code => code.to_string(),
+                        }
+                    );
+                    done = true;
+                }
+                Some(_) => {}
+            }
+        }
+        println!();
+        if done || INTERRUPTED.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{icmp::TTL_EXCEEDED, traits::WriteTo};
+
+    use super::*;
+
+    const STACK: IpV4Addr = IpV4Addr(0xC0A8_0002);
+    const ROUTER: IpV4Addr = IpV4Addr(0xC0A8_0001);
+    const HOST: IpV4Addr = IpV4Addr(0x0A00_0001);
+
+    #[test]
+    fn time_exceeded_quoting_a_udp_probe() {
+        let mut header = IPV4Header::new(IpProtocol::Udp, STACK, HOST);
+        header.ttl = 1;
+        let probe = IPV4Packet::new(
+            header,
+            UDPPacket::new(
+                UDPHeader {
+                    source_port: 0x8001,
+                    destination_port: TRACEROUTE_PORT + 1,
+                    ..Default::default()
+                },
+                vec![0; TRACEROUTE_DATA],
+            ),
+        )
+        .to_bytes()
+        .unwrap();
+        let error = IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Icmp, ROUTER, STACK),
+            ICMPPacket::error(TIME_EXCEEDED, TTL_EXCEEDED, &probe),
+        )
+        .to_bytes()
+        .unwrap();
+        let reply = parse_reply(&error).unwrap();
+        assert_eq!(reply.source, ROUTER);
+        assert_eq!(reply.error, Some((TIME_EXCEEDED, TTL_EXCEEDED)));
+        assert_eq!(
+            reply.probe,
+            Probe::Udp {
+                source_port: 0x8001,
+                destination_port: TRACEROUTE_PORT + 1
+            }
+        );
+    }
+
+    #[test]
+    fn echo_reply() {
+        let mut request = ICMPPacket::echo_request(0x8001, 7, &[1, 2, 3]);
+        request.header.message_type = ECHO_REPLY;
+        let reply = IPV4Packet::new(IPV4Header::new(IpProtocol::Icmp, HOST, STACK), request)
+            .to_bytes()
+            .unwrap();
+        let reply = parse_reply(&reply).unwrap();
+        assert_eq!((reply.source, reply.length, reply.error), (HOST, 11, None));
+        assert_eq!(
+            reply.probe,
+            Probe::Echo {
+                identifier: 0x8001,
+                sequence_number: 7
+            }
+        );
+        assert_eq!(parse_reply(&reply_to_nothing()), None);
+    }
+
+    /// An echo request, which answers no probe
+    fn reply_to_nothing() -> Vec<u8> {
+        IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Icmp, HOST, STACK),
+            ICMPPacket::echo_request(0x8001, 7, &[]),
+        )
+        .to_bytes()
+        .unwrap()
+    }
+}
@@ -0,0 +1,398 @@
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use tracing::debug;
+
+use crate::{
+    arp::ARPManager,
+    ethernet::{ETHERTYPE_IPV4, MacAddr},
+    icmp::{
+        DESTINATION_UNREACHABLE, ICMPPacket, NET_UNREACHABLE, TIME_EXCEEDED, TTL_EXCEEDED,
+        is_icmp_error,
+    },
+    interface::{Interface, InterfaceConfig},
+    ip::{IPV4Header, IPV4HeaderView, IPV4Packet, IpProtocol, IpV4Addr, decrement_ttl},
+    traits::Data,
+    tun_tap::Mode,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub destination: IpV4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<IpV4Addr>,
+    /// Index of the output interface
+    pub interface: usize,
+}
+
+impl Route {
+    pub fn netmask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0)
+    }
+
+    pub fn contains(&self, address: IpV4Addr) -> bool {
+        (self.destination.0 ^ address.0) & self.netmask() == 0
+    }
+
+    /// Route to the network an interface is directly connected to
+    pub fn connected(config: &InterfaceConfig, interface: usize) -> Option<Self> {
+        let address = config.address?;
+        Some(Self {
+            destination: IpV4Addr(address.0 & config.netmask.0),
+            prefix_len: config.prefix_len(),
+            gateway: None,
+            interface,
+        })
+    }
+}
+
+impl Display for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.destination, self.prefix_len)?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {gateway}")?;
+        }
+        write!(f, " dev {}", self.interface)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseRouteError;
+
+/// Parse a route written as `<destination>/<prefix length>,<interface>[,<gateway>]`
+impl FromStr for Route {
+    type Err = ParseRouteError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let (destination, prefix_len) = parts
+            .next()
+            .and_then(|prefix| prefix.split_once('/'))
+            .ok_or(ParseRouteError)?;
+        let interface = parts
+            .next()
+            .and_then(|interface| interface.parse().ok())
+            .ok_or(ParseRouteError)?;
+        let gateway = parts
+            .next()
+            .map(|gateway| gateway.parse().map_err(|_| ParseRouteError))
+            .transpose()?;
+        let prefix_len = prefix_len.parse().map_err(|_| ParseRouteError)?;
+        if prefix_len > 32 {
+            return Err(ParseRouteError);
+        }
+        Ok(Self {
+            destination: destination.parse().map_err(|_| ParseRouteError)?,
+            prefix_len,
+            gateway,
+            interface,
+        })
+    }
+}
+
+/// Routes sorted from the most to the least specific, so that the first match is the longest
+#[derive(Debug, Clone, Default)]
+pub struct RoutingTable {
+    routes: Vec<Route>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, route: Route) {
+        let index = self
+            .routes
+            .partition_point(|other| other.prefix_len >= route.prefix_len);
+        self.routes.insert(index, route);
+    }
+
+    pub fn remove(&mut self, destination: IpV4Addr, prefix_len: u8) {
+        self.routes
+            .retain(|route| route.destination != destination || route.prefix_len != prefix_len);
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    pub fn lookup(&self, address: IpV4Addr) -> Option<&Route> {
+        self.routes.iter().find(|route| route.contains(address))
+    }
+
+    /// Forward the IPv4 packet received by `interfaces[input]` toward its destination.
+    ///
+    /// The packet is copied as is to the output interface, only its TTL and checksum are patched.
+    pub fn forward<T: Read + Write>(
+        &self,
+        interfaces: &mut [Interface<T>],
+        input: usize,
+        arp_manager: &ARPManager,
+    ) {
+        let Ok(header) = interfaces[input].try_get_packet::<IPV4HeaderView>() else {
+            return;
+        };
+        let destination = header.get_destination_address();
+
+        if header.get_ttl() <= 1 {
+            send_error(&mut interfaces[input], TIME_EXCEEDED, TTL_EXCEEDED);
+            debug!("TTL exceeded for a packet to {destination}");
+            return;
+        }
+        let Some(route) = self.lookup(destination) else {
+            send_error(
+                &mut interfaces[input],
+                DESTINATION_UNREACHABLE,
+                NET_UNREACHABLE,
+            );
+            debug!("no route to {destination}");
+            return;
+        };
+
+        let next_hop = route.gateway.unwrap_or(destination);
+        let Some(output) = interfaces.get_mut(route.interface) else {
+            debug!(
+                "no interface {} for the route to {destination}",
+                route.interface
+            );
+            return;
+        };
+        let Some(next_hop_mac) = next_hop_mac(output, next_hop, arp_manager) else {
+            return;
+        };
+
+        match interfaces.get_disjoint_mut([input, route.interface]) {
+            Ok([input, output]) => output.write_frame(next_hop_mac, ETHERTYPE_IPV4, input.packet()),
+            // Sent back on the interface it came from, the packet is copied out of the buffer the
+            // frame is built in
+            Err(_) => {
+                let packet = interfaces[input].packet().to_vec();
+                interfaces[input].write_frame(next_hop_mac, ETHERTYPE_IPV4, &packet[..]);
+            }
+        }
+        let output = &mut interfaces[route.interface];
+        decrement_ttl(output.get_packet_mut());
+        output.send();
+    }
+}
+
+/// Link address of a neighbour on `interface`, which is asked for when unknown
+pub fn next_hop_mac(
+    interface: &mut Interface<impl Read + Write>,
+    next_hop: IpV4Addr,
+    arp_manager: &ARPManager,
+) -> Option<MacAddr> {
+    match interface.mode {
+        Mode::Tun => Some(MacAddr::BROADCAST),
+        Mode::Tap => {
+            let mac = arp_manager.lookup(next_hop);
+            if mac.is_none() {
+                // Drop the packet, the sender will retransmit once the neighbour is known
+                arp_manager.request(interface, next_hop);
+            }
+            mac
+        }
+    }
+}
+
+/// Answer the packet received by `interface` with an ICMP error
+pub fn send_error(interface: &mut Interface<impl Read + Write>, message_type: u8, code: u8) {
+    let original = interface.packet();
+    let Ok(header) = IPV4HeaderView::parse(original) else {
+        return;
+    };
+    // Never answer an error with another error (RFC 1122 §3.2.2)
+    if header.get_protocol() == IpProtocol::Icmp
+        && original
+            .get(header.size())
+            .is_some_and(|&message_type| is_icmp_error(message_type))
+    {
+        return;
+    }
+    // The stack may not have an address in TUN mode, in which case it speaks for the destination
+    let source = interface
+        .config
+        .address
+        .unwrap_or(header.get_destination_address());
+    let response = IPV4Packet::new(
+        IPV4Header::new(IpProtocol::Icmp, source, header.get_source_address()),
+        ICMPPacket::error(message_type, code, original),
+    );
+    interface.write(response);
+    interface.send();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checksum::Checksum, icmp::ICMPPacketView, ip::IPV4PacketView, test_support::Wire,
+        traits::WriteTo,
+    };
+
+    const HOST: IpV4Addr = IpV4Addr(0x0A01_0203);
+
+    fn route(s: &str) -> Route {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn longest_prefix_first() {
+        let mut table = RoutingTable::new();
+        table.add(route("0.0.0.0/0,0,192.168.0.1"));
+        table.add(route("10.1.0.0/16,2"));
+        table.add(route("10.0.0.0/8,1"));
+        table.add(route("10.1.2.3/32,3"));
+        let interface = |table: &RoutingTable, address: &str| {
+            table.lookup(address.parse().unwrap()).unwrap().interface
+        };
+        assert_eq!(interface(&table, "10.1.2.3"), 3);
+        assert_eq!(interface(&table, "10.1.2.4"), 2);
+        assert_eq!(interface(&table, "10.200.0.1"), 1);
+        assert_eq!(interface(&table, "8.8.8.8"), 0);
+
+        table.remove("10.1.0.0".parse().unwrap(), 16);
+        assert_eq!(interface(&table, "10.1.2.4"), 1);
+        table.remove(IpV4Addr(0), 0);
+        assert!(table.lookup("8.8.8.8".parse().unwrap()).is_none());
+
+        assert!("10.0.0.0/33,1".parse::<Route>().is_err());
+        assert!("10.0.0.0,1".parse::<Route>().is_err());
+        assert!("10.0.0.0/8".parse::<Route>().is_err());
+    }
+
+    /// Two TUN interfaces, 192.168.0.2/24 and 10.0.0.2/8, each with its wire
+    fn router() -> (RoutingTable, Vec<Interface<Wire>>, [Wire; 2]) {
+        let wires = [Wire::default(), Wire::default()];
+        let mut table = RoutingTable::new();
+        let interfaces = ["192.168.0.2/24", "10.0.0.2/8"]
+            .into_iter()
+            .zip(&wires)
+            .enumerate()
+            .map(|(i, (address, wire))| {
+                let mut interface = Interface::new(wire.clone());
+                interface.config = InterfaceConfig::parse(address).unwrap();
+                table.add(Route::connected(&interface.config, i).unwrap());
+                interface
+            })
+            .collect();
+        (table, interfaces, wires)
+    }
+
+    fn forward(
+        table: &RoutingTable,
+        interfaces: &mut [Interface<Wire>],
+        protocol: IpProtocol,
+        destination: IpV4Addr,
+        ttl: u8,
+    ) -> Vec<u8> {
+        let mut header = IPV4Header::new(protocol, IpV4Addr(0xC0A8_0001), destination);
+        header.ttl = ttl;
+        let packet = IPV4Packet::new(header, b"payload".to_vec())
+            .to_bytes()
+            .unwrap();
+        interfaces[0].receive_frame(&[&[0, 0, 8, 0][..], &packet].concat());
+        table.forward(interfaces, 0, &ARPManager::new());
+        packet
+    }
+
+    fn is_valid(header: &[u8]) -> bool {
+        Checksum::new().add_slice(header).ones_complement() == 0
+    }
+
+    #[test]
+    fn ttl_decremented_or_expired() {
+        let (table, mut interfaces, wires) = router();
+        let sent = forward(&table, &mut interfaces, IpProtocol::Udp, HOST, 64);
+        let frame = wires[1].sent.borrow_mut().pop().unwrap();
+        let header = IPV4HeaderView::parse(&frame[4..]).unwrap();
+        assert_eq!(header.get_ttl(), 63);
+        assert!(is_valid(header.as_ref()));
+        assert_eq!(frame[4 + 20..], sent[20..]);
+
+        forward(&table, &mut interfaces, IpProtocol::Udp, HOST, 1);
+        assert!(wires[1].sent.borrow().is_empty());
+        let frame = wires[0].sent.borrow_mut().pop().unwrap();
+        let error = IPV4PacketView::<ICMPPacketView>::try_from(&frame[4..]).unwrap();
+        assert_eq!(
+            error.header.get_destination_address(),
+            IpV4Addr(0xC0A8_0001)
+        );
+        assert_eq!(
+            (
+                error.payload.header.get_message_type(),
+                error.payload.header.get_code()
+            ),
+            (TIME_EXCEEDED, TTL_EXCEEDED)
+        );
+    }
+
+    #[test]
+    fn unreachable_and_unknown_protocols() {
+        let (mut table, mut interfaces, wires) = router();
+        forward(
+            &table,
+            &mut interfaces,
+            IpProtocol::Udp,
+            IpV4Addr(0x0808_0808),
+            64,
+        );
+        let frame = wires[0].sent.borrow_mut().pop().unwrap();
+        let error = IPV4PacketView::<ICMPPacketView>::try_from(&frame[4..]).unwrap();
+        assert_eq!(
+            (
+                error.payload.header.get_message_type(),
+                error.payload.header.get_code()
+            ),
+            (DESTINATION_UNREACHABLE, NET_UNREACHABLE)
+        );
+
+        // A protocol the stack does not speak is forwarded as is
+        table.add(route("0.0.0.0/0,1,10.0.0.1"));
+        forward(
+            &table,
+            &mut interfaces,
+            IpProtocol::Other(253),
+            IpV4Addr(0x0808_0808),
+            64,
+        );
+        let frame = wires[1].sent.borrow_mut().pop().unwrap();
+        let header = IPV4HeaderView::parse(&frame[4..]).unwrap();
+        assert_eq!(header.get_protocol(), IpProtocol::Other(253));
+        assert_eq!(frame[4 + 9], 253);
+        assert!(is_valid(header.as_ref()));
+    }
+
+    #[test]
+    fn routes_back_to_the_ingress_or_to_no_interface() {
+        let (mut table, mut interfaces, wires) = router();
+        // Sent back on the interface it came from, toward a gateway of its network
+        table.add(route("172.16.0.0/12,0,192.168.0.254"));
+        let sent = forward(
+            &table,
+            &mut interfaces,
+            IpProtocol::Udp,
+            IpV4Addr(0xAC10_0001),
+            64,
+        );
+        let frame = wires[0].sent.borrow_mut().pop().unwrap();
+        assert_eq!(IPV4HeaderView::parse(&frame[4..]).unwrap().get_ttl(), 63);
+        assert_eq!(frame[4 + 20..], sent[20..]);
+
+        // A route to an interface which does not exist drops the packet
+        table.add(route("8.0.0.0/8,3"));
+        forward(
+            &table,
+            &mut interfaces,
+            IpProtocol::Udp,
+            IpV4Addr(0x0808_0808),
+            64,
+        );
+        assert!(wires.iter().all(|wire| wire.sent.borrow().is_empty()));
+    }
+}
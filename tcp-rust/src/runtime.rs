@@ -0,0 +1,521 @@
+//! The stack driven by tokio, with sockets implementing `AsyncRead` and `AsyncWrite`.
+//!
+//! A `Stack` is shared between the task running it on an `AsyncInterface` and the sockets of the
+//! application. The connections to a port bound by a `TCPListener` are handed to the application,
+//! the other ports being still answered by the HTTP server of the connections.
+
+use std::{
+    future::poll_fn,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddrV4},
+    os::fd::AsRawFd,
+    pin::Pin,
+    slice,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf, unix::AsyncFd},
+    sync::Notify,
+    time,
+};
+use tracing::debug;
+
+use crate::{
+    arp::ARPManager,
+    buffer::BufferPool,
+    ethernet::ETHERTYPE_ARP,
+    icmp::{self, ECHO_REQUEST, ICMPPacketView},
+    interface::Interface,
+    ip::{IPV4PacketView, IpProtocol, IpV4Addr},
+    tcp::manager::{ConnectionId, TCPConfig, TCPConnection, TCPManager},
+};
+
+/// Maximum time spent waiting for a packet before running the timers
+const TICK: Duration = Duration::from_millis(100);
+
+/// Interface whose device is read when tokio reports it readable
+#[derive(Debug)]
+pub struct AsyncInterface<T: Read + Write + AsRawFd> {
+    inner: AsyncFd<Interface<T>>,
+}
+
+impl<T: Read + Write + AsRawFd> AsyncInterface<T> {
+    /// Register the device with tokio, switching it to non-blocking mode
+    pub fn new(interface: Interface<T>) -> io::Result<Self> {
+        let fd = interface.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            inner: AsyncFd::new(interface)?,
+        })
+    }
+
+    /// Wait for a frame and receive it in the buffer of the interface
+    pub async fn receive(&mut self) -> io::Result<()> {
+        loop {
+            let mut guard = self.inner.readable_mut().await?;
+            // The readiness is cleared when the device has nothing after all
+            if let Ok(result) = guard.try_io(|inner| inner.get_mut().try_receive()) {
+                return result;
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut Interface<T> {
+        self.inner.get_mut()
+    }
+}
+
+fn socket_address(address: IpV4Addr, port: u16) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::from_bits(address.0), port)
+}
+
+#[derive(Debug, Default)]
+struct State {
+    tcp_manager: TCPManager,
+    /// Tasks waiting for a connection, for data or for room in a send buffer, all woken whenever
+    /// the stack handles a packet or runs its timers
+    wakers: Vec<Waker>,
+}
+
+impl State {
+    fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|other| other.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    fn wake_all(&mut self) {
+        self.wakers.drain(..).for_each(Waker::wake);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Raised by the sockets so that the stack sends what they queued
+    notify: Notify,
+}
+
+/// Handle on a stack, shared by the task running it and the sockets
+#[derive(Debug, Clone, Default)]
+pub struct Stack {
+    shared: Arc<Shared>,
+}
+
+impl Stack {
+    pub fn new(config: TCPConfig) -> Self {
+        let stack = Self::default();
+        stack.lock().tcp_manager.config = config;
+        stack
+    }
+
+    /// Restrict the built-in HTTP server to `ports`, see `TCPManager::ports`
+    pub fn set_ports(&self, ports: Vec<u16>) {
+        self.lock().tcp_manager.ports = ports;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap()
+    }
+
+    fn notify(&self) {
+        self.shared.notify.notify_one();
+    }
+
+    /// Receive and answer packets, and send what the sockets queue, until the device fails
+    pub async fn run<T: Read + Write + AsRawFd>(
+        self,
+        mut interface: AsyncInterface<T>,
+    ) -> io::Result<()> {
+        let mut arp_manager = ARPManager::new();
+        let mut buffer_pool = BufferPool::new();
+        loop {
+            let timeout = {
+                let mut state = self.lock();
+                state.tcp_manager.poll(slice::from_mut(interface.get_mut()));
+                state.wake_all();
+                state.tcp_manager.next_timer().map_or(TICK, |timer| {
+                    timer.saturating_duration_since(Instant::now()).min(TICK)
+                })
+            };
+            tokio::select! {
+                received = interface.receive() => {
+                    received?;
+                    self.handle_packet(interface.get_mut(), &mut arp_manager, &mut buffer_pool);
+                }
+                _ = self.shared.notify.notified() => {}
+                _ = time::sleep(timeout) => {}
+            }
+        }
+    }
+
+    fn handle_packet(
+        &self,
+        interface: &mut Interface<impl Read + Write>,
+        arp_manager: &mut ARPManager,
+        buffer_pool: &mut BufferPool,
+    ) {
+        if !interface.is_for_us() {
+            return;
+        }
+        if interface.get_proto() == ETHERTYPE_ARP {
+            arp_manager.handle_arp_packet(interface);
+            return;
+        }
+        if !interface.is_ip() {
+            debug!("Not an IP Packet: {}", interface.get_proto());
+            return;
+        }
+        if !interface.is_addressed_to_us() {
+            return;
+        }
+        match interface.get_ip_protocol() {
+            Some(IpProtocol::Icmp) => {
+                let Ok(ip_packet) = interface.try_get_packet::<IPV4PacketView<ICMPPacketView>>()
+                else {
+                    debug!("truncated ICMP message dropped");
+                    return;
+                };
+                if ip_packet.payload.header.get_message_type() == ECHO_REQUEST {
+                    icmp::reply_to_echo(interface, buffer_pool);
+                }
+            }
+            Some(IpProtocol::Tcp) => self.lock().tcp_manager.handle_tcp_packet(interface, 0),
+            protocol => debug!("received a packet of protocol {protocol:?}, ignored"),
+        }
+    }
+}
+
+/// Socket accepting the connections to a port of the stack
+#[derive(Debug)]
+pub struct TCPListener {
+    stack: Stack,
+    port: u16,
+}
+
+impl TCPListener {
+    /// Listen to `port`, failing with `AddrInUse` when another listener has it
+    pub fn bind(stack: &Stack, port: u16) -> io::Result<Self> {
+        if !stack.lock().tcp_manager.listen(port) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        Ok(Self {
+            stack: stack.clone(),
+            port,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for a connection whose handshake is over
+    pub async fn accept(&self) -> io::Result<(TCPStream, SocketAddrV4)> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(TCPStream, SocketAddrV4)>> {
+        let mut state = self.stack.lock();
+        let Some(id) = state.tcp_manager.accept(self.port) else {
+            state.register(cx.waker());
+            return Poll::Pending;
+        };
+        let stream = TCPStream {
+            stack: self.stack.clone(),
+            id,
+        };
+        Poll::Ready(Ok((stream, socket_address(id.peer_address, id.peer_port))))
+    }
+}
+
+impl Drop for TCPListener {
+    fn drop(&mut self) {
+        self.stack.lock().tcp_manager.unlisten(self.port);
+        self.stack.notify();
+    }
+}
+
+/// Connection accepted by a `TCPListener`, closed when dropped
+#[derive(Debug)]
+pub struct TCPStream {
+    stack: Stack,
+    id: ConnectionId,
+}
+
+impl TCPStream {
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        socket_address(self.id.local_address, self.id.local_port)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        socket_address(self.id.peer_address, self.id.peer_port)
+    }
+
+    /// Take the last urgent byte received, which is left out of the data read like without
+    /// `SO_OOBINLINE`
+    pub fn read_urgent(&self) -> Option<u8> {
+        self.stack
+            .lock()
+            .tcp_manager
+            .connection(self.id)?
+            .read_urgent()
+    }
+
+    /// Run `operation` on the connection, waiting for the stack while it would block. A connection
+    /// which is gone gives `closed` instead, along with the error of a reset or a timeout.
+    fn poll_connection<R>(
+        &self,
+        cx: &mut Context<'_>,
+        operation: impl FnOnce(&mut TCPConnection) -> io::Result<R>,
+        closed: impl FnOnce(Option<io::ErrorKind>) -> io::Result<R>,
+    ) -> Poll<io::Result<R>> {
+        let mut state = self.stack.lock();
+        let error = state.tcp_manager.error(self.id);
+        let Some(connection) = state.tcp_manager.connection(self.id) else {
+            return Poll::Ready(closed(error));
+        };
+        match operation(connection) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                state.register(cx.waker());
+                Poll::Pending
+            }
+            result => {
+                // Sent data, or a window opened by a read, is for the stack to send
+                self.stack.notify();
+                Poll::Ready(result)
+            }
+        }
+    }
+}
+
+impl AsyncRead for TCPStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let length = std::task::ready!(self.poll_connection(
+            cx,
+            |connection| connection.read(buf.initialize_unfilled()),
+            // The end of the stream after a close, the error otherwise
+            |error| error.map_or(Ok(0), |kind| Err(kind.into())),
+        ))?;
+        buf.advance(length);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TCPStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_connection(
+            cx,
+            |connection| connection.write(buf),
+            |error| Err(error.unwrap_or(io::ErrorKind::BrokenPipe).into()),
+        )
+    }
+
+    /// The stack sends the queued data on its own
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Send a FIN once the queued data has been sent
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_connection(
+            cx,
+            |connection| {
+                connection.close();
+                Ok(())
+            },
+            |_| Ok(()),
+        )
+    }
+}
+
+impl Drop for TCPStream {
+    fn drop(&mut self) {
+        self.stack.lock().tcp_manager.release(self.id);
+        self.stack.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        ip::{IPV4Header, IPV4Packet},
+        tcp::{TCPHeader, TCPHeaderView, TCPPacket, TCPPacketView},
+        test_support::Datagram,
+        traits::{ToMutable, WriteTo},
+    };
+
+    const LOCAL_ADDRESS: IpV4Addr = IpV4Addr(0xC0A80002);
+    const PEER_ADDRESS: IpV4Addr = IpV4Addr(0xC0A80001);
+
+    /// The peer end of the device
+    struct Peer(tokio::net::UnixDatagram);
+
+    impl Peer {
+        async fn send(&self, header: TCPHeader, payload: &[u8]) {
+            let mut packet = IPV4Packet::new(
+                IPV4Header::new(IpProtocol::Tcp, PEER_ADDRESS, LOCAL_ADDRESS),
+                TCPPacket::new(
+                    TCPHeader {
+                        source_port: 40000,
+                        destination_port: 7,
+                        window: 65535,
+                        ..header
+                    },
+                    payload.to_vec(),
+                ),
+            );
+            let mut frame = vec![0, 0, 8, 0];
+            frame.extend(packet.to_bytes().unwrap());
+            self.0.send(&frame).await.unwrap();
+        }
+
+        /// The next segment matching `predicate`, and its payload
+        async fn receive(
+            &self,
+            predicate: impl Fn(TCPHeaderView, &[u8]) -> bool,
+        ) -> (TCPHeader, Vec<u8>) {
+            let mut frame = [0; 1518];
+            loop {
+                let length = self.0.recv(&mut frame).await.unwrap();
+                let ip_packet =
+                    IPV4PacketView::<TCPPacketView>::try_from(&frame[4..length]).unwrap();
+                let tcp_packet = ip_packet.payload;
+                if predicate(tcp_packet.header, tcp_packet.payload) {
+                    return (tcp_packet.header.to_mutable(), tcp_packet.payload.to_vec());
+                }
+            }
+        }
+    }
+
+    /// A stack running on a device whose other end is the peer, listening to port 7
+    fn start() -> (Stack, TCPListener, Peer) {
+        let (device, peer) = UnixDatagram::pair().unwrap();
+        peer.set_nonblocking(true).unwrap();
+        let peer = Peer(tokio::net::UnixDatagram::from_std(peer).unwrap());
+        let stack = Stack::new(TCPConfig::default());
+        let listener = TCPListener::bind(&stack, 7).unwrap();
+        let interface = AsyncInterface::new(Interface::new(Datagram(device))).unwrap();
+        tokio::spawn(stack.clone().run(interface));
+        (stack, listener, peer)
+    }
+
+    /// Open a connection from the peer, returning the stream accepted and the header of the peer
+    /// once established
+    async fn connect(listener: &TCPListener, peer: &Peer) -> (TCPStream, TCPHeader) {
+        peer.send(
+            TCPHeader {
+                sequence_number: 1000,
+                syn: true,
+                ..Default::default()
+            },
+            &[],
+        )
+        .await;
+        let (syn_ack, _) = peer.receive(|header, _| header.get_syn()).await;
+        let established = TCPHeader {
+            sequence_number: 1001,
+            acknowledgement_number: syn_ack.sequence_number.wrapping_add(1),
+            ack: true,
+            ..Default::default()
+        };
+        peer.send(established.clone(), &[]).await;
+        let (stream, peer_address) = listener.accept().await.unwrap();
+        assert_eq!(peer_address, socket_address(PEER_ADDRESS, 40000));
+        (stream, established)
+    }
+
+    #[tokio::test]
+    async fn echo_through_a_socket() {
+        let (stack, listener, peer) = start();
+        assert!(TCPListener::bind(&stack, 7).is_err());
+        let (mut stream, established) = connect(&listener, &peer).await;
+
+        peer.send(
+            TCPHeader {
+                psh: true,
+                ..established.clone()
+            },
+            b"hello",
+        )
+        .await;
+        let mut buffer = [0; 5];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+        stream.write_all(&buffer).await.unwrap();
+        let (_, payload) = peer.receive(|_, payload| !payload.is_empty()).await;
+        assert_eq!(payload, b"hello");
+
+        // The urgent byte is taken out of the stream
+        peer.send(
+            TCPHeader {
+                sequence_number: 1006,
+                psh: true,
+                urg: true,
+                urgent_pointer: 1,
+                ..established.clone()
+            },
+            b"!?",
+        )
+        .await;
+        stream.read_exact(&mut buffer[..1]).await.unwrap();
+        assert_eq!(&buffer[..1], b"?");
+        assert_eq!(stream.read_urgent(), Some(b'!'));
+        assert_eq!(stream.read_urgent(), None);
+
+        stream.shutdown().await.unwrap();
+        let (fin, _) = peer.receive(|header, _| header.get_fin()).await;
+        assert_eq!(
+            fin.sequence_number,
+            established.acknowledgement_number.wrapping_add(5)
+        );
+        peer.send(
+            TCPHeader {
+                sequence_number: 1008,
+                fin: true,
+                ..established
+            },
+            &[],
+        )
+        .await;
+        assert_eq!(stream.read(&mut buffer).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn reset_reported_by_the_socket() {
+        let (_stack, listener, peer) = start();
+        let (mut stream, established) = connect(&listener, &peer).await;
+        peer.send(
+            TCPHeader {
+                rst: true,
+                ..established
+            },
+            &[],
+        )
+        .await;
+        let mut buffer = [0; 5];
+        // Waiting for data until the reset comes in
+        let err = stream.read(&mut buffer).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        let err = stream.write(b"hello").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+}
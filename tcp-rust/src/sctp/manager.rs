@@ -0,0 +1,1372 @@
+//! SCTP associations (RFC 9260) of the stack, set up by the peers or by the application.
+//!
+//! The INIT of a peer is answered without keeping any state: everything the association needs
+//! goes in the state cookie of the INIT-ACK, signed so that the peer can only echo it back. The
+//! messages of a stream are delivered in their order, regardless of the losses on the other
+//! streams, and unordered messages as soon as they are complete. Unless the application listens
+//! to the port, the messages received are echoed back on their stream.
+//!
+//! The peers are expected on the link: there is neither multi-homing nor congestion control, the
+//! data in flight only being limited by the window of the peer, and each packet with DATA is
+//! acknowledged right away.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+    io::{self, Read, Write},
+    mem,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use tracing::{Span, debug, info, info_span};
+
+use crate::{
+    ethernet::{ETHERTYPE_IPV4, MacAddr},
+    interface::Interface,
+    ip::{IPV4Header, IPV4HeaderView, IPV4Packet, IPV4PacketView, IpProtocol, IpV4Addr},
+    sctp::{
+        ABORT, BEGINNING, COOKIE_ACK, COOKIE_ECHO, Chunk, DATA, DATA_HEADER_SIZE, DataChunk,
+        ENDING, ERROR, HEARTBEAT, HEARTBEAT_ACK, INIT, INIT_ACK, InitChunk, SACK, SCTPHeader,
+        SCTPHeaderView, SCTPPacket, SHUTDOWN, SHUTDOWN_ACK, SHUTDOWN_COMPLETE, SackChunk,
+        TAG_REFLECTED, UNORDERED, chunks, verify_checksum,
+    },
+    traits::{AsArrayUnchecked, Data, ToMutable},
+};
+
+/// Size of the IPv4 and SCTP common headers, which the MTU leaves the chunks
+const HEADERS_SIZE: usize = 32;
+/// Receive window advertised, the messages not read by the application taking room in it
+const RECEIVE_WINDOW: u32 = 65536;
+/// Streams opened in each direction, fewer when the peer asks for fewer
+const STREAMS: u16 = 16;
+/// First and longest retransmission timeouts. RTO.Min is RTO.Initial, so the round trip times
+/// of the link are too short to be worth measuring.
+const RTO_INITIAL: Duration = Duration::from_secs(1);
+const RTO_MAX: Duration = Duration::from_secs(60);
+/// Retransmissions in a row after which the peer is given up
+const MAX_RETRANSMISSIONS: u32 = 10;
+/// SACKs reporting a chunk missing after which it is retransmitted without waiting for the
+/// timeout
+const FAST_RETRANSMIT_MISSES: u32 = 3;
+/// Time a peer has to echo the state cookie it was given
+const COOKIE_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The addresses and ports identifying an association
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AssociationId {
+    pub local_address: IpV4Addr,
+    pub local_port: u16,
+    pub peer_address: IpV4Addr,
+    pub peer_port: u16,
+}
+
+impl AssociationId {
+    /// Association a received packet belongs to
+    pub fn of(ip_header: IPV4HeaderView, header: SCTPHeaderView) -> Self {
+        Self {
+            local_address: ip_header.get_destination_address(),
+            local_port: header.get_destination_port(),
+            peer_address: ip_header.get_source_address(),
+            peer_port: header.get_source_port(),
+        }
+    }
+}
+
+impl Display for AssociationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{} - {}:{}",
+            self.local_address, self.local_port, self.peer_address, self.peer_port
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AssociationState {
+    #[default]
+    CookieWait,
+    CookieEchoed,
+    Established,
+    /// Closed by the application, the data queued being sent before the SHUTDOWN
+    ShutdownPending,
+    ShutdownSent,
+    /// Closed by the peer, the data queued being sent before the SHUTDOWN-ACK
+    ShutdownReceived,
+    ShutdownAckSent,
+    Closed,
+}
+
+/// A message received on a stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub stream: u16,
+    /// Payload protocol identifier, given by the sender for the application
+    pub ppid: u32,
+    pub data: Vec<u8>,
+}
+
+/// State of an association being set up, which the peer holds until it echoes it back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cookie {
+    /// Milliseconds since the epoch when it was made
+    created: u64,
+    id: AssociationId,
+    local_tag: u32,
+    peer_tag: u32,
+    local_tsn: u32,
+    peer_tsn: u32,
+    peer_window: u32,
+    outbound_streams: u16,
+    inbound_streams: u16,
+}
+
+impl Cookie {
+    const SIZE: usize = 44;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    /// The fields, followed by their signature
+    fn sign(&self, key: &hmac::Key) -> Vec<u8> {
+        let mut cookie = [
+            &self.created.to_be_bytes()[..],
+            &self.id.local_address.0.to_be_bytes(),
+            &self.id.local_port.to_be_bytes(),
+            &self.id.peer_address.0.to_be_bytes(),
+            &self.id.peer_port.to_be_bytes(),
+            &self.local_tag.to_be_bytes(),
+            &self.peer_tag.to_be_bytes(),
+            &self.local_tsn.to_be_bytes(),
+            &self.peer_tsn.to_be_bytes(),
+            &self.peer_window.to_be_bytes(),
+            &self.outbound_streams.to_be_bytes(),
+            &self.inbound_streams.to_be_bytes(),
+        ]
+        .concat();
+        let signature = hmac::sign(key, &cookie);
+        cookie.extend(signature.as_ref());
+        cookie
+    }
+
+    /// Read a cookie echoed by a peer, failing unless it was signed with `key`
+    fn verify(cookie: &[u8], key: &hmac::Key) -> Option<Self> {
+        let (fields, signature) = cookie.split_at_checked(Self::SIZE)?;
+        hmac::verify(key, fields, signature).ok()?;
+        let u16_at = |offset: usize| {
+            u16::from_be_bytes(*unsafe { fields[offset..offset + 2].as_array_unchecked() })
+        };
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes(*unsafe { fields[offset..offset + 4].as_array_unchecked() })
+        };
+        Some(Self {
+            created: u64::from_be_bytes(*unsafe { fields[0..8].as_array_unchecked() }),
+            id: AssociationId {
+                local_address: IpV4Addr(u32_at(8)),
+                local_port: u16_at(12),
+                peer_address: IpV4Addr(u32_at(14)),
+                peer_port: u16_at(18),
+            },
+            local_tag: u32_at(20),
+            peer_tag: u32_at(24),
+            local_tsn: u32_at(28),
+            peer_tsn: u32_at(32),
+            peer_window: u32_at(36),
+            outbound_streams: u16_at(40),
+            inbound_streams: u16_at(42),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SCTPManager {
+    associations: HashMap<AssociationId, Association>,
+    /// Ports whose associations go to the application, and the associations not accepted yet
+    listeners: HashMap<u16, VecDeque<AssociationId>>,
+    /// Key signing the state cookies, drawn at start so that the cookies of a previous run are
+    /// refused
+    cookie_key: hmac::Key,
+    random: SystemRandom,
+}
+
+impl Default for SCTPManager {
+    fn default() -> Self {
+        let random = SystemRandom::new();
+        Self {
+            associations: HashMap::new(),
+            listeners: HashMap::new(),
+            cookie_key: hmac::Key::generate(hmac::HMAC_SHA256, &random)
+                .expect("no random source for the SCTP cookie key"),
+            random,
+        }
+    }
+}
+
+impl SCTPManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Random verification tag or initial TSN, which a tag cannot be 0
+    fn random_nonzero(&self) -> u32 {
+        let mut bytes = [0; 4];
+        self.random
+            .fill(&mut bytes)
+            .expect("no random source for the SCTP tags");
+        u32::from_be_bytes(bytes).max(1)
+    }
+
+    pub fn handle_sctp_packet(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        input: usize,
+    ) {
+        let Ok(ip_packet) = interface.try_get_packet::<IPV4PacketView>() else {
+            return;
+        };
+        let Ok(header) = SCTPHeaderView::parse(ip_packet.payload) else {
+            debug!("truncated SCTP packet dropped");
+            return;
+        };
+        if !verify_checksum(ip_packet.payload) {
+            debug!("SCTP packet with a wrong checksum dropped");
+            return;
+        }
+        let id = AssociationId::of(ip_packet.header, header);
+        let tag = header.get_verification_tag();
+        let chunks: Vec<Chunk> = chunks(&ip_packet.payload[SCTPHeaderView::SIZE..])
+            .map(|chunk| chunk.to_mutable())
+            .collect();
+        let Some(first) = chunks.first() else {
+            return;
+        };
+
+        match first.chunk_type {
+            // An INIT must be alone in its packet
+            INIT => return self.answer_init(interface, id, tag, first),
+            COOKIE_ECHO if !self.echo_cookie(interface, input, id, tag, first) => return,
+            _ => {}
+        }
+        let Some(association) = self.associations.get_mut(&id) else {
+            return answer_out_of_the_blue(interface, id, tag, &chunks);
+        };
+        // Only the ABORT and SHUTDOWN-COMPLETE sent before the peer knew our tag carry its own
+        let reflected = matches!(first.chunk_type, ABORT | SHUTDOWN_COMPLETE)
+            && first.flags & TAG_REFLECTED != 0;
+        let expected = match reflected {
+            true => association.peer_tag,
+            false => association.local_tag,
+        };
+        if tag != expected {
+            debug!(tag, "SCTP packet with a wrong verification tag dropped");
+            return;
+        }
+
+        let span = association.span.clone();
+        let _entered = span.enter();
+        association.handle_chunks(interface, &chunks);
+        if association.state == AssociationState::Closed {
+            self.associations.remove(&id);
+            info!("association closed");
+        }
+    }
+
+    /// Answer an INIT with an INIT-ACK holding everything the association needs in its cookie.
+    /// An INIT from a peer already associated, which restarted, is answered the same way, the
+    /// cookie replacing the association once echoed.
+    fn answer_init(
+        &self,
+        interface: &mut Interface<impl Read + Write>,
+        id: AssociationId,
+        tag: u32,
+        chunk: &Chunk,
+    ) {
+        let Some(init) = InitChunk::parse(&chunk.value)
+            .filter(|init| tag == 0 && init.initiate_tag != 0 && init.outbound_streams != 0)
+        else {
+            debug!("invalid INIT dropped");
+            return;
+        };
+        let cookie = Cookie {
+            created: Cookie::now(),
+            id,
+            local_tag: self.random_nonzero(),
+            peer_tag: init.initiate_tag,
+            local_tsn: self.random_nonzero(),
+            peer_tsn: init.initial_tsn,
+            peer_window: init.a_rwnd,
+            outbound_streams: STREAMS.min(init.inbound_streams),
+            inbound_streams: STREAMS.min(init.outbound_streams),
+        };
+        let init_ack = InitChunk {
+            initiate_tag: cookie.local_tag,
+            a_rwnd: RECEIVE_WINDOW,
+            outbound_streams: cookie.outbound_streams,
+            inbound_streams: STREAMS,
+            initial_tsn: cookie.local_tsn,
+            cookie: Some(cookie.sign(&self.cookie_key)),
+        };
+        debug!(%id, "INIT answered");
+        let peer_mac = interface.get_peer_mac();
+        send_chunks(
+            interface,
+            peer_mac,
+            id,
+            init.initiate_tag,
+            &mut [init_ack.to_chunk(INIT_ACK)],
+        );
+    }
+
+    /// Create the association of a valid echoed cookie, returning whether the packet goes on to
+    /// an association
+    fn echo_cookie(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        input: usize,
+        id: AssociationId,
+        tag: u32,
+        chunk: &Chunk,
+    ) -> bool {
+        let Some(cookie) = Cookie::verify(&chunk.value, &self.cookie_key)
+            .filter(|cookie| cookie.id == id && cookie.local_tag == tag)
+        else {
+            debug!("COOKIE-ECHO with an invalid cookie dropped");
+            return false;
+        };
+        if Cookie::now().saturating_sub(cookie.created) > COOKIE_LIFETIME.as_millis() as u64 {
+            debug!("COOKIE-ECHO with a stale cookie dropped");
+            return false;
+        }
+        // A COOKIE-ACK lost, the association answers the cookie again
+        if self.associations.get(&id).is_some_and(|association| {
+            (association.local_tag, association.peer_tag) == (cookie.local_tag, cookie.peer_tag)
+        }) {
+            return true;
+        }
+
+        let span = info_span!(
+            "association",
+            peer = %id.peer_address,
+            peer_port = id.peer_port,
+            port = id.local_port,
+        );
+        span.in_scope(|| info!("association established"));
+        let mut association = Association::new(
+            id,
+            input,
+            interface.get_peer_mac(),
+            interface.mtu,
+            (cookie.local_tag, cookie.local_tsn),
+            span,
+        );
+        association.state = AssociationState::Established;
+        association.set_peer(
+            cookie.peer_tag,
+            cookie.peer_tsn,
+            cookie.peer_window,
+            (cookie.outbound_streams, cookie.inbound_streams),
+        );
+        if let Some(queue) = self.listeners.get_mut(&id.local_port) {
+            association.socket = true;
+            queue.push_back(id);
+        }
+        self.associations.insert(id, association);
+        true
+    }
+
+    /// Set up an association with a peer at `peer_mac` on the interface `input`, the messages
+    /// sent in the meantime waiting for the handshake to be over
+    pub fn connect(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        input: usize,
+        peer_mac: MacAddr,
+        local_port: u16,
+        peer_address: IpV4Addr,
+        peer_port: u16,
+    ) -> AssociationId {
+        let id = AssociationId {
+            local_address: interface.config.address.unwrap_or_default(),
+            local_port,
+            peer_address,
+            peer_port,
+        };
+        let span = info_span!(
+            "association",
+            peer = %id.peer_address,
+            peer_port = id.peer_port,
+            port = id.local_port,
+        );
+        let local = (self.random_nonzero(), self.random_nonzero());
+        let mut association =
+            Association::new(id, input, peer_mac, interface.mtu, local, span.clone());
+        association.socket = true;
+        let init = InitChunk {
+            initiate_tag: association.local_tag,
+            a_rwnd: RECEIVE_WINDOW,
+            outbound_streams: STREAMS,
+            inbound_streams: STREAMS,
+            initial_tsn: association.next_tsn,
+            cookie: None,
+        };
+        span.in_scope(|| {
+            info!("association opened");
+            association.send_control(interface, init.to_chunk(INIT));
+        });
+        self.associations.insert(id, association);
+        id
+    }
+
+    /// Hand the associations to `port` to the application instead of echoing their messages,
+    /// returning false when the port is already listened to
+    pub fn listen(&mut self, port: u16) -> bool {
+        if self.listeners.contains_key(&port) {
+            return false;
+        }
+        self.listeners.insert(port, VecDeque::new());
+        true
+    }
+
+    /// Stop listening to `port`, shutting down the associations which were not accepted
+    pub fn unlisten(&mut self, port: u16) {
+        for id in self.listeners.remove(&port).into_iter().flatten() {
+            if let Some(association) = self.associations.get_mut(&id) {
+                association.close();
+            }
+        }
+    }
+
+    /// Take the oldest association to `port`
+    pub fn accept(&mut self, port: u16) -> Option<AssociationId> {
+        let queue = self.listeners.get_mut(&port)?;
+        queue.retain(|id| self.associations.contains_key(id));
+        queue.pop_front()
+    }
+
+    pub fn association(&mut self, id: AssociationId) -> Option<&mut Association> {
+        self.associations.get_mut(&id)
+    }
+
+    /// Earliest retransmission, which the main loop should not oversleep
+    pub fn next_timer(&self) -> Option<Instant> {
+        self.associations
+            .values()
+            .filter_map(|association| association.retransmit_deadline)
+            .min()
+    }
+
+    /// Run the retransmission timers and send the messages queued by the application, dropping
+    /// the associations whose peer is gone
+    pub fn poll(&mut self, interfaces: &mut [Interface<impl Read + Write>]) {
+        self.associations.retain(|_, association| {
+            let span = association.span.clone();
+            let _entered = span.enter();
+            if association.poll(&mut interfaces[association.interface]) {
+                return true;
+            }
+            info!("association dropped in {:?}", association.state);
+            false
+        });
+    }
+}
+
+/// Answer a packet belonging to no association with an ABORT, unless it is part of one closing
+/// (RFC 9260 §8.4)
+fn answer_out_of_the_blue(
+    interface: &mut Interface<impl Read + Write>,
+    id: AssociationId,
+    tag: u32,
+    chunks: &[Chunk],
+) {
+    let mut chunk_types = chunks.iter().map(|chunk| chunk.chunk_type);
+    let answer = if chunk_types
+        .clone()
+        .any(|chunk_type| matches!(chunk_type, ABORT | SHUTDOWN_COMPLETE | COOKIE_ACK | ERROR))
+    {
+        return;
+    } else if chunk_types.any(|chunk_type| chunk_type == SHUTDOWN_ACK) {
+        SHUTDOWN_COMPLETE
+    } else {
+        ABORT
+    };
+    debug!(%id, "SCTP packet out of the blue answered");
+    let peer_mac = interface.get_peer_mac();
+    send_chunks(
+        interface,
+        peer_mac,
+        id,
+        tag,
+        &mut [Chunk::new(answer, TAG_REFLECTED, vec![])],
+    );
+}
+
+/// Send chunks bundled in a packet of an association
+fn send_chunks(
+    interface: &mut Interface<impl Read + Write>,
+    peer_mac: MacAddr,
+    id: AssociationId,
+    tag: u32,
+    chunks: &mut [Chunk],
+) {
+    let ip_header = IPV4Header::new(IpProtocol::Sctp, id.local_address, id.peer_address);
+    let packet = SCTPPacket::with_chunks(SCTPHeader::new(id.local_port, id.peer_port, tag), chunks);
+    interface.write_frame(peer_mac, ETHERTYPE_IPV4, IPV4Packet::new(ip_header, packet));
+    interface.send();
+}
+
+/// Whether TSN `a` comes before `b`, in serial number arithmetic
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// A DATA chunk sent and not acknowledged yet
+#[derive(Debug, Clone)]
+struct InFlight {
+    chunk: DataChunk,
+    /// Reported received in a gap block, which the peer may still go back on
+    acked: bool,
+    /// SACKs which acknowledged later chunks and not this one
+    misses: u32,
+    /// Lost, to be sent again with the next packet
+    retransmit: bool,
+}
+
+/// Messages of a stream received before those preceding them
+#[derive(Debug, Clone, Default)]
+struct InboundStream {
+    next_ssn: u16,
+    ready: HashMap<u16, Message>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Association {
+    id: AssociationId,
+    /// Interface the association goes through, and link address of the peer on it
+    interface: usize,
+    peer_mac: MacAddr,
+    mtu: usize,
+    state: AssociationState,
+    /// Verification tags of the packets we receive and of those we send
+    local_tag: u32,
+    peer_tag: u32,
+    /// TSN of the next DATA chunk queued
+    next_tsn: u32,
+    /// Next SSN of each outbound stream
+    outbound_ssns: Vec<u16>,
+    /// DATA chunks waiting for room in the window of the peer, and those sent and not
+    /// acknowledged, both in TSN order
+    send_queue: VecDeque<DataChunk>,
+    in_flight: VecDeque<InFlight>,
+    /// Window advertised in the last SACK of the peer, from its cumulative TSN
+    peer_window: u32,
+    /// INIT, COOKIE-ECHO, SHUTDOWN or SHUTDOWN-ACK sent again until answered
+    pending_control: Option<Chunk>,
+    rto: Duration,
+    retransmit_deadline: Option<Instant>,
+    /// Timeouts in a row without any answer of the peer
+    retransmissions: u32,
+    /// TSN up to which every DATA chunk of the peer was received, and those received beyond it
+    cumulative_tsn: u32,
+    out_of_order: HashSet<u32>,
+    /// TSNs received again since the last SACK
+    duplicates: Vec<u32>,
+    /// Fragments of the messages not complete yet, by TSN
+    reassembly: HashMap<u32, DataChunk>,
+    inbound_streams: Vec<InboundStream>,
+    /// Messages delivered, waiting for the application
+    received: VecDeque<Message>,
+    /// Messages are read and written by the application, instead of being echoed
+    socket: bool,
+    /// Span of the events of this association
+    span: Span,
+}
+
+impl Association {
+    /// A new association, whose peer is known once `set_peer` is called
+    fn new(
+        id: AssociationId,
+        interface: usize,
+        peer_mac: MacAddr,
+        mtu: usize,
+        (local_tag, local_tsn): (u32, u32),
+        span: Span,
+    ) -> Self {
+        Self {
+            id,
+            interface,
+            peer_mac,
+            mtu,
+            state: AssociationState::CookieWait,
+            local_tag,
+            peer_tag: 0,
+            next_tsn: local_tsn,
+            outbound_ssns: vec![0; STREAMS as usize],
+            send_queue: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            peer_window: 0,
+            pending_control: None,
+            rto: RTO_INITIAL,
+            retransmit_deadline: None,
+            retransmissions: 0,
+            cumulative_tsn: 0,
+            out_of_order: HashSet::new(),
+            duplicates: vec![],
+            reassembly: HashMap::new(),
+            inbound_streams: vec![],
+            received: VecDeque::new(),
+            socket: false,
+            span,
+        }
+    }
+
+    fn set_peer(
+        &mut self,
+        peer_tag: u32,
+        peer_tsn: u32,
+        peer_window: u32,
+        (outbound_streams, inbound_streams): (u16, u16),
+    ) {
+        self.peer_tag = peer_tag;
+        self.cumulative_tsn = peer_tsn.wrapping_sub(1);
+        self.peer_window = peer_window;
+        self.outbound_ssns.truncate(outbound_streams as usize);
+        self.inbound_streams = vec![InboundStream::default(); inbound_streams as usize];
+        // Messages queued on streams the peer did not open are lost
+        self.send_queue
+            .retain(|chunk| chunk.stream < outbound_streams);
+    }
+
+    pub fn id(&self) -> AssociationId {
+        self.id
+    }
+
+    pub fn state(&self) -> AssociationState {
+        self.state
+    }
+
+    /// Number of streams messages can be sent on
+    pub fn outbound_streams(&self) -> u16 {
+        self.outbound_ssns.len() as u16
+    }
+
+    /// Queue a message for the peer, delivered after the previous messages of its stream
+    pub fn send(&mut self, stream: u16, ppid: u32, data: &[u8]) -> io::Result<()> {
+        self.queue(stream, ppid, data, 0)
+    }
+
+    /// Queue a message for the peer, delivered as soon as it is received
+    pub fn send_unordered(&mut self, stream: u16, ppid: u32, data: &[u8]) -> io::Result<()> {
+        self.queue(stream, ppid, data, UNORDERED)
+    }
+
+    /// Split a message in DATA chunks fitting in the MTU
+    fn queue(&mut self, stream: u16, ppid: u32, data: &[u8], flags: u8) -> io::Result<()> {
+        if !matches!(
+            self.state,
+            AssociationState::CookieWait
+                | AssociationState::CookieEchoed
+                | AssociationState::Established
+        ) {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        if data.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty SCTP message",
+            ));
+        }
+        let Some(next_ssn) = self.outbound_ssns.get_mut(stream as usize) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SCTP stream not open",
+            ));
+        };
+        let ssn = match flags & UNORDERED {
+            0 => mem::replace(next_ssn, next_ssn.wrapping_add(1)),
+            _ => 0,
+        };
+        let fragment_size = self.mtu - HEADERS_SIZE - DATA_HEADER_SIZE;
+        let fragments = data.chunks(fragment_size);
+        let last = fragments.len() - 1;
+        for (i, fragment) in fragments.enumerate() {
+            let mut fragment_flags = flags;
+            if i == 0 {
+                fragment_flags |= BEGINNING;
+            }
+            if i == last {
+                fragment_flags |= ENDING;
+            }
+            self.send_queue.push_back(DataChunk {
+                flags: fragment_flags,
+                tsn: self.next_tsn,
+                stream,
+                ssn,
+                ppid,
+                payload: fragment.to_vec(),
+            });
+            self.next_tsn = self.next_tsn.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    /// Take the oldest message delivered
+    pub fn receive(&mut self) -> Option<Message> {
+        self.received.pop_front()
+    }
+
+    /// Shut the association down once the messages queued are acknowledged
+    pub fn close(&mut self) {
+        match self.state {
+            AssociationState::Established => self.state = AssociationState::ShutdownPending,
+            AssociationState::CookieWait | AssociationState::CookieEchoed => {
+                self.state = AssociationState::Closed
+            }
+            _ => {}
+        }
+    }
+
+    /// Run the retransmission timer and send the messages queued, returning false when the
+    /// association is over
+    fn poll(&mut self, interface: &mut Interface<impl Read + Write>) -> bool {
+        if self.state == AssociationState::Closed {
+            return false;
+        }
+        let now = Instant::now();
+        if self
+            .retransmit_deadline
+            .is_some_and(|deadline| deadline <= now)
+        {
+            self.retransmissions += 1;
+            if self.retransmissions > MAX_RETRANSMISSIONS {
+                self.send_packet(interface, &mut [Chunk::new(ABORT, 0, vec![])]);
+                return false;
+            }
+            self.rto = (self.rto * 2).min(RTO_MAX);
+            self.retransmit_deadline = Some(now + self.rto);
+            if let Some(chunk) = self.pending_control.clone() {
+                debug!(chunk_type = chunk.chunk_type, "control chunk retransmitted");
+                self.send_packet(interface, &mut [chunk]);
+            } else {
+                debug!(chunks = self.in_flight.len(), "retransmission timeout");
+                for in_flight in self.in_flight.iter_mut().filter(|chunk| !chunk.acked) {
+                    in_flight.retransmit = true;
+                }
+            }
+        }
+        self.transmit(interface);
+        self.shutdown_when_done(interface);
+        true
+    }
+
+    fn handle_chunks(&mut self, interface: &mut Interface<impl Read + Write>, chunks: &[Chunk]) {
+        let mut replies = vec![];
+        let mut data_received = false;
+        for chunk in chunks {
+            match chunk.chunk_type {
+                DATA => {
+                    if let Some(data) = DataChunk::parse(chunk.flags, &chunk.value) {
+                        self.receive_data(data);
+                        data_received = true;
+                    }
+                }
+                INIT_ACK if self.state == AssociationState::CookieWait => {
+                    let Some((init_ack, cookie)) = InitChunk::parse(&chunk.value)
+                        .and_then(|init_ack| Some((init_ack.clone(), init_ack.cookie?)))
+                        .filter(|(init_ack, _)| init_ack.initiate_tag != 0)
+                    else {
+                        debug!("invalid INIT-ACK dropped");
+                        return;
+                    };
+                    self.set_peer(
+                        init_ack.initiate_tag,
+                        init_ack.initial_tsn,
+                        init_ack.a_rwnd,
+                        (
+                            self.outbound_streams().min(init_ack.inbound_streams),
+                            STREAMS.min(init_ack.outbound_streams),
+                        ),
+                    );
+                    self.state = AssociationState::CookieEchoed;
+                    self.retransmissions = 0;
+                    self.rto = RTO_INITIAL;
+                    self.send_control(interface, Chunk::new(COOKIE_ECHO, 0, cookie));
+                }
+                COOKIE_ECHO => replies.push(Chunk::new(COOKIE_ACK, 0, vec![])),
+                COOKIE_ACK if self.state == AssociationState::CookieEchoed => {
+                    info!("association established");
+                    self.state = AssociationState::Established;
+                    self.stop_control_timer();
+                }
+                SACK => {
+                    if let Some(sack) = SackChunk::parse(&chunk.value) {
+                        self.handle_sack(sack);
+                    }
+                }
+                HEARTBEAT => replies.push(Chunk::new(HEARTBEAT_ACK, 0, chunk.value.clone())),
+                ABORT => {
+                    info!("association aborted by the peer");
+                    self.state = AssociationState::Closed;
+                    return;
+                }
+                SHUTDOWN => {
+                    let Some(cumulative_tsn) = chunk.value.first_chunk() else {
+                        continue;
+                    };
+                    self.handle_sack(SackChunk {
+                        cumulative_tsn: u32::from_be_bytes(*cumulative_tsn),
+                        a_rwnd: self.peer_window,
+                        ..Default::default()
+                    });
+                    if matches!(
+                        self.state,
+                        AssociationState::Established
+                            | AssociationState::ShutdownPending
+                            | AssociationState::ShutdownSent
+                    ) {
+                        debug!("shutdown by the peer");
+                        self.state = AssociationState::ShutdownReceived;
+                        self.stop_control_timer();
+                    }
+                }
+                SHUTDOWN_ACK
+                    if matches!(
+                        self.state,
+                        AssociationState::ShutdownSent | AssociationState::ShutdownAckSent
+                    ) =>
+                {
+                    self.send_packet(interface, &mut [Chunk::new(SHUTDOWN_COMPLETE, 0, vec![])]);
+                    self.state = AssociationState::Closed;
+                    return;
+                }
+                SHUTDOWN_COMPLETE if self.state == AssociationState::ShutdownAckSent => {
+                    self.state = AssociationState::Closed;
+                    return;
+                }
+                ERROR => debug!(error = ?chunk.value, "error reported by the peer"),
+                INIT_ACK | COOKIE_ACK | HEARTBEAT_ACK | SHUTDOWN_ACK | SHUTDOWN_COMPLETE => {}
+                // The highest bit of an unknown type tells whether the rest can be processed
+                chunk_type if chunk_type & 0x80 == 0 => {
+                    debug!(chunk_type, "unknown chunk, rest of the packet dropped");
+                    break;
+                }
+                _ => {}
+            }
+        }
+        if data_received {
+            replies.push(self.sack().to_chunk());
+        }
+        if !replies.is_empty() {
+            self.send_packet(interface, &mut replies);
+        }
+        if !self.socket {
+            self.echo();
+        }
+        self.transmit(interface);
+        self.shutdown_when_done(interface);
+    }
+
+    fn send_packet(&self, interface: &mut Interface<impl Read + Write>, chunks: &mut [Chunk]) {
+        send_chunks(interface, self.peer_mac, self.id, self.peer_tag, chunks);
+    }
+
+    /// Send a chunk which is retransmitted until the peer answers it
+    fn send_control(&mut self, interface: &mut Interface<impl Read + Write>, chunk: Chunk) {
+        self.pending_control = Some(chunk.clone());
+        self.retransmit_deadline = Some(Instant::now() + self.rto);
+        // An INIT is sent before the tag of the peer is known
+        let tag = match chunk.chunk_type {
+            INIT => 0,
+            _ => self.peer_tag,
+        };
+        send_chunks(interface, self.peer_mac, self.id, tag, &mut [chunk]);
+    }
+
+    fn stop_control_timer(&mut self) {
+        self.pending_control = None;
+        self.retransmit_deadline = None;
+        self.retransmissions = 0;
+        self.rto = RTO_INITIAL;
+    }
+
+    /// Bytes sent and not acknowledged
+    fn outstanding(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|in_flight| !in_flight.acked)
+            .map(|in_flight| in_flight.chunk.payload.len())
+            .sum()
+    }
+
+    /// Bytes received and not read by the application, which the window leaves out
+    fn buffered(&self) -> usize {
+        let fragments = self.reassembly.values().map(|chunk| chunk.payload.len());
+        let ready = self
+            .inbound_streams
+            .iter()
+            .flat_map(|stream| stream.ready.values())
+            .chain(&self.received)
+            .map(|message| message.data.len());
+        fragments.chain(ready).sum()
+    }
+
+    fn is_received(&self, tsn: u32) -> bool {
+        !before(self.cumulative_tsn, tsn) || self.out_of_order.contains(&tsn)
+    }
+
+    fn receive_data(&mut self, data: DataChunk) {
+        let tsn = data.tsn;
+        if self.is_received(tsn) {
+            self.duplicates.push(tsn);
+            return;
+        }
+        // Every DATA takes at least a byte of the window, which no more TSNs can follow
+        if self.buffered() + data.payload.len() > RECEIVE_WINDOW as usize
+            || tsn.wrapping_sub(self.cumulative_tsn) > RECEIVE_WINDOW
+        {
+            debug!(tsn, "DATA beyond the receive window dropped");
+            return;
+        }
+        if tsn == self.cumulative_tsn.wrapping_add(1) {
+            self.cumulative_tsn = tsn;
+            while self
+                .out_of_order
+                .remove(&self.cumulative_tsn.wrapping_add(1))
+            {
+                self.cumulative_tsn = self.cumulative_tsn.wrapping_add(1);
+            }
+        } else {
+            self.out_of_order.insert(tsn);
+        }
+        if data.stream as usize >= self.inbound_streams.len() {
+            debug!(stream = data.stream, "DATA on a stream not open dropped");
+            return;
+        }
+        self.reassembly.insert(tsn, data);
+        self.reassemble(tsn);
+    }
+
+    /// Deliver the message of a fragment once all its fragments are received, which have
+    /// consecutive TSNs from the one flagged as its beginning to the one flagged as its ending
+    fn reassemble(&mut self, tsn: u32) {
+        let stream = self.reassembly[&tsn].stream;
+        let fragment_of = |chunk: Option<&DataChunk>, flag| {
+            chunk.is_some_and(|chunk| chunk.stream == stream && chunk.flags & flag == 0)
+        };
+        let mut first = tsn;
+        while self.reassembly[&first].flags & BEGINNING == 0 {
+            if !fragment_of(self.reassembly.get(&first.wrapping_sub(1)), ENDING) {
+                return;
+            }
+            first = first.wrapping_sub(1);
+        }
+        let mut last = tsn;
+        while self.reassembly[&last].flags & ENDING == 0 {
+            if !fragment_of(self.reassembly.get(&last.wrapping_add(1)), BEGINNING) {
+                return;
+            }
+            last = last.wrapping_add(1);
+        }
+
+        let fragments: Vec<_> = (0..=last.wrapping_sub(first))
+            .filter_map(|i| self.reassembly.remove(&first.wrapping_add(i)))
+            .collect();
+        let message = Message {
+            stream,
+            ppid: fragments[0].ppid,
+            data: fragments
+                .iter()
+                .flat_map(|fragment| &fragment.payload)
+                .copied()
+                .collect(),
+        };
+        if fragments[0].flags & UNORDERED != 0 {
+            self.received.push_back(message);
+            return;
+        }
+        let inbound = &mut self.inbound_streams[stream as usize];
+        inbound.ready.insert(fragments[0].ssn, message);
+        while let Some(message) = inbound.ready.remove(&inbound.next_ssn) {
+            self.received.push_back(message);
+            inbound.next_ssn = inbound.next_ssn.wrapping_add(1);
+        }
+    }
+
+    /// Acknowledgement of the DATA received, with the ranges received beyond the cumulative TSN
+    /// that 16 bits offsets reach
+    fn sack(&mut self) -> SackChunk {
+        let mut offsets: Vec<_> = self
+            .out_of_order
+            .iter()
+            .map(|tsn| tsn.wrapping_sub(self.cumulative_tsn))
+            .collect();
+        offsets.sort_unstable();
+        let mut blocks: Vec<(u32, u32)> = vec![];
+        for offset in offsets {
+            match blocks.last_mut() {
+                Some((_, end)) if *end + 1 == offset => *end = offset,
+                _ => blocks.push((offset, offset)),
+            }
+        }
+        let gap_blocks = blocks
+            .into_iter()
+            .filter(|(start, _)| *start <= u16::MAX as u32)
+            .map(|(start, end)| (start as u16, end.min(u16::MAX as u32) as u16))
+            .collect();
+        SackChunk {
+            cumulative_tsn: self.cumulative_tsn,
+            a_rwnd: RECEIVE_WINDOW.saturating_sub(self.buffered() as u32),
+            gap_blocks,
+            duplicates: mem::take(&mut self.duplicates),
+        }
+    }
+
+    fn handle_sack(&mut self, sack: SackChunk) {
+        let mut progress = false;
+        while let Some(in_flight) = self.in_flight.front()
+            && !before(sack.cumulative_tsn, in_flight.chunk.tsn)
+        {
+            self.in_flight.pop_front();
+            progress = true;
+        }
+        let offset = |tsn: u32| tsn.wrapping_sub(sack.cumulative_tsn);
+        let highest_acked = sack.gap_blocks.iter().map(|(_, end)| *end as u32).max();
+        for in_flight in &mut self.in_flight {
+            let offset = offset(in_flight.chunk.tsn);
+            let acked = sack
+                .gap_blocks
+                .iter()
+                .any(|(start, end)| (*start as u32..=*end as u32).contains(&offset));
+            progress |= acked && !in_flight.acked;
+            in_flight.acked = acked;
+            // Later chunks got through while this one did not
+            if !acked && highest_acked.is_some_and(|highest| offset < highest) {
+                in_flight.misses += 1;
+                if in_flight.misses == FAST_RETRANSMIT_MISSES {
+                    debug!(tsn = in_flight.chunk.tsn, "fast retransmission");
+                    in_flight.retransmit = true;
+                }
+            }
+        }
+        self.peer_window = sack.a_rwnd;
+        if self.pending_control.is_some() {
+            return;
+        }
+        if self.in_flight.iter().all(|in_flight| in_flight.acked) {
+            self.retransmit_deadline = None;
+        } else if progress {
+            self.retransmissions = 0;
+            self.rto = RTO_INITIAL;
+            self.retransmit_deadline = Some(Instant::now() + self.rto);
+        }
+    }
+
+    /// Send back the messages received, on their stream
+    fn echo(&mut self) {
+        while let Some(message) = self.received.pop_front() {
+            if let Err(error) = self.send(message.stream, message.ppid, &message.data) {
+                debug!(%error, "message not echoed");
+            }
+        }
+    }
+
+    /// Send the chunks to retransmit and as many new ones as the window of the peer takes,
+    /// bundled up to the MTU
+    fn transmit(&mut self, interface: &mut Interface<impl Read + Write>) {
+        if !matches!(
+            self.state,
+            AssociationState::Established
+                | AssociationState::ShutdownPending
+                | AssociationState::ShutdownReceived
+        ) {
+            return;
+        }
+        let mut chunks = vec![];
+        for in_flight in self.in_flight.iter_mut().filter(|chunk| chunk.retransmit) {
+            in_flight.retransmit = false;
+            in_flight.misses = 0;
+            chunks.push(in_flight.chunk.to_chunk());
+        }
+        while let Some(data) = self.send_queue.front() {
+            // A single chunk may go beyond the window when nothing is in flight, probing it
+            let outstanding = self.outstanding();
+            if outstanding > 0 && outstanding + data.payload.len() > self.peer_window as usize {
+                break;
+            }
+            let Some(data) = self.send_queue.pop_front() else {
+                break;
+            };
+            chunks.push(data.to_chunk());
+            self.in_flight.push_back(InFlight {
+                chunk: data,
+                acked: false,
+                misses: 0,
+                retransmit: false,
+            });
+        }
+        if chunks.is_empty() {
+            return;
+        }
+
+        let room = self.mtu - HEADERS_SIZE;
+        let mut packet = vec![];
+        let mut size = 0;
+        for chunk in chunks {
+            if size + chunk.size() > room && !packet.is_empty() {
+                self.send_packet(interface, &mut packet);
+                packet.clear();
+                size = 0;
+            }
+            size += chunk.size();
+            packet.push(chunk);
+        }
+        self.send_packet(interface, &mut packet);
+        if self.retransmit_deadline.is_none() {
+            self.retransmit_deadline = Some(Instant::now() + self.rto);
+        }
+    }
+
+    /// Send the SHUTDOWN or SHUTDOWN-ACK waiting for the data queued to be acknowledged
+    fn shutdown_when_done(&mut self, interface: &mut Interface<impl Read + Write>) {
+        if !self.send_queue.is_empty() || !self.in_flight.is_empty() {
+            return;
+        }
+        let chunk = match self.state {
+            AssociationState::ShutdownPending => {
+                self.state = AssociationState::ShutdownSent;
+                Chunk::new(SHUTDOWN, 0, self.cumulative_tsn.to_be_bytes().to_vec())
+            }
+            AssociationState::ShutdownReceived => {
+                self.state = AssociationState::ShutdownAckSent;
+                Chunk::new(SHUTDOWN_ACK, 0, vec![])
+            }
+            _ => return,
+        };
+        self.send_control(interface, chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interface::InterfaceConfig, test_support::Wire, traits::WriteTo};
+
+    const SERVER: IpV4Addr = IpV4Addr(0xC0A8_0002);
+    const CLIENT: IpV4Addr = IpV4Addr(0xC0A8_0001);
+
+    fn interface(address: IpV4Addr) -> (Wire, Interface<Wire>) {
+        let wire = Wire::default();
+        let mut interface = Interface::new(wire.clone());
+        interface.config = InterfaceConfig {
+            address: Some(address),
+            ..Default::default()
+        };
+        (wire, interface)
+    }
+
+    /// Hand the frames sent on a wire to the manager at its other end, returning how many there
+    /// were
+    fn deliver(wire: &Wire, interface: &mut Interface<Wire>, manager: &mut SCTPManager) -> usize {
+        let frames: Vec<_> = wire.sent.borrow_mut().drain(..).collect();
+        for frame in &frames {
+            interface.receive_frame(frame);
+            manager.handle_sctp_packet(interface, 0);
+        }
+        frames.len()
+    }
+
+    #[test]
+    fn echo_on_several_streams() {
+        let (client_wire, mut client_interface) = interface(CLIENT);
+        let (server_wire, mut server_interface) = interface(SERVER);
+        let mut client = SCTPManager::new();
+        let mut server = SCTPManager::new();
+        let id = client.connect(
+            &mut client_interface,
+            0,
+            MacAddr::default(),
+            5000,
+            SERVER,
+            7,
+        );
+        let association = client.association(id).unwrap();
+        // Queued during the handshake, and split in three chunks by the MTU
+        let large: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+        association.send(3, 42, &large).unwrap();
+        association.send(0, 0, b"first").unwrap();
+        association.send_unordered(1, 0, b"urgent").unwrap();
+        assert!(association.send(STREAMS, 0, b"nowhere").is_err());
+
+        let mut exchanges = 0;
+        while deliver(&client_wire, &mut server_interface, &mut server)
+            + deliver(&server_wire, &mut client_interface, &mut client)
+            > 0
+        {
+            client.poll(slice(&mut client_interface));
+            exchanges += 1;
+            assert!(exchanges < 20);
+        }
+        let association = client.association(id).unwrap();
+        assert_eq!(association.state(), AssociationState::Established);
+        let mut messages: Vec<_> = std::iter::from_fn(|| association.receive()).collect();
+        messages.sort_by_key(|message| message.stream);
+        assert_eq!(
+            messages,
+            [
+                Message {
+                    stream: 0,
+                    ppid: 0,
+                    data: b"first".to_vec()
+                },
+                Message {
+                    stream: 1,
+                    ppid: 0,
+                    data: b"urgent".to_vec()
+                },
+                Message {
+                    stream: 3,
+                    ppid: 42,
+                    data: large
+                },
+            ]
+        );
+
+        association.close();
+        client.poll(slice(&mut client_interface));
+        while deliver(&client_wire, &mut server_interface, &mut server)
+            + deliver(&server_wire, &mut client_interface, &mut client)
+            > 0
+        {}
+        assert!(client.association(id).is_none());
+        assert!(server.associations.is_empty());
+    }
+
+    fn slice(interface: &mut Interface<Wire>) -> &mut [Interface<Wire>] {
+        std::slice::from_mut(interface)
+    }
+
+    #[test]
+    fn streams_delivered_independently() {
+        let id = AssociationId {
+            local_address: SERVER,
+            local_port: 7,
+            peer_address: CLIENT,
+            peer_port: 5000,
+        };
+        let mut association =
+            Association::new(id, 0, MacAddr::default(), 1500, (1, 1), Span::none());
+        association.set_peer(2, 10, RECEIVE_WINDOW, (STREAMS, STREAMS));
+        let data = |tsn, stream, ssn, flags| DataChunk {
+            flags,
+            tsn,
+            stream,
+            ssn,
+            ppid: 0,
+            payload: vec![tsn as u8],
+        };
+        let whole = BEGINNING | ENDING;
+        // The first message of stream 1 is lost, the second one of stream 1 and the one of
+        // stream 2 arrive
+        association.receive_data(data(11, 1, 1, whole));
+        association.receive_data(data(12, 2, 0, whole));
+        association.receive_data(data(14, 2, 1, ENDING));
+        association.receive_data(data(12, 2, 0, whole));
+        assert_eq!(
+            association.receive().map(|message| message.data),
+            Some(vec![12])
+        );
+        assert_eq!(association.receive(), None);
+        let sack = association.sack();
+        assert_eq!(sack.cumulative_tsn, 9);
+        assert_eq!(sack.gap_blocks, [(2, 3), (5, 5)]);
+        assert_eq!(sack.duplicates, [12]);
+
+        association.receive_data(data(13, 2, 1, BEGINNING));
+        association.receive_data(data(10, 1, 0, whole));
+        let messages: Vec<_> = std::iter::from_fn(|| association.receive())
+            .map(|message| (message.stream, message.data))
+            .collect();
+        assert_eq!(messages, [(2, vec![13, 14]), (1, vec![10]), (1, vec![11])]);
+        assert_eq!(association.sack().cumulative_tsn, 14);
+    }
+
+    #[test]
+    fn far_ahead_tsns_dropped() {
+        let id = AssociationId {
+            local_address: SERVER,
+            local_port: 7,
+            peer_address: CLIENT,
+            peer_port: 5000,
+        };
+        let mut association =
+            Association::new(id, 0, MacAddr::default(), 1500, (1, 1), Span::none());
+        association.set_peer(2, 10, RECEIVE_WINDOW, (STREAMS, STREAMS));
+        let data = |tsn: u32| DataChunk {
+            flags: BEGINNING | ENDING,
+            tsn,
+            stream: 0,
+            ssn: 0,
+            ppid: 0,
+            payload: vec![0],
+        };
+        // Beyond the window, and far enough for the offset not to fit in 16 bits
+        association.receive_data(data(9 + RECEIVE_WINDOW + 1));
+        association.receive_data(data(9 + 0x1_0005));
+        assert!(association.out_of_order.is_empty());
+        assert!(association.sack().gap_blocks.is_empty());
+
+        // The last TSN of the window is kept, its block clamped to the largest offset
+        association.receive_data(data(9 + RECEIVE_WINDOW - 1));
+        association.receive_data(data(9 + RECEIVE_WINDOW));
+        association.receive_data(data(9 + 0xFFFE));
+        assert_eq!(association.sack().gap_blocks, [(0xFFFE, 0xFFFF)]);
+
+        // A TSN wrapping around is as far from the cumulative TSN as the others
+        let mut association =
+            Association::new(id, 0, MacAddr::default(), 1500, (1, 1), Span::none());
+        association.set_peer(2, u32::MAX - 1, RECEIVE_WINDOW, (STREAMS, STREAMS));
+        association.receive_data(data(1));
+        assert_eq!(association.sack().gap_blocks, [(4, 4)]);
+    }
+
+    #[test]
+    fn out_of_the_blue_packets_aborted() {
+        let (server_wire, mut server_interface) = interface(SERVER);
+        let mut server = SCTPManager::new();
+        let data = DataChunk {
+            flags: BEGINNING | ENDING,
+            tsn: 1,
+            stream: 0,
+            ssn: 0,
+            ppid: 0,
+            payload: b"hello".to_vec(),
+        };
+        let packet =
+            SCTPPacket::with_chunks(SCTPHeader::new(5000, 7, 0xABCD), &mut [data.to_chunk()]);
+        let packet = IPV4Packet::new(IPV4Header::new(IpProtocol::Sctp, CLIENT, SERVER), packet)
+            .to_bytes()
+            .unwrap();
+        server_interface.receive_frame(&[&[0, 0, 8, 0][..], &packet].concat());
+        server.handle_sctp_packet(&mut server_interface, 0);
+
+        let frames = server_wire.sent.borrow();
+        let ip_packet = IPV4PacketView::try_from(&frames[0][4..]).unwrap();
+        let header = SCTPHeaderView::parse(ip_packet.payload).unwrap();
+        assert!(verify_checksum(ip_packet.payload));
+        assert_eq!(header.get_verification_tag(), 0xABCD);
+        let abort = chunks(&ip_packet.payload[SCTPHeaderView::SIZE..])
+            .next()
+            .unwrap();
+        assert_eq!(
+            (abort.get_chunk_type(), abort.get_flags()),
+            (ABORT, TAG_REFLECTED)
+        );
+    }
+}
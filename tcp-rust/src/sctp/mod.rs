@@ -0,0 +1,432 @@
+//! SCTP packets (RFC 9260): a common header followed by chunks, each made of a type, flags and a
+//! length, and padded to 4 bytes. Unlike TCP and UDP, the whole packet is covered by a CRC32c
+//! rather than by the Internet checksum of a pseudo header.
+
+pub mod manager;
+
+use std::io::{self, Write};
+
+use tcp_rust_macros::PacketHeader;
+
+use crate::{
+    checksum::Crc32c,
+    packet::{Packet, PacketView, ParseHeaderError},
+    traits::{AsArrayUnchecked, Data, DataOwned, Prepare, ToMutable, WriteTo},
+};
+
+pub const DATA: u8 = 0;
+pub const INIT: u8 = 1;
+pub const INIT_ACK: u8 = 2;
+pub const SACK: u8 = 3;
+pub const HEARTBEAT: u8 = 4;
+pub const HEARTBEAT_ACK: u8 = 5;
+pub const ABORT: u8 = 6;
+pub const SHUTDOWN: u8 = 7;
+pub const SHUTDOWN_ACK: u8 = 8;
+pub const ERROR: u8 = 9;
+pub const COOKIE_ECHO: u8 = 10;
+pub const COOKIE_ACK: u8 = 11;
+pub const SHUTDOWN_COMPLETE: u8 = 14;
+
+/// Flags of the DATA chunks
+pub const UNORDERED: u8 = 0x04;
+pub const BEGINNING: u8 = 0x02;
+pub const ENDING: u8 = 0x01;
+/// Flag of the ABORT and SHUTDOWN-COMPLETE chunks sent with the verification tag of the packet
+/// they answer, instead of the one of the peer
+pub const TAG_REFLECTED: u8 = 0x01;
+
+/// Parameter of an INIT-ACK holding the state cookie
+pub const STATE_COOKIE: u16 = 7;
+
+/// Size of the fields of a DATA chunk before its user data
+pub const DATA_HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, PacketHeader)]
+pub struct SCTPHeader {
+    pub source_port: u16,
+    pub destination_port: u16,
+    /// Tag chosen by the receiver when the association was set up, 0 for an INIT
+    pub verification_tag: u32,
+    /// CRC32c, which is little endian unlike every other field
+    pub checksum: u32,
+}
+
+impl SCTPHeader {
+    pub fn new(source_port: u16, destination_port: u16, verification_tag: u32) -> Self {
+        Self {
+            source_port,
+            destination_port,
+            verification_tag,
+            checksum: 0,
+        }
+    }
+}
+
+pub type SCTPPacket<C = Vec<u8>> = Packet<SCTPHeader, C>;
+pub type SCTPPacketView<'a, C = &'a [u8]> = PacketView<'a, SCTPHeaderView<'a>, C>;
+
+impl SCTPPacket {
+    /// Bundle chunks in a packet, in their order
+    pub fn with_chunks(header: SCTPHeader, chunks: &mut [Chunk]) -> Self {
+        let mut payload = Vec::with_capacity(chunks.iter().map(Chunk::size).sum());
+        for chunk in chunks {
+            chunk.write_to(&mut payload).unwrap();
+        }
+        Self::new(header, payload)
+    }
+}
+
+impl<C: DataOwned> Prepare for SCTPPacket<C> {
+    /// The CRC32c is computed over the packet with a zero checksum field
+    fn prepare(&mut self) {
+        self.header.prepare();
+        self.payload.prepare();
+        self.header.checksum = 0;
+        let mut bytes = Vec::with_capacity(self.size());
+        self.write_to_inner(&mut bytes).unwrap();
+        self.header.checksum = Crc32c::new().add_slice(&bytes).finish().swap_bytes();
+    }
+}
+
+/// Whether the CRC32c of a received packet matches its content
+pub fn verify_checksum(packet: &[u8]) -> bool {
+    let (Some(header), Some(chunks)) = (packet.get(..8), packet.get(12..)) else {
+        return false;
+    };
+    let checksum = u32::from_le_bytes(*unsafe { packet[8..12].as_array_unchecked() });
+    Crc32c::new()
+        .add_slice(header)
+        .add_slice(&[0; 4])
+        .add_slice(chunks)
+        .finish()
+        == checksum
+}
+
+/// A chunk of a received packet, without its padding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkView<'a> {
+    content: &'a [u8],
+}
+
+impl<'a> ChunkView<'a> {
+    /// Parse a chunk, failing if its length does not fit in the slice
+    pub fn parse(value: &'a [u8]) -> Result<Self, ParseHeaderError> {
+        let length = value
+            .get(2..4)
+            .map(|length| u16::from_be_bytes(*unsafe { length.as_array_unchecked() }) as usize)
+            .ok_or(ParseHeaderError)?;
+        match value.get(..length) {
+            Some(content) if length >= 4 => Ok(Self { content }),
+            _ => Err(ParseHeaderError),
+        }
+    }
+
+    pub fn get_chunk_type(&self) -> u8 {
+        self.content[0]
+    }
+    pub fn get_flags(&self) -> u8 {
+        self.content[1]
+    }
+    pub fn get_length(&self) -> u16 {
+        self.content.len() as u16
+    }
+    pub fn get_value(&self) -> &'a [u8] {
+        &self.content[4..]
+    }
+}
+
+impl ToMutable for ChunkView<'_> {
+    type MutableType = Chunk;
+
+    fn to_mutable(&self) -> Self::MutableType {
+        Chunk::new(
+            self.get_chunk_type(),
+            self.get_flags(),
+            self.get_value().to_vec(),
+        )
+    }
+}
+
+/// Chunks of the payload of a packet, up to the first malformed one
+#[derive(Debug, Clone)]
+pub struct Chunks<'a>(&'a [u8]);
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = ChunkView<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = ChunkView::parse(self.0).ok()?;
+        let padded = (chunk.get_length() as usize).next_multiple_of(4);
+        self.0 = self.0.get(padded..).unwrap_or_default();
+        Some(chunk)
+    }
+}
+
+pub fn chunks(payload: &[u8]) -> Chunks<'_> {
+    Chunks(payload)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub chunk_type: u8,
+    pub flags: u8,
+    pub value: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn new(chunk_type: u8, flags: u8, value: Vec<u8>) -> Self {
+        Self {
+            chunk_type,
+            flags,
+            value,
+        }
+    }
+}
+
+impl Data for Chunk {
+    fn size(&self) -> usize {
+        (4 + self.value.len()).next_multiple_of(4)
+    }
+}
+
+impl Prepare for Chunk {}
+
+impl WriteTo for Chunk {
+    fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        let length = 4 + self.value.len();
+        writer.write_all(&[self.chunk_type, self.flags])?;
+        writer.write_all(&(length as u16).to_be_bytes())?;
+        writer.write_all(&self.value)?;
+        writer.write_all(&[0; 3][..self.size() - length])?;
+        Ok(self.size())
+    }
+}
+
+/// Parameters of INIT and INIT-ACK chunks, which share the layout of the chunks with a 16 bits
+/// type, up to the first malformed one
+pub fn parameters(mut value: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let header = value.get(..4)?;
+        let parameter_type = u16::from_be_bytes(*unsafe { header[0..2].as_array_unchecked() });
+        let length = u16::from_be_bytes(*unsafe { header[2..4].as_array_unchecked() }) as usize;
+        let parameter = value.get(4..length)?;
+        value = value.get(length.next_multiple_of(4)..).unwrap_or_default();
+        Some((parameter_type, parameter))
+    })
+}
+
+/// The fields of an INIT or INIT-ACK chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitChunk {
+    /// Verification tag the sender expects in the packets it receives
+    pub initiate_tag: u32,
+    /// Receive window of the sender
+    pub a_rwnd: u32,
+    pub outbound_streams: u16,
+    /// Most streams the sender accepts
+    pub inbound_streams: u16,
+    pub initial_tsn: u32,
+    /// State cookie of an INIT-ACK, the other parameters being ignored
+    pub cookie: Option<Vec<u8>>,
+}
+
+impl InitChunk {
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let fields = value.get(..16)?;
+        let cookie = parameters(&value[16..])
+            .find(|(parameter_type, _)| *parameter_type == STATE_COOKIE)
+            .map(|(_, cookie)| cookie.to_vec());
+        Some(Self {
+            initiate_tag: u32::from_be_bytes(*unsafe { fields[0..4].as_array_unchecked() }),
+            a_rwnd: u32::from_be_bytes(*unsafe { fields[4..8].as_array_unchecked() }),
+            outbound_streams: u16::from_be_bytes(*unsafe { fields[8..10].as_array_unchecked() }),
+            inbound_streams: u16::from_be_bytes(*unsafe { fields[10..12].as_array_unchecked() }),
+            initial_tsn: u32::from_be_bytes(*unsafe { fields[12..16].as_array_unchecked() }),
+            cookie,
+        })
+    }
+
+    pub fn to_chunk(&self, chunk_type: u8) -> Chunk {
+        let mut value = [
+            &self.initiate_tag.to_be_bytes()[..],
+            &self.a_rwnd.to_be_bytes(),
+            &self.outbound_streams.to_be_bytes(),
+            &self.inbound_streams.to_be_bytes(),
+            &self.initial_tsn.to_be_bytes(),
+        ]
+        .concat();
+        if let Some(cookie) = &self.cookie {
+            value.extend(STATE_COOKIE.to_be_bytes());
+            value.extend((4 + cookie.len() as u16).to_be_bytes());
+            value.extend(cookie);
+        }
+        Chunk::new(chunk_type, 0, value)
+    }
+}
+
+/// A fragment of a message, the whole message when flagged as both its beginning and its ending
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataChunk {
+    pub flags: u8,
+    pub tsn: u32,
+    pub stream: u16,
+    /// Stream sequence number, the order of the message in its stream unless it is unordered
+    pub ssn: u16,
+    /// Payload protocol identifier, carried for the application
+    pub ppid: u32,
+    pub payload: Vec<u8>,
+}
+
+impl DataChunk {
+    /// Parse the value of a DATA chunk, which is invalid without user data
+    pub fn parse(flags: u8, value: &[u8]) -> Option<Self> {
+        let fields = value.get(..12)?;
+        if value.len() == 12 {
+            return None;
+        }
+        Some(Self {
+            flags,
+            tsn: u32::from_be_bytes(*unsafe { fields[0..4].as_array_unchecked() }),
+            stream: u16::from_be_bytes(*unsafe { fields[4..6].as_array_unchecked() }),
+            ssn: u16::from_be_bytes(*unsafe { fields[6..8].as_array_unchecked() }),
+            ppid: u32::from_be_bytes(*unsafe { fields[8..12].as_array_unchecked() }),
+            payload: value[12..].to_vec(),
+        })
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let value = [
+            &self.tsn.to_be_bytes()[..],
+            &self.stream.to_be_bytes(),
+            &self.ssn.to_be_bytes(),
+            &self.ppid.to_be_bytes(),
+            &self.payload,
+        ]
+        .concat();
+        Chunk::new(DATA, self.flags, value)
+    }
+}
+
+/// Acknowledgement of the DATA chunks received
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SackChunk {
+    /// Last TSN up to which every chunk was received
+    pub cumulative_tsn: u32,
+    pub a_rwnd: u32,
+    /// Ranges of TSNs received beyond the cumulative one, as offsets from it
+    pub gap_blocks: Vec<(u16, u16)>,
+    /// TSNs received more than once since the last SACK
+    pub duplicates: Vec<u32>,
+}
+
+impl SackChunk {
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let fields = value.get(..12)?;
+        let gap_blocks = u16::from_be_bytes(*unsafe { fields[8..10].as_array_unchecked() });
+        let duplicates = u16::from_be_bytes(*unsafe { fields[10..12].as_array_unchecked() });
+        let gap_blocks = value
+            .get(12..12 + 4 * gap_blocks as usize)?
+            .chunks_exact(4)
+            .map(|block| {
+                (
+                    u16::from_be_bytes(*unsafe { block[0..2].as_array_unchecked() }),
+                    u16::from_be_bytes(*unsafe { block[2..4].as_array_unchecked() }),
+                )
+            })
+            .collect::<Vec<_>>();
+        let offset = 12 + 4 * gap_blocks.len();
+        let duplicates = value
+            .get(offset..offset + 4 * duplicates as usize)?
+            .chunks_exact(4)
+            .map(|tsn| u32::from_be_bytes(*unsafe { tsn.as_array_unchecked() }))
+            .collect();
+        Some(Self {
+            cumulative_tsn: u32::from_be_bytes(*unsafe { fields[0..4].as_array_unchecked() }),
+            a_rwnd: u32::from_be_bytes(*unsafe { fields[4..8].as_array_unchecked() }),
+            gap_blocks,
+            duplicates,
+        })
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let mut value = [
+            &self.cumulative_tsn.to_be_bytes()[..],
+            &self.a_rwnd.to_be_bytes(),
+            &(self.gap_blocks.len() as u16).to_be_bytes(),
+            &(self.duplicates.len() as u16).to_be_bytes(),
+        ]
+        .concat();
+        for (start, end) in &self.gap_blocks {
+            value.extend(start.to_be_bytes());
+            value.extend(end.to_be_bytes());
+        }
+        for tsn in &self.duplicates {
+            value.extend(tsn.to_be_bytes());
+        }
+        Chunk::new(SACK, 0, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32c_check_values() {
+        assert_eq!(Crc32c::new().add_slice(b"123456789").finish(), 0xE306_9283);
+        // iSCSI test pattern of RFC 3720 §B.4, fed in two pieces
+        let crc = Crc32c::new().add_slice(&[0; 12]).add_slice(&[0; 20]);
+        assert_eq!(crc.finish(), 0x8A91_36AA);
+    }
+
+    #[test]
+    fn chunks_of_a_packet() {
+        let init = InitChunk {
+            initiate_tag: 0x1234_5678,
+            a_rwnd: 65536,
+            outbound_streams: 10,
+            inbound_streams: 2048,
+            initial_tsn: 7,
+            cookie: Some(b"cookie!".to_vec()),
+        };
+        let data = DataChunk {
+            flags: BEGINNING | ENDING,
+            tsn: 7,
+            stream: 3,
+            ssn: 0,
+            ppid: 51,
+            payload: b"hello".to_vec(),
+        };
+        let mut packet = SCTPPacket::with_chunks(
+            SCTPHeader::new(5000, 36412, 0),
+            &mut [init.to_chunk(INIT_ACK), data.to_chunk()],
+        );
+        let bytes = packet.to_bytes().unwrap();
+        assert!(verify_checksum(&bytes));
+        let mut corrupted = bytes.clone();
+        corrupted[20] ^= 1;
+        assert!(!verify_checksum(&corrupted));
+
+        let packet = SCTPPacketView::try_from(&bytes[..]).unwrap();
+        assert_eq!(packet.header.get_destination_port(), 36412);
+        let chunks: Vec<_> = chunks(packet.payload).collect();
+        assert_eq!(chunks.len(), 2);
+        // The cookie parameter is padded to 4 bytes, its length is not
+        assert_eq!(chunks[0].get_length(), 4 + 16 + 11);
+        assert_eq!(InitChunk::parse(chunks[0].get_value()), Some(init));
+        assert_eq!(chunks[1].get_chunk_type(), DATA);
+        assert_eq!(
+            DataChunk::parse(chunks[1].get_flags(), chunks[1].get_value()),
+            Some(data)
+        );
+
+        let sack = SackChunk {
+            cumulative_tsn: 9,
+            a_rwnd: 1000,
+            gap_blocks: vec![(2, 3), (5, 5)],
+            duplicates: vec![4],
+        };
+        assert_eq!(SackChunk::parse(&sack.to_chunk().value), Some(sack));
+    }
+}
@@ -0,0 +1,561 @@
+//! Scripted conformance tests of the TCP state machine, in the spirit of packetdrill.
+//!
+//! A script runs against a `TCPManager` over an in-memory interface. Each line starts with a time
+//! in seconds, absolute or `+` relative to the previous line, then `<` for a segment injected into
+//! the stack or `>` for a segment the stack must emit, with `//` comments:
+//!
+//! ```text
+//! 0.000 < S 1000:1000(0) win 65535 mss 1460
+//! 0.000 > S. 0:0(0) ack 1001 win 64240 mss 1460
+//! ```
+//!
+//! The flags are `S`, `F`, `P`, `R`, `U`, `E` for ECE, `W` for CWR and `.` for ACK, followed by
+//! the first and next sequence numbers and the length of the payload. The sequence numbers of the
+//! stack, which the peer acknowledges, are relative to its initial sequence number. The window, the
+//! urgent pointer, the MSS option and a quoted payload ending the line may follow, an emitted
+//! segment being checked against those given only. Emitted segments must come at their time within
+//! `TOLERANCE`, and the stack must not emit anything the script does not expect.
+//!
+//! The stack runs on a manual clock, which the script moves forward instead of sleeping, so that the
+//! times are exact whatever the load of the machine.
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    interface::Interface,
+    ip::{IPV4Header, IPV4Packet, IPV4PacketView, IpProtocol, IpV4Addr},
+    tcp::{
+        TCPHeader, TCPOption, TCPPacket, TCPPacketView,
+        manager::{Clock, TCPConfig, TCPManager},
+    },
+    test_support::Wire,
+    traits::WriteTo,
+};
+
+/// Largest difference between the time of an emitted segment and the one of the script
+const TOLERANCE: Duration = Duration::from_millis(1);
+/// Step the clock is moved forward by, the timers running after each
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+const LOCAL_ADDRESS: IpV4Addr = IpV4Addr(0xC0A80002);
+const LOCAL_PORT: u16 = 80;
+const PEER_ADDRESS: IpV4Addr = IpV4Addr(0xC0A80001);
+const PEER_PORT: u16 = 40000;
+
+/// Flags in the order they are written in the scripts
+const FLAGS: &str = "SFPRUEW.";
+
+#[derive(Debug)]
+pub struct ScriptError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "script line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Inject,
+    Expect,
+}
+
+/// A segment as written in the scripts, the optional fields being left out of the comparisons
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    flags: String,
+    sequence_number: u32,
+    length: u32,
+    acknowledgement_number: Option<u32>,
+    window: Option<u16>,
+    urgent_pointer: Option<u16>,
+    maximum_segment_size: Option<u16>,
+    payload: Option<Vec<u8>>,
+}
+
+impl Segment {
+    fn has_flag(&self, flag: char) -> bool {
+        self.flags.contains(flag)
+    }
+
+    /// Whether an emitted segment has the fields given by the script
+    fn matches(&self, emitted: &Segment) -> bool {
+        let optional =
+            |expected: Option<u16>, emitted: Option<u16>| expected.is_none() || expected == emitted;
+        self.flags == emitted.flags
+            && self.sequence_number == emitted.sequence_number
+            && self.length == emitted.length
+            && self.acknowledgement_number == emitted.acknowledgement_number
+            && optional(self.window, emitted.window)
+            && optional(self.urgent_pointer, emitted.urgent_pointer)
+            && optional(self.maximum_segment_size, emitted.maximum_segment_size)
+            && (self.payload.is_none() || self.payload == emitted.payload)
+    }
+}
+
+impl Display for Segment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let end = self.sequence_number.wrapping_add(self.length);
+        write!(
+            f,
+            "{} {}:{end}({})",
+            self.flags, self.sequence_number, self.length
+        )?;
+        if let Some(acknowledgement_number) = self.acknowledgement_number {
+            write!(f, " ack {acknowledgement_number}")?;
+        }
+        if let Some(window) = self.window {
+            write!(f, " win {window}")?;
+        }
+        if let Some(urgent_pointer) = self.urgent_pointer {
+            write!(f, " urg {urgent_pointer}")?;
+        }
+        if let Some(maximum_segment_size) = self.maximum_segment_size {
+            write!(f, " mss {maximum_segment_size}")?;
+        }
+        if let Some(payload) = &self.payload {
+            write!(f, " \"{}\"", payload.escape_ascii())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Event {
+    line: usize,
+    /// Time since the start of the script
+    time: Duration,
+    direction: Direction,
+    segment: Segment,
+}
+
+/// Replace the escapes of a quoted payload by the bytes they stand for
+fn unescape(quoted: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next()? {
+            'r' => b'\r',
+            'n' => b'\n',
+            't' => b'\t',
+            '0' => 0,
+            '\\' => b'\\',
+            '"' => b'"',
+            _ => return None,
+        });
+    }
+    Some(bytes)
+}
+
+fn parse_seconds(value: &str) -> Option<Duration> {
+    Duration::try_from_secs_f64(value.parse().ok()?).ok()
+}
+
+fn parse(script: &str) -> Result<Vec<Event>, ScriptError> {
+    let mut events = vec![];
+    let mut time = Duration::ZERO;
+    for (i, line) in script.lines().enumerate() {
+        let error = |message: &str| ScriptError {
+            line: i + 1,
+            message: message.to_string(),
+        };
+        let line = line.split_once("//").map_or(line, |(line, _)| line);
+        // The payload may hold spaces, it is taken out before splitting the rest
+        let (line, payload) = match line.split_once('"') {
+            Some((line, quoted)) => {
+                let quoted = quoted
+                    .strip_suffix('"')
+                    .or_else(|| quoted.trim_end().strip_suffix('"'))
+                    .ok_or_else(|| error("unterminated payload"))?;
+                let payload = unescape(quoted).ok_or_else(|| error("invalid escape in payload"))?;
+                (line, Some(payload))
+            }
+            None => (line, None),
+        };
+        let mut tokens = line.split_whitespace();
+        let Some(first) = tokens.next() else {
+            continue;
+        };
+
+        time = match first.strip_prefix('+') {
+            Some(delay) => time + parse_seconds(delay).ok_or_else(|| error("invalid time"))?,
+            None => parse_seconds(first).ok_or_else(|| error("invalid time"))?,
+        };
+        let direction = match tokens.next() {
+            Some("<") => Direction::Inject,
+            Some(">") => Direction::Expect,
+            _ => return Err(error("expected < or >")),
+        };
+        let flags = tokens.next().ok_or_else(|| error("missing flags"))?;
+        if let Some(flag) = flags.chars().find(|flag| !FLAGS.contains(*flag)) {
+            return Err(error(&format!("unknown flag {flag}")));
+        }
+        let flags = FLAGS.chars().filter(|flag| flags.contains(*flag)).collect();
+
+        // `first:next(length)`
+        let range = tokens
+            .next()
+            .ok_or_else(|| error("missing sequence numbers"))?;
+        let invalid_range = || error(&format!("invalid sequence numbers {range}"));
+        let (first, rest) = range.split_once(':').ok_or_else(invalid_range)?;
+        let (next, length) = rest
+            .strip_suffix(')')
+            .and_then(|rest| rest.split_once('('))
+            .ok_or_else(invalid_range)?;
+        let sequence_number: u32 = first.parse().map_err(|_| invalid_range())?;
+        let next: u32 = next.parse().map_err(|_| invalid_range())?;
+        let length: u32 = length.parse().map_err(|_| invalid_range())?;
+        if next.wrapping_sub(sequence_number) != length {
+            return Err(error("the sequence numbers do not match the length"));
+        }
+        if payload
+            .as_ref()
+            .is_some_and(|payload| payload.len() != length as usize)
+        {
+            return Err(error("the payload does not match the length"));
+        }
+
+        let mut segment = Segment {
+            flags,
+            sequence_number,
+            length,
+            acknowledgement_number: None,
+            window: None,
+            urgent_pointer: None,
+            maximum_segment_size: None,
+            payload,
+        };
+        while let Some(key) = tokens.next() {
+            let value = tokens
+                .next()
+                .ok_or_else(|| error(&format!("missing value for {key}")))?;
+            let invalid = || error(&format!("invalid {key} {value}"));
+            match key {
+                "ack" => {
+                    segment.acknowledgement_number = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "win" => segment.window = Some(value.parse().map_err(|_| invalid())?),
+                "urg" => segment.urgent_pointer = Some(value.parse().map_err(|_| invalid())?),
+                "mss" => segment.maximum_segment_size = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(error(&format!("unknown field {key}"))),
+            }
+        }
+        if segment.has_flag('.') != segment.acknowledgement_number.is_some() {
+            return Err(error("an acknowledgement number goes with the . flag"));
+        }
+        events.push(Event {
+            line: i + 1,
+            time,
+            direction,
+            segment,
+        });
+    }
+    Ok(events)
+}
+
+struct Harness {
+    manager: TCPManager,
+    interfaces: Vec<Interface<Wire>>,
+    wire: Wire,
+    /// Frames written by the stack, and when
+    sent: VecDeque<(Instant, Vec<u8>)>,
+    clock: Clock,
+    start: Instant,
+    /// Learned from the first SYN of the stack
+    initial_sequence_number: Option<u32>,
+}
+
+impl Harness {
+    fn new(config: TCPConfig) -> Self {
+        let wire = Wire::default();
+        let clock = Clock::manual();
+        let mut manager = TCPManager::new();
+        manager.config = config;
+        manager.clock = clock.clone();
+        Self {
+            manager,
+            interfaces: vec![Interface::new(wire.clone())],
+            wire,
+            sent: VecDeque::new(),
+            start: clock.now(),
+            clock,
+            initial_sequence_number: None,
+        }
+    }
+
+    fn poll(&mut self) {
+        self.manager.poll(&mut self.interfaces);
+        self.take_sent();
+    }
+
+    /// Keep the frames the stack just wrote, the clock not having moved since
+    fn take_sent(&mut self) {
+        let now = self.clock.now();
+        self.sent
+            .extend(self.wire.sent.take().into_iter().map(|frame| (now, frame)));
+    }
+
+    /// Move the clock forward to `time`, running the timers on the way
+    fn advance_to(&mut self, time: Instant) {
+        while let Some(wait) = time.checked_duration_since(self.clock.now())
+            && !wait.is_zero()
+        {
+            self.poll();
+            self.clock.advance(wait.min(POLL_INTERVAL));
+        }
+    }
+
+    /// Fail on a segment emitted and not expected by the script
+    fn check_nothing_sent(&mut self) -> Result<(), String> {
+        self.poll();
+        match self.sent.pop_front() {
+            Some((_, frame)) => Err(format!("unexpected segment {}", self.decode(&frame)?)),
+            None => Ok(()),
+        }
+    }
+
+    fn inject(&mut self, segment: &Segment) -> Result<(), String> {
+        self.check_nothing_sent()?;
+        let initial_sequence_number = self.initial_sequence_number.unwrap_or(0);
+        let header = TCPHeader {
+            source_port: PEER_PORT,
+            destination_port: LOCAL_PORT,
+            sequence_number: segment.sequence_number,
+            acknowledgement_number: segment
+                .acknowledgement_number
+                .map_or(0, |ack| ack.wrapping_add(initial_sequence_number)),
+            syn: segment.has_flag('S'),
+            fin: segment.has_flag('F'),
+            psh: segment.has_flag('P'),
+            rst: segment.has_flag('R'),
+            urg: segment.has_flag('U'),
+            ece: segment.has_flag('E'),
+            cwr: segment.has_flag('W'),
+            ack: segment.has_flag('.'),
+            window: segment.window.unwrap_or(u16::MAX),
+            urgent_pointer: segment.urgent_pointer.unwrap_or(0),
+            options: segment
+                .maximum_segment_size
+                .map(TCPOption::maximum_segment_size)
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let payload = segment
+            .payload
+            .clone()
+            .unwrap_or_else(|| vec![0; segment.length as usize]);
+        let mut packet = IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Tcp, PEER_ADDRESS, LOCAL_ADDRESS),
+            TCPPacket::new(header, payload),
+        );
+        // Behind the packet information header of the TUN device
+        let mut frame = vec![0, 0, 8, 0];
+        frame.extend(packet.to_bytes().map_err(|err| err.to_string())?);
+        self.interfaces[0].receive_frame(&frame);
+        self.manager.handle_tcp_packet(&mut self.interfaces[0], 0);
+        self.take_sent();
+        Ok(())
+    }
+
+    /// Wait for the next emitted segment, running the timers meanwhile
+    fn expect(&mut self, expected: &Segment, time: Instant) -> Result<(), String> {
+        let (sent_at, frame) = loop {
+            let sent = self.sent.pop_front();
+            if let Some(sent) = sent {
+                break sent;
+            }
+            if self.clock.now() > time + TOLERANCE {
+                return Err(format!("expected {expected}, nothing was sent"));
+            }
+            self.poll();
+            if self.sent.is_empty() {
+                self.clock.advance(POLL_INTERVAL);
+            }
+        };
+        if self.initial_sequence_number.is_none() && expected.has_flag('S') {
+            let emitted = self.decode(&frame)?;
+            self.initial_sequence_number = Some(
+                emitted
+                    .sequence_number
+                    .wrapping_sub(expected.sequence_number),
+            );
+        }
+        let emitted = self.decode(&frame)?;
+        if !expected.matches(&emitted) {
+            return Err(format!("expected {expected}, got {emitted}"));
+        }
+        if sent_at + TOLERANCE < time {
+            return Err(format!(
+                "{emitted} sent {:?} too early",
+                time.duration_since(sent_at)
+            ));
+        }
+        Ok(())
+    }
+
+    /// An emitted segment as it would be written in a script
+    fn decode(&self, frame: &[u8]) -> Result<Segment, String> {
+        let ip_packet = IPV4PacketView::<TCPPacketView>::try_from(&frame[4..])
+            .map_err(|err| format!("invalid packet {err:?}"))?;
+        let header = ip_packet.payload.header;
+        if ip_packet.header.get_destination_address() != PEER_ADDRESS
+            || header.get_destination_port() != PEER_PORT
+            || ip_packet.header.get_source_address() != LOCAL_ADDRESS
+            || header.get_source_port() != LOCAL_PORT
+        {
+            return Err("segment sent to another connection".to_string());
+        }
+        let flags = [
+            header.get_syn(),
+            header.get_fin(),
+            header.get_psh(),
+            header.get_rst(),
+            header.get_urg(),
+            header.get_ece(),
+            header.get_cwr(),
+            header.get_ack(),
+        ];
+        Ok(Segment {
+            flags: FLAGS
+                .chars()
+                .zip(flags)
+                .filter(|(_, set)| *set)
+                .map(|(flag, _)| flag)
+                .collect(),
+            sequence_number: header
+                .get_sequence_number()
+                .wrapping_sub(self.initial_sequence_number.unwrap_or(0)),
+            length: ip_packet.payload.payload.len() as u32,
+            acknowledgement_number: header
+                .get_ack()
+                .then(|| header.get_acknowledgement_number()),
+            window: Some(header.get_window()),
+            urgent_pointer: header.get_urg().then(|| header.get_urgent_pointer()),
+            maximum_segment_size: header.get_maximum_segment_size(),
+            payload: Some(ip_packet.payload.payload.to_vec()),
+        })
+    }
+}
+
+/// Run a script against a new stack
+pub fn run(script: &str, config: TCPConfig) -> Result<(), ScriptError> {
+    let events = parse(script)?;
+    let mut harness = Harness::new(config);
+    for event in &events {
+        let time = harness.start + event.time;
+        harness.advance_to(time);
+        match event.direction {
+            Direction::Inject => harness.inject(&event.segment),
+            Direction::Expect => harness.expect(&event.segment, time),
+        }
+        .map_err(|message| ScriptError {
+            line: event.line,
+            message,
+        })?;
+    }
+    harness.check_nothing_sent().map_err(|message| ScriptError {
+        line: events.last().map_or(0, |event| event.line),
+        message,
+    })
+}
+
+fn run_script(script: &str, config: TCPConfig) {
+    if let Err(err) = run(script, config) {
+        panic!("{err}");
+    }
+}
+
+/// Timeouts short enough for the scripts to wait for them
+fn short_timeouts() -> TCPConfig {
+    TCPConfig {
+        syn_received_timeout: Duration::from_millis(200),
+        fin_wait2_timeout: Duration::from_millis(200),
+        time_wait_timeout: Duration::from_millis(200),
+        ..Default::default()
+    }
+}
+
+macro_rules! scripts {
+    ($($name:ident $(with $config:expr)?),* $(,)?) => {
+        $(
+            #[test]
+            fn $name() {
+                run_script(
+                    include_str!(concat!(stringify!($name), ".pkt")),
+                    None$(.or(Some($config)))?.unwrap_or_default(),
+                );
+            }
+        )*
+    };
+}
+
+scripts! {
+    handshake,
+    handshake_ack_with_fin,
+    syn_retransmitted,
+    syn_received_timeout with short_timeouts(),
+    http_request,
+    request_in_segments,
+    delayed_ack,
+    out_of_order,
+    data_retransmitted,
+    response_retransmitted,
+    syn_ack_retransmitted,
+    active_close,
+    fin_with_request,
+    passive_close,
+    simultaneous_close,
+    fin_retransmitted_in_time_wait,
+    time_wait_timeout with short_timeouts(),
+    last_ack_timeout with TCPConfig {
+        fin_wait2_timeout: Duration::from_millis(100),
+        ..short_timeouts()
+    },
+    rst_in_syn_received,
+    rst_in_established,
+    rst_in_fin_wait,
+    rst_in_last_ack,
+}
+
+#[test]
+fn parse_errors() {
+    let line = |script: &str| parse(script).unwrap_err().line;
+    assert_eq!(line("0.0 < S 0:0(0)\n0.0 ? S 0:0(0)"), 2);
+    assert_eq!(line("0.0 < X 0:0(0)"), 1);
+    assert_eq!(line("0.0 < S 0:1(0)"), 1);
+    assert_eq!(line("0.0 < . 0:0(0)"), 1);
+    assert_eq!(line("0.0 < P 0:2(2) \"abc\""), 1);
+    assert_eq!(line("0.0 < S 0:0(0) win"), 1);
+}
+
+#[test]
+fn parse_relative_times() {
+    let events = parse("// handshake\n0.1 < S 0:0(0)\n+0.05 > S. 0:0(0) ack 1 \"\"").unwrap();
+    assert_eq!(events[1].time, Duration::from_millis(150));
+    assert_eq!(events[1].segment.flags, "S.");
+    assert_eq!(events[1].segment.payload, Some(vec![]));
+}
+
+#[test]
+fn segments_checked_at_their_time() {
+    let script = include_str!("delayed_ack.pkt");
+    for (delay, error) in [("+0.030", "nothing was sent"), ("+0.050", "too early")] {
+        let mistimed = script.replacen("+0.040", delay, 1);
+        let err = run(&mistimed, TCPConfig::default()).unwrap_err();
+        assert_eq!(err.line, 6);
+        assert!(err.message.contains(error), "{err}");
+    }
+}
@@ -1,19 +1,235 @@
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, Read, Write},
+    ops::Range,
+    str::FromStr,
+    sync::{Arc, Mutex, atomic::Ordering},
+    time::{Duration, Instant},
 };
 
+use tracing::{Span, debug, info, info_span, warn};
+
 use crate::{
-    http::{HTTPRequestHeaderView, HTTPResponseHeader, HTTPResponsePacket},
+    buffer::BufferPool,
+    ethernet::{ETHERTYPE_IPV4, MacAddr},
+    http::{self, HTTPRequestHeaderView},
     interface::Interface,
-    ip::{IPV4Packet, IPV4PacketView},
-    tcp::{TCPHeader, TCPPacket, TCPPacketView},
-    traits::{Data, ToMutable},
+    ip::{
+        ECN_CE, ECN_ECT0, IPV4Header, IPV4HeaderView, IPV4Packet, IPV4PacketView, IpProtocol,
+        IpV4Addr,
+    },
+    metrics::METRICS,
+    tcp::{TCPHeader, TCPHeaderView, TCPOption, TCPPacket, TCPPacketView},
+    traits::{Data, WriteTo},
 };
 
+/// Size of the IPv4 and TCP headers without options, which the MTU leaves the segments
+const HEADERS_SIZE: usize = 40;
+/// Segment size assumed when the SYN of the peer does not announce one
+const DEFAULT_SEGMENT_SIZE: usize = 536;
+/// Size of the receive buffer, whose free space is the window advertised to the peers
+const WINDOW: u16 = 64240;
+/// Most data queued by the application and not acknowledged yet
+const SEND_BUFFER: usize = 65536;
+/// Congestion window of a new connection, in segments
+const INITIAL_WINDOW: u32 = 10;
+/// First and longest interval between two zero-window probes, which back off exponentially
+const PERSIST_MIN: Duration = Duration::from_millis(200);
+const PERSIST_MAX: Duration = Duration::from_secs(60);
+/// Retransmission timeout before the first round trip time is measured, and its bounds, the lower
+/// one being that of Linux rather than the second of RFC 6298
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+/// Retransmissions of a segment after which the peer is given up, like `tcp_retries2` of Linux
+const MAX_RETRANSMITS: u32 = 15;
+
+#[derive(Debug)]
+pub struct ParseKeepaliveError;
+
+/// Probes sent on an idle connection to find out whether the peer is still there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Time without receiving anything before the first probe
+    pub idle: Duration,
+    /// Time between two unanswered probes
+    pub interval: Duration,
+    /// Unanswered probes after which the connection is dropped
+    pub probes: u32,
+}
+
+impl Default for Keepalive {
+    /// The defaults of Linux
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(7200),
+            interval: Duration::from_secs(75),
+            probes: 9,
+        }
+    }
+}
+
+impl FromStr for Keepalive {
+    type Err = ParseKeepaliveError;
+
+    /// Parse `idle,interval,probes`, the times being in seconds
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut values = s.split(',').map(|value| value.trim().parse::<u64>());
+        let mut next = || {
+            values
+                .next()
+                .and_then(Result::ok)
+                .ok_or(ParseKeepaliveError)
+        };
+        let keepalive = Self {
+            idle: Duration::from_secs(next()?),
+            interval: Duration::from_secs(next()?),
+            probes: next()?.try_into().map_err(|_| ParseKeepaliveError)?,
+        };
+        if values.next().is_some() {
+            return Err(ParseKeepaliveError);
+        }
+        Ok(keepalive)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TCPConfig {
+    /// Probes of the idle connections, which are kept forever when `None`
+    pub keepalive: Option<Keepalive>,
+    /// Time given to a peer to finish the handshake
+    pub syn_received_timeout: Duration,
+    /// Time given to a peer to finish closing the connection, in FinWait1, FinWait2, Closing and
+    /// LastAck
+    pub fin_wait2_timeout: Duration,
+    /// Time spent in TimeWait, twice the maximum segment lifetime
+    pub time_wait_timeout: Duration,
+    /// Most connections in SynReceived, the oldest being dropped to make room for a new one
+    pub max_half_open: usize,
+    /// Send small segments right away instead of waiting for the data in flight to be
+    /// acknowledged, like `TCP_NODELAY`
+    pub no_delay: bool,
+    /// Longest time the acknowledgement of a single segment is held back
+    pub delayed_ack: Duration,
+    /// Agree on ECN when a peer asks for it in its SYN
+    pub ecn: bool,
+}
+
+impl Default for TCPConfig {
+    fn default() -> Self {
+        Self {
+            keepalive: Some(Keepalive::default()),
+            syn_received_timeout: Duration::from_secs(30),
+            fin_wait2_timeout: Duration::from_secs(60),
+            time_wait_timeout: Duration::from_secs(60),
+            max_half_open: 128,
+            no_delay: false,
+            delayed_ack: Duration::from_millis(40),
+            ecn: true,
+        }
+    }
+}
+
+impl TCPConfig {
+    /// Longest time a connection may stay in `state` without receiving anything
+    fn timeout(&self, state: TCPConnectionState) -> Option<Duration> {
+        match state {
+            TCPConnectionState::SynReceived => Some(self.syn_received_timeout),
+            TCPConnectionState::FinWait1
+            | TCPConnectionState::FinWait2
+            | TCPConnectionState::Closing
+            | TCPConnectionState::LastAck => Some(self.fin_wait2_timeout),
+            TCPConnectionState::TimeWait => Some(self.time_wait_timeout),
+            _ => None,
+        }
+    }
+}
+
+/// Time the timers of the connections and impairments run on, the real one unless a test moves it
+/// forward by hand
+#[derive(Debug, Clone, Default)]
+pub struct Clock(Option<Arc<Mutex<Instant>>>);
+
+impl Clock {
+    /// A clock standing still at the current time until it is advanced
+    #[cfg(test)]
+    pub fn manual() -> Self {
+        Self(Some(Arc::new(Mutex::new(Instant::now()))))
+    }
+
+    pub fn now(&self) -> Instant {
+        match &self.0 {
+            Some(time) => *time.lock().unwrap(),
+            None => Instant::now(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn advance(&self, duration: Duration) {
+        if let Some(time) = &self.0 {
+            *time.lock().unwrap() += duration;
+        }
+    }
+}
+
+/// The addresses and ports identifying a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId {
+    pub local_address: IpV4Addr,
+    pub local_port: u16,
+    pub peer_address: IpV4Addr,
+    pub peer_port: u16,
+}
+
+impl ConnectionId {
+    /// Connection a received segment belongs to
+    pub fn of<'a>(ip_packet: IPV4PacketView<'a, TCPPacketView<'a>>) -> Self {
+        Self {
+            local_address: ip_packet.header.get_destination_address(),
+            local_port: ip_packet.payload.header.get_destination_port(),
+            peer_address: ip_packet.header.get_source_address(),
+            peer_port: ip_packet.payload.header.get_source_port(),
+        }
+    }
+
+    /// Shard of a connection table split in `shards`, the same in every thread and every run
+    pub fn shard(&self, shards: usize) -> usize {
+        // Unlike `RandomState`, the default hasher has fixed keys
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        (hasher.finish() % shards as u64) as usize
+    }
+}
+
+impl Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{} - {}:{}",
+            self.local_address, self.local_port, self.peer_address, self.peer_port
+        )
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct TCPManager {
-    connections: HashMap<u16, TCPConnection>,
+    connections: HashMap<ConnectionId, TCPConnection>,
+    /// Ports whose connections go to the application, and the connections not accepted yet
+    listeners: HashMap<u16, VecDeque<ConnectionId>>,
+    /// Ports the built-in HTTP server answers on, any when empty. The connections to the others
+    /// are reset, unless the application listens to them.
+    pub ports: Vec<u16>,
+    pub config: TCPConfig,
+    /// Buffers the data segments of all the connections are built in
+    pool: BufferPool,
+    /// Connections in SynReceived, kept up to date instead of counted on every SYN
+    half_open: usize,
+    /// Why the accepted connections reset or timed out went away, for their sockets to report it
+    /// until they are released
+    aborted: HashMap<ConnectionId, io::ErrorKind>,
+    pub clock: Clock,
 }
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
@@ -32,190 +248,2028 @@ pub enum TCPConnectionState {
     Closed,
 }
 
-#[derive(Debug, Clone, Default)]
+impl TCPConnectionState {
+    pub const ALL: [Self; 11] = [
+        Self::Listen,
+        Self::SynSent,
+        Self::SynReceived,
+        Self::Established,
+        Self::FinWait1,
+        Self::FinWait2,
+        Self::CloseWait,
+        Self::Closing,
+        Self::LastAck,
+        Self::TimeWait,
+        Self::Closed,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Listen => "listen",
+            Self::SynSent => "syn_sent",
+            Self::SynReceived => "syn_received",
+            Self::Established => "established",
+            Self::FinWait1 => "fin_wait_1",
+            Self::FinWait2 => "fin_wait_2",
+            Self::CloseWait => "close_wait",
+            Self::Closing => "closing",
+            Self::LastAck => "last_ack",
+            Self::TimeWait => "time_wait",
+            Self::Closed => "closed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct TCPConnection {
+    id: ConnectionId,
+    /// Interface the connection goes through, and link address of the peer on it
+    interface: usize,
+    peer_mac: MacAddr,
     state: TCPConnectionState,
+    /// Next sequence number to send, and the oldest one not acknowledged by the peer
     sequence_number: u32,
+    send_unacknowledged: u32,
+    /// Data from `send_unacknowledged` on, kept until the peer acknowledges it
+    send_buffer: VecDeque<u8>,
+    /// Window advertised by the peer, from `send_unacknowledged`
+    peer_window: u32,
+    /// Largest payload of our segments, within our MTU and the size announced by the peer
+    max_segment_size: usize,
+    /// A FIN is sent once `send_buffer` has been sent
+    closing: bool,
+    no_delay: bool,
+    /// When the next zero-window probe is due, and the probes sent since the window closed
+    persist_deadline: Option<Instant>,
+    persist_backoff: u32,
+    /// Bytes in flight allowed by congestion control, growing exponentially up to the slow start
+    /// threshold then linearly
+    congestion_window: u32,
+    slow_start_threshold: u32,
+    /// Both ends agreed on ECN, our data being sent ECN capable
+    ecn: bool,
+    /// Sequence number up to which the congestion window was already reduced, and whether the
+    /// next data segment tells the peer with CWR
+    recovery_point: Option<u32>,
+    cwr_pending: bool,
+    /// Next sequence number expected from the peer, to spot retransmitted segments
+    receive_next: u32,
+    /// Data received and not delivered yet, the application getting it when the peer pushes
+    receive_buffer: Vec<u8>,
+    /// Sequence number following the urgent byte announced by the peer, and that byte once
+    /// received, which is kept out of the stream
+    urgent_mark: Option<u32>,
+    urgent_data: Option<u8>,
+    /// Segments from the peer experienced a congestion, echoed with ECE until the peer answers
+    /// with CWR
+    ece_pending: bool,
+    /// Segments received since our last acknowledgement, and when it is due at the latest
+    segments_unacknowledged: u32,
+    ack_deadline: Option<Instant>,
+    /// Sequence number whose acknowledgement is awaited to measure the round trip time
+    rtt_probe: Option<(u32, Instant)>,
+    /// Smoothed round trip time and its variation, once measured, giving the retransmission
+    /// timeout (RFC 6298)
+    smoothed_rtt: Option<Duration>,
+    rtt_variation: Duration,
+    rto: Duration,
+    /// When the oldest unacknowledged segment is sent again, and how many times it already was
+    retransmit_deadline: Option<Instant>,
+    retransmits: u32,
+    last_received: Instant,
+    /// Keepalive probes sent since `last_received`, and when the last one was
+    probes_sent: u32,
+    last_probe: Option<Instant>,
+    /// Data is read and written by the application through a socket, instead of the HTTP server
+    socket: bool,
+    /// Taken by the application from the queue of its listener
+    accepted: bool,
+    /// The peer reset the connection, rather than closing it
+    reset: bool,
+    /// Span of the events of this connection
+    span: Span,
+    clock: Clock,
 }
 
 impl TCPManager {
     pub fn new() -> Self {
-        Self {
-            connections: HashMap::new(),
-        }
+        Self::default()
     }
 
-    pub fn handle_tcp_packet(&mut self, interface: &mut Interface<impl Read + Write>) {
-        let ip_packet = interface.get_packet::<IPV4PacketView<TCPPacketView>>();
+    pub fn handle_tcp_packet(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        input: usize,
+    ) {
+        let config = self.config;
+        let Ok(ip_packet) = interface.try_get_ip_packet::<TCPPacketView>() else {
+            debug!("truncated TCP segment dropped");
+            return;
+        };
+        METRICS
+            .tcp_segments_received
+            .fetch_add(1, Ordering::Relaxed);
 
+        let id = ConnectionId::of(ip_packet);
         if ip_packet.payload.header.get_syn()
             && !ip_packet.payload.header.get_ack()
-            && !self
-                .connections
-                .contains_key(&ip_packet.payload.header.get_destination_port())
+            && !self.connections.contains_key(&id)
         {
-            self.connections.insert(
-                ip_packet.payload.header.get_destination_port(),
-                TCPConnection::new(),
+            if !self.ports.is_empty()
+                && !self.ports.contains(&id.local_port)
+                && !self.listeners.contains_key(&id.local_port)
+            {
+                debug!(port = id.local_port, "connection to a closed port reset");
+                send_reset(interface);
+                return;
+            }
+            // A bounded half-open queue keeps a SYN flood from taking all the memory, the oldest
+            // attempt making room so that the flood does not lock the real peers out either
+            if self.half_open >= self.config.max_half_open
+                && let Some(oldest) = self
+                    .connections
+                    .iter()
+                    .filter(|(_, connection)| connection.state == TCPConnectionState::SynReceived)
+                    .min_by_key(|(_, connection)| connection.last_received)
+                    .map(|(id, _)| *id)
+                && let Some(connection) = self.connections.remove(&oldest)
+            {
+                connection
+                    .span
+                    .in_scope(|| debug!("half-open queue full, connection dropped"));
+                METRICS
+                    .tcp_half_open_dropped
+                    .fetch_add(1, Ordering::Relaxed);
+                METRICS.connection_state(Some(connection.state), None);
+                self.half_open -= 1;
+            }
+            let span = info_span!(
+                "connection",
+                peer = %id.peer_address,
+                peer_port = id.peer_port,
+                port = id.local_port,
             );
+            span.in_scope(|| info!("connection opened"));
+            METRICS
+                .tcp_connections_opened
+                .fetch_add(1, Ordering::Relaxed);
+            METRICS.connection_state(None, Some(TCPConnectionState::Listen));
+            let mut connection = TCPConnection::new(
+                id,
+                input,
+                interface.get_peer_mac(),
+                span,
+                self.clock.clone(),
+            );
+            connection.set_no_delay(self.config.no_delay);
+            if let Some(queue) = self.listeners.get_mut(&id.local_port) {
+                connection.socket = true;
+                queue.push_back(id);
+            }
+            self.connections.insert(id, connection);
+        }
+        if let Some(connection) = self.connections.get_mut(&id) {
+            let state = connection.state;
+            connection.handle_packet(&config, &mut self.pool, interface);
+            self.half_open = update_half_open(self.half_open, state, Some(connection.state));
         }
-        let destination_port = ip_packet.payload.header.get_destination_port();
-        self.connections
-            .entry(destination_port)
-            .and_modify(|connection| connection.handle_packet(interface));
 
         if self
             .connections
-            .get(&destination_port)
+            .get(&id)
             .is_some_and(|conn| conn.state == TCPConnectionState::Closed)
+            && let Some(connection) = self.connections.remove(&id)
         {
-            self.connections.remove(&destination_port);
+            connection.span.in_scope(|| info!("connection closed"));
+            METRICS.connection_state(Some(TCPConnectionState::Closed), None);
+            record_abort(&mut self.aborted, &connection);
+        }
+    }
+
+    /// Hand the connections to `port` to the application instead of the HTTP server, returning
+    /// false when the port is already listened to
+    pub fn listen(&mut self, port: u16) -> bool {
+        if self.listeners.contains_key(&port) {
+            return false;
+        }
+        self.listeners.insert(port, VecDeque::new());
+        true
+    }
+
+    /// Stop listening to `port`, closing the connections which were not accepted
+    pub fn unlisten(&mut self, port: u16) {
+        for id in self.listeners.remove(&port).into_iter().flatten() {
+            if let Some(connection) = self.connections.get_mut(&id) {
+                connection.close();
+            }
+        }
+    }
+
+    /// Take the oldest connection to `port` whose handshake is over
+    pub fn accept(&mut self, port: u16) -> Option<ConnectionId> {
+        let queue = self.listeners.get_mut(&port)?;
+        queue.retain(|id| self.connections.contains_key(id));
+        let position = queue
+            .iter()
+            .position(|id| self.connections[id].is_synchronized())?;
+        let id = queue.remove(position)?;
+        self.connections.get_mut(&id)?.accepted = true;
+        Some(id)
+    }
+
+    pub fn connection(&mut self, id: ConnectionId) -> Option<&mut TCPConnection> {
+        self.connections.get_mut(&id)
+    }
+
+    /// Error of an accepted connection which is gone after a reset or a timeout, `None` when it
+    /// was closed normally or is still there
+    pub fn error(&self, id: ConnectionId) -> Option<io::ErrorKind> {
+        self.aborted.get(&id).copied()
+    }
+
+    /// Close an accepted connection whose socket is dropped, nothing being left to report its
+    /// end to
+    pub fn release(&mut self, id: ConnectionId) {
+        self.aborted.remove(&id);
+        if let Some(connection) = self.connections.get_mut(&id) {
+            connection.accepted = false;
+            connection.close();
         }
     }
+
+    /// Earliest delayed acknowledgement, zero-window probe or retransmission, which the main loop
+    /// should not oversleep
+    pub fn next_timer(&self) -> Option<Instant> {
+        self.connections
+            .values()
+            .flat_map(|connection| {
+                [
+                    connection.ack_deadline,
+                    connection.persist_deadline,
+                    connection.retransmit_deadline,
+                ]
+            })
+            .flatten()
+            .min()
+    }
+
+    /// Run the timers of the connections, probing the idle ones and dropping those whose peer is
+    /// gone
+    pub fn poll(&mut self, interfaces: &mut [Interface<impl Read + Write>]) {
+        let config = self.config;
+        let mut half_open = self.half_open;
+        self.connections.retain(|_, connection| {
+            let span = connection.span.clone();
+            let _entered = span.enter();
+            let state = connection.state;
+            let alive = connection.poll(
+                &config,
+                &mut self.pool,
+                &mut interfaces[connection.interface],
+            );
+            half_open = update_half_open(half_open, state, alive.then_some(connection.state));
+            if alive {
+                return true;
+            }
+            info!("connection timed out in {:?}", connection.state);
+            METRICS.tcp_timeouts.fetch_add(1, Ordering::Relaxed);
+            METRICS.connection_state(Some(connection.state), None);
+            record_abort(&mut self.aborted, connection);
+            false
+        });
+        self.half_open = half_open;
+    }
+}
+
+/// Keep why an accepted connection went away, unless it was closed normally. Those never accepted
+/// have no socket to tell.
+fn record_abort(aborted: &mut HashMap<ConnectionId, io::ErrorKind>, connection: &TCPConnection) {
+    if connection.accepted
+        && let Some(kind) = connection.error()
+    {
+        aborted.insert(connection.id, kind);
+    }
+}
+
+/// Count of the half-open connections once one went from `from` to `to`, `None` if it was dropped
+fn update_half_open(
+    half_open: usize,
+    from: TCPConnectionState,
+    to: Option<TCPConnectionState>,
+) -> usize {
+    let half_open = half_open - (from == TCPConnectionState::SynReceived) as usize;
+    half_open + (to == Some(TCPConnectionState::SynReceived)) as usize
 }
 
 impl TCPConnection {
-    pub fn new() -> Self {
+    pub fn new(
+        id: ConnectionId,
+        interface: usize,
+        peer_mac: MacAddr,
+        span: Span,
+        clock: Clock,
+    ) -> Self {
         Self {
+            id,
+            interface,
+            peer_mac,
             state: TCPConnectionState::Listen,
             sequence_number: 0,
+            send_unacknowledged: 0,
+            send_buffer: VecDeque::new(),
+            peer_window: 0,
+            max_segment_size: DEFAULT_SEGMENT_SIZE,
+            closing: false,
+            no_delay: false,
+            persist_deadline: None,
+            persist_backoff: 0,
+            congestion_window: INITIAL_WINDOW * DEFAULT_SEGMENT_SIZE as u32,
+            slow_start_threshold: u32::MAX,
+            ecn: false,
+            recovery_point: None,
+            cwr_pending: false,
+            receive_next: 0,
+            receive_buffer: vec![],
+            urgent_mark: None,
+            urgent_data: None,
+            ece_pending: false,
+            segments_unacknowledged: 0,
+            ack_deadline: None,
+            rtt_probe: None,
+            smoothed_rtt: None,
+            rtt_variation: Duration::ZERO,
+            rto: INITIAL_RTO,
+            retransmit_deadline: None,
+            retransmits: 0,
+            last_received: clock.now(),
+            probes_sent: 0,
+            last_probe: None,
+            socket: false,
+            accepted: false,
+            reset: false,
+            span,
+            clock,
         }
     }
-    pub fn handle_packet(&mut self, interface: &mut Interface<impl Read + Write>) {
-        let ip_packet = interface.get_packet::<IPV4PacketView<TCPPacketView>>();
-        let tcp_packet = ip_packet.payload;
-        match self.state {
+
+    /// Disable Nagle's algorithm, sending small segments without waiting for the data in flight
+    /// to be acknowledged
+    pub fn set_no_delay(&mut self, no_delay: bool) {
+        self.no_delay = no_delay;
+    }
+
+    /// Queue data to send, transmitted as the window of the peer allows
+    pub fn send(&mut self, data: &[u8]) {
+        self.send_buffer.extend(data);
+    }
+
+    /// Send a FIN once the queued data has been sent
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
+
+    /// Queue data to send within the send buffer, failing with `WouldBlock` while it is full
+    pub fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.closing
+            || !matches!(
+                self.state,
+                TCPConnectionState::Established | TCPConnectionState::CloseWait
+            )
+        {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let length = data
+            .len()
+            .min(SEND_BUFFER.saturating_sub(self.send_buffer.len()));
+        if length == 0 && !data.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.send(&data[..length]);
+        Ok(length)
+    }
+
+    /// Take received data, failing with `WouldBlock` while there is none and the peer may still
+    /// send some
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.receive_buffer.is_empty() {
+            if self.peer_closed() {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let window = self.receive_window() as usize;
+        let length = buf.len().min(self.receive_buffer.len());
+        buf[..length].copy_from_slice(&self.receive_buffer[..length]);
+        self.receive_buffer.drain(..length);
+        // The peer may be waiting for a window smaller than a segment to open, it is told at once
+        if window < self.max_segment_size && self.receive_window() as usize >= self.max_segment_size
+        {
+            self.ack_deadline = Some(self.clock.now());
+        }
+        Ok(length)
+    }
+
+    /// Whether the handshake is over, the connection being ready to be accepted
+    pub fn is_synchronized(&self) -> bool {
+        !matches!(
+            self.state,
             TCPConnectionState::Listen
-                if tcp_packet.header.get_syn() && !tcp_packet.header.get_ack() =>
-            {
-                self.sequence_number = 3453253245;
+                | TCPConnectionState::SynSent
+                | TCPConnectionState::SynReceived
+        )
+    }
+
+    /// Why the connection is going away, a reset or a timeout before it was over, `None` after a
+    /// normal close
+    fn error(&self) -> Option<io::ErrorKind> {
+        if self.reset {
+            return Some(io::ErrorKind::ConnectionReset);
+        }
+        match self.state {
+            TCPConnectionState::Closed | TCPConnectionState::TimeWait => None,
+            _ => Some(io::ErrorKind::TimedOut),
+        }
+    }
+
+    /// Whether the FIN of the peer was received, no data coming after the buffered one
+    fn peer_closed(&self) -> bool {
+        matches!(
+            self.state,
+            TCPConnectionState::CloseWait
+                | TCPConnectionState::Closing
+                | TCPConnectionState::LastAck
+                | TCPConnectionState::TimeWait
+                | TCPConnectionState::Closed
+        )
+    }
+
+    /// Take the last urgent byte received, delivered out of band rather than in the stream
+    pub fn read_urgent(&mut self) -> Option<u8> {
+        self.urgent_data.take()
+    }
+
+    fn set_state(&mut self, state: TCPConnectionState) {
+        debug!("{:?} -> {state:?}", self.state);
+        METRICS.connection_state(Some(self.state), Some(state));
+        self.state = state;
+    }
+
+    /// Sequence numbers sent and not acknowledged yet, the SYN and FIN included
+    fn in_flight(&self) -> u32 {
+        self.sequence_number.wrapping_sub(self.send_unacknowledged)
+    }
+
+    /// Free space of the receive buffer
+    fn receive_window(&self) -> u16 {
+        (WINDOW as usize).saturating_sub(self.receive_buffer.len()) as u16
+    }
+
+    /// Queued bytes which were not sent yet
+    fn unsent(&self) -> usize {
+        self.send_buffer
+            .len()
+            .saturating_sub(self.in_flight() as usize)
+    }
+
+    /// Run the timers, returning whether the connection is still alive
+    fn poll(
+        &mut self,
+        config: &TCPConfig,
+        pool: &mut BufferPool,
+        interface: &mut Interface<impl Read + Write>,
+    ) -> bool {
+        let now = self.clock.now();
+        // A connection whose peer is gone has nothing more to send
+        let idle = now.saturating_duration_since(self.last_received);
+        let timeout = config.timeout(self.state);
+        if timeout.is_some_and(|timeout| idle >= timeout) {
+            return false;
+        }
+        if self.ack_deadline.is_some_and(|deadline| deadline <= now) {
+            debug!("delayed acknowledgement");
+            self.send_ack(interface);
+        }
+        if self
+            .persist_deadline
+            .is_some_and(|deadline| deadline <= now)
+        {
+            self.probe_window(interface);
+        }
+        if self
+            .retransmit_deadline
+            .is_some_and(|deadline| deadline <= now)
+        {
+            if self.retransmits >= MAX_RETRANSMITS {
+                return false;
+            }
+            self.retransmit(pool, interface);
+        }
+        self.transmit(pool, interface);
+
+        if timeout.is_some() {
+            return true;
+        }
+        let Some(keepalive) = config.keepalive else {
+            return true;
+        };
+        if !matches!(
+            self.state,
+            TCPConnectionState::Established | TCPConnectionState::CloseWait
+        ) || idle < keepalive.idle
+            || self.last_probe.is_some_and(|last_probe| {
+                now.saturating_duration_since(last_probe) < keepalive.interval
+            })
+        {
+            return true;
+        }
+        if self.probes_sent >= keepalive.probes {
+            return false;
+        }
+        self.probes_sent += 1;
+        self.last_probe = Some(now);
+        debug!(probe = self.probes_sent, "keepalive probe");
+        // An acknowledgement of an already acknowledged byte, which the peer must answer
+        let probe = self.segment(self.send_unacknowledged.wrapping_sub(1), vec![]);
+        self.send_segment(interface, probe);
+        true
+    }
+
+    /// A segment from this connection, acknowledging everything received so far
+    fn segment(&self, sequence_number: u32, payload: Vec<u8>) -> IPV4Packet<TCPPacket> {
+        let header = TCPHeader {
+            source_port: self.id.local_port,
+            destination_port: self.id.peer_port,
+            sequence_number,
+            acknowledgement_number: self.receive_next,
+            ack: true,
+            ece: self.ece_pending,
+            window: self.receive_window(),
+            ..Default::default()
+        };
+        IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Tcp, self.id.local_address, self.id.peer_address),
+            TCPPacket::new(header, payload),
+        )
+    }
+
+    /// Send a segment, which acknowledges the pending segments whatever it carries
+    fn send_segment(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        segment: IPV4Packet<TCPPacket>,
+    ) {
+        self.segments_unacknowledged = 0;
+        self.ack_deadline = None;
+        interface.write_frame(self.peer_mac, ETHERTYPE_IPV4, segment);
+        interface.send();
+    }
+
+    /// Send a segment carrying the queued data in `range`, built in a pooled buffer instead of
+    /// being copied into a payload of its own
+    fn send_data(
+        &mut self,
+        pool: &mut BufferPool,
+        interface: &mut Interface<impl Read + Write>,
+        segment: IPV4Packet<TCPPacket>,
+        range: Range<usize>,
+    ) {
+        self.segments_unacknowledged = 0;
+        self.ack_deadline = None;
+        // The range may wrap around the end of the ring buffer
+        let (front, back) = self.send_buffer.as_slices();
+        let first = &front[range.start.min(front.len())..range.end.min(front.len())];
+        let second =
+            &back[range.start.saturating_sub(front.len())..range.end.saturating_sub(front.len())];
+        let mut buffer = pool.take();
+        let built = buffer
+            .extend_from_slice(first)
+            .and_then(|()| buffer.extend_from_slice(second))
+            .and_then(|()| {
+                buffer.push_transport_header::<TCPPacket>(segment.payload.header, &segment.header)
+            })
+            .and_then(|()| buffer.push_ipv4_header(segment.header));
+        match built {
+            Ok(()) => interface.transmit(self.peer_mac, ETHERTYPE_IPV4, &mut buffer),
+            Err(err) => warn!("could not send a segment: {err}"),
+        }
+        pool.give_back(buffer);
+    }
+
+    fn send_ack(&mut self, interface: &mut Interface<impl Read + Write>) {
+        let ack = self.segment(self.sequence_number, vec![]);
+        self.send_segment(interface, ack);
+    }
+
+    /// Answer the SYN of the peer, announcing the largest segment our MTU takes
+    fn send_syn_ack(&mut self, interface: &mut Interface<impl Read + Write>) {
+        let segment_size = interface.mtu - HEADERS_SIZE;
+        let mut response = self.segment(self.send_unacknowledged, vec![]);
+        response.payload.header.syn = true;
+        response.payload.header.ece = self.ecn;
+        response
+            .payload
+            .header
+            .options
+            .push(TCPOption::maximum_segment_size(segment_size as u16));
+        self.send_segment(interface, response);
+    }
+
+    /// Send the queued data the window of the peer allows, then the FIN once it is all sent
+    fn transmit(&mut self, pool: &mut BufferPool, interface: &mut Interface<impl Read + Write>) {
+        if !matches!(
+            self.state,
+            TCPConnectionState::Established | TCPConnectionState::CloseWait
+        ) {
+            return;
+        }
+        loop {
+            let unsent = self.unsent();
+            let window = self
+                .peer_window
+                .min(self.congestion_window)
+                .saturating_sub(self.in_flight()) as usize;
+            let length = unsent.min(window).min(self.max_segment_size);
+            if length == 0 {
+                break;
+            }
+            // Nagle's algorithm: a small segment waits for the data in flight to be acknowledged,
+            // so that the data queued meanwhile is sent in full segments
+            if length < self.max_segment_size && self.in_flight() > 0 && !self.no_delay {
+                break;
+            }
+            let offset = self.in_flight() as usize;
+            let mut segment = self.segment(self.sequence_number, vec![]);
+            segment.payload.header.psh = length == unsent;
+            if self.ecn {
+                segment.header.ecn = ECN_ECT0;
+                segment.payload.header.cwr = std::mem::take(&mut self.cwr_pending);
+            }
+            self.sequence_number = self.sequence_number.wrapping_add(length as u32);
+            self.rtt_probe
+                .get_or_insert((self.sequence_number, self.clock.now()));
+            self.send_data(pool, interface, segment, offset..offset + length);
+            self.arm_retransmit();
+        }
+
+        let unsent = self.unsent();
+        if self.closing && unsent == 0 {
+            let mut fin = self.segment(self.sequence_number, vec![]);
+            fin.payload.header.fin = true;
+            self.sequence_number = self.sequence_number.wrapping_add(1);
+            self.send_segment(interface, fin);
+            self.arm_retransmit();
+            self.set_state(match self.state {
+                TCPConnectionState::CloseWait => TCPConnectionState::LastAck,
+                _ => TCPConnectionState::FinWait1,
+            });
+        }
+
+        // With nothing in flight, no acknowledgement would tell us the window opened again
+        if unsent > 0 && self.peer_window == 0 && self.in_flight() == 0 {
+            self.persist_deadline
+                .get_or_insert_with(|| self.clock.now() + PERSIST_MIN);
+        } else {
+            self.persist_deadline = None;
+            self.persist_backoff = 0;
+        }
+    }
+
+    /// Start the retransmission timer, unless it already runs for an older segment
+    fn arm_retransmit(&mut self) {
+        self.retransmit_deadline
+            .get_or_insert_with(|| self.clock.now() + self.rto);
+    }
 
-                let mut response = self.make_ack_packet(ip_packet);
-                response.payload.header.syn = true;
+    /// Send the oldest unacknowledged segment again, its acknowledgement having timed out: the
+    /// timeout doubles and congestion control starts over from one segment (RFC 5681 §3.1)
+    fn retransmit(&mut self, pool: &mut BufferPool, interface: &mut Interface<impl Read + Write>) {
+        let in_flight = self.in_flight();
+        if in_flight == 0 {
+            self.retransmit_deadline = None;
+            return;
+        }
+        debug!(rto = ?self.rto, "retransmission timeout");
+        METRICS.tcp_retransmits.fetch_add(1, Ordering::Relaxed);
+        self.retransmits += 1;
+        // Karn's algorithm: the acknowledgement of a retransmitted segment gives no round trip time
+        self.rtt_probe = None;
+        let data = (in_flight as usize).min(self.send_buffer.len());
+        if self.state == TCPConnectionState::SynReceived {
+            self.send_syn_ack(interface);
+        } else if data > 0 {
+            let segment = self.segment(self.send_unacknowledged, vec![]);
+            self.send_data(pool, interface, segment, 0..data.min(self.max_segment_size));
+        } else {
+            // Only our FIN is in flight
+            let mut fin = self.segment(self.sequence_number.wrapping_sub(1), vec![]);
+            fin.payload.header.fin = true;
+            self.send_segment(interface, fin);
+        }
+        let mss = self.max_segment_size as u32;
+        self.slow_start_threshold = (in_flight / 2).max(2 * mss);
+        self.congestion_window = mss;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.retransmit_deadline = Some(self.clock.now() + self.rto);
+    }
+
+    /// Take a round trip time into the retransmission timeout (RFC 6298 §2)
+    fn update_rto(&mut self, rtt: Duration) {
+        let smoothed_rtt = match self.smoothed_rtt {
+            None => {
+                self.rtt_variation = rtt / 2;
+                rtt
+            }
+            Some(smoothed_rtt) => {
+                self.rtt_variation = (self.rtt_variation * 3 + smoothed_rtt.abs_diff(rtt)) / 4;
+                (smoothed_rtt * 7 + rtt) / 8
+            }
+        };
+        self.smoothed_rtt = Some(smoothed_rtt);
+        self.rto = (smoothed_rtt + self.rtt_variation * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Send the next byte beyond the closed window, whose acknowledgement carries the window
+    fn probe_window(&mut self, interface: &mut Interface<impl Read + Write>) {
+        let Some(&byte) = self.send_buffer.front() else {
+            self.persist_deadline = None;
+            return;
+        };
+        debug!(backoff = self.persist_backoff, "zero window probe");
+        let probe = self.segment(self.send_unacknowledged, vec![byte]);
+        self.send_segment(interface, probe);
+        self.persist_backoff += 1;
+        let interval = PERSIST_MIN
+            .saturating_mul(1 << self.persist_backoff.min(16))
+            .min(PERSIST_MAX);
+        self.persist_deadline = Some(self.clock.now() + interval);
+    }
+
+    /// Release the acknowledged data and take the window of the peer
+    fn handle_ack(&mut self, tcp_packet: TCPPacketView) {
+        let acknowledged = tcp_packet
+            .header
+            .get_acknowledgement_number()
+            .wrapping_sub(self.send_unacknowledged);
+        // Old acknowledgements wrap around to huge values, a zero-window probe may be covered
+        let probed = (self.persist_backoff > 0 && !self.send_buffer.is_empty()) as u32;
+        let in_flight = self.in_flight();
+        if acknowledged > in_flight.max(probed) {
+            return;
+        }
+        if self.ecn && tcp_packet.header.get_ece() {
+            self.reduce_congestion_window();
+        }
+        let data = (acknowledged as usize).min(self.send_buffer.len());
+        self.send_buffer.drain(..data);
+        self.grow_congestion_window(data as u32);
+        self.send_unacknowledged = tcp_packet.header.get_acknowledgement_number();
+        if acknowledged > in_flight {
+            self.sequence_number = self.send_unacknowledged;
+        }
+        self.peer_window = tcp_packet.header.get_window() as u32;
+        // New data acknowledged: the timer restarts for the rest, if anything is left in flight
+        if acknowledged > 0 {
+            self.retransmits = 0;
+            self.retransmit_deadline = (self.in_flight() > 0).then(|| self.clock.now() + self.rto);
+        }
+    }
 
-                self.sequence_number += 1;
+    fn grow_congestion_window(&mut self, acknowledged: u32) {
+        let mss = self.max_segment_size as u32;
+        let growth = if self.congestion_window < self.slow_start_threshold {
+            acknowledged.min(mss)
+        } else if acknowledged > 0 {
+            (mss * mss / self.congestion_window).max(1)
+        } else {
+            0
+        };
+        self.congestion_window = self.congestion_window.saturating_add(growth);
+    }
 
-                interface.write(response);
-                interface.send();
+    /// Halve the congestion window, at most once per window of data, the peer having echoed a
+    /// congestion experienced by our segments
+    fn reduce_congestion_window(&mut self) {
+        if let Some(recovery_point) = self.recovery_point
+            && recovery_point
+                .wrapping_sub(self.send_unacknowledged)
+                .wrapping_sub(1)
+                < 1 << 31
+        {
+            return;
+        }
+        self.slow_start_threshold = (self.in_flight() / 2).max(2 * self.max_segment_size as u32);
+        self.congestion_window = self.slow_start_threshold;
+        self.recovery_point = Some(self.sequence_number);
+        self.cwr_pending = true;
+        debug!(
+            congestion_window = self.congestion_window,
+            "congestion window reduced"
+        );
+    }
 
-                self.state = TCPConnectionState::SynReceived;
+    /// Append received data to the receive buffer, keeping the urgent byte out of it
+    fn buffer_data(&mut self, sequence_number: u32, payload: &[u8]) {
+        let Some(urgent_mark) = self.urgent_mark else {
+            self.receive_buffer.extend(payload);
+            return;
+        };
+        let offset = urgent_mark.wrapping_sub(1).wrapping_sub(sequence_number);
+        if offset as usize >= payload.len() {
+            // The urgent byte comes in a later segment, or was in an earlier one
+            if offset >= 1 << 31 {
+                self.urgent_mark = None;
             }
-            TCPConnectionState::SynReceived
-                if !tcp_packet.header.get_syn() && tcp_packet.header.get_ack() =>
+            self.receive_buffer.extend(payload);
+            return;
+        }
+        let offset = offset as usize;
+        self.receive_buffer.extend(&payload[..offset]);
+        self.receive_buffer.extend(&payload[offset + 1..]);
+        self.urgent_data = Some(payload[offset]);
+        self.urgent_mark = None;
+    }
+
+    /// Hand the received data to the application, here an HTTP server, returning how much of it
+    /// was consumed. The urgent byte is left for `read_urgent`, out of the stream.
+    fn receive(&mut self, data: &[u8]) -> usize {
+        // The request was answered, anything sent after it is dropped
+        if self.closing {
+            return data.len();
+        }
+        let http_packet = match HTTPRequestHeaderView::try_from(data) {
+            Ok(p) => p,
+            // The header is not complete yet, unless it no longer fits in the receive buffer
+            Err(_)
+                if data.len() < WINDOW as usize
+                    && !data.windows(4).any(|window| window == b"\r\n\r\n") =>
             {
-                self.state = TCPConnectionState::Established;
+                return 0;
             }
-            TCPConnectionState::Established if tcp_packet.header.get_fin() => {
-                let ack_response = self.make_ack_packet(ip_packet);
+            // Not an http packet, ignored
+            Err(_) => return data.len(),
+        };
+        // The header, the empty line ending it and the body
+        let consumed =
+            http_packet.as_ref().len() + 4 + http_packet.get_content_length().unwrap_or(0);
+        // The body is waited for, unless it does not fit in the receive buffer
+        if consumed > data.len() && data.len() < WINDOW as usize {
+            return 0;
+        }
+        debug!(path = http_packet.get_path(), "HTTP request");
+        match http::respond(http_packet).to_bytes() {
+            Ok(response) => self.send(&response),
+            Err(err) => warn!("could not write the HTTP response: {err}"),
+        }
+        // As announced by the response, the connection is closed once it is sent
+        self.close();
+        consumed.min(data.len())
+    }
 
-                let mut ip_response_fin = IPV4Packet::new(
-                    ack_response.header,
-                    TCPPacket::new(TCPHeader::default(), vec![]),
-                );
+    /// Update the metrics with a received segment, returning whether it was already received
+    fn observe_segment(&mut self, tcp_packet: TCPPacketView) -> bool {
+        let header = tcp_packet.header;
+        METRICS.tcp_window.observe(header.get_window() as u64);
+        if header.get_ack()
+            && let Some((sequence_number, sent)) = self.rtt_probe
+            && header
+                .get_acknowledgement_number()
+                .wrapping_sub(sequence_number)
+                < 1 << 31
+        {
+            let rtt = self.clock.now().saturating_duration_since(sent);
+            debug!(?rtt, "round trip time");
+            METRICS.observe_rtt(rtt);
+            self.update_rto(rtt);
+            self.rtt_probe = None;
+        }
+
+        let end = segment_end(tcp_packet);
+        if self.state == TCPConnectionState::Listen || end == header.get_sequence_number() {
+            return false;
+        }
+        // The segment ends before the next expected byte: all of it was received already
+        if self.receive_next.wrapping_sub(end) < 1 << 31 {
+            debug!(
+                sequence_number = header.get_sequence_number(),
+                "duplicate segment"
+            );
+            METRICS
+                .tcp_duplicate_segments_received
+                .fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    /// Accept a reset only at the next sequence number expected, so that a blind attacker has to
+    /// guess it exactly. One elsewhere in the window is answered with a challenge ACK, which a
+    /// peer that really lost the connection answers with a reset we accept (RFC 5961 §3).
+    fn handle_reset(&mut self, sequence_number: u32, interface: &mut Interface<impl Read + Write>) {
+        if self.state == TCPConnectionState::Listen {
+            return;
+        }
+        let offset = sequence_number.wrapping_sub(self.receive_next);
+        if offset == 0 {
+            debug!("connection reset by the peer");
+            self.reset = true;
+            self.set_state(TCPConnectionState::Closed);
+        } else if offset < (self.receive_window() as u32).max(1) {
+            debug!(offset, "reset in the window challenged");
+            self.send_ack(interface);
+        }
+    }
 
-                let tcp_response_fin = &mut ip_response_fin.payload.header;
-                tcp_response_fin.answer(tcp_packet.header);
-                tcp_response_fin.fin = true;
-                tcp_response_fin.sequence_number = self.sequence_number;
-                tcp_response_fin.acknowledgement_number = tcp_packet.header.get_sequence_number()
-                    + tcp_packet.get_payload().size() as u32;
-                tcp_response_fin.window = tcp_packet.header.get_window();
+    pub fn handle_packet(
+        &mut self,
+        config: &TCPConfig,
+        pool: &mut BufferPool,
+        interface: &mut Interface<impl Read + Write>,
+    ) {
+        let span = self.span.clone();
+        let _entered = span.enter();
+        let Ok(ip_packet) = interface.try_get_ip_packet::<TCPPacketView>() else {
+            debug!("truncated TCP segment dropped");
+            return;
+        };
+        let tcp_packet = ip_packet.payload;
+        let header = tcp_packet.header;
+        let retransmitted = self.observe_segment(tcp_packet);
+        self.last_received = self.clock.now();
+        self.probes_sent = 0;
+        self.last_probe = None;
+        if header.get_rst() {
+            self.handle_reset(header.get_sequence_number(), interface);
+            return;
+        }
 
-                interface.write(ack_response);
-                interface.send();
+        if self.state == TCPConnectionState::Listen {
+            if header.get_syn() && !header.get_ack() {
+                self.receive_next = segment_end(tcp_packet);
+                self.peer_window = header.get_window() as u32;
+                self.send_unacknowledged = 3453253245;
+                // The SYN of a peer asking for ECN carries both ECE and CWR, our SYN-ACK only ECE
+                self.ecn = config.ecn && header.get_ece() && header.get_cwr();
+                // A size below the one every host takes (RFC 9293 §3.7.1) would stall the
+                // connection with tiny or, when zero, empty segments
+                let segment_size = interface.mtu - HEADERS_SIZE;
+                self.max_segment_size = header
+                    .get_maximum_segment_size()
+                    .map_or(DEFAULT_SEGMENT_SIZE, usize::from)
+                    .max(DEFAULT_SEGMENT_SIZE)
+                    .min(segment_size);
+                self.congestion_window = INITIAL_WINDOW * self.max_segment_size as u32;
 
-                self.state = TCPConnectionState::CloseWait;
+                self.sequence_number = self.send_unacknowledged.wrapping_add(1);
+                self.rtt_probe = Some((self.sequence_number, self.clock.now()));
+                self.send_syn_ack(interface);
+                self.arm_retransmit();
 
-                interface.write(ip_response_fin);
-                interface.send();
+                self.set_state(TCPConnectionState::SynReceived);
+            }
+            return;
+        }
 
-                self.state = TCPConnectionState::LastAck;
+        let mut congested = false;
+        if self.ecn {
+            if header.get_cwr() {
+                self.ece_pending = false;
             }
-            TCPConnectionState::LastAck => {
-                if tcp_packet.header.get_ack() && tcp_packet.payload.is_empty() {
-                    self.state = TCPConnectionState::Closed;
-                }
+            if ip_packet.header.get_ecn() == ECN_CE {
+                METRICS.tcp_ecn_ce_received.fetch_add(1, Ordering::Relaxed);
+                self.ece_pending = true;
+                congested = true;
+            }
+        }
+        if header.get_ack() {
+            self.handle_ack(tcp_packet);
+        }
+        let everything_acknowledged = self.in_flight() == 0;
+        match self.state {
+            TCPConnectionState::SynReceived if !header.get_syn() && everything_acknowledged => {
+                self.set_state(TCPConnectionState::Established);
+            }
+            TCPConnectionState::FinWait1 if everything_acknowledged => {
+                self.set_state(TCPConnectionState::FinWait2);
+            }
+            TCPConnectionState::Closing if everything_acknowledged => {
+                self.set_state(TCPConnectionState::TimeWait);
+            }
+            TCPConnectionState::LastAck if everything_acknowledged => {
+                self.set_state(TCPConnectionState::Closed);
+                return;
+            }
+            _ => {}
+        }
+
+        if retransmitted {
+            // Our answer was lost, the peer is told again what we received
+            if self.state == TCPConnectionState::SynReceived && header.get_syn() {
+                self.send_syn_ack(interface);
+            } else {
+                self.send_ack(interface);
+            }
+            return;
+        }
+
+        if matches!(
+            self.state,
+            TCPConnectionState::Established
+                | TCPConnectionState::FinWait1
+                | TCPConnectionState::FinWait2
+        ) && segment_end(tcp_packet) != header.get_sequence_number()
+        {
+            if header.get_sequence_number() != self.receive_next
+                || tcp_packet.payload.len() > self.receive_window() as usize
+            {
+                // Answer segments beyond a gap or the window at once, so that the peer knows what
+                // is missing
+                self.send_ack(interface);
+                return;
+            }
+            if header.get_urg() {
+                self.urgent_mark = Some(
+                    header
+                        .get_sequence_number()
+                        .wrapping_add(header.get_urgent_pointer() as u32),
+                );
+            }
+            self.receive_next = segment_end(tcp_packet);
+            if !tcp_packet.payload.is_empty() {
+                self.segments_unacknowledged += 1;
+                self.buffer_data(header.get_sequence_number(), tcp_packet.payload);
             }
-            TCPConnectionState::Established => {
-                if tcp_packet.header.get_ack() && tcp_packet.payload.is_empty() {
-                    return;
+            // The peer pushes what it has written so far, the application gets it without
+            // waiting for more. A socket reads the buffer whenever it wants.
+            if !self.socket && (header.get_psh() || header.get_fin() || self.receive_window() == 0)
+            {
+                let data = std::mem::take(&mut self.receive_buffer);
+                let consumed = self.receive(&data);
+                self.receive_buffer = data[consumed..].to_vec();
+            }
+            if header.get_fin() {
+                self.set_state(match self.state {
+                    TCPConnectionState::Established => TCPConnectionState::CloseWait,
+                    // Both ends closed at once, our FIN is not acknowledged yet
+                    TCPConnectionState::FinWait1 => TCPConnectionState::Closing,
+                    _ => TCPConnectionState::TimeWait,
+                });
+                // The HTTP server has nothing more to say once the request was answered, a socket
+                // is closed by the application
+                if !self.socket {
+                    self.close();
                 }
-                let http_packet = HTTPRequestHeaderView::try_from(tcp_packet.payload);
-                let http_packet = match http_packet {
-                    Ok(p) => p,
-                    // Not an http packet, exit
-                    Err(_) => return,
-                };
-
-                let ack_response = self.make_ack_packet(ip_packet);
-
-                let mut http_response = IPV4Packet::new(
-                    ack_response.header,
-                    TCPPacket::new(
-                        TCPHeader::default(),
-                        HTTPResponsePacket::new(
-                            HTTPResponseHeader::default(),
-                            "<html><b>Hello</b> World !</html>",
-                        ),
-                    ),
+                self.send_ack(interface);
+            }
+        }
+
+        // Queued data carries the acknowledgement along, otherwise it is delayed until a second
+        // segment comes in
+        self.transmit(pool, interface);
+        if self.segments_unacknowledged >= 2 || congested && self.segments_unacknowledged > 0 {
+            self.send_ack(interface);
+        } else if self.segments_unacknowledged > 0 {
+            self.ack_deadline
+                .get_or_insert_with(|| self.clock.now() + config.delayed_ack);
+        }
+    }
+}
+
+/// Answer the TCP segment received by `interface` with a reset (RFC 9293 §3.10.7.1)
+pub fn send_reset(interface: &mut Interface<impl Read + Write>) {
+    let packet = interface.packet();
+    let Ok(header) = IPV4HeaderView::parse(packet) else {
+        return;
+    };
+    let end = (header.get_total_length() as usize).clamp(header.size(), packet.len());
+    let segment = &packet[header.size()..end];
+    let Ok(tcp_header) = TCPHeaderView::parse(segment) else {
+        return;
+    };
+    if tcp_header.get_rst() {
+        return;
+    }
+    let mut reset = TCPHeader {
+        source_port: tcp_header.get_destination_port(),
+        destination_port: tcp_header.get_source_port(),
+        rst: true,
+        ..Default::default()
+    };
+    if tcp_header.get_ack() {
+        reset.sequence_number = tcp_header.get_acknowledgement_number();
+    } else {
+        // SYN and FIN each take a sequence number
+        let length = (segment.len() - tcp_header.size()) as u32
+            + tcp_header.get_syn() as u32
+            + tcp_header.get_fin() as u32;
+        reset.ack = true;
+        reset.acknowledgement_number = tcp_header.get_sequence_number().wrapping_add(length);
+    }
+    let response = IPV4Packet::new(
+        IPV4Header::new(
+            IpProtocol::Tcp,
+            header.get_destination_address(),
+            header.get_source_address(),
+        ),
+        TCPPacket::new(reset, vec![]),
+    );
+    interface.write(response);
+    interface.send();
+}
+
+/// Sequence number following the segment, the SYN and FIN flags counting as one byte each
+fn segment_end(tcp_packet: TCPPacketView) -> u32 {
+    let header = tcp_packet.header;
+    header
+        .get_sequence_number()
+        .wrapping_add(tcp_packet.payload.len() as u32)
+        .wrapping_add(header.get_syn() as u32 + header.get_fin() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{checksum::Checksum, test_support::Wire, traits::ToMutable};
+
+    const LOCAL_ADDRESS: IpV4Addr = IpV4Addr(0xC0A8_0002);
+    const PEER_ADDRESS: IpV4Addr = IpV4Addr(0xC0A8_0001);
+    const PORT: u16 = 8080;
+    const PEER_PORT: u16 = 40000;
+
+    /// A peer talking to the stack over an in-memory interface
+    struct Peer {
+        manager: TCPManager,
+        interfaces: Vec<Interface<Wire>>,
+        wire: Wire,
+        /// Next sequence number of the peer, and initial one of the stack
+        sequence_number: u32,
+        initial_sequence_number: u32,
+        /// ECN field of the IP header of the injected segments
+        ecn: u8,
+    }
+
+    impl Peer {
+        fn new() -> Self {
+            let wire = Wire::default();
+            Self {
+                manager: TCPManager::new(),
+                interfaces: vec![Interface::new(wire.clone())],
+                wire,
+                sequence_number: 1000,
+                initial_sequence_number: 0,
+                ecn: 0,
+            }
+        }
+
+        fn id() -> ConnectionId {
+            ConnectionId {
+                local_address: LOCAL_ADDRESS,
+                local_port: PORT,
+                peer_address: PEER_ADDRESS,
+                peer_port: PEER_PORT,
+            }
+        }
+
+        fn connection(&mut self) -> &mut TCPConnection {
+            self.manager.connection(Self::id()).unwrap()
+        }
+
+        /// A segment of the peer, acknowledging `ack` bytes of the stack
+        fn header(&self, ack: u32) -> TCPHeader {
+            TCPHeader {
+                source_port: PEER_PORT,
+                destination_port: PORT,
+                sequence_number: self.sequence_number,
+                acknowledgement_number: self.initial_sequence_number.wrapping_add(1 + ack),
+                ack: true,
+                window: u16::MAX,
+                ..Default::default()
+            }
+        }
+
+        fn inject(&mut self, header: TCPHeader, payload: &[u8]) {
+            let packet = IPV4Packet::new(
+                IPV4Header {
+                    ecn: self.ecn,
+                    ..IPV4Header::new(IpProtocol::Tcp, PEER_ADDRESS, LOCAL_ADDRESS)
+                },
+                TCPPacket::new(header, payload.to_vec()),
+            )
+            .to_bytes()
+            .unwrap();
+            self.inject_packet(&packet);
+        }
+
+        fn inject_packet(&mut self, packet: &[u8]) {
+            self.interfaces[0].receive_frame(&[&[0, 0, 8, 0][..], packet].concat());
+            self.manager.handle_tcp_packet(&mut self.interfaces[0], 0);
+        }
+
+        /// Take the segments sent by the stack, checking their checksums
+        fn sent(&self) -> Vec<(TCPHeader, Vec<u8>)> {
+            self.wire
+                .sent
+                .take()
+                .into_iter()
+                .map(|frame| {
+                    let packet = &frame[4..];
+                    let segment = &packet[20..];
+                    let checksum = Checksum::new()
+                        .add_slice(&packet[12..20])
+                        .add_byte(packet[9])
+                        .add_2bytes((segment.len() as u16).to_be_bytes())
+                        .add_slice(segment);
+                    assert_eq!(checksum.ones_complement(), 0);
+                    let tcp_packet = IPV4PacketView::<TCPPacketView>::try_from(packet)
+                        .unwrap()
+                        .payload;
+                    (tcp_packet.header.to_mutable(), tcp_packet.payload.to_vec())
+                })
+                .collect()
+        }
+
+        /// Open a connection handed to the application listening to `PORT`
+        fn connect(&mut self) {
+            self.manager.listen(PORT);
+            self.handshake();
+            assert_eq!(self.manager.accept(PORT), Some(Self::id()));
+        }
+
+        /// Open a connection, answered by the HTTP server unless `PORT` is listened to
+        fn handshake(&mut self) {
+            let syn = TCPHeader {
+                syn: true,
+                ack: false,
+                ..self.header(0)
+            };
+            self.inject(syn, &[]);
+            self.sequence_number += 1;
+            let syn_ack = self.sent().remove(0);
+            self.initial_sequence_number = syn_ack.0.sequence_number;
+            self.inject(self.header(0), &[]);
+        }
+    }
+
+    #[test]
+    fn data_segments_built_in_pooled_buffers() {
+        let mut peer = Peer::new();
+        peer.connect();
+        let data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let mut received = vec![];
+        let mut written = 0;
+        while received.len() < data.len() {
+            let chunk = &data[written..(written + 3000).min(data.len())];
+            written += peer.connection().write(chunk).unwrap();
+            peer.manager.poll(&mut peer.interfaces);
+            let sent = peer.sent();
+            assert!(!sent.is_empty());
+            for (header, payload) in sent {
+                assert_eq!(
+                    header.sequence_number,
+                    peer.initial_sequence_number + 1 + received.len() as u32
                 );
+                assert!(payload.len() <= DEFAULT_SEGMENT_SIZE);
+                received.extend(payload);
+            }
+            // Acknowledging frees the front of the send buffer, the next data wrapping around
+            let ack = peer.header(received.len() as u32);
+            peer.inject(ack, &[]);
+        }
+        assert_eq!(received, data);
+        assert_eq!(peer.manager.pool.allocated, 1);
+    }
+
+    #[test]
+    fn malformed_segments_dropped() {
+        let mut peer = Peer::new();
+        peer.connect();
+        let packet = IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Tcp, PEER_ADDRESS, LOCAL_ADDRESS),
+            TCPPacket::new(peer.header(0), b"data".to_vec()),
+        )
+        .to_bytes()
+        .unwrap();
+
+        // A header cut in the middle of its fixed part
+        peer.inject_packet(&packet[..20 + 12]);
+        // A data offset of 4 words, shorter than the fixed part
+        let mut short_offset = packet.clone();
+        short_offset[20 + 12] = 4 << 4;
+        peer.inject_packet(&short_offset);
 
-                let tcp_response = &mut http_response.payload.header;
-                tcp_response.answer(tcp_packet.header);
-                tcp_response.sequence_number = self.sequence_number;
-                self.sequence_number += http_response.payload.payload.payload.size() as u32;
-                tcp_response.window = tcp_packet.header.get_window();
-                tcp_response.ack = true;
-                tcp_response.acknowledgement_number =
-                    ack_response.payload.header.acknowledgement_number;
-
-                let http_header = &mut http_response.payload.payload.header;
-                http_header.version = http_packet.get_version();
-                http_header.code = 200;
-                http_header.headers.extend([
-                    (
-                        "Content-Type".to_string(),
-                        "text/html; charset=UTF-8".to_string(),
-                    ),
-                    (
-                        "Content-Length".to_string(),
-                        http_response.payload.payload.payload.len().to_string(),
-                    ),
-                    ("Connection".to_string(), "close".to_string()),
-                ]);
-
-                interface.write(ack_response);
-                interface.send();
-
-                interface.write(http_response);
-                interface.send();
+        assert!(peer.sent().is_empty());
+        assert_eq!(peer.connection().state, TCPConnectionState::Established);
+        let read = peer.connection().read(&mut [0; 16]);
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        // The connection still takes well-formed segments
+        peer.inject_packet(&packet);
+        assert_eq!(peer.connection().read(&mut [0; 16]).unwrap(), 4);
+    }
+
+    #[test]
+    fn frame_padding_left_out_of_the_stream() {
+        let mut peer = Peer::new();
+        peer.connect();
+        let packet = IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Tcp, PEER_ADDRESS, LOCAL_ADDRESS),
+            TCPPacket::new(peer.header(0), b"hi".to_vec()),
+        )
+        .to_bytes()
+        .unwrap();
+        // 20 + 20 + 2 bytes padded to the 46 bytes of the smallest Ethernet payload
+        peer.inject_packet(&[&packet[..], &[0; 4]].concat());
+        let mut buf = [0; 16];
+        assert_eq!(peer.connection().read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"hi");
+    }
+
+    #[test]
+    fn duplicate_segments_counted() {
+        let mut peer = Peer::new();
+        peer.connect();
+        peer.connection().write(b"ping").unwrap();
+        peer.manager.poll(&mut peer.interfaces);
+        peer.sent();
+        let request = peer.header(4);
+        peer.inject(request.clone(), b"data");
+        peer.sequence_number += 4;
+        assert_eq!(peer.connection().read(&mut [0; 16]).unwrap(), 4);
+
+        // The metrics are shared with the tests running in parallel, only an increase is checked
+        let duplicates = || {
+            METRICS
+                .tcp_duplicate_segments_received
+                .load(Ordering::Relaxed)
+        };
+        let before = duplicates();
+        peer.inject(request, b"data");
+        assert!(duplicates() > before);
+        // The duplicate is acknowledged again, its data not delivered twice
+        let (ack, _) = peer.sent().pop().unwrap();
+        assert_eq!(ack.acknowledgement_number, peer.sequence_number);
+        assert!(peer.connection().read(&mut [0; 16]).is_err());
+    }
+
+    #[test]
+    fn http_request_answered_in_segments_then_closed() {
+        let mut peer = Peer::new();
+        peer.handshake();
+        let request = b"GET /metrics HTTP/1.1\r\nHost: stack\r\n\r\n";
+        peer.inject(
+            TCPHeader {
+                psh: true,
+                ..peer.header(0)
+            },
+            request,
+        );
+        peer.sequence_number += request.len() as u32;
+
+        let mut response = vec![];
+        let mut fin = None;
+        while fin.is_none() {
+            let sent = peer.sent();
+            assert!(!sent.is_empty());
+            for (header, payload) in sent {
+                // Every segment acknowledges the request up to its end
+                assert!(header.ack);
+                assert_eq!(header.acknowledgement_number, peer.sequence_number);
+                assert!(payload.len() <= DEFAULT_SEGMENT_SIZE);
+                response.extend(payload);
+                if header.fin {
+                    fin = Some(header);
+                }
             }
-            _ => {}
+            let ack = peer.header(response.len() as u32);
+            peer.inject(ack, &[]);
         }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("text/plain; version=0.0.4"));
+        assert!(response.contains("\ntcp_rust_tcp_segments_received_total "));
+        // More than a segment was needed
+        assert!(response.len() > DEFAULT_SEGMENT_SIZE);
 
-        println!("answered TCP packet");
+        // The FIN of the peer, acknowledged one past its sequence number
+        let fin = TCPHeader {
+            fin: true,
+            ..peer.header(response.len() as u32 + 1)
+        };
+        peer.inject(fin, &[]);
+        let (ack, _) = peer.sent().pop().unwrap();
+        assert_eq!(ack.acknowledgement_number, peer.sequence_number + 1);
+        assert_eq!(peer.connection().state, TCPConnectionState::TimeWait);
     }
 
-    fn make_ack_packet<'a>(
-        &self,
-        ip_packet: IPV4PacketView<'a, TCPPacketView<'a>>,
-    ) -> IPV4Packet<TCPPacket> {
-        let tcp_packet = ip_packet.payload;
+    #[test]
+    fn keepalive_parsed() {
+        assert_eq!(
+            " 60, 10 ,3".parse::<Keepalive>().unwrap(),
+            Keepalive {
+                idle: Duration::from_secs(60),
+                interval: Duration::from_secs(10),
+                probes: 3,
+            }
+        );
+        for invalid in [
+            "",
+            "60,10",
+            "60,10,3,1",
+            "60,ten,3",
+            "60,10,-3",
+            "60,10,4294967296",
+        ] {
+            assert!(invalid.parse::<Keepalive>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn connections_time_out_per_state() {
+        let config = TCPConfig::default();
+        assert_eq!(
+            config.timeout(TCPConnectionState::SynReceived),
+            Some(config.syn_received_timeout)
+        );
+        for state in [
+            TCPConnectionState::FinWait1,
+            TCPConnectionState::FinWait2,
+            TCPConnectionState::Closing,
+            TCPConnectionState::LastAck,
+        ] {
+            assert_eq!(config.timeout(state), Some(config.fin_wait2_timeout));
+        }
+        assert_eq!(
+            config.timeout(TCPConnectionState::TimeWait),
+            Some(config.time_wait_timeout)
+        );
+        // Kept alive by the probes instead
+        assert_eq!(config.timeout(TCPConnectionState::Established), None);
+
+        // A handshake left unfinished
+        let mut peer = Peer::new();
+        let syn = TCPHeader {
+            syn: true,
+            ack: false,
+            ..peer.header(0)
+        };
+        peer.inject(syn, &[]);
+        assert_eq!(peer.manager.half_open, 1);
+        peer.manager.poll(&mut peer.interfaces);
+        assert!(peer.manager.connection(Peer::id()).is_some());
+        peer.connection().last_received -= config.syn_received_timeout;
+        peer.manager.poll(&mut peer.interfaces);
+        assert!(peer.manager.connection(Peer::id()).is_none());
+        assert_eq!(peer.manager.half_open, 0);
+    }
+
+    #[test]
+    fn sockets_told_how_their_connection_ended() {
+        let reset = |peer: &Peer| TCPHeader {
+            rst: true,
+            ..peer.header(0)
+        };
+
+        // Reset once accepted
+        let mut peer = Peer::new();
+        peer.connect();
+        peer.inject(reset(&peer), &[]);
+        assert!(peer.manager.connection(Peer::id()).is_none());
+        assert_eq!(
+            peer.manager.error(Peer::id()),
+            Some(io::ErrorKind::ConnectionReset)
+        );
+        peer.manager.release(Peer::id());
+        assert_eq!(peer.manager.error(Peer::id()), None);
+
+        // Reset before being accepted, which no socket is told
+        let mut peer = Peer::new();
+        peer.manager.listen(PORT);
+        peer.handshake();
+        peer.inject(reset(&peer), &[]);
+        assert!(peer.manager.connection(Peer::id()).is_none());
+        assert_eq!(peer.manager.error(Peer::id()), None);
+
+        // Closed by both ends
+        let mut peer = Peer::new();
+        peer.connect();
+        let fin = TCPHeader {
+            fin: true,
+            ..peer.header(0)
+        };
+        peer.inject(fin, &[]);
+        peer.sequence_number += 1;
+        peer.connection().close();
+        peer.manager.poll(&mut peer.interfaces);
+        assert_eq!(peer.connection().state, TCPConnectionState::LastAck);
+        peer.inject(peer.header(1), &[]);
+        assert!(peer.manager.connection(Peer::id()).is_none());
+        assert_eq!(peer.manager.error(Peer::id()), None);
+
+        // Timed out waiting for the FIN of the peer
+        let mut peer = Peer::new();
+        peer.connect();
+        peer.connection().close();
+        peer.manager.poll(&mut peer.interfaces);
+        peer.inject(peer.header(1), &[]);
+        assert_eq!(peer.connection().state, TCPConnectionState::FinWait2);
+        peer.connection().last_received -= TCPConfig::default().fin_wait2_timeout;
+        peer.manager.poll(&mut peer.interfaces);
+        assert!(peer.manager.connection(Peer::id()).is_none());
+        assert_eq!(
+            peer.manager.error(Peer::id()),
+            Some(io::ErrorKind::TimedOut)
+        );
+    }
+
+    #[test]
+    fn oldest_half_open_connection_evicted() {
+        let mut peer = Peer::new();
+        peer.manager.config.max_half_open = 2;
+        let id = |peer_port| ConnectionId {
+            peer_port,
+            ..Peer::id()
+        };
+        for peer_port in [40001, 40002, 40003] {
+            let syn = TCPHeader {
+                source_port: peer_port,
+                syn: true,
+                ack: false,
+                ..peer.header(0)
+            };
+            peer.inject(syn, &[]);
+            // The first attempt is the oldest, the second one being retried since
+            if peer_port == 40002 {
+                peer.manager.connection(id(40001)).unwrap().last_received -= Duration::from_secs(2);
+                peer.manager.connection(id(40002)).unwrap().last_received -= Duration::from_secs(1);
+            }
+        }
+        assert!(peer.manager.connection(id(40001)).is_none());
+        assert!(peer.manager.connection(id(40002)).is_some());
+        assert!(peer.manager.connection(id(40003)).is_some());
+        assert_eq!(peer.manager.half_open, 2);
+
+        // Finishing a handshake makes room without evicting anyone
+        peer.initial_sequence_number = peer.sent().pop().unwrap().0.sequence_number;
+        peer.sequence_number += 1;
+        peer.inject(
+            TCPHeader {
+                source_port: 40002,
+                ..peer.header(0)
+            },
+            &[],
+        );
+        assert_eq!(peer.manager.half_open, 1);
+    }
+
+    #[test]
+    fn reset_accepted_only_at_the_next_sequence_number() {
+        let mut peer = Peer::new();
+        peer.connect();
+        let reset = |peer: &Peer, offset: u32| TCPHeader {
+            sequence_number: peer.sequence_number.wrapping_add(offset),
+            rst: true,
+            ack: false,
+            ..peer.header(0)
+        };
+
+        // Out of the window, ignored
+        peer.inject(reset(&peer, u32::from(WINDOW) + 1), &[]);
+        assert!(peer.sent().is_empty());
+        // In the window, challenged with an acknowledgement of what we expect
+        peer.inject(reset(&peer, 1), &[]);
+        let sent = peer.sent();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].0.ack && !sent[0].0.rst);
+        assert_eq!(sent[0].0.acknowledgement_number, peer.sequence_number);
+        assert_eq!(peer.connection().state, TCPConnectionState::Established);
+
+        peer.inject(reset(&peer, 0), &[]);
+        assert!(peer.manager.connection(Peer::id()).is_none());
+    }
 
-        let mut ip_response = IPV4Packet::new(
-            ip_packet.header.to_mutable(),
-            TCPPacket::new(TCPHeader::default(), vec![]),
+    #[test]
+    fn closed_window_probed_with_backoff() {
+        let mut peer = Peer::new();
+        peer.connect();
+        let closed = TCPHeader {
+            window: 0,
+            ..peer.header(0)
+        };
+        peer.inject(closed, &[]);
+        peer.connection().write(b"queued").unwrap();
+        peer.manager.poll(&mut peer.interfaces);
+        assert!(peer.sent().is_empty());
+        let deadline = peer.connection().persist_deadline.unwrap();
+        assert!(peer.manager.next_timer() <= Some(deadline));
+
+        // The first byte beyond the window, probed again less and less often
+        for backoff in 1..=2 {
+            peer.connection().persist_deadline = Some(Instant::now());
+            peer.manager.poll(&mut peer.interfaces);
+            let sent = peer.sent();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].0.sequence_number, peer.initial_sequence_number + 1);
+            assert_eq!(sent[0].1, b"q");
+            let deadline = peer.connection().persist_deadline.unwrap();
+            let interval = PERSIST_MIN * (1 << backoff);
+            assert!(deadline > Instant::now() + interval - Duration::from_millis(50));
+        }
+
+        // The window opens, the data flows and the probes stop
+        peer.inject(peer.header(0), &[]);
+        let sent = peer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, b"queued");
+        assert_eq!(peer.connection().persist_deadline, None);
+    }
+
+    #[test]
+    fn small_segments_wait_for_the_data_in_flight() {
+        let mut peer = Peer::new();
+        peer.connect();
+        peer.connection().write(b"first").unwrap();
+        peer.connection().write(b"second").unwrap();
+        peer.manager.poll(&mut peer.interfaces);
+        let sent = peer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, b"firstsecond");
+
+        // Nagle's algorithm holds the small segment back until the acknowledgement
+        peer.connection().write(b"third").unwrap();
+        peer.manager.poll(&mut peer.interfaces);
+        assert!(peer.sent().is_empty());
+        peer.inject(peer.header(11), &[]);
+        let sent = peer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, b"third");
+
+        // Unless disabled
+        peer.connection().set_no_delay(true);
+        peer.connection().write(b"fourth").unwrap();
+        peer.manager.poll(&mut peer.interfaces);
+        assert_eq!(peer.sent()[0].1, b"fourth");
+    }
+
+    #[test]
+    fn acknowledgements_delayed_until_a_second_segment() {
+        let mut peer = Peer::new();
+        peer.connect();
+        peer.inject(peer.header(0), b"one");
+        peer.sequence_number += 3;
+        peer.manager.poll(&mut peer.interfaces);
+        assert!(peer.sent().is_empty());
+        let deadline = peer.connection().ack_deadline.unwrap();
+        assert!(deadline <= Instant::now() + TCPConfig::default().delayed_ack);
+        assert_eq!(peer.manager.next_timer(), Some(deadline));
+
+        // The second segment is acknowledged along with the first at once
+        peer.inject(peer.header(0), b"two");
+        peer.sequence_number += 3;
+        let sent = peer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.acknowledgement_number, peer.sequence_number);
+        assert_eq!(peer.connection().ack_deadline, None);
+
+        // A single segment is acknowledged once the delay is over
+        peer.inject(peer.header(0), b"three");
+        peer.sequence_number += 5;
+        peer.connection().ack_deadline = Some(Instant::now());
+        peer.manager.poll(&mut peer.interfaces);
+        let sent = peer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.acknowledgement_number, peer.sequence_number);
+    }
+
+    #[test]
+    fn socket_closed_first() {
+        let mut peer = Peer::new();
+        peer.connect();
+        peer.connection().write(b"bye").unwrap();
+        peer.connection().close();
+        peer.manager.poll(&mut peer.interfaces);
+        let sent = peer.sent();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1].0.fin && sent[1].0.ack);
+        assert_eq!(peer.connection().state, TCPConnectionState::FinWait1);
+
+        peer.inject(peer.header(4), &[]);
+        assert_eq!(peer.connection().state, TCPConnectionState::FinWait2);
+        // The peer may still send data before its FIN
+        peer.inject(peer.header(4), b"late");
+        peer.sequence_number += 4;
+        let fin = TCPHeader {
+            fin: true,
+            ..peer.header(4)
+        };
+        peer.inject(fin, &[]);
+        let (ack, _) = peer.sent().pop().unwrap();
+        assert_eq!(ack.acknowledgement_number, peer.sequence_number + 1);
+        assert_eq!(peer.connection().state, TCPConnectionState::TimeWait);
+        let mut buf = [0; 8];
+        assert_eq!(peer.connection().read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"late");
+        assert_eq!(peer.connection().read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn fin_wait1_left_for_closing_or_time_wait() {
+        let close = |peer: &mut Peer| {
+            peer.connect();
+            peer.connection().close();
+            peer.manager.poll(&mut peer.interfaces);
+            assert!(peer.sent()[0].0.fin);
+            assert_eq!(peer.connection().state, TCPConnectionState::FinWait1);
+        };
+        let fin = |peer: &Peer, ack| TCPHeader {
+            fin: true,
+            ..peer.header(ack)
+        };
+
+        // Both ends close at once, our FIN being acknowledged after theirs
+        let mut peer = Peer::new();
+        close(&mut peer);
+        // Data still comes in before the FIN of the peer
+        peer.inject(peer.header(0), b"late");
+        peer.sequence_number += 4;
+        assert_eq!(peer.connection().state, TCPConnectionState::FinWait1);
+        peer.inject(fin(&peer, 0), &[]);
+        peer.sequence_number += 1;
+        assert_eq!(peer.connection().state, TCPConnectionState::Closing);
+        let (ack, _) = peer.sent().pop().unwrap();
+        assert_eq!(ack.acknowledgement_number, peer.sequence_number);
+        peer.inject(peer.header(1), &[]);
+        assert_eq!(peer.connection().state, TCPConnectionState::TimeWait);
+        let mut buf = [0; 8];
+        assert_eq!(peer.connection().read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"late");
+
+        // Their FIN acknowledges ours, skipping FinWait2
+        let mut peer = Peer::new();
+        close(&mut peer);
+        peer.inject(fin(&peer, 1), &[]);
+        assert_eq!(peer.connection().state, TCPConnectionState::TimeWait);
+        let (ack, _) = peer.sent().pop().unwrap();
+        assert_eq!(ack.acknowledgement_number, peer.sequence_number + 1);
+    }
+
+    #[test]
+    fn unacknowledged_data_retransmitted_then_given_up() {
+        let mut peer = Peer::new();
+        peer.connect();
+        let data: Vec<u8> = (0..3 * DEFAULT_SEGMENT_SIZE).map(|i| i as u8).collect();
+        peer.connection().write(&data).unwrap();
+        peer.manager.poll(&mut peer.interfaces);
+        assert_eq!(peer.sent().len(), 3);
+        let rto = peer.connection().rto;
+
+        // Only the oldest segment is sent again, with a doubled timeout and one segment of
+        // congestion window
+        peer.connection().retransmit_deadline = Some(Instant::now());
+        peer.manager.poll(&mut peer.interfaces);
+        let sent = peer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.sequence_number, peer.initial_sequence_number + 1);
+        assert_eq!(sent[0].1, data[..DEFAULT_SEGMENT_SIZE]);
+        assert_eq!(peer.connection().rto, rto * 2);
+        assert_eq!(
+            peer.connection().congestion_window,
+            DEFAULT_SEGMENT_SIZE as u32
         );
 
-        ip_response.header.answer();
+        // Acknowledging everything stops the timer
+        peer.inject(peer.header(data.len() as u32), &[]);
+        assert_eq!(peer.connection().retransmit_deadline, None);
+        assert_eq!(peer.connection().retransmits, 0);
+
+        peer.connection().write(b"lost").unwrap();
+        peer.manager.poll(&mut peer.interfaces);
+        peer.sent();
+        for _ in 0..MAX_RETRANSMITS {
+            peer.connection().retransmit_deadline = Some(Instant::now());
+            peer.manager.poll(&mut peer.interfaces);
+            assert_eq!(peer.sent()[0].1, b"lost");
+        }
+        peer.connection().retransmit_deadline = Some(Instant::now());
+        peer.manager.poll(&mut peer.interfaces);
+        assert!(peer.manager.connection(Peer::id()).is_none());
+    }
+
+    #[test]
+    fn retransmission_timeout_follows_the_round_trip_time() {
+        let new = || {
+            TCPConnection::new(
+                Peer::id(),
+                0,
+                MacAddr::default(),
+                Span::none(),
+                Clock::default(),
+            )
+        };
+        let mut connection = new();
+        assert_eq!(connection.rto, INITIAL_RTO);
+        connection.update_rto(Duration::from_millis(100));
+        // 100ms and four times half of it
+        assert_eq!(connection.rto, Duration::from_millis(300));
+        connection.update_rto(Duration::from_millis(100));
+        assert_eq!(connection.rto, Duration::from_millis(250));
+        // Within bounds
+        let mut connection = new();
+        connection.update_rto(Duration::from_millis(1));
+        assert_eq!(connection.rto, MIN_RTO);
+        let mut connection = new();
+        connection.update_rto(Duration::from_secs(100));
+        assert_eq!(connection.rto, MAX_RTO);
+    }
+
+    #[test]
+    fn congestion_experienced_echoed_until_cwr() {
+        let mut peer = Peer::new();
+        peer.manager.listen(PORT);
+        let syn = TCPHeader {
+            syn: true,
+            ack: false,
+            ece: true,
+            cwr: true,
+            ..peer.header(0)
+        };
+        peer.inject(syn, &[]);
+        peer.sequence_number += 1;
+        let (syn_ack, _) = peer.sent().remove(0);
+        assert!(syn_ack.ece && !syn_ack.cwr);
+        peer.initial_sequence_number = syn_ack.sequence_number;
+        peer.inject(peer.header(0), &[]);
+        peer.manager.accept(PORT).unwrap();
+
+        // Our data is sent ECN capable
+        peer.connection().write(b"data").unwrap();
+        peer.manager.poll(&mut peer.interfaces);
+        let frame = peer.wire.sent.take().pop().unwrap();
+        assert_eq!(frame[4 + 1] & 0b11, ECN_ECT0);
+
+        // A congestion experienced is acknowledged at once with ECE, until the peer sends CWR
+        peer.ecn = ECN_CE;
+        peer.inject(peer.header(4), b"one");
+        peer.sequence_number += 3;
+        peer.ecn = ECN_ECT0;
+        let (ack, _) = peer.sent().pop().unwrap();
+        assert!(ack.ece);
+        peer.inject(peer.header(4), b"two");
+        peer.sequence_number += 3;
+        peer.inject(peer.header(4), b"three");
+        peer.sequence_number += 5;
+        assert!(peer.sent().pop().unwrap().0.ece);
+        let cwr = TCPHeader {
+            cwr: true,
+            ..peer.header(4)
+        };
+        peer.inject(cwr, b"four");
+        peer.sequence_number += 4;
+        peer.inject(peer.header(4), b"five");
+        peer.sequence_number += 4;
+        assert!(!peer.sent().pop().unwrap().0.ece);
 
-        let tcp_response = &mut ip_response.payload.header;
-        tcp_response.answer(tcp_packet.header);
-        tcp_response.ack = true;
-        tcp_response.sequence_number = self.sequence_number;
-        tcp_response.acknowledgement_number = tcp_packet.header.get_sequence_number() + 1;
-        tcp_response.window = tcp_packet.header.get_window();
+        // Our own congestion window halves when the peer echoes ECE, once per window
+        let window = peer.connection().congestion_window;
+        peer.connection().write(&[0; 1000]).unwrap();
+        peer.manager.poll(&mut peer.interfaces);
+        let ece = TCPHeader {
+            ece: true,
+            ..peer.header(4 + DEFAULT_SEGMENT_SIZE as u32)
+        };
+        peer.inject(ece, &[]);
+        assert!(peer.connection().congestion_window < window);
+        let (segment, _) = peer.sent().pop().unwrap();
+        assert!(segment.cwr);
+    }
+
+    #[test]
+    fn http_request_answered_once_pushed_with_its_body() {
+        let mut peer = Peer::new();
+        peer.handshake();
+        // Without PSH the server waits for the rest of the request
+        let header = b"GET / HTTP/1.1\r\nContent-Length: 5\r\n";
+        peer.inject(peer.header(0), header);
+        peer.sequence_number += header.len() as u32;
+        peer.manager.poll(&mut peer.interfaces);
+        assert!(peer.sent().is_empty());
+
+        // Pushed, but the body announced is not there yet
+        let push = TCPHeader {
+            psh: true,
+            ..peer.header(0)
+        };
+        peer.inject(push.clone(), b"\r\n");
+        peer.sequence_number += 2;
+        assert!(peer.sent().iter().all(|(_, payload)| payload.is_empty()));
 
-        ip_response
+        // The urgent byte stays out of the request, for the application to take
+        let urgent = TCPHeader {
+            urg: true,
+            urgent_pointer: 3,
+            sequence_number: peer.sequence_number,
+            ..push
+        };
+        peer.inject(urgent, b"he!llo");
+        peer.sequence_number += 6;
+        let sent = peer.sent();
+        assert!(sent[0].1.starts_with(b"HTTP/1.1 200"));
+        assert_eq!(sent[0].0.acknowledgement_number, peer.sequence_number);
+        assert_eq!(peer.connection().read_urgent(), Some(b'!'));
+    }
+
+    #[test]
+    fn http_request_without_headers_answered() {
+        let mut peer = Peer::new();
+        peer.handshake();
+        let request = b"GET / HTTP/1.0\r\n\r\n";
+        let push = TCPHeader {
+            psh: true,
+            ..peer.header(0)
+        };
+        peer.inject(push, request);
+        peer.sequence_number += request.len() as u32;
+        let sent = peer.sent();
+        assert!(sent[0].1.starts_with(b"HTTP/1.0 200"));
+        assert_eq!(sent[0].0.acknowledgement_number, peer.sequence_number);
+    }
+
+    #[test]
+    fn malformed_http_request_line_rejected() {
+        for request in [&b"GET /\r\nHost: a\r\n\r\n"[..], b"GET / HTTP/1.10\r\n\r\n"] {
+            let mut peer = Peer::new();
+            peer.handshake();
+            let push = TCPHeader {
+                psh: true,
+                ..peer.header(0)
+            };
+            peer.inject(push, request);
+            let sent = peer.sent();
+            assert!(sent[0].1.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+            assert!(sent.iter().any(|(header, _)| header.fin));
+        }
+    }
+
+    #[test]
+    fn segment_size_negotiated_within_bounds() {
+        for (announced, mtu, expected) in [
+            (None, 1500, DEFAULT_SEGMENT_SIZE),
+            (Some(1460), 1500, 1460),
+            (Some(9000), 1500, 1460),
+            (Some(1400), 1500, 1400),
+            (Some(1460), 1000, 960),
+            // A peer announcing nothing usable gets the minimum every host takes
+            (Some(0), 1500, DEFAULT_SEGMENT_SIZE),
+            (Some(100), 1500, DEFAULT_SEGMENT_SIZE),
+            // Unless our own MTU is smaller
+            (Some(0), 576, 536),
+            (Some(0), 300, 260),
+        ] {
+            let mut peer = Peer::new();
+            peer.interfaces[0].mtu = mtu;
+            let syn = TCPHeader {
+                syn: true,
+                ack: false,
+                options: announced
+                    .map(TCPOption::maximum_segment_size)
+                    .into_iter()
+                    .collect(),
+                ..peer.header(0)
+            };
+            peer.inject(syn, &[]);
+            let (syn_ack, _) = peer.sent().remove(0);
+            assert_eq!(
+                syn_ack.options,
+                [TCPOption::maximum_segment_size((mtu - HEADERS_SIZE) as u16)]
+            );
+            assert_eq!(
+                peer.connection().max_segment_size,
+                expected,
+                "{announced:?}"
+            );
+            // Congestion control keeps working with the smallest sizes
+            peer.connection().grow_congestion_window(1);
+            peer.connection().slow_start_threshold = 0;
+            peer.connection().grow_congestion_window(1);
+        }
+    }
+
+    #[test]
+    fn connections_sharded_by_their_addresses_and_ports() {
+        let id = |peer_port| ConnectionId {
+            peer_port,
+            ..Peer::id()
+        };
+        assert_eq!(id(PEER_PORT).shard(1), 0);
+        // The shard only depends on the connection, as every worker must agree on it
+        assert_eq!(id(PEER_PORT).shard(4), Peer::id().shard(4));
+        let mut counts = [0; 4];
+        for peer_port in 0..4000 {
+            let shard = id(peer_port).shard(counts.len());
+            assert_eq!(shard, id(peer_port).shard(counts.len()));
+            counts[shard] += 1;
+        }
+        // Spread evenly enough over the shards
+        assert!(
+            counts.iter().all(|&count| (800..1200).contains(&count)),
+            "{counts:?}"
+        );
     }
 }
@@ -1,19 +1,61 @@
+#[cfg(test)]
+mod conformance;
 pub mod manager;
 
-use std::{
-    fmt::Debug,
-    io::{self, Write},
-};
+use std::io::{self, Write};
+
+use tcp_rust_macros::PacketHeader;
 
 use crate::{
-    ip::IPV4Packet,
+    ip::TransportChecksum,
     packet::{Packet, PacketView},
-    traits::{AsArrayUnchecked, Data, DataOwned, Prepare, ToMutable, WriteTo},
+    traits::{Data, DataOwned, Prepare, ToMutable, WriteTo},
 };
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct TCPHeaderView<'a> {
-    content: &'a [u8],
+/// Kind of the option announcing the largest segment the sender of a SYN accepts
+pub const OPTION_MAXIMUM_SEGMENT_SIZE: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct TCPOption {
+    kind: u8,
+    len: u8,
+    data: Option<Vec<u8>>,
+}
+
+impl TCPOption {
+    pub fn maximum_segment_size(size: u16) -> Self {
+        Self {
+            kind: OPTION_MAXIMUM_SEGMENT_SIZE,
+            len: 4,
+            data: Some(size.to_be_bytes().to_vec()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default, PacketHeader)]
+#[packet_header(length = header_length)]
+pub struct TCPHeader {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub sequence_number: u32,
+    pub acknowledgement_number: u32,
+    #[bits(4)]
+    pub data_offset: u8,
+    #[bits(4)]
+    pub reserved: u8,
+    pub cwr: bool,
+    pub ece: bool,
+    pub urg: bool,
+    pub ack: bool,
+    pub psh: bool,
+    pub rst: bool,
+    pub syn: bool,
+    pub fin: bool,
+    pub window: u16,
+    pub checksum: u16,
+    pub urgent_pointer: u16,
+    #[options]
+    pub options: Vec<TCPOption>,
 }
 
 impl ToMutable for TCPHeaderView<'_> {
@@ -43,93 +85,38 @@ impl ToMutable for TCPHeaderView<'_> {
     }
 }
 
-impl<'a> From<&'a [u8]> for TCPHeaderView<'a> {
-    fn from(value: &'a [u8]) -> Self {
-        let length = (value[12] >> 4) as usize * 4;
-        Self {
-            content: &value[..length],
-        }
-    }
-}
-
-impl<'a> AsRef<[u8]> for TCPHeaderView<'a> {
-    fn as_ref(&self) -> &[u8] {
-        self.content
-    }
-}
-
-impl Data for TCPHeaderView<'_> {
-    fn size(&self) -> usize {
-        self.content.len()
-    }
-}
-
 impl<'a> TCPHeaderView<'a> {
-    pub fn get_source_port(&self) -> u16 {
-        u16::from_be_bytes(*unsafe { self.content[0..2].as_array_unchecked() })
-    }
-    pub fn get_destination_port(&self) -> u16 {
-        u16::from_be_bytes(*unsafe { self.content[2..4].as_array_unchecked() })
-    }
-    pub fn get_sequence_number(&self) -> u32 {
-        u32::from_be_bytes(*unsafe { self.content[4..8].as_array_unchecked() })
-    }
-    pub fn get_acknowledgement_number(&self) -> u32 {
-        u32::from_be_bytes(*unsafe { self.content[8..12].as_array_unchecked() })
-    }
-    pub fn get_data_offset(&self) -> u8 {
-        self.content[12] >> 4
-    }
-    pub fn get_reserved(&self) -> u8 {
-        self.content[12] & 0xF
-    }
-    pub fn get_cwr(&self) -> bool {
-        (self.content[13] & (1 << 7)) != 0
-    }
-    pub fn get_ece(&self) -> bool {
-        (self.content[13] & (1 << 6)) != 0
-    }
-    pub fn get_urg(&self) -> bool {
-        (self.content[13] & (1 << 5)) != 0
-    }
-    pub fn get_ack(&self) -> bool {
-        (self.content[13] & (1 << 4)) != 0
-    }
-    pub fn get_psh(&self) -> bool {
-        (self.content[13] & (1 << 3)) != 0
-    }
-    pub fn get_rst(&self) -> bool {
-        (self.content[13] & (1 << 2)) != 0
-    }
-    pub fn get_syn(&self) -> bool {
-        (self.content[13] & (1 << 1)) != 0
-    }
-    pub fn get_fin(&self) -> bool {
-        (self.content[13] & (1 << 0)) != 0
-    }
-    pub fn get_window(&self) -> u16 {
-        u16::from_be_bytes(*unsafe { self.content[14..16].as_array_unchecked() })
-    }
-    pub fn get_checksum(&self) -> u16 {
-        u16::from_be_bytes(*unsafe { self.content[16..18].as_array_unchecked() })
-    }
-    pub fn get_urgent_pointer(&self) -> u16 {
-        u16::from_be_bytes(*unsafe { self.content[18..20].as_array_unchecked() })
-    }
-    pub fn get_options(&self) -> &[u8] {
-        &self.content[20..self.size() - 20]
+    /// Length of the header with its options
+    fn header_length(&self) -> usize {
+        self.get_data_offset() as usize * 4
     }
     pub fn get_parsed_options(&self) -> Vec<TCPOption> {
-        let mut i = 20;
+        let options = self.get_options();
+        let mut i = 0;
         let mut res = vec![];
-        while i < self.size() {
-            let kind = self.content[i];
-            let len = if kind >= 2 { self.content[i + 1] } else { 1 };
+        while i < options.len() {
+            let kind = options[i];
+            // End of the option list, the rest is padding
+            if kind == 0 {
+                break;
+            }
+            let len = if kind >= 2 {
+                match options.get(i + 1) {
+                    Some(&len) => len,
+                    None => break,
+                }
+            } else {
+                1
+            };
+            // A malformed option ends the parsing, instead of looping or reading past the header
+            if kind >= 2 && (len < 2 || i + len as usize > options.len()) {
+                break;
+            }
             res.push(TCPOption {
                 kind,
                 len,
                 data: if len > 2 {
-                    Some(self.content[i + 2..i + len as usize].to_vec())
+                    Some(options[i + 2..i + len as usize].to_vec())
                 } else {
                     None
                 },
@@ -138,60 +125,14 @@ impl<'a> TCPHeaderView<'a> {
         }
         res
     }
-}
-
-impl Debug for TCPHeaderView<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("TCPHeaderView")
-            .field("source_port", &self.get_source_port())
-            .field("destination_port", &self.get_destination_port())
-            .field("sequence_number", &self.get_sequence_number())
-            .field("acknowledgement_number", &self.get_acknowledgement_number())
-            .field("data_offset", &self.get_data_offset())
-            .field("reserved", &self.get_reserved())
-            .field("cwr", &self.get_cwr())
-            .field("ece", &self.get_ece())
-            .field("urg", &self.get_urg())
-            .field("ack", &self.get_ack())
-            .field("psh", &self.get_psh())
-            .field("rst", &self.get_rst())
-            .field("syn", &self.get_syn())
-            .field("fin", &self.get_fin())
-            .field("window", &self.get_window())
-            .field("checksum", &self.get_checksum())
-            .field("urgent_pointer", &self.get_urgent_pointer())
-            .field("options", &self.get_parsed_options())
-            .finish()
+    /// Largest segment the sender accepts, announced in its SYN
+    pub fn get_maximum_segment_size(&self) -> Option<u16> {
+        self.get_parsed_options()
+            .into_iter()
+            .find(|option| option.kind == OPTION_MAXIMUM_SEGMENT_SIZE)
+            .and_then(|option| Some(u16::from_be_bytes(option.data?.try_into().ok()?)))
     }
 }
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
-pub struct TCPOption {
-    kind: u8,
-    len: u8,
-    data: Option<Vec<u8>>,
-}
-
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
-pub struct TCPHeader {
-    pub source_port: u16,
-    pub destination_port: u16,
-    pub sequence_number: u32,
-    pub acknowledgement_number: u32,
-    pub data_offset: u8,
-    pub reserved: u8,
-    pub cwr: bool,
-    pub ece: bool,
-    pub urg: bool,
-    pub ack: bool,
-    pub psh: bool,
-    pub rst: bool,
-    pub syn: bool,
-    pub fin: bool,
-    pub window: u16,
-    pub checksum: u16,
-    pub urgent_pointer: u16,
-    pub options: Vec<TCPOption>,
-}
 
 impl Prepare for TCPHeader {
     fn prepare(&mut self) {
@@ -258,20 +199,87 @@ impl TCPHeader {
 pub type TCPPacket<C = Vec<u8>> = Packet<TCPHeader, C>;
 pub type TCPPacketView<'a, C = &'a [u8]> = PacketView<'a, TCPHeaderView<'a>, C>;
 
-impl<C: DataOwned> Prepare for IPV4Packet<TCPPacket<C>> {
-    fn prepare(&mut self) {
-        self.header.prepare();
-        self.payload.prepare();
-        self.header.prepare_ip_header(self.size());
-        self.payload.header.checksum = 0;
-        let checksum = self
-            .payload
-            .compute_checksum()
-            .add_4bytes(self.header.source_address.0.to_be_bytes())
-            .add_4bytes(self.header.destination_address.0.to_be_bytes())
-            .add_byte(self.header.protocol as u8)
-            .add_2bytes((self.payload.payload.size() as u16).to_be_bytes())
-            .add_2bytes((self.payload.header.data_offset as u16 * 4).to_be_bytes());
-        self.payload.header.checksum = checksum.ones_complement()
+impl<C: DataOwned> TransportChecksum for TCPPacket<C> {
+    const CHECKSUM_OFFSET: usize = 16;
+
+    fn set_checksum(&mut self, checksum: u16) {
+        self.header.checksum = checksum;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header followed by `options`, padded to a multiple of 4 bytes
+    fn header_with(options: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; 20];
+        bytes.extend(options);
+        bytes.resize(bytes.len().next_multiple_of(4), 0);
+        bytes[12] = ((bytes.len() / 4) as u8) << 4;
+        bytes
+    }
+
+    fn option(kind: u8, data: &[u8]) -> TCPOption {
+        TCPOption {
+            kind,
+            len: data.len() as u8 + 2,
+            data: (!data.is_empty()).then(|| data.to_vec()),
+        }
+    }
+
+    #[test]
+    fn options_parsed() {
+        // MSS, NOP, window scale, SACK permitted, timestamps
+        let bytes = header_with(&[
+            2, 4, 5, 180, 1, 3, 3, 7, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 2,
+        ]);
+        let header = TCPHeaderView::try_from(&bytes[..]).unwrap();
+        assert_eq!(header.size(), 40);
+        assert_eq!(
+            header.get_parsed_options(),
+            [
+                TCPOption::maximum_segment_size(1460),
+                TCPOption {
+                    kind: 1,
+                    len: 1,
+                    data: None,
+                },
+                option(3, &[7]),
+                option(4, &[]),
+                option(8, &[0, 0, 0, 1, 0, 0, 0, 2]),
+            ]
+        );
+        assert_eq!(header.get_maximum_segment_size(), Some(1460));
+    }
+
+    #[test]
+    fn malformed_options_end_the_parsing() {
+        for options in [
+            // A length of zero or one would loop forever
+            &[2, 0, 5, 180][..],
+            &[2, 1, 5, 180],
+            // Longer than the header
+            &[1, 8, 10, 0, 0],
+            // The length itself is missing
+            &[1, 1, 1, 2],
+        ] {
+            let bytes = header_with(options);
+            let header = TCPHeaderView::try_from(&bytes[..]).unwrap();
+            let parsed = header.get_parsed_options();
+            assert!(parsed.iter().all(|option| option.kind == 1), "{options:?}");
+            assert_eq!(header.get_maximum_segment_size(), None);
+        }
+
+        // An MSS option of the wrong length is no MSS
+        let bytes = header_with(&[2, 3, 5]);
+        let header = TCPHeaderView::try_from(&bytes[..]).unwrap();
+        assert_eq!(header.get_parsed_options(), [option(2, &[5])]);
+        assert_eq!(header.get_maximum_segment_size(), None);
+
+        // Options announced beyond the segment
+        let mut bytes = header_with(&[]);
+        bytes[12] = 6 << 4;
+        assert!(TCPHeaderView::try_from(&bytes[..]).is_err());
     }
 }
@@ -0,0 +1,71 @@
+//! Devices standing for the link of the stack in the tests
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    rc::Rc,
+};
+
+/// An in-memory link keeping the frames the stack sends, and handing it the frames queued by the
+/// test
+#[derive(Debug, Default, Clone)]
+pub struct Wire {
+    pub sent: Rc<RefCell<Vec<Vec<u8>>>>,
+    pub incoming: Rc<RefCell<VecDeque<Vec<u8>>>>,
+}
+
+impl Read for Wire {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let frame = self
+            .incoming
+            .borrow_mut()
+            .pop_front()
+            .ok_or(io::ErrorKind::WouldBlock)?;
+        // Truncated to the buffer, as a datagram is
+        let len = frame.len().min(buf.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+        Ok(len)
+    }
+}
+
+impl Write for Wire {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.borrow_mut().push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One end of a datagram socket pair standing for the TUN device
+#[derive(Debug)]
+pub struct Datagram(pub UnixDatagram);
+
+impl Read for Datagram {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl Write for Datagram {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Datagram {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
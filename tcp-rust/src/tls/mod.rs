@@ -0,0 +1,179 @@
+//! HTTPS over the sockets of the stack, TLS being terminated by rustls in a task per connection.
+//!
+//! The page and the metrics are the same as those the connections serve over plain HTTP. A
+//! self-signed certificate is enough for `curl -k`:
+//!
+//! ```text
+//! openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -days 365 \
+//!     -subj /CN=localhost -keyout key.pem -out cert.pem
+//! ```
+
+use std::{io, path::Path, sync::Arc};
+
+use rustls::{
+    ServerConfig,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+
+use crate::{
+    http::{self, HTTPRequestHeaderView},
+    runtime::TCPListener,
+    traits::WriteTo,
+};
+
+pub const HTTPS_PORT: u16 = 443;
+
+/// Server configuration with a certificate chain and its private key, read from PEM files
+pub fn load_config(
+    certificates: impl AsRef<Path>,
+    key: impl AsRef<Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let invalid = |err: &dyn std::error::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("TLS configuration: {err}"),
+        )
+    };
+    let certificates = CertificateDer::pem_file_iter(certificates)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|err| invalid(&err))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|err| invalid(&err))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| invalid(&err))?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|err| invalid(&err))?;
+    Ok(Arc::new(config))
+}
+
+/// Answer the HTTPS requests of the connections to a listener
+pub async fn serve(listener: TCPListener, config: Arc<ServerConfig>) -> io::Result<()> {
+    let acceptor = TlsAcceptor::from(config);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            if let Err(err) = answer(&acceptor, stream).await {
+                debug!(%peer, "HTTPS connection failed: {err}");
+            }
+        });
+    }
+}
+
+/// Run the handshake on a connection, then answer its request and close it
+async fn answer(
+    acceptor: &TlsAcceptor,
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+) -> io::Result<()> {
+    let mut stream = acceptor.accept(stream).await?;
+    let request = http::read_request(&mut stream).await?;
+    let request = HTTPRequestHeaderView::try_from(&request[..])
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+    debug!(path = request.get_path(), "HTTPS request");
+    stream
+        .write_all(&http::respond(request).to_bytes()?)
+        .await?;
+    // The response announces the close, notified to the peer before the FIN
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use rustls::{ClientConfig, RootCertStore, pki_types::ServerName};
+    use tokio_rustls::TlsConnector;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    /// PEM files of a certificate for localhost and its key, generated for a test and removed
+    /// once it is over
+    struct Credentials {
+        directory: PathBuf,
+        certificate: PathBuf,
+        key: PathBuf,
+    }
+
+    impl Credentials {
+        fn generate(test: &str) -> Self {
+            let certified = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+            let directory =
+                std::env::temp_dir().join(format!("tcp-rust-{test}-{}", std::process::id()));
+            fs::create_dir_all(&directory).unwrap();
+            let certificate = directory.join("cert.pem");
+            let key = directory.join("key.pem");
+            fs::write(&certificate, certified.cert.pem()).unwrap();
+            fs::write(&key, certified.signing_key.serialize_pem()).unwrap();
+            Self {
+                directory,
+                certificate,
+                key,
+            }
+        }
+    }
+
+    impl Drop for Credentials {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    /// Send a request over a TLS connection to the server, returning its response
+    async fn exchange(test: &str, request: &[u8]) -> String {
+        let credentials = Credentials::generate(test);
+        let (client, server) = tokio::io::duplex(16384);
+        let config = load_config(&credentials.certificate, &credentials.key).unwrap();
+        let acceptor = TlsAcceptor::from(config);
+        tokio::spawn(async move { answer(&acceptor, server).await.unwrap() });
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(&credentials.certificate).unwrap())
+            .unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), client)
+            .await
+            .unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn https_request() {
+        let response = exchange(
+            "https_request",
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("<html><b>Hello</b> World !</html>"));
+    }
+
+    #[tokio::test]
+    async fn request_line_without_version_is_a_bad_request() {
+        let response = exchange("bad_request", b"GET /\r\nHost: a\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn missing_key() {
+        let credentials = Credentials::generate("missing_key");
+        let certificate = &credentials.certificate;
+        assert!(load_config(certificate, certificate).is_err());
+        assert!(load_config(certificate, credentials.directory.join("none.pem")).is_err());
+    }
+}
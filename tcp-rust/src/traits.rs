@@ -33,11 +33,7 @@ impl<
 {
 }
 
-pub trait DataOwned: Data + WriteTo {
-    fn compute_checksum(&mut self) -> Checksum {
-        Checksum::new().add_slice(&self.to_bytes().unwrap())
-    }
-}
+pub trait DataOwned: Data + WriteTo {}
 
 impl<T: Data + WriteTo> DataOwned for T {}
 
@@ -65,6 +61,11 @@ pub trait WriteTo: Prepare {
 
     fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize>;
 
+    /// Write into a buffer, where checksums can be filled in once the data they cover is written
+    fn write_to_buffer(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.write_to(&mut &mut buffer[..])
+    }
+
     fn to_bytes(&mut self) -> io::Result<Vec<u8>> {
         let mut buffer = Vec::new();
         self.write_to(&mut buffer)?;
@@ -1,18 +1,23 @@
 use std::ffi::CStr;
 use std::fs::{File, OpenOptions};
-use std::io::{Error, Read, Result, Write};
+use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
 
+use crate::ethernet::{ETHERTYPE_IPV4, ETHERTYPE_IPV6};
+use crate::interface::{PACKET_INFO_SIZE, packet_info};
+
 use libc::{
-    __c_anonymous_ifr_ifru, ifreq, ioctl, IFF_NO_PI, IFF_TAP, IFF_TUN, IFNAMSIZ, TUNSETIFF,
+    __c_anonymous_ifr_ifru, ifreq, ioctl, AF_INET, IFF_MULTI_QUEUE, IFF_NO_PI, IFF_TAP, IFF_TUN,
+    IFF_UP, IFNAMSIZ, Ioctl, SIOCGIFFLAGS, SIOCSIFFLAGS, SIOCSIFMTU, SOCK_DGRAM, TUNSETIFF,
 };
 
-unsafe fn tuntap_setup(fd: RawFd, name: &str, mode: Mode, packet_info: bool) -> Result<String> {
-    if name.len() > IFNAMSIZ {
-        panic!(
-            "Interface name cannot be greater than IFNAMSIZ = {}",
-            IFNAMSIZ
-        )
+/// Request naming an interface, whose name must leave room for its NUL terminator
+fn new_ifreq(name: &str) -> Result<ifreq> {
+    if name.len() >= IFNAMSIZ || name.contains('\0') {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("interface name must be shorter than IFNAMSIZ = {}", IFNAMSIZ),
+        ));
     }
     let mut ifr: ifreq = ifreq {
         ifr_name: [0; IFNAMSIZ],
@@ -20,6 +25,17 @@ unsafe fn tuntap_setup(fd: RawFd, name: &str, mode: Mode, packet_info: bool) ->
     };
     ifr.ifr_name[..name.len()]
         .copy_from_slice(unsafe { &*(name.as_bytes() as *const [u8] as *const [i8]) });
+    Ok(ifr)
+}
+
+unsafe fn tuntap_setup(
+    fd: RawFd,
+    name: &str,
+    mode: Mode,
+    packet_info: bool,
+    multi_queue: bool,
+) -> Result<String> {
+    let mut ifr = new_ifreq(name)?;
 
     match mode {
         Mode::Tun => ifr.ifr_ifru.ifru_flags = IFF_TUN as i16,
@@ -30,6 +46,11 @@ unsafe fn tuntap_setup(fd: RawFd, name: &str, mode: Mode, packet_info: bool) ->
             ifr.ifr_ifru.ifru_flags |= IFF_NO_PI as i16;
         }
     }
+    if multi_queue {
+        unsafe {
+            ifr.ifr_ifru.ifru_flags |= IFF_MULTI_QUEUE as i16;
+        }
+    }
     let ioresult = unsafe { ioctl(fd, TUNSETIFF, &raw const ifr) };
 
     if ioresult < 0 {
@@ -40,6 +61,51 @@ unsafe fn tuntap_setup(fd: RawFd, name: &str, mode: Mode, packet_info: bool) ->
     Ok(new_name.to_string())
 }
 
+/// Run an interface ioctl through a throwaway socket, as `ip link` does
+unsafe fn interface_ioctl(request: Ioctl, ifr: &mut ifreq) -> Result<()> {
+    let socket = unsafe { libc::socket(AF_INET, SOCK_DGRAM, 0) };
+    if socket < 0 {
+        return Err(Error::last_os_error());
+    }
+    let ioresult = unsafe { ioctl(socket, request, ifr as *mut ifreq) };
+    let error = Error::last_os_error();
+    unsafe { libc::close(socket) };
+    if ioresult < 0 {
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// Set the MTU of any interface by its name
+pub fn set_mtu(name: &str, mtu: u32) -> Result<()> {
+    let mut ifr = new_ifreq(name)?;
+    ifr.ifr_ifru.ifru_mtu = mtu as i32;
+    unsafe { interface_ioctl(SIOCSIFMTU, &mut ifr) }
+}
+
+/// Bring any interface up or down by its name
+pub fn set_up(name: &str, up: bool) -> Result<()> {
+    let mut ifr = new_ifreq(name)?;
+    unsafe {
+        interface_ioctl(SIOCGIFFLAGS, &mut ifr)?;
+        if up {
+            ifr.ifr_ifru.ifru_flags |= IFF_UP as i16;
+        } else {
+            ifr.ifr_ifru.ifru_flags &= !(IFF_UP as i16);
+        }
+        interface_ioctl(SIOCSIFFLAGS, &mut ifr)
+    }
+}
+
+/// Whether any interface is up, by its name
+pub fn is_up(name: &str) -> Result<bool> {
+    let mut ifr = new_ifreq(name)?;
+    unsafe {
+        interface_ioctl(SIOCGIFFLAGS, &mut ifr)?;
+        Ok(ifr.ifr_ifru.ifru_flags & IFF_UP as i16 != 0)
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub enum Mode {
     Tun = 1,
@@ -50,29 +116,68 @@ pub enum Mode {
 pub struct Interface {
     fd: File,
     pub name: String,
+    mode: Mode,
+    /// Whether the driver frames the packets with a packet information header, which is made up
+    /// on reads and left out of writes otherwise
+    packet_info: bool,
 }
 
 impl Interface {
     pub fn new(ifname: &str, mode: Mode) -> Result<Self> {
-        Interface::with_options(ifname, mode, true)
+        Interface::with_options(ifname, mode, true, false)
     }
     pub fn without_packet_info(ifname: &str, mode: Mode) -> Result<Self> {
-        Interface::with_options(ifname, mode, false)
+        Interface::with_options(ifname, mode, false, false)
+    }
+    /// Open `queues` queues of one device, each with its own file descriptor, the kernel
+    /// spreading the flows over them
+    pub fn multi_queue(ifname: &str, mode: Mode, queues: usize) -> Result<Vec<Self>> {
+        let first = Interface::with_options(ifname, mode, true, true)?;
+        let mut interfaces = Vec::with_capacity(queues);
+        for _ in 1..queues {
+            interfaces.push(Interface::with_options(&first.name, mode, true, true)?);
+        }
+        interfaces.insert(0, first);
+        Ok(interfaces)
     }
 
-    fn with_options(ifname: &str, mode: Mode, packet_info: bool) -> Result<Self> {
+    fn with_options(
+        ifname: &str,
+        mode: Mode,
+        packet_info: bool,
+        multi_queue: bool,
+    ) -> Result<Self> {
         let fd = OpenOptions::new()
             .read(true)
             .write(true)
             .open("/dev/net/tun")?;
-        let name = unsafe { tuntap_setup(fd.as_raw_fd(), ifname, mode, packet_info) }?;
-        Ok(Interface { fd, name })
+        let name = unsafe { tuntap_setup(fd.as_raw_fd(), ifname, mode, packet_info, multi_queue) }?;
+        Ok(Interface {
+            fd,
+            name,
+            mode,
+            packet_info,
+        })
+    }
+
+    pub fn set_mtu(&self, mtu: u32) -> Result<()> {
+        set_mtu(&self.name, mtu)
+    }
+
+    pub fn set_up(&self, up: bool) -> Result<()> {
+        set_up(&self.name, up)
     }
 }
 
 impl Write for Interface {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        self.fd.write(buf)
+        if self.packet_info {
+            return self.fd.write(buf);
+        }
+        let Some(frame) = buf.get(PACKET_INFO_SIZE..) else {
+            return Err(ErrorKind::InvalidInput.into());
+        };
+        Ok(PACKET_INFO_SIZE + self.fd.write(frame)?)
     }
 
     fn flush(&mut self) -> Result<()> {
@@ -82,6 +187,49 @@ impl Write for Interface {
 
 impl Read for Interface {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        self.fd.read(buf)
+        if self.packet_info {
+            return self.fd.read(buf);
+        }
+        let Some(frame) = buf.get_mut(PACKET_INFO_SIZE..) else {
+            return Err(ErrorKind::InvalidInput.into());
+        };
+        let nbytes = self.fd.read(frame)?;
+        let frame = &buf[PACKET_INFO_SIZE..PACKET_INFO_SIZE + nbytes];
+        // The protocol of the header is the ethertype of the frame, or follows the IP version
+        let ethertype = match self.mode {
+            Mode::Tun => match frame.first().map(|byte| byte >> 4) {
+                Some(4) => ETHERTYPE_IPV4,
+                Some(6) => ETHERTYPE_IPV6,
+                _ => 0,
+            },
+            Mode::Tap if nbytes >= 14 => u16::from_be_bytes([frame[12], frame[13]]),
+            Mode::Tap => 0,
+        };
+        buf[..PACKET_INFO_SIZE].copy_from_slice(&packet_info(ethertype));
+        Ok(PACKET_INFO_SIZE + nbytes)
+    }
+}
+
+impl AsRawFd for Interface {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interface_names_left_room_for_their_terminator() {
+        let ifr = new_ifreq("tun0").unwrap();
+        let name: Vec<u8> = ifr.ifr_name[..5].iter().map(|&byte| byte as u8).collect();
+        assert_eq!(name, b"tun0\0");
+        let longest = "a".repeat(IFNAMSIZ - 1);
+        assert_eq!(new_ifreq(&longest).unwrap().ifr_name[IFNAMSIZ - 1], 0);
+        for name in ["a".repeat(IFNAMSIZ), "a".repeat(64), "tun\x000".to_string()] {
+            let error = new_ifreq(&name).err().map(|error| error.kind());
+            assert_eq!(error, Some(ErrorKind::InvalidInput));
+        }
     }
 }
@@ -0,0 +1,753 @@
+//! Tunnel endpoints: IP-in-IP (RFC 2003), GRE (RFC 2784, with the keys of RFC 2890) and VXLAN
+//! (RFC 7348).
+//!
+//! Each tunnel is an interface of its own, whose device is one end of a socket pair. Decapsulated
+//! packets are written to the other end, so that the interface receives them and they go through
+//! the firewall, NAT, forwarding and the local protocols like any other. What the stack sends on
+//! the interface is read back from there, encapsulated and sent toward the remote endpoint.
+//! IP-in-IP and GRE carry IP packets like a TUN device, VXLAN carries Ethernet frames like a TAP
+//! one.
+
+use std::{
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    str::FromStr,
+};
+
+use tcp_rust_macros::PacketHeader;
+use tracing::debug;
+
+use crate::{
+    arp::ARPManager,
+    checksum::Checksum,
+    ethernet::{ETHERTYPE_IPV4, EthernetHeaderView, MacAddr},
+    interface::{Interface, InterfaceConfig, PACKET_INFO_SIZE, packet_info},
+    ip::{IPV4Header, IPV4HeaderView, IPV4Packet, IpProtocol, IpV4Addr},
+    packet::{Packet, PacketView, ParseHeaderError},
+    route::{RoutingTable, next_hop_mac},
+    traits::{AsArrayUnchecked, Data, DataOwned, Prepare, ToMutable, WriteTo},
+    tun_tap::Mode,
+    udp::{UDPHeader, UDPHeaderView, UDPPacket},
+};
+
+pub const VXLAN_PORT: u16 = 4789;
+
+const GRE_CHECKSUM_PRESENT: u8 = 0x80;
+const GRE_KEY_PRESENT: u8 = 0x20;
+const GRE_SEQUENCE_PRESENT: u8 = 0x10;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GREHeaderView<'a> {
+    content: &'a [u8],
+}
+
+impl<'a> GREHeaderView<'a> {
+    /// Parse a header, failing if the slice is too short to hold it and its optional fields
+    pub fn parse(value: &'a [u8]) -> Result<Self, ParseHeaderError> {
+        let flags = *value.first().ok_or(ParseHeaderError)?;
+        match value.get(..Self::size_of(flags)) {
+            Some(content) => Ok(Self { content }),
+            None => Err(ParseHeaderError),
+        }
+    }
+
+    fn size_of(flags: u8) -> usize {
+        4 + 4
+            * (flags & (GRE_CHECKSUM_PRESENT | GRE_KEY_PRESENT | GRE_SEQUENCE_PRESENT)).count_ones()
+                as usize
+    }
+
+    /// The word of an optional field, after those of the flags before its own
+    fn optional_word(&self, flag: u8) -> Option<u32> {
+        if self.content[0] & flag == 0 {
+            return None;
+        }
+        // The fields present are in the order of their flags, the checksum first
+        let offset = Self::size_of(self.content[0] & !(flag | (flag - 1)));
+        Some(u32::from_be_bytes(*unsafe {
+            self.content[offset..offset + 4].as_array_unchecked()
+        }))
+    }
+
+    pub fn get_version(&self) -> u8 {
+        self.content[1] & 0x7
+    }
+    pub fn get_protocol_type(&self) -> u16 {
+        u16::from_be_bytes(*unsafe { self.content[2..4].as_array_unchecked() })
+    }
+    pub fn get_checksum(&self) -> Option<u16> {
+        self.optional_word(GRE_CHECKSUM_PRESENT)
+            .map(|word| (word >> 16) as u16)
+    }
+    pub fn get_key(&self) -> Option<u32> {
+        self.optional_word(GRE_KEY_PRESENT)
+    }
+    pub fn get_sequence_number(&self) -> Option<u32> {
+        self.optional_word(GRE_SEQUENCE_PRESENT)
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for GREHeaderView<'a> {
+    type Error = ParseHeaderError;
+
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        Self::parse(value)
+    }
+}
+
+impl<'a> AsRef<[u8]> for GREHeaderView<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.content
+    }
+}
+
+impl Data for GREHeaderView<'_> {
+    fn size(&self) -> usize {
+        self.content.len()
+    }
+}
+
+impl ToMutable for GREHeaderView<'_> {
+    type MutableType = GREHeader;
+
+    fn to_mutable(&self) -> Self::MutableType {
+        GREHeader {
+            protocol_type: self.get_protocol_type(),
+            key: self.get_key(),
+            sequence_number: self.get_sequence_number(),
+        }
+    }
+}
+
+impl std::fmt::Debug for GREHeaderView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GREHeaderView")
+            .field("version", &self.get_version())
+            .field("protocol_type", &self.get_protocol_type())
+            .field("checksum", &self.get_checksum())
+            .field("key", &self.get_key())
+            .field("sequence_number", &self.get_sequence_number())
+            .finish()
+    }
+}
+
+/// A GRE header, always sent without checksum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct GREHeader {
+    pub protocol_type: u16,
+    pub key: Option<u32>,
+    pub sequence_number: Option<u32>,
+}
+
+impl GREHeader {
+    pub fn new(protocol_type: u16, key: Option<u32>) -> Self {
+        Self {
+            protocol_type,
+            key,
+            sequence_number: None,
+        }
+    }
+}
+
+impl Prepare for GREHeader {}
+impl Data for GREHeader {
+    fn size(&self) -> usize {
+        4 + 4 * (self.key.is_some() as usize + self.sequence_number.is_some() as usize)
+    }
+}
+
+impl WriteTo for GREHeader {
+    fn write_to_inner<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        let flags = if self.key.is_some() {
+            GRE_KEY_PRESENT
+        } else {
+            0
+        } | if self.sequence_number.is_some() {
+            GRE_SEQUENCE_PRESENT
+        } else {
+            0
+        };
+        writer.write_all(&[flags, 0])?;
+        writer.write_all(&self.protocol_type.to_be_bytes())?;
+        for word in [self.key, self.sequence_number].into_iter().flatten() {
+            writer.write_all(&word.to_be_bytes())?;
+        }
+        Ok(self.size())
+    }
+}
+
+pub type GREPacket<C = Vec<u8>> = Packet<GREHeader, C>;
+pub type GREPacketView<'a, C = &'a [u8]> = PacketView<'a, GREHeaderView<'a>, C>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, PacketHeader)]
+pub struct VXLANHeader {
+    #[bits(4)]
+    pub reserved: u8,
+    /// The `I` flag, set when the VNI is valid
+    #[bits(1)]
+    pub vni_present: bool,
+    #[bits(27)]
+    pub reserved_flags: u32,
+    #[bits(24)]
+    pub vni: u32,
+    pub reserved_low: u8,
+}
+
+impl VXLANHeader {
+    pub fn new(vni: u32) -> Self {
+        Self {
+            vni_present: true,
+            vni,
+            ..Default::default()
+        }
+    }
+}
+
+pub type VXLANPacket<C = Vec<u8>> = Packet<VXLANHeader, C>;
+pub type VXLANPacketView<'a, C = &'a [u8]> = PacketView<'a, VXLANHeaderView<'a>, C>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelKind {
+    IpIp,
+    Gre { key: Option<u32> },
+    Vxlan { vni: u32 },
+}
+
+impl TunnelKind {
+    /// Tunnels carrying Ethernet frames have an interface in TAP mode
+    pub fn mode(&self) -> Mode {
+        match self {
+            Self::IpIp | Self::Gre { .. } => Mode::Tun,
+            Self::Vxlan { .. } => Mode::Tap,
+        }
+    }
+
+    /// Bytes added in front of the IP packets of the tunnel, which its MTU leaves room for
+    pub fn overhead(&self) -> usize {
+        match self {
+            Self::IpIp => 20,
+            Self::Gre { key } => 20 + GREHeader::new(ETHERTYPE_IPV4, *key).size(),
+            Self::Vxlan { .. } => 20 + 8 + VXLANHeaderView::SIZE + 14,
+        }
+    }
+}
+
+/// A tunnel of `--tunnel`, and the address of its interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelConfig {
+    pub kind: TunnelKind,
+    pub local: IpV4Addr,
+    pub remote: IpV4Addr,
+    pub interface: InterfaceConfig,
+}
+
+#[derive(Debug)]
+pub struct ParseTunnelError;
+
+/// Parse a tunnel written as `<ipip|gre|vxlan>,<local>,<remote>,<address>/<prefix length>`,
+/// followed by `,<key>` for GRE or `,<VNI>` for VXLAN
+impl FromStr for TunnelConfig {
+    type Err = ParseTunnelError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let kind = parts.next().ok_or(ParseTunnelError)?;
+        let mut address = || {
+            parts
+                .next()
+                .and_then(|address| address.parse().ok())
+                .ok_or(ParseTunnelError)
+        };
+        let (local, remote) = (address()?, address()?);
+        let interface = parts
+            .next()
+            .and_then(InterfaceConfig::parse)
+            .ok_or(ParseTunnelError)?;
+        let id = parts
+            .next()
+            .map(|id| id.parse::<u32>().map_err(|_| ParseTunnelError))
+            .transpose()?;
+        let kind = match (kind, id) {
+            ("ipip", None) => TunnelKind::IpIp,
+            ("gre", key) => TunnelKind::Gre { key },
+            ("vxlan", Some(vni)) if vni < 1 << 24 => TunnelKind::Vxlan { vni },
+            _ => return Err(ParseTunnelError),
+        };
+        if parts.next().is_some() {
+            return Err(ParseTunnelError);
+        }
+        Ok(Self {
+            kind,
+            local,
+            remote,
+            interface,
+        })
+    }
+}
+
+/// Device of a tunnel interface, the other end of its socket pair being held by the tunnel
+#[derive(Debug)]
+pub struct TunnelDevice {
+    pub name: String,
+    socket: UnixDatagram,
+}
+
+impl Read for TunnelDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.recv(buf)
+    }
+}
+
+impl Write for TunnelDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for TunnelDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[derive(Debug)]
+pub struct Tunnel {
+    pub config: TunnelConfig,
+    /// Index of the tunnel interface
+    pub interface: usize,
+    socket: UnixDatagram,
+}
+
+impl Tunnel {
+    /// Open a tunnel whose interface will be `interfaces[interface]`, returning its device
+    pub fn open(config: TunnelConfig, interface: usize) -> io::Result<(Self, TunnelDevice)> {
+        let (socket, device) = UnixDatagram::pair()?;
+        // Nothing waits on either end: a full queue drops packets rather than blocking the stack
+        socket.set_nonblocking(true)?;
+        device.set_nonblocking(true)?;
+        let name = match config.kind {
+            TunnelKind::IpIp => "ipip",
+            TunnelKind::Gre { .. } => "gre",
+            TunnelKind::Vxlan { .. } => "vxlan",
+        };
+        let device = TunnelDevice {
+            name: format!("{name}{interface}"),
+            socket: device,
+        };
+        Ok((
+            Self {
+                config,
+                interface,
+                socket,
+            },
+            device,
+        ))
+    }
+
+    /// The packet or frame carried by an IP packet from the remote endpoint, with its ethertype
+    fn inner<'a>(&self, packet: &'a [u8]) -> Option<(u16, &'a [u8])> {
+        let header = IPV4HeaderView::parse(packet).ok()?;
+        if header.get_source_address() != self.config.remote
+            || header.get_destination_address() != self.config.local
+        {
+            return None;
+        }
+        let payload = packet.get(header.size()..header.get_total_length() as usize)?;
+        match (self.config.kind, header.get_protocol()) {
+            (TunnelKind::IpIp, IpProtocol::IpIp) => Some((ETHERTYPE_IPV4, payload)),
+            (TunnelKind::Gre { key }, IpProtocol::Gre) => {
+                let gre = GREHeaderView::parse(payload).ok()?;
+                (gre.get_version() == 0
+                    && gre.get_protocol_type() == ETHERTYPE_IPV4
+                    && gre.get_key() == key)
+                    .then(|| (ETHERTYPE_IPV4, &payload[gre.size()..]))
+            }
+            (TunnelKind::Vxlan { vni }, IpProtocol::Udp) => {
+                let udp = UDPHeaderView::parse(payload).ok()?;
+                let vxlan = VXLANHeaderView::parse(&payload[UDPHeaderView::SIZE..]).ok()?;
+                let frame = &payload[UDPHeaderView::SIZE + VXLANHeaderView::SIZE..];
+                let ethernet = EthernetHeaderView::parse(frame).ok()?;
+                (udp.get_destination_port() == VXLAN_PORT
+                    && vxlan.get_vni_present()
+                    && vxlan.get_vni() == vni)
+                    .then(|| (ethernet.get_ethertype(), frame))
+            }
+            _ => None,
+        }
+    }
+
+    /// Hand the packet received by `interface` to the tunnel interface if it comes through the
+    /// tunnel, returning whether it did
+    pub fn decapsulate(&self, interface: &Interface<impl Read + Write>) -> bool {
+        let Some((ethertype, inner)) = self.inner(interface.packet()) else {
+            return false;
+        };
+        let mut frame = packet_info(ethertype).to_vec();
+        frame.extend_from_slice(inner);
+        if let Err(err) = self.socket.send(&frame) {
+            debug!(
+                "dropped a packet received through {}: {err}",
+                self.interface
+            );
+        }
+        true
+    }
+
+    /// Send the packets written to the tunnel interface, encapsulated, toward the remote endpoint
+    pub fn transmit<T: Read + Write>(
+        &self,
+        interfaces: &mut [Interface<T>],
+        routing_table: &RoutingTable,
+        arp_manager: &ARPManager,
+    ) {
+        let TunnelConfig {
+            kind,
+            local,
+            remote,
+            ..
+        } = self.config;
+        let mut frame = [0; PACKET_INFO_SIZE + 1514];
+        while let Ok(length) = self.socket.recv(&mut frame) {
+            let inner = &frame[PACKET_INFO_SIZE.min(length)..length];
+            // Without a route, the remote endpoint is on the link of the first interface
+            let route = routing_table.lookup(remote);
+            let output = route.map_or(0, |route| route.interface);
+            if output == self.interface {
+                debug!("the remote endpoint of tunnel {output} is routed through itself");
+                continue;
+            }
+            let Some(interface) = interfaces.get_mut(output) else {
+                debug!(
+                    "no interface {output} to reach the remote endpoint of tunnel {}",
+                    self.interface
+                );
+                continue;
+            };
+            let next_hop = route.and_then(|route| route.gateway).unwrap_or(remote);
+            let Some(mac) = next_hop_mac(interface, next_hop, arp_manager) else {
+                continue;
+            };
+            let ip_header = |protocol| IPV4Header::new(protocol, local, remote);
+            match kind {
+                TunnelKind::IpIp => send(
+                    interface,
+                    mac,
+                    IPV4Packet::new(ip_header(IpProtocol::IpIp), inner),
+                ),
+                TunnelKind::Gre { key } => send(
+                    interface,
+                    mac,
+                    IPV4Packet::new(
+                        ip_header(IpProtocol::Gre),
+                        GREPacket::new(GREHeader::new(ETHERTYPE_IPV4, key), inner),
+                    ),
+                ),
+                TunnelKind::Vxlan { vni } => {
+                    // The source port spreads the flows over the paths of the underlay
+                    let hash = Checksum::new()
+                        .add_slice(&inner[..inner.len().min(14)])
+                        .ones_complement();
+                    let udp_header = UDPHeader {
+                        source_port: 49152 | (hash & 0x3FFF),
+                        destination_port: VXLAN_PORT,
+                        ..Default::default()
+                    };
+                    send(
+                        interface,
+                        mac,
+                        IPV4Packet::new(
+                            ip_header(IpProtocol::Udp),
+                            UDPPacket::new(
+                                udp_header,
+                                VXLANPacket::new(VXLANHeader::new(vni), inner),
+                            ),
+                        ),
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// Send an encapsulated packet on the underlay, unless it is larger than its MTU
+fn send<T: Read + Write, C: DataOwned>(
+    interface: &mut Interface<T>,
+    mac: MacAddr,
+    packet: IPV4Packet<C>,
+) where
+    IPV4Packet<C>: WriteTo,
+{
+    if packet.size() > interface.mtu {
+        debug!("dropped a tunneled packet of {} bytes", packet.size());
+        return;
+    }
+    interface.write_frame(mac, ETHERTYPE_IPV4, packet);
+    interface.send();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        buffer::BufferPool,
+        icmp::{self, ECHO_REPLY, ICMPPacket, ICMPPacketView},
+        ip::IPV4PacketView,
+        udp::UDPPacketView,
+    };
+
+    use super::*;
+
+    const LOCAL: IpV4Addr = IpV4Addr(0xC0A8_0002);
+    const REMOTE: IpV4Addr = IpV4Addr(0xC0A8_0001);
+
+    /// Both ends of a tunnel: its own, and that of an underlay interface whose packets are read
+    /// from `wire`
+    fn open(kind: TunnelKind) -> (Tunnel, TunnelDevice, Interface<TunnelDevice>, UnixDatagram) {
+        let config = TunnelConfig {
+            kind,
+            local: LOCAL,
+            remote: REMOTE,
+            interface: InterfaceConfig::parse("10.0.0.1/30").unwrap(),
+        };
+        let (tunnel, device) = Tunnel::open(config, 1).unwrap();
+        let (wire, underlay) = UnixDatagram::pair().unwrap();
+        let underlay = TunnelDevice {
+            name: "underlay".to_string(),
+            socket: underlay,
+        };
+        (tunnel, device, Interface::new(underlay), wire)
+    }
+
+    /// Send an inner packet through the tunnel, returning the outer one
+    fn encapsulate(kind: TunnelKind, inner: &[u8]) -> Vec<u8> {
+        let (tunnel, mut device, mut underlay, wire) = open(kind);
+        device
+            .write_all(&[&[0, 0, 0x08, 0x00], inner].concat())
+            .unwrap();
+        tunnel.transmit(
+            std::slice::from_mut(&mut underlay),
+            &RoutingTable::new(),
+            &ARPManager::new(),
+        );
+        let mut frame = [0; 1518];
+        let length = wire.recv(&mut frame).unwrap();
+        frame[PACKET_INFO_SIZE..length].to_vec()
+    }
+
+    /// Receive an outer packet on the underlay, returning what the tunnel interface receives
+    fn decapsulate(kind: TunnelKind, outer: &[u8]) -> Option<Vec<u8>> {
+        let (tunnel, mut device, mut underlay, _wire) = open(kind);
+        underlay.receive_frame(&[&[0, 0, 0x08, 0x00], outer].concat());
+        if !tunnel.decapsulate(&underlay) {
+            return None;
+        }
+        let mut frame = [0; 1518];
+        let length = device.read(&mut frame).unwrap();
+        Some(frame[..length].to_vec())
+    }
+
+    /// The outer packet the remote endpoint sends back, its addresses swapped
+    fn reply_of(outer: &[u8]) -> Vec<u8> {
+        let mut reply = outer.to_vec();
+        let mut header = IPV4HeaderView::parse(outer).unwrap().to_mutable();
+        header.answer();
+        header.set_checksum();
+        header.write_to_buffer(&mut reply).unwrap();
+        reply
+    }
+
+    fn inner_packet() -> Vec<u8> {
+        IPV4Packet::new(
+            IPV4Header::new(
+                IpProtocol::Udp,
+                IpV4Addr(0x0A00_0001),
+                IpV4Addr(0x0A00_0002),
+            ),
+            UDPPacket::new(UDPHeader::default(), b"inner".to_vec()),
+        )
+        .to_bytes()
+        .unwrap()
+    }
+
+    #[test]
+    fn ip_in_ip_round_trip() {
+        let kind = TunnelKind::IpIp;
+        let inner = inner_packet();
+        let outer = encapsulate(kind, &inner);
+        let packet = IPV4PacketView::<&[u8]>::try_from(&outer[..]).unwrap();
+        assert_eq!(packet.header.get_protocol(), IpProtocol::IpIp);
+        assert_eq!(packet.header.get_source_address(), LOCAL);
+        assert_eq!(packet.header.get_destination_address(), REMOTE);
+        assert_eq!(packet.payload, inner);
+        assert_eq!(outer.len(), inner.len() + kind.overhead());
+
+        assert_eq!(
+            decapsulate(kind, &reply_of(&outer)),
+            Some([&[0, 0, 0x08, 0x00], &inner[..]].concat())
+        );
+        // Only the remote endpoint is listened to, and only for IP-in-IP
+        assert_eq!(decapsulate(kind, &outer), None);
+        assert_eq!(
+            decapsulate(TunnelKind::Gre { key: None }, &reply_of(&outer)),
+            None
+        );
+    }
+
+    #[test]
+    fn gre_with_every_optional_field() {
+        let inner = inner_packet();
+        // Checksum and reserved word, key, then sequence number
+        let gre = [
+            &[0xB0, 0x00, 0x08, 0x00][..],
+            &[0x12, 0x34, 0x00, 0x00],
+            &42u32.to_be_bytes(),
+            &7u32.to_be_bytes(),
+            &inner,
+        ]
+        .concat();
+        let header = GREHeaderView::parse(&gre).unwrap();
+        assert_eq!(header.size(), 16);
+        assert_eq!(header.get_checksum(), Some(0x1234));
+        assert_eq!(header.get_key(), Some(42));
+        assert_eq!(header.get_sequence_number(), Some(7));
+        assert!(GREHeaderView::parse(&gre[..15]).is_err());
+        // Without the checksum, the key and sequence number move up a word
+        let header = GREHeaderView::parse(&[&[0x30, 0x00, 0x08, 0x00][..], &gre[8..]].concat())
+            .unwrap()
+            .to_mutable();
+        assert_eq!(
+            header,
+            GREHeader {
+                protocol_type: ETHERTYPE_IPV4,
+                key: Some(42),
+                sequence_number: Some(7),
+            }
+        );
+
+        let outer = IPV4Packet::new(IPV4Header::new(IpProtocol::Gre, REMOTE, LOCAL), gre)
+            .to_bytes()
+            .unwrap();
+        assert_eq!(
+            decapsulate(TunnelKind::Gre { key: Some(42) }, &outer),
+            Some([&[0, 0, 0x08, 0x00], &inner[..]].concat())
+        );
+        assert_eq!(decapsulate(TunnelKind::Gre { key: None }, &outer), None);
+    }
+
+    #[test]
+    fn decapsulated_packets_answered_through_the_tunnel() {
+        let (tunnel, device, mut underlay, wire) = open(TunnelKind::IpIp);
+        let mut interface = Interface::new(device);
+        interface.config = tunnel.config.interface;
+        let peer = IpV4Addr(0x0A00_0002);
+        let local = interface.config.address.unwrap();
+        let request = IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Icmp, peer, local),
+            ICMPPacket::echo_request(1, 2, b"ping"),
+        )
+        .to_bytes()
+        .unwrap();
+        let outer = IPV4Packet::new(IPV4Header::new(IpProtocol::IpIp, REMOTE, LOCAL), request)
+            .to_bytes()
+            .unwrap();
+        underlay.receive_frame(&[&[0, 0, 0x08, 0x00], &outer[..]].concat());
+        assert!(tunnel.decapsulate(&underlay));
+
+        // The tunnel interface takes the inner packet like any other IP packet
+        interface.receive();
+        assert!(interface.is_ip() && interface.is_addressed_to_us());
+        assert_eq!(interface.get_ip_protocol(), Some(IpProtocol::Icmp));
+        icmp::reply_to_echo(&mut interface, &mut BufferPool::new());
+        tunnel.transmit(
+            std::slice::from_mut(&mut underlay),
+            &RoutingTable::new(),
+            &ARPManager::new(),
+        );
+
+        let mut frame = [0; 1518];
+        let length = wire.recv(&mut frame).unwrap();
+        let outer = IPV4PacketView::<&[u8]>::try_from(&frame[PACKET_INFO_SIZE..length]).unwrap();
+        assert_eq!(outer.header.get_protocol(), IpProtocol::IpIp);
+        assert_eq!(outer.header.get_destination_address(), REMOTE);
+        let reply = IPV4PacketView::<ICMPPacketView>::try_from(outer.payload).unwrap();
+        assert_eq!(reply.header.get_source_address(), local);
+        assert_eq!(reply.header.get_destination_address(), peer);
+        assert_eq!(reply.payload.header.get_message_type(), ECHO_REPLY);
+        assert_eq!(reply.payload.payload, b"\0\x01\0\x02ping");
+    }
+
+    #[test]
+    fn gre_with_key() {
+        let kind = TunnelKind::Gre { key: Some(42) };
+        let inner = inner_packet();
+        let outer = encapsulate(kind, &inner);
+        let packet = IPV4PacketView::<GREPacketView>::try_from(&outer[..]).unwrap();
+        assert_eq!(packet.header.get_protocol(), IpProtocol::Gre);
+        assert_eq!(packet.header.get_source_address(), LOCAL);
+        assert_eq!(packet.header.get_destination_address(), REMOTE);
+        assert_eq!(
+            packet.payload.header.to_mutable(),
+            GREHeader::new(ETHERTYPE_IPV4, Some(42))
+        );
+        assert_eq!(packet.payload.payload, inner);
+        assert_eq!(outer.len(), inner.len() + kind.overhead());
+
+        // The remote endpoint sends the same packet back, with the addresses swapped
+        let reply = reply_of(&outer);
+        assert_eq!(
+            decapsulate(kind, &reply),
+            Some([&[0, 0, 0x08, 0x00], &inner[..]].concat())
+        );
+        assert_eq!(decapsulate(TunnelKind::Gre { key: Some(7) }, &reply), None);
+        assert_eq!(decapsulate(kind, &outer), None);
+    }
+
+    #[test]
+    fn vxlan_carries_frames() {
+        let kind = TunnelKind::Vxlan { vni: 0x123456 };
+        let frame = [&[0xFF; 6][..], &[2, 0, 0, 0, 0, 2], &[0x08, 0x06], &[0; 28]].concat();
+        let outer = encapsulate(kind, &frame);
+        let packet = IPV4PacketView::<UDPPacketView>::try_from(&outer[..]).unwrap();
+        assert_eq!(packet.payload.header.get_destination_port(), VXLAN_PORT);
+        let vxlan = VXLANPacketView::<&[u8]>::try_from(packet.payload.payload).unwrap();
+        assert_eq!(vxlan.header.to_mutable(), VXLANHeader::new(0x123456));
+        assert_eq!(vxlan.payload, frame);
+
+        let reply = reply_of(&outer);
+        // The frame is an ARP request, announced as such to the interface
+        assert_eq!(
+            decapsulate(kind, &reply),
+            Some([&[0, 0, 0x08, 0x06], &frame[..]].concat())
+        );
+    }
+
+    #[test]
+    fn parse_tunnels() {
+        let config: TunnelConfig = "gre,192.168.0.2,192.168.0.1,10.0.0.1/30,42"
+            .parse()
+            .unwrap();
+        assert_eq!(config.kind, TunnelKind::Gre { key: Some(42) });
+        assert_eq!((config.local, config.remote), (LOCAL, REMOTE));
+        assert_eq!(config.interface.prefix_len(), 30);
+        assert!(
+            "ipip,192.168.0.2,192.168.0.1,10.0.0.1/30"
+                .parse::<TunnelConfig>()
+                .is_ok()
+        );
+        assert!(
+            "vxlan,192.168.0.2,192.168.0.1,10.0.0.1/30"
+                .parse::<TunnelConfig>()
+                .is_err()
+        );
+        assert!(
+            "ipip,192.168.0.2,192.168.0.1,10.0.0.1/30,1"
+                .parse::<TunnelConfig>()
+                .is_err()
+        );
+    }
+}
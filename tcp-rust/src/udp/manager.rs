@@ -0,0 +1,339 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{Read, Write},
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    dhcp::{DHCP_CLIENT_PORT, DHCPMessageView, client::DHCPClient},
+    dns::{DNS_PORT, resolver::Resolver, server::DNSServer},
+    ethernet::ETHERTYPE_IPV4,
+    igmp::{IGMPManager, multicast_mac},
+    interface::Interface,
+    ip::{IPV4Header, IPV4Packet, IpProtocol, IpV4Addr},
+    udp::{UDPHeader, UDPPacket, UDPPacketView},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UDPDatagram {
+    pub source_address: IpV4Addr,
+    pub source_port: u16,
+    /// Address of the stack, or the multicast group the datagram was sent to
+    pub destination_address: IpV4Addr,
+    pub payload: Vec<u8>,
+}
+
+/// Handle of a socket, given by `UDPManager::bind`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SocketHandle(usize);
+
+/// Datagrams received on a bound port, waiting to be read by the application
+#[derive(Debug, Clone, Default)]
+pub struct UDPSocket {
+    pub port: u16,
+    pub queue: VecDeque<UDPDatagram>,
+    /// Multicast groups joined, whose datagrams to the port the socket receives
+    pub groups: Vec<IpV4Addr>,
+}
+
+impl UDPSocket {
+    pub fn receive(&mut self) -> Option<UDPDatagram> {
+        self.queue.pop_front()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UDPManager {
+    sockets: BTreeMap<SocketHandle, UDPSocket>,
+    next_socket: usize,
+    pub igmp: IGMPManager,
+    pub dns_server: Option<DNSServer>,
+    pub dhcp_client: Option<DHCPClient>,
+    pub resolver: Resolver,
+}
+
+impl UDPManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind a new socket to `port`. Like with `SO_REUSEPORT`, several sockets may share a port:
+    /// the unicast datagrams go to the first of them, and the multicast ones to each of those
+    /// which joined their group.
+    pub fn bind(&mut self, port: u16) -> SocketHandle {
+        let handle = SocketHandle(self.next_socket);
+        self.next_socket += 1;
+        self.sockets.insert(
+            handle,
+            UDPSocket {
+                port,
+                ..Default::default()
+            },
+        );
+        handle
+    }
+
+    /// Close a socket, leaving its groups
+    pub fn unbind(&mut self, interface: &mut Interface<impl Read + Write>, handle: SocketHandle) {
+        for group in self
+            .sockets
+            .remove(&handle)
+            .into_iter()
+            .flat_map(|socket| socket.groups)
+        {
+            self.igmp.leave(interface, group);
+        }
+    }
+
+    pub fn socket(&mut self, handle: SocketHandle) -> Option<&mut UDPSocket> {
+        self.sockets.get_mut(&handle)
+    }
+
+    /// Have a socket receive the datagrams sent to a multicast group, false when it is not one
+    pub fn join(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        handle: SocketHandle,
+        group: IpV4Addr,
+    ) -> bool {
+        let Some(socket) = self.sockets.get_mut(&handle) else {
+            return false;
+        };
+        if !group.is_multicast() {
+            return false;
+        }
+        if !socket.groups.contains(&group) {
+            socket.groups.push(group);
+            self.igmp.join(interface, group);
+        }
+        true
+    }
+
+    pub fn leave(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        handle: SocketHandle,
+        group: IpV4Addr,
+    ) {
+        let Some(socket) = self.sockets.get_mut(&handle) else {
+            return;
+        };
+        if let Some(i) = socket.groups.iter().position(|joined| *joined == group) {
+            socket.groups.swap_remove(i);
+            self.igmp.leave(interface, group);
+        }
+    }
+
+    /// Send a datagram from a socket to a multicast group, which it does not need to join. Like
+    /// the multicast sockets of the system, the datagram does not leave the link.
+    pub fn send_multicast(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        handle: SocketHandle,
+        group: IpV4Addr,
+        port: u16,
+        payload: &[u8],
+    ) {
+        let Some(socket) = self.sockets.get(&handle) else {
+            return;
+        };
+        let mut ip_header = IPV4Header::new(
+            IpProtocol::Udp,
+            interface.config.address.unwrap_or_default(),
+            group,
+        );
+        ip_header.ttl = 1;
+        let udp_header = UDPHeader {
+            source_port: socket.port,
+            destination_port: port,
+            ..Default::default()
+        };
+        interface.write_frame(
+            multicast_mac(group),
+            ETHERTYPE_IPV4,
+            IPV4Packet::new(ip_header, UDPPacket::new(udp_header, payload)),
+        );
+        interface.send();
+    }
+
+    /// Resolve a name, looking first into the zone served by the stack
+    pub fn resolve(
+        &mut self,
+        interface: &mut Interface<impl Read + Write>,
+        source_address: IpV4Addr,
+        name: &str,
+    ) -> Option<IpV4Addr> {
+        self.dns_server
+            .as_ref()
+            .and_then(|server| server.zone.resolve(name))
+            .or_else(|| self.resolver.resolve(interface, source_address, name))
+    }
+
+    /// Run the timers of the protocols handled over UDP
+    pub fn poll(&mut self, interface: &mut Interface<impl Read + Write>) {
+        self.igmp.poll(interface);
+        if let Some(dhcp_client) = &mut self.dhcp_client {
+            dhcp_client.poll(interface);
+            self.resolver.server = interface.config.dns_server.or(self.resolver.server);
+        }
+    }
+
+    pub fn handle_udp_packet(&mut self, interface: &mut Interface<impl Read + Write>) {
+        let Ok(ip_packet) = interface.try_get_ip_packet::<UDPPacketView>() else {
+            debug!("truncated UDP datagram dropped");
+            return;
+        };
+        let udp_packet = ip_packet.payload;
+
+        match udp_packet.header.get_destination_port() {
+            DNS_PORT if self.dns_server.is_some() => {
+                if let Err(err) = self.dns_server.as_ref().unwrap().handle_packet(interface) {
+                    warn!("could not answer a DNS query: {err}");
+                }
+            }
+            DHCP_CLIENT_PORT if self.dhcp_client.is_some() => {
+                // The message is copied as the client needs to reconfigure the interface
+                let payload = udp_packet.payload.to_vec();
+                if let Ok(message) = DHCPMessageView::try_from(&payload[..]) {
+                    self.dhcp_client
+                        .as_mut()
+                        .unwrap()
+                        .handle_packet(interface, message);
+                }
+            }
+            port if self.resolver.is_waiting_on(port) => self
+                .resolver
+                .handle_datagram(ip_packet.header.get_source_address(), udp_packet),
+            port => {
+                let datagram = UDPDatagram {
+                    source_address: ip_packet.header.get_source_address(),
+                    source_port: udp_packet.header.get_source_port(),
+                    destination_address: ip_packet.header.get_destination_address(),
+                    payload: udp_packet.payload.to_vec(),
+                };
+                let group = datagram.destination_address;
+                let mut sockets = self
+                    .sockets
+                    .values_mut()
+                    .filter(|socket| socket.port == port);
+                if group.is_multicast() {
+                    let mut delivered = false;
+                    for socket in sockets.filter(|socket| socket.groups.contains(&group)) {
+                        socket.queue.push_back(datagram.clone());
+                        delivered = true;
+                    }
+                    if !delivered {
+                        debug!("received an UDP packet for {group}:{port}, not joined");
+                    }
+                } else if let Some(socket) = sockets.next() {
+                    socket.queue.push_back(datagram);
+                } else {
+                    debug!("received an UDP packet on closed port {port}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ip::IPV4PacketView, test_support::Wire, traits::WriteTo};
+
+    const GROUP: IpV4Addr = IpV4Addr(0xE000_00FB);
+    const PEER: IpV4Addr = IpV4Addr(0xC0A8_0001);
+    const MDNS_PORT: u16 = 5353;
+
+    fn receive(
+        manager: &mut UDPManager,
+        interface: &mut Interface<Wire>,
+        destination: IpV4Addr,
+        payload: &[u8],
+    ) {
+        receive_padded(manager, interface, destination, payload, 0);
+    }
+
+    /// Receive a datagram followed by `padding` bytes, like a short Ethernet frame
+    fn receive_padded(
+        manager: &mut UDPManager,
+        interface: &mut Interface<Wire>,
+        destination: IpV4Addr,
+        payload: &[u8],
+        padding: usize,
+    ) {
+        let udp_header = UDPHeader {
+            source_port: MDNS_PORT,
+            destination_port: MDNS_PORT,
+            ..Default::default()
+        };
+        let packet = IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Udp, PEER, destination),
+            UDPPacket::new(udp_header, payload),
+        )
+        .to_bytes()
+        .unwrap();
+        interface.receive_frame(&[&[0, 0, 8, 0][..], &packet, &vec![0; padding]].concat());
+        manager.handle_udp_packet(interface);
+    }
+
+    #[test]
+    fn frame_padding_left_out_of_datagrams() {
+        let mut interface = Interface::new(Wire::default());
+        let mut manager = UDPManager::new();
+        let socket = manager.bind(MDNS_PORT);
+        let destination = IpV4Addr(0xC0A8_0002);
+        // 20 + 8 + 2 bytes padded to the 46 bytes of the smallest Ethernet payload
+        receive_padded(&mut manager, &mut interface, destination, b"hi", 16);
+        let datagram = manager.socket(socket).unwrap().receive().unwrap();
+        assert_eq!(datagram.payload, b"hi");
+    }
+
+    #[test]
+    fn multicast_delivered_to_each_member() {
+        let wire = Wire::default();
+        let mut interface = Interface::new(wire.clone());
+        let mut manager = UDPManager::new();
+        let first = manager.bind(MDNS_PORT);
+        let second = manager.bind(MDNS_PORT);
+        let outsider = manager.bind(MDNS_PORT);
+        assert!(manager.join(&mut interface, first, GROUP));
+        assert!(manager.join(&mut interface, second, GROUP));
+        assert!(!manager.join(&mut interface, outsider, PEER));
+        // A single report for both sockets
+        assert_eq!(wire.sent.borrow().len(), 1);
+
+        receive(&mut manager, &mut interface, GROUP, b"query");
+        receive(
+            &mut manager,
+            &mut interface,
+            IpV4Addr(0xC0A8_0002),
+            b"unicast",
+        );
+        for handle in [first, second] {
+            let datagram = manager.socket(handle).unwrap().receive().unwrap();
+            assert_eq!(
+                (datagram.destination_address, &datagram.payload[..]),
+                (GROUP, &b"query"[..])
+            );
+        }
+        assert_eq!(
+            manager.socket(first).unwrap().receive().unwrap().payload,
+            b"unicast"
+        );
+        assert!(manager.socket(second).unwrap().receive().is_none());
+        assert!(manager.socket(outsider).unwrap().receive().is_none());
+
+        manager.send_multicast(&mut interface, second, GROUP, MDNS_PORT, b"answer");
+        manager.unbind(&mut interface, first);
+        manager.leave(&mut interface, second, GROUP);
+        receive(&mut manager, &mut interface, GROUP, b"query");
+        assert!(manager.socket(second).unwrap().receive().is_none());
+        // The answer, then the leave of the group once no socket is in it
+        let sent = wire.sent.borrow();
+        assert_eq!(sent.len(), 3);
+        let answer = IPV4PacketView::<UDPPacketView>::try_from(&sent[1][4..]).unwrap();
+        assert_eq!(answer.header.get_destination_address(), GROUP);
+        assert_eq!(answer.payload.payload, b"answer");
+    }
+}
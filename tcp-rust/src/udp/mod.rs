@@ -0,0 +1,54 @@
+pub mod manager;
+
+use tcp_rust_macros::PacketHeader;
+
+use crate::{
+    checksum::Checksum,
+    ip::TransportChecksum,
+    packet::{Packet, PacketView},
+    traits::{Data, DataOwned, Prepare},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, PacketHeader)]
+pub struct UDPHeader {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub length: u16,
+    pub checksum: u16,
+}
+
+impl UDPHeader {
+    pub fn answer(&mut self, incoming_packet: UDPHeaderView) {
+        (self.destination_port, self.source_port) = (
+            incoming_packet.get_source_port(),
+            incoming_packet.get_destination_port(),
+        );
+    }
+}
+
+pub type UDPPacket<C = Vec<u8>> = Packet<UDPHeader, C>;
+pub type UDPPacketView<'a, C = &'a [u8]> = PacketView<'a, UDPHeaderView<'a>, C>;
+
+impl<C: DataOwned> Prepare for UDPPacket<C> {
+    fn prepare(&mut self) {
+        self.header.prepare();
+        self.payload.prepare();
+        self.header.length = self.size() as u16;
+    }
+}
+
+impl<C: DataOwned> TransportChecksum for UDPPacket<C> {
+    const CHECKSUM_OFFSET: usize = 6;
+
+    fn set_checksum(&mut self, checksum: u16) {
+        self.header.checksum = checksum;
+    }
+
+    /// A zero checksum means "no checksum" for UDP over IPv4, so it is sent as all ones
+    fn checksum_field(checksum: Checksum) -> u16 {
+        match checksum.ones_complement() {
+            0 => 0xFFFF,
+            checksum => checksum,
+        }
+    }
+}
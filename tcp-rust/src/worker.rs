@@ -0,0 +1,341 @@
+//! Packet processing spread over threads, one per queue of a multi-queue TUN/TAP device.
+//!
+//! Each worker owns the connections of one shard, chosen by a hash of their addresses and ports,
+//! so that no connection is ever shared between threads. The kernel spreads the flows over the
+//! queues with a hash of its own, and a segment read by the wrong worker is handed over to the
+//! owner of its connection. The owner answers on its queue, which the kernel then sends the rest
+//! of the flow to.
+
+use std::{
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    slice,
+    sync::{
+        Arc,
+        mpsc::{self, Receiver, Sender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info};
+
+use crate::{
+    arp::ARPManager,
+    buffer::BufferPool,
+    ethernet::ETHERTYPE_ARP,
+    icmp::{self, ECHO_REQUEST, ICMPPacketView},
+    interface::Interface,
+    ip::{IPV4PacketView, IpProtocol},
+    tcp::{
+        TCPPacketView,
+        manager::{ConnectionId, TCPManager},
+    },
+    udp::manager::UDPManager,
+};
+
+/// Maximum time spent waiting for a packet before running the timers
+const TICK: Duration = Duration::from_millis(100);
+
+/// Event file descriptor waking a worker up when frames are handed over to it
+#[derive(Debug)]
+struct Wakeup(OwnedFd);
+
+impl Wakeup {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    fn notify(&self) {
+        let value = 1u64;
+        unsafe {
+            libc::write(
+                self.0.as_raw_fd(),
+                &raw const value as *const libc::c_void,
+                size_of::<u64>(),
+            )
+        };
+    }
+
+    fn clear(&self) {
+        let mut value = 0u64;
+        unsafe {
+            libc::read(
+                self.0.as_raw_fd(),
+                &raw mut value as *mut libc::c_void,
+                size_of::<u64>(),
+            )
+        };
+    }
+}
+
+/// Way to hand frames over to another worker
+#[derive(Debug, Clone)]
+struct Peer {
+    sender: Sender<Vec<u8>>,
+    wakeup: Arc<Wakeup>,
+}
+
+#[derive(Debug)]
+pub struct Worker<T: Read + Write> {
+    /// Shard of the connections owned by the worker
+    pub index: usize,
+    pub interface: Interface<T>,
+    pub tcp_manager: TCPManager,
+    pub udp_manager: UDPManager,
+    arp_manager: ARPManager,
+    buffer_pool: BufferPool,
+    inbox: Receiver<Vec<u8>>,
+    wakeup: Arc<Wakeup>,
+    peers: Vec<Peer>,
+}
+
+impl<T: Read + Write> Worker<T> {
+    /// One worker per interface, each being a queue of the same device
+    pub fn new_group(interfaces: Vec<Interface<T>>) -> io::Result<Vec<Self>> {
+        let mut inboxes = vec![];
+        let mut peers = vec![];
+        for _ in &interfaces {
+            let (sender, inbox) = mpsc::channel();
+            let wakeup = Arc::new(Wakeup::new()?);
+            peers.push(Peer {
+                sender,
+                wakeup: wakeup.clone(),
+            });
+            inboxes.push((inbox, wakeup));
+        }
+        Ok(interfaces
+            .into_iter()
+            .zip(inboxes)
+            .enumerate()
+            .map(|(index, (interface, (inbox, wakeup)))| Self {
+                index,
+                interface,
+                tcp_manager: TCPManager::new(),
+                udp_manager: UDPManager::new(),
+                arp_manager: ARPManager::new(),
+                buffer_pool: BufferPool::new(),
+                inbox,
+                wakeup,
+                peers: peers.clone(),
+            })
+            .collect())
+    }
+
+    /// Answer the frame in the buffer of the interface, unless its connection belongs to another
+    /// worker
+    pub fn handle_packet(&mut self) {
+        let interface = &mut self.interface;
+        if !interface.is_for_us() {
+            return;
+        }
+        if interface.get_proto() == ETHERTYPE_ARP {
+            self.arp_manager.handle_arp_packet(interface);
+            return;
+        }
+        if !interface.is_ip() {
+            debug!("Not an IP Packet: {}", interface.get_proto());
+            return;
+        }
+        if !interface.is_addressed_to_us() {
+            return;
+        }
+
+        match interface.get_ip_protocol() {
+            Some(IpProtocol::Icmp) => {
+                let Ok(ip_packet) = interface.try_get_packet::<IPV4PacketView<ICMPPacketView>>()
+                else {
+                    debug!("truncated ICMP message dropped");
+                    return;
+                };
+                if ip_packet.payload.header.get_message_type() == ECHO_REQUEST {
+                    icmp::reply_to_echo(interface, &mut self.buffer_pool);
+                }
+            }
+            Some(IpProtocol::Tcp) => {
+                let Ok(ip_packet) = interface.try_get_packet::<IPV4PacketView<TCPPacketView>>()
+                else {
+                    debug!("truncated TCP segment dropped");
+                    return;
+                };
+                let shard = ConnectionId::of(ip_packet).shard(self.peers.len());
+                if shard == self.index {
+                    self.tcp_manager.handle_tcp_packet(interface, 0);
+                } else {
+                    debug!(shard, "segment handed over");
+                    let peer = &self.peers[shard];
+                    // The receiving end only goes away with its worker, when the stack stops
+                    if peer.sender.send(interface.frame().to_vec()).is_ok() {
+                        peer.wakeup.notify();
+                    }
+                }
+            }
+            Some(IpProtocol::Udp) => self.udp_manager.handle_udp_packet(interface),
+            Some(IpProtocol::Igmp) => self.udp_manager.igmp.handle_packet(interface),
+            protocol => debug!("received a non ICMP packet, protocol {protocol:?}"),
+        }
+    }
+
+    /// Handle the frames other workers handed over, returning how many there were
+    pub fn handle_handovers(&mut self) -> usize {
+        let mut handled = 0;
+        while let Ok(frame) = self.inbox.try_recv() {
+            self.interface.receive_frame(&frame);
+            self.handle_packet();
+            handled += 1;
+        }
+        handled
+    }
+}
+
+impl<T: Read + Write + AsRawFd> Worker<T> {
+    /// Receive and answer packets until the device fails
+    pub fn run(mut self) -> io::Result<()> {
+        loop {
+            self.udp_manager.poll(&mut self.interface);
+            self.tcp_manager.poll(slice::from_mut(&mut self.interface));
+            let timeout = self.tcp_manager.next_timer().map_or(TICK, |timer| {
+                timer.saturating_duration_since(Instant::now()).min(TICK)
+            });
+
+            let mut fds =
+                [self.interface.as_raw_fd(), self.wakeup.0.as_raw_fd()].map(|fd| libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            let result = unsafe {
+                libc::poll(
+                    fds.as_mut_ptr(),
+                    fds.len() as libc::nfds_t,
+                    timeout.as_millis() as i32,
+                )
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Clearing the event before emptying the queue, a frame handed over meanwhile
+            // raises it again
+            if fds[1].revents & libc::POLLIN != 0 {
+                self.wakeup.clear();
+                self.handle_handovers();
+            }
+            if fds[0].revents & libc::POLLIN != 0 {
+                self.interface.receive();
+                self.handle_packet();
+            }
+        }
+    }
+}
+
+/// Run each worker on its own thread, until one of them fails
+pub fn run<T: Read + Write + AsRawFd + Send + 'static>(workers: Vec<Worker<T>>) -> io::Result<()> {
+    let (sender, failures) = mpsc::channel();
+    for worker in workers {
+        let sender = sender.clone();
+        let index = worker.index;
+        thread::Builder::new()
+            .name(format!("worker{index}"))
+            .spawn(move || {
+                info!(index, "worker started");
+                let _ = sender.send(worker.run());
+            })?;
+    }
+    drop(sender);
+    // The workers only stop on an error, or all together when they panic
+    failures.recv().unwrap_or(Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ip::{IPV4Header, IPV4Packet, IpV4Addr},
+        tcp::{TCPHeader, TCPPacket},
+        test_support::Wire,
+        traits::WriteTo,
+    };
+
+    fn woken_up(wakeup: &Wakeup) -> bool {
+        let mut fd = libc::pollfd {
+            fd: wakeup.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&raw mut fd, 1, 0) == 1 }
+    }
+
+    /// SYN of a connection owned by the worker `shard` of two
+    fn syn(shard: usize) -> (ConnectionId, Vec<u8>) {
+        let id = (40000..)
+            .map(|peer_port| ConnectionId {
+                local_address: IpV4Addr(0xC0A8_0002),
+                local_port: 80,
+                peer_address: IpV4Addr(0xC0A8_0001),
+                peer_port,
+            })
+            .find(|id| id.shard(2) == shard)
+            .unwrap();
+        let packet = IPV4Packet::new(
+            IPV4Header::new(IpProtocol::Tcp, id.peer_address, id.local_address),
+            TCPPacket::new(
+                TCPHeader {
+                    source_port: id.peer_port,
+                    destination_port: id.local_port,
+                    sequence_number: 1000,
+                    syn: true,
+                    window: u16::MAX,
+                    ..Default::default()
+                },
+                vec![],
+            ),
+        )
+        .to_bytes()
+        .unwrap();
+        (id, [&[0, 0, 8, 0][..], &packet].concat())
+    }
+
+    /// Whether the frames sent on a wire are one SYN-ACK
+    fn answered(wire: &Wire) -> bool {
+        let frames: Vec<_> = wire.sent.borrow_mut().drain(..).collect();
+        let [frame] = &frames[..] else {
+            return false;
+        };
+        let segment = IPV4PacketView::<TCPPacketView>::try_from(&frame[4..]).unwrap();
+        segment.payload.header.get_syn() && segment.payload.header.get_ack()
+    }
+
+    #[test]
+    fn segments_handed_over_to_the_owner_of_their_connection() {
+        let wires = [Wire::default(), Wire::default()];
+        let interfaces = wires.iter().cloned().map(Interface::new).collect();
+        let mut workers = Worker::new_group(interfaces).unwrap();
+
+        // Read by its owner, the segment is answered there without waking anyone up
+        let (id, frame) = syn(0);
+        workers[0].interface.receive_frame(&frame);
+        workers[0].handle_packet();
+        assert!(answered(&wires[0]));
+        assert!(workers[0].tcp_manager.connection(id).is_some());
+        assert!(!woken_up(&workers[1].wakeup));
+
+        // Read by the other worker, it is handed over and only answered by its owner
+        let (id, frame) = syn(1);
+        workers[0].interface.receive_frame(&frame);
+        workers[0].handle_packet();
+        assert!(wires[0].sent.borrow().is_empty());
+        assert!(workers[0].tcp_manager.connection(id).is_none());
+        assert_eq!(workers[0].handle_handovers(), 0);
+        assert!(woken_up(&workers[1].wakeup));
+        workers[1].wakeup.clear();
+        assert!(!woken_up(&workers[1].wakeup));
+        assert_eq!(workers[1].handle_handovers(), 1);
+        assert!(answered(&wires[1]));
+        assert!(workers[1].tcp_manager.connection(id).is_some());
+        assert_eq!(workers[1].handle_handovers(), 0);
+    }
+}
@@ -0,0 +1,599 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    Data, DeriveInput, Error, Expr, Field, Fields, Ident, Lit, LitInt, Type, parse_macro_input,
+    spanned::Spanned,
+};
+
+/// How a field is stored in the header
+enum Kind {
+    Bool,
+    /// Byte aligned `[u8; N]`, returned as is
+    Bytes,
+    /// Unsigned big endian integer, converted from and into the field type
+    Integer(Ident),
+}
+
+struct HeaderField {
+    name: Ident,
+    ty: Type,
+    kind: Kind,
+    offset: usize,
+    bits: usize,
+}
+
+/// Derive the zero-copy view of a header from its owned struct.
+///
+/// Fields are laid out in declaration order, most significant bit first. Integers and booleans
+/// may be narrowed with `#[bits(n)]`, other types must give their width and convert from and into
+/// the unsigned integer holding it. The view is named after the struct with a `View` suffix,
+/// which `#[packet_header(view = Name)]` overrides.
+///
+/// Headers longer than their fixed fields name with `#[packet_header(length = method)]` a method
+/// of the view giving their length in bytes, which `parse` checks against the slice. A last field
+/// marked `#[options]` holds what follows the fixed fields: the view returns those bytes as they
+/// are, and the owned struct implements `Data`, `Prepare`, `WriteTo` and the view `ToMutable` by
+/// hand, since only it knows how to encode them. Without them, `#[packet_header(prepare = method)]`
+/// names a method of the owned struct run by `Prepare`, before the header is written.
+/// ```ignore
+/// #[derive(Debug, Clone, PartialEq, Eq, PacketHeader)]
+/// pub struct ExampleHeader {
+///     #[bits(4)]
+///     pub version: u8,
+///     #[bits(1)]
+///     pub flag: bool,
+///     #[bits(3)]
+///     pub reserved: u8,
+///     pub length: u16,
+///     #[bits(32)]
+///     pub address: IpV4Addr,
+/// }
+/// ```
+/// This generates `ExampleHeaderView` with its getters, a bounds-checked `parse` backing
+/// `TryFrom<&[u8]>`, and the `ToMutable`, `Data`, `AsRef` and `Debug` implementations,
+/// `ExampleHeaderViewMut` with the setters, and the `Data`, `Prepare` and `WriteTo`
+/// implementations of the owned struct.
+#[proc_macro_derive(PacketHeader, attributes(bits, packet_header, options))]
+pub fn derive_packet_header(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    packet_header(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn field_bits(field: &Field) -> syn::Result<Option<usize>> {
+    let mut bits = None;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("bits"))
+    {
+        bits = Some(attr.parse_args::<LitInt>()?.base10_parse()?);
+    }
+    Ok(bits)
+}
+
+/// Smallest unsigned integer holding `bits` bits
+fn integer_type(bits: usize, span: Span) -> syn::Result<Ident> {
+    let name = match bits {
+        1..=8 => "u8",
+        9..=16 => "u16",
+        17..=32 => "u32",
+        33..=64 => "u64",
+        _ => return Err(Error::new(span, "fields are at most 64 bits wide")),
+    };
+    Ok(Ident::new(name, span))
+}
+
+fn parse_field(field: &Field, offset: usize) -> syn::Result<HeaderField> {
+    let name = field.ident.clone().unwrap();
+    let bits = field_bits(field)?;
+    let (kind, bits) = match &field.ty {
+        Type::Path(path) if path.path.is_ident("bool") => (Kind::Bool, bits.unwrap_or(1)),
+        Type::Path(path)
+            if ["u8", "u16", "u32", "u64"]
+                .iter()
+                .any(|name| path.path.is_ident(name)) =>
+        {
+            let ident = path.path.get_ident().unwrap();
+            let width = ident.to_string()[1..].parse().unwrap();
+            let bits = bits.unwrap_or(width);
+            if bits > width {
+                return Err(Error::new(
+                    field.span(),
+                    format!("{ident} has {width} bits"),
+                ));
+            }
+            (Kind::Integer(ident.clone()), bits)
+        }
+        Type::Array(array) => {
+            let Expr::Lit(length) = &array.len else {
+                return Err(Error::new(array.len.span(), "expected a literal length"));
+            };
+            let Lit::Int(length) = &length.lit else {
+                return Err(Error::new(array.len.span(), "expected a literal length"));
+            };
+            if !offset.is_multiple_of(8) {
+                return Err(Error::new(field.span(), "byte arrays must be byte aligned"));
+            }
+            (Kind::Bytes, length.base10_parse::<usize>()? * 8)
+        }
+        _ => {
+            let Some(bits) = bits else {
+                return Err(Error::new(
+                    field.span(),
+                    "the width of this field must be given with #[bits(n)]",
+                ));
+            };
+            (Kind::Integer(integer_type(bits, field.ty.span())?), bits)
+        }
+    };
+    if bits == 0 {
+        return Err(Error::new(field.span(), "fields are at least 1 bit wide"));
+    }
+    // Unaligned fields are read through a 64 bits integer made of the bytes they span
+    if (offset % 8 + bits).div_ceil(8) > 8 {
+        return Err(Error::new(
+            field.span(),
+            "this field spans more than 8 bytes, it must be byte aligned",
+        ));
+    }
+    Ok(HeaderField {
+        name,
+        ty: field.ty.clone(),
+        kind,
+        offset,
+        bits,
+    })
+}
+
+impl HeaderField {
+    fn first_byte(&self) -> usize {
+        self.offset / 8
+    }
+    fn last_byte(&self) -> usize {
+        (self.offset + self.bits).div_ceil(8)
+    }
+    fn is_aligned(&self) -> bool {
+        self.offset.is_multiple_of(8) && matches!(self.bits, 8 | 16 | 32 | 64)
+    }
+
+    /// Bits of the field in the 64 bits integer made of the bytes it spans
+    fn bit_field(&self) -> (TokenStream2, TokenStream2, usize, u64) {
+        let (first, last) = (self.first_byte(), self.last_byte());
+        let shift = last * 8 - (self.offset + self.bits);
+        let mask = u64::MAX >> (64 - self.bits);
+        let span = last - first;
+        let read = quote! {
+            let mut bytes = [0u8; 8];
+            bytes[8 - #span..].copy_from_slice(&self.content[#first..#last]);
+            let word = u64::from_be_bytes(bytes);
+        };
+        let write = quote! {
+            self.content[#first..#last].copy_from_slice(&word.to_be_bytes()[8 - #span..]);
+        };
+        (read, write, shift, mask)
+    }
+
+    fn getter(&self) -> TokenStream2 {
+        let name = format_ident!("get_{}", self.name);
+        let ty = &self.ty;
+        let (first, last) = (self.first_byte(), self.last_byte());
+        let body = match &self.kind {
+            Kind::Bytes => quote! {
+                *unsafe {
+                    crate::traits::AsArrayUnchecked::as_array_unchecked(&self.content[#first..#last])
+                }
+            },
+            Kind::Integer(raw) if self.is_aligned() => quote! {
+                <#ty as From<#raw>>::from(#raw::from_be_bytes(*unsafe {
+                    crate::traits::AsArrayUnchecked::as_array_unchecked(&self.content[#first..#last])
+                }))
+            },
+            kind => {
+                let (read, _, shift, mask) = self.bit_field();
+                let value = match kind {
+                    Kind::Bool => quote! { value != 0 },
+                    Kind::Integer(raw) => quote! { <#ty as From<#raw>>::from(value as #raw) },
+                    Kind::Bytes => unreachable!(),
+                };
+                quote! {
+                    #read
+                    let value = (word >> #shift) & #mask;
+                    #value
+                }
+            }
+        };
+        quote! {
+            pub fn #name(&self) -> #ty {
+                #body
+            }
+        }
+    }
+
+    fn setter(&self) -> TokenStream2 {
+        let name = format_ident!("set_{}", self.name);
+        let ty = &self.ty;
+        let (first, last) = (self.first_byte(), self.last_byte());
+        let body = match &self.kind {
+            Kind::Bytes => quote! {
+                self.content[#first..#last].copy_from_slice(&value);
+            },
+            Kind::Integer(raw) if self.is_aligned() => quote! {
+                self.content[#first..#last]
+                    .copy_from_slice(&<#raw as From<#ty>>::from(value).to_be_bytes());
+            },
+            kind => {
+                let (read, write, shift, mask) = self.bit_field();
+                let value = match kind {
+                    Kind::Bool => quote! { value as u64 },
+                    Kind::Integer(raw) => quote! { <#raw as From<#ty>>::from(value) as u64 },
+                    Kind::Bytes => unreachable!(),
+                };
+                quote! {
+                    #read
+                    let word = (word & !(#mask << #shift)) | ((#value & #mask) << #shift);
+                    #write
+                }
+            }
+        };
+        quote! {
+            pub fn #name(&mut self, value: #ty) {
+                #body
+            }
+        }
+    }
+}
+
+fn packet_header(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(
+            input.span(),
+            "only structs can be packet headers",
+        ));
+    };
+    let Fields::Named(named) = &data.fields else {
+        return Err(Error::new(
+            input.span(),
+            "packet header fields must be named",
+        ));
+    };
+
+    let owned = &input.ident;
+    let vis = &input.vis;
+    let mut view = format_ident!("{owned}View");
+    let mut length = None;
+    let mut prepare = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("packet_header"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("view") {
+                view = meta.value()?.parse()?;
+            } else if meta.path.is_ident("length") {
+                length = Some(meta.value()?.parse::<Ident>()?);
+            } else if meta.path.is_ident("prepare") {
+                prepare = Some(meta.value()?.parse::<Ident>()?);
+            } else {
+                return Err(meta.error("unknown packet_header attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    let view_mut = format_ident!("{view}Mut");
+    let view_name = view.to_string();
+
+    let mut fields = vec![];
+    let mut options = None;
+    let mut offset = 0;
+    for field in &named.named {
+        if options.is_some() {
+            return Err(Error::new(
+                field.span(),
+                "the options must be the last field",
+            ));
+        }
+        if field
+            .attrs
+            .iter()
+            .any(|attr| attr.path().is_ident("options"))
+        {
+            if length.is_none() {
+                return Err(Error::new(
+                    field.span(),
+                    "options need the length of the header, #[packet_header(length = method)]",
+                ));
+            }
+            if prepare.is_some() {
+                return Err(Error::new(
+                    field.span(),
+                    "headers with options implement `Prepare` by hand",
+                ));
+            }
+            options = field.ident.clone();
+            continue;
+        }
+        let field = parse_field(field, offset)?;
+        offset += field.bits;
+        fields.push(field);
+    }
+    if !offset.is_multiple_of(8) {
+        return Err(Error::new(
+            input.span(),
+            format!("the header is {offset} bits long, which is not a whole number of bytes"),
+        ));
+    }
+    let size = offset / 8;
+
+    let names: Vec<_> = fields.iter().map(|field| &field.name).collect();
+    let name_strings: Vec<_> = names.iter().map(|name| name.to_string()).collect();
+    let getters: Vec<_> = names
+        .iter()
+        .map(|name| format_ident!("get_{name}"))
+        .collect();
+    let setters: Vec<_> = names
+        .iter()
+        .map(|name| format_ident!("set_{name}"))
+        .collect();
+    let getter_fns = fields.iter().map(HeaderField::getter);
+    let setter_fns = fields.iter().map(HeaderField::setter);
+
+    // A header with options is only as long as it says, the slice going beyond it
+    let (parse_body, view_size) = match &length {
+        Some(length) => (
+            quote! {
+                let Some(fixed) = value.get(..Self::SIZE) else {
+                    return Err(crate::packet::ParseHeaderError);
+                };
+                let length = Self { content: fixed }.#length();
+                match value.get(..length) {
+                    Some(content) if length >= Self::SIZE => Ok(Self { content }),
+                    _ => Err(crate::packet::ParseHeaderError),
+                }
+            },
+            quote! { self.content.len() },
+        ),
+        None => (
+            quote! {
+                match value.get(..Self::SIZE) {
+                    Some(content) => Ok(Self { content }),
+                    None => Err(crate::packet::ParseHeaderError),
+                }
+            },
+            quote! { #size },
+        ),
+    };
+    let (options_getter, options_debug) = match &options {
+        Some(options) => {
+            let getter = format_ident!("get_{options}");
+            let name = options.to_string();
+            (
+                quote! {
+                    /// Bytes following the fixed fields, up to the length of the header
+                    pub fn #getter(&self) -> &'a [u8] {
+                        &self.content[Self::SIZE..]
+                    }
+                },
+                quote! { .field(#name, &self.#getter()) },
+            )
+        }
+        None => (quote! {}, quote! {}),
+    };
+    let prepare_body = match &prepare {
+        Some(prepare) => quote! {
+            fn prepare(&mut self) {
+                self.#prepare();
+            }
+        },
+        None => quote! {},
+    };
+    // The options are encoded by hand, along with the rest of the owned struct
+    let owned_impls = match options {
+        Some(_) => quote! {},
+        None => quote! {
+            impl crate::traits::ToMutable for #view<'_> {
+                type MutableType = #owned;
+
+                fn to_mutable(&self) -> Self::MutableType {
+                    #owned {
+                        #(#names: self.#getters(),)*
+                    }
+                }
+            }
+
+            impl crate::traits::Prepare for #owned {
+                #prepare_body
+            }
+            impl crate::traits::Data for #owned {
+                fn size(&self) -> usize {
+                    #size
+                }
+            }
+            impl crate::traits::WriteTo for #owned {
+                fn write_to_inner<W: std::io::Write>(&mut self, writer: &mut W) -> std::io::Result<usize> {
+                    let mut content = [0u8; #size];
+                    let mut header = #view_mut { content: &mut content };
+                    #(header.#setters(self.#names.clone());)*
+                    writer.write_all(&content)?;
+                    Ok(#size)
+                }
+            }
+        },
+    };
+
+    Ok(quote! {
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+        #vis struct #view<'a> {
+            content: &'a [u8],
+        }
+
+        impl<'a> #view<'a> {
+            pub const SIZE: usize = #size;
+
+            /// Parse a header, failing if the slice is too short to hold it
+            pub fn parse(value: &'a [u8]) -> Result<Self, crate::packet::ParseHeaderError> {
+                #parse_body
+            }
+
+            #(#getter_fns)*
+
+            #options_getter
+        }
+
+        impl<'a> TryFrom<&'a [u8]> for #view<'a> {
+            type Error = crate::packet::ParseHeaderError;
+
+            fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+                Self::parse(value)
+            }
+        }
+
+        impl<'a> AsRef<[u8]> for #view<'a> {
+            fn as_ref(&self) -> &[u8] {
+                self.content
+            }
+        }
+
+        impl crate::traits::Data for #view<'_> {
+            fn size(&self) -> usize {
+                #view_size
+            }
+        }
+
+        impl std::fmt::Debug for #view<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct(#view_name)
+                    #(.field(#name_strings, &self.#getters()))*
+                    #options_debug
+                    .finish()
+            }
+        }
+
+        /// Header in a mutable buffer, to patch its fields in place
+        #vis struct #view_mut<'a> {
+            content: &'a mut [u8],
+        }
+
+        impl<'a> #view_mut<'a> {
+            #(#setter_fns)*
+
+            pub fn view(&self) -> #view<'_> {
+                #view { content: self.content }
+            }
+        }
+
+        impl<'a> TryFrom<&'a mut [u8]> for #view_mut<'a> {
+            type Error = crate::packet::ParseHeaderError;
+
+            fn try_from(value: &'a mut [u8]) -> Result<Self, Self::Error> {
+                let size = crate::traits::Data::size(&#view::parse(value)?);
+                Ok(Self {
+                    content: &mut value[..size],
+                })
+            }
+        }
+
+        #owned_impls
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::parse_quote;
+
+    use super::*;
+
+    fn error(input: DeriveInput) -> String {
+        packet_header(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn fields_laid_out_most_significant_bit_first() {
+        let fields: Vec<Field> = vec![
+            parse_quote!(#[bits(4)] version: u8),
+            parse_quote!(#[bits(12)] length: u16),
+            parse_quote!(#[bits(32)] address: IpV4Addr),
+        ];
+        let mut offset = 0;
+        let fields = fields
+            .iter()
+            .map(|field| {
+                let field = parse_field(field, offset).unwrap();
+                offset += field.bits;
+                field
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(offset, 48);
+        assert_eq!((fields[0].first_byte(), fields[0].last_byte()), (0, 1));
+        assert_eq!((fields[1].first_byte(), fields[1].last_byte()), (0, 2));
+        assert!(!fields[1].is_aligned() && fields[2].is_aligned());
+        assert!(matches!(&fields[2].kind, Kind::Integer(raw) if raw == "u32"));
+        // The 12 low bits of the two first bytes
+        let (_, _, shift, mask) = fields[1].bit_field();
+        assert_eq!((shift, mask), (0, 0xFFF));
+    }
+
+    #[test]
+    fn invalid_headers_rejected() {
+        assert!(
+            error(parse_quote!(
+                struct A {
+                    #[bits(9)]
+                    a: u8,
+                }
+            ))
+            .contains("u8 has 8 bits")
+        );
+        assert!(
+            error(parse_quote!(
+                struct A {
+                    a: IpV4Addr,
+                }
+            ))
+            .contains("#[bits(n)]")
+        );
+        assert!(
+            error(parse_quote!(
+                struct A {
+                    #[bits(4)]
+                    a: u8,
+                    b: [u8; 2],
+                }
+            ))
+            .contains("byte aligned")
+        );
+        assert!(
+            error(parse_quote!(
+                struct A {
+                    #[bits(4)]
+                    a: u8,
+                    b: u64,
+                }
+            ))
+            .contains("more than 8 bytes")
+        );
+        assert!(
+            error(parse_quote!(
+                struct A {
+                    #[options]
+                    a: Vec<u8>,
+                }
+            ))
+            .contains("length")
+        );
+        assert!(
+            error(parse_quote!(
+                #[packet_header(length = length)]
+                struct A {
+                    #[options]
+                    a: Vec<u8>,
+                    b: u8,
+                }
+            ))
+            .contains("last")
+        );
+    }
+}